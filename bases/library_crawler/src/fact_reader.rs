@@ -40,6 +40,8 @@ pub struct AggregatedTrack {
     pub encoder_software: Option<String>,
     pub encoded_by: Option<String>,
     pub fact_count: usize,
+    /// Every copy's path not retracted yet, newest last
+    paths: Vec<PathBuf>,
 }
 
 /// Implement FactAggregator for AggregatedTrack
@@ -50,7 +52,11 @@ impl FactAggregator<ContentHash, MusicValue, FactSource> for AggregatedTrack {
         self.fact_count += 1;
 
         match value {
-            FilePath(path) => self.file_path = Some(path.clone()),
+            FilePath(path) => {
+                self.paths.retain(|copy| copy != path);
+                self.paths.push(path.clone());
+                self.file_path = Some(path.clone());
+            }
             Title(s) => self.title = Some(s.clone()),
             Artist(s) => self.artist = Some(s.clone()),
             Album(s) => self.album = Some(s.clone()),
@@ -92,7 +98,11 @@ impl FactAggregator<ContentHash, MusicValue, FactSource> for AggregatedTrack {
 
         // For cardinality-one fields, retract sets to None
         match value {
-            FilePath(_) => self.file_path = None,
+            // Duplicate files share a hash, so fall back to a surviving copy
+            FilePath(path) => {
+                self.paths.retain(|copy| copy != path);
+                self.file_path = self.paths.last().cloned();
+            }
             Title(_) => self.title = None,
             Artist(_) => self.artist = None,
             Album(_) => self.album = None,
@@ -147,20 +157,59 @@ impl FactAggregator<ContentHash, MusicValue, FactSource> for AggregatedTrack {
     }
 }
 
-/// Read and aggregate facts from a fact stream file
-pub fn read_and_aggregate(path: impl AsRef<Path>) -> Result<HashMap<ContentHash, AggregatedTrack>> {
-    let mut reader = FactStreamReader::open(path)?;
+/// Values asserted about a track and not retracted since, with their sources
+///
+/// Unlike [`AggregatedTrack`] nothing is overwritten, every value is kept
+/// until it is retracted, so a rescan can tell what it has to change.
+#[derive(Debug, Default)]
+pub struct Asserted(pub Vec<(MusicValue, FactSource)>);
 
-    let mut facts = Vec::new();
+impl Asserted {
+    pub fn contains(&self, value: &MusicValue) -> bool {
+        self.0.iter().any(|(asserted, _)| asserted == value)
+    }
 
-    // Read all facts from the stream
-    while let Some(result) = reader.next() {
-        let fact: Fact<ContentHash, MusicValue, FactSource> = result?;
-        facts.push(fact);
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.0.iter().filter_map(|(value, _)| match value {
+            MusicValue::FilePath(path) => Some(path),
+            _ => None,
+        })
+    }
+}
+
+impl FactAggregator<ContentHash, MusicValue, FactSource> for Asserted {
+    fn assert(&mut self, value: &MusicValue, source: &FactSource) {
+        if !self.contains(value) {
+            self.0.push((value.clone(), source.clone()));
+        }
     }
 
+    fn retract(&mut self, value: &MusicValue, _source: &FactSource) {
+        self.0.retain(|(asserted, _)| asserted != value);
+    }
+
+    fn assert_unknown(
+        &mut self,
+        _attribute: &str,
+        _value: &serde_json::Value,
+        _source: &FactSource,
+    ) {
+        // Left to the tool that knows them, a rescan never touches them
+    }
+
+    fn retract_unknown(
+        &mut self,
+        _attribute: &str,
+        _value: &serde_json::Value,
+        _source: &FactSource,
+    ) {
+    }
+}
+
+/// Read and aggregate facts from a fact stream file
+pub fn read_and_aggregate(path: impl AsRef<Path>) -> Result<HashMap<ContentHash, AggregatedTrack>> {
     // Use stainless-facts aggregate_facts function
-    let mut aggregated: HashMap<ContentHash, AggregatedTrack> = aggregate_facts(facts);
+    let mut aggregated: HashMap<ContentHash, AggregatedTrack> = aggregate_facts(read_facts(path)?);
 
     // Set entity field on each track
     for (entity, track) in aggregated.iter_mut() {
@@ -170,6 +219,24 @@ pub fn read_and_aggregate(path: impl AsRef<Path>) -> Result<HashMap<ContentHash,
     Ok(aggregated)
 }
 
+/// What each track has asserted about it, for diffing a rescan against
+pub fn read_asserted(path: impl AsRef<Path>) -> Result<HashMap<ContentHash, Asserted>> {
+    Ok(aggregate_facts(read_facts(path)?))
+}
+
+fn read_facts(path: impl AsRef<Path>) -> Result<Vec<Fact<ContentHash, MusicValue, FactSource>>> {
+    let mut reader = FactStreamReader::open(path)?;
+
+    let mut facts = Vec::new();
+
+    // Read all facts from the stream
+    while let Some(result) = reader.next() {
+        let fact: Fact<ContentHash, MusicValue, FactSource> = result?;
+        facts.push(fact);
+    }
+    Ok(facts)
+}
+
 impl AggregatedTrack {
     /// Get display name for track
     pub fn display_name(&self) -> String {
@@ -305,4 +372,46 @@ mod tests {
         // Should have the second title, not the first
         assert_eq!(track.title, Some("Second Title".to_string()));
     }
+
+    #[test]
+    fn retracting_one_copy_keeps_the_other() {
+        let temp = NamedTempFile::new().unwrap();
+        let content_hash = ContentHash("test_hash_789".to_string());
+        let source = FactSource::new("test", "1.0.0", FactOrigin::Unknown);
+        let now = Utc::now();
+        let path = |name: &str| MusicValue::FilePath(PathBuf::from(name));
+
+        let facts = vec![
+            Fact::new(
+                content_hash.clone(),
+                path("/music/a.flac"),
+                now,
+                source.clone(),
+                Operation::Assert,
+            ),
+            Fact::new(
+                content_hash.clone(),
+                path("/music/copy/a.flac"),
+                now,
+                source.clone(),
+                Operation::Assert,
+            ),
+            Fact::new(
+                content_hash.clone(),
+                path("/music/copy/a.flac"),
+                now,
+                source.clone(),
+                Operation::Retract,
+            ),
+        ];
+
+        let mut writer = FactStreamWriter::open(temp.path()).unwrap();
+        writer.write_batch(&facts).unwrap();
+        drop(writer);
+
+        let aggregated = read_and_aggregate(temp.path()).unwrap();
+        let track = aggregated.get(&content_hash).unwrap();
+
+        assert_eq!(track.file_path, Some(PathBuf::from("/music/a.flac")));
+    }
 }
@@ -1,33 +1,12 @@
 use color_eyre::Result;
 use music_facts::ContentHash;
-use sha2::{Digest, Sha256};
-use std::fs::File;
-use std::io::{BufReader, Read};
 use std::path::Path;
 
 /// Compute SHA256 hash of file contents
 /// 
 /// This is the entity ID for tracks in the fact stream
 pub fn compute_content_hash(path: &Path) -> Result<ContentHash> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut hasher = Sha256::new();
-    
-    // Read file in chunks and update hash
-    let mut buffer = [0u8; 8192];
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        hasher.update(&buffer[..bytes_read]);
-    }
-    
-    // Finalize and convert to hex string
-    let result = hasher.finalize();
-    let hash_string = hex::encode(result);
-    
-    Ok(ContentHash(format!("sha256:{}", hash_string)))
+    Ok(ContentHash::from_file(path)?)
 }
 
 #[cfg(test)]
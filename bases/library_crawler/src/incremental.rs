@@ -0,0 +1,570 @@
+use crate::fact_reader::Asserted;
+use crate::pipeline::read_all;
+use atomic_file::write_atomically;
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use music_facts::{ContentHash, FactSource, MusicValue};
+use serde::{Deserialize, Serialize};
+use stainless_facts::{Fact, Operation};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+/// What a file gave when it was last read
+pub type FileFacts = (ContentHash, Vec<(MusicValue, FactSource)>);
+
+/// Size and modification time of a file, a change in either means reading it again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl FileStamp {
+    pub fn of(metadata: &Metadata) -> std::io::Result<Self> {
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub stamp: FileStamp,
+    pub hash: ContentHash,
+}
+
+/// Every file a scan read, kept beside the facts file between runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScanIndex {
+    pub files: BTreeMap<PathBuf, IndexEntry>,
+    /// Files that could not be read, not tried again until they change
+    #[serde(default)]
+    pub failed: BTreeMap<PathBuf, FileStamp>,
+}
+
+impl ScanIndex {
+    /// Index from an earlier scan, empty when there hasn't been one
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Keep the index for the next run to pick up
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, &serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+}
+
+/// Where the index for a facts file is kept, "facts.jsonl" has "facts.index.json"
+pub fn index_path(facts_path: &Path) -> PathBuf {
+    facts_path.with_extension("index.json")
+}
+
+/// The part of a library a rescan looks at
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    /// Files and directories that may have changed, everything when `None`
+    pub paths: Option<Vec<PathBuf>>,
+    /// Files and directories seen being renamed, from where to where
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Scope {
+    fn contains(&self, path: &Path) -> bool {
+        let paths = self.paths.as_deref();
+        paths.is_none_or(|paths| paths.iter().any(|changed| path.starts_with(changed)))
+    }
+
+    // Where `path` was before a rename, if it was renamed
+    fn renamed_from(&self, path: &Path) -> Option<PathBuf> {
+        self.renamed
+            .iter()
+            .find_map(|(from, to)| Some(from.join(path.strip_prefix(to).ok()?)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Assert,
+    Retract,
+}
+
+/// One fact to write for the facts to match the files again
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub kind: Kind,
+    pub hash: ContentHash,
+    pub value: MusicValue,
+    pub source: FactSource,
+}
+
+impl Change {
+    pub fn into_fact(self, at: DateTime<Utc>) -> Fact<ContentHash, MusicValue, FactSource> {
+        let operation = match self.kind {
+            Kind::Assert => Operation::Assert,
+            Kind::Retract => Operation::Retract,
+        };
+        Fact::new(self.hash, self.value, at, self.source, operation)
+    }
+}
+
+/// The outcome of rescanning a library against what is already known
+#[derive(Debug, Default)]
+pub struct Rescan {
+    /// Retractions and then assertions for each track that changed
+    pub changes: Vec<Change>,
+    pub index: ScanIndex,
+    /// Files read again, new or changed or sharing a track with one that was
+    pub read: usize,
+    pub unchanged: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl Rescan {
+    pub fn count(&self, kind: Kind) -> usize {
+        self.changes
+            .iter()
+            .filter(|change| change.kind == kind)
+            .count()
+    }
+}
+
+/// Find what changed in the FLAC files under `root` since `previous`
+///
+/// Only the files in `scope` are looked at, the rest are taken to be as
+/// they were. Files with the size and modification time they had are not
+/// read again, nor are those that could not be read last time. Nor is a new
+/// file with the size and modification time of a file that went missing,
+/// it is taken to have moved there when it was seen renamed from there or
+/// when only one file went missing with that stamp. A track is
+/// made up of the files with its content hash, and only tracks that gained
+/// or lost a file or had one change are compared with `state`, the facts
+/// asserted so far. A moved file keeps its hash, so all that changes is its
+/// path. Tracks with no file left under `root` have everything about them
+/// retracted, tracks elsewhere are left alone. Files that do have to be
+/// read are read on `workers` threads.
+pub fn rescan(
+    root: &Path,
+    scope: &Scope,
+    previous: &ScanIndex,
+    state: &HashMap<ContentHash, Asserted>,
+    workers: usize,
+    read: impl Fn(&Path) -> Result<FileFacts> + Sync,
+) -> Rescan {
+    let mut rescan = Rescan::default();
+    // What touched tracks should have asserted, from all their files
+    let mut wanted: HashMap<ContentHash, Vec<(MusicValue, FactSource)>> = HashMap::new();
+    let mut unchanged = Vec::new();
+    // New or changed files, read once it is known which of them only moved
+    let mut fresh = Vec::new();
+
+    for (path, known) in &previous.files {
+        if !scope.contains(path) {
+            unchanged.push((path.clone(), known.hash.clone()));
+            rescan.index.files.insert(path.clone(), known.clone());
+        }
+    }
+    for (path, stamp) in &previous.failed {
+        if !scope.contains(path) {
+            rescan.index.failed.insert(path.clone(), *stamp);
+        }
+    }
+
+    // Each changed directory once, not again for what changed inside it
+    let mut tops: Vec<&Path> = match &scope.paths {
+        Some(paths) => paths.iter().map(PathBuf::as_path).collect(),
+        None => vec![root],
+    };
+    tops.sort();
+    tops.dedup_by(|inside, outside| inside.starts_with(outside));
+    let walked = tops.into_iter().flat_map(|top| {
+        WalkDir::new(top)
+            .follow_links(false)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+    });
+
+    for entry in walked {
+        let path = entry.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("flac") {
+            continue;
+        }
+        let stamp = entry
+            .metadata()
+            .map_err(std::io::Error::from)
+            .and_then(|metadata| FileStamp::of(&metadata));
+        let stamp = match stamp {
+            Ok(stamp) => stamp,
+            Err(e) => {
+                rescan.failed.push((path.to_path_buf(), e.to_string()));
+                continue;
+            }
+        };
+
+        let known = previous.files.get(path);
+        if let Some(known) = known.filter(|known| known.stamp == stamp) {
+            unchanged.push((path.to_path_buf(), known.hash.clone()));
+            rescan.index.files.insert(path.to_path_buf(), known.clone());
+            continue;
+        }
+        if previous.failed.get(path) == Some(&stamp) {
+            rescan.index.failed.insert(path.to_path_buf(), stamp);
+            if let Some(known) = known {
+                rescan.index.files.insert(path.to_path_buf(), known.clone());
+            }
+            continue;
+        }
+        fresh.push((path.to_path_buf(), stamp));
+    }
+
+    // Files that went missing, by the stamp a moved file would keep
+    let changed: HashSet<&PathBuf> = fresh.iter().map(|(path, _)| path).collect();
+    let mut missing: HashMap<FileStamp, Vec<&PathBuf>> = HashMap::new();
+    for (path, entry) in &previous.files {
+        if !rescan.index.files.contains_key(path) && !changed.contains(path) {
+            missing.entry(entry.stamp).or_default().push(path);
+        }
+    }
+    let mut moved = Vec::new();
+    let mut unread = Vec::new();
+    for (path, stamp) in fresh {
+        let from = missing.get_mut(&stamp).and_then(|paths| {
+            let renamed = scope.renamed_from(&path);
+            match renamed.and_then(|old| paths.iter().position(|p| **p == old)) {
+                Some(i) => Some(paths.swap_remove(i)),
+                None if paths.len() == 1 => paths.pop(),
+                None => None,
+            }
+        });
+        if let Some(from) = from {
+            let entry = previous.files[from].clone();
+            unchanged.push((path.clone(), entry.hash.clone()));
+            moved.push((from, path.clone(), entry.hash.clone()));
+            rescan.index.files.insert(path, entry);
+            continue;
+        }
+        unread.push((path, stamp));
+    }
+    let (paths, stamps): (Vec<_>, Vec<_>) = unread.into_iter().unzip();
+    for ((path, facts), stamp) in read_all(paths, workers, &read).into_iter().zip(stamps) {
+        let known = previous.files.get(&path);
+        match facts {
+            Ok((hash, facts)) => {
+                rescan.read += 1;
+                wanted.entry(hash.clone()).or_default().extend(facts);
+                // Whatever the file held before has lost it
+                if let Some(known) = known {
+                    wanted.entry(known.hash.clone()).or_default();
+                }
+                rescan.index.files.insert(path, IndexEntry { stamp, hash });
+            }
+            Err(e) => {
+                rescan.failed.push((path.clone(), e.to_string()));
+                rescan.index.failed.insert(path.clone(), stamp);
+                // Likely still being written, what it was stays until it can be read
+                if let Some(known) = known {
+                    rescan.index.files.insert(path, known.clone());
+                }
+            }
+        }
+    }
+
+    // Tracks that lost a file, what copies they have left is what they should have
+    for from in missing.into_values().flatten() {
+        wanted.entry(previous.files[from].hash.clone()).or_default();
+    }
+
+    // Tracks that lost their last file here
+    let present: HashSet<_> = rescan
+        .index
+        .files
+        .values()
+        .map(|entry| &entry.hash)
+        .collect();
+    for (hash, asserted) in state {
+        if !present.contains(hash) && asserted.paths().any(|path| path.starts_with(root)) {
+            wanted.entry(hash.clone()).or_default();
+        }
+    }
+
+    // Unchanged copies of a touched track are part of what it should have
+    let (copies, left): (Vec<_>, Vec<_>) = unchanged
+        .into_iter()
+        .partition(|(_, hash)| wanted.contains_key(hash));
+    rescan.unchanged += left.len();
+    let (paths, hashes): (Vec<_>, Vec<_>) = copies.into_iter().unzip();
+    for ((path, more), hash) in read_all(paths, workers, &read).into_iter().zip(hashes) {
+        match more {
+            Ok((_, more)) => {
+                rescan.read += 1;
+                wanted.entry(hash).or_default().extend(more);
+            }
+            Err(e) => rescan.failed.push((path, e.to_string())),
+        }
+    }
+
+    // Moved files of tracks otherwise left alone only change their path
+    let touched: HashSet<ContentHash> = wanted.keys().cloned().collect();
+    for (from, to, hash) in moved {
+        let Some(asserted) = state.get(&hash).filter(|_| !touched.contains(&hash)) else {
+            continue;
+        };
+        let facts = wanted.entry(hash).or_insert_with(|| asserted.0.clone());
+        for (value, _) in facts.iter_mut() {
+            if *value == MusicValue::FilePath(from.clone()) {
+                *value = MusicValue::FilePath(to.clone());
+            }
+        }
+    }
+
+    // Tracks in hash order, so the same changes always come out the same
+    let mut wanted: Vec<_> = wanted.into_iter().collect();
+    wanted.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+    for (hash, facts) in wanted {
+        let asserted = state.get(&hash);
+        rescan.changes.extend(differences(&hash, asserted, facts));
+    }
+    rescan
+}
+
+// Retract what is no longer so, then assert what is new
+fn differences(
+    hash: &ContentHash,
+    asserted: Option<&Asserted>,
+    wanted: Vec<(MusicValue, FactSource)>,
+) -> Vec<Change> {
+    let change = |kind, value: &MusicValue, source: &FactSource| Change {
+        kind,
+        hash: hash.clone(),
+        value: value.clone(),
+        source: source.clone(),
+    };
+    let mut changes = Vec::new();
+    for (value, source) in asserted.map_or(&[][..], |asserted| &asserted.0) {
+        if !wanted.iter().any(|(wanted, _)| wanted == value) {
+            changes.push(change(Kind::Retract, value, source));
+        }
+    }
+
+    let mut asserting: Vec<&MusicValue> = Vec::new();
+    for (value, source) in &wanted {
+        let known = asserted.is_some_and(|asserted| asserted.contains(value));
+        if known || asserting.contains(&value) {
+            continue;
+        }
+        asserting.push(value);
+        changes.push(change(Kind::Assert, value, source));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{changed, title, Library};
+    use music_facts::FactOrigin;
+    use stainless_facts::FactAggregator;
+
+    #[test]
+    fn new_files_are_asserted_once() {
+        let mut library = Library::new();
+        library.write("a.flac", "Opener");
+        library.write("notes.txt", "not music");
+
+        let first = library.rescan();
+        assert_eq!((first.read, first.count(Kind::Assert)), (1, 2));
+        let path = MusicValue::FilePath(library.path("a.flac"));
+        assert_eq!(library.values("a.flac"), vec![path, title("Opener")]);
+
+        let again = library.rescan();
+        assert!(again.changes.is_empty());
+        assert_eq!((again.read, again.unchanged), (0, 1));
+    }
+
+    #[test]
+    fn modified_files_move_to_their_new_hash() {
+        let mut library = Library::new();
+        library.write("a.flac", "Opener");
+        library.rescan();
+        let old = ContentHash::from_file(&library.path("a.flac")).unwrap();
+
+        library.write("a.flac", "Opener (Extended Mix)");
+        let rescan = library.rescan();
+        assert_eq!(rescan.read, 1);
+        assert_eq!(rescan.count(Kind::Retract), 2);
+        assert_eq!(rescan.count(Kind::Assert), 2);
+        assert!(library.state[&old].0.is_empty());
+        assert!(library
+            .values("a.flac")
+            .contains(&title("Opener (Extended Mix)")));
+    }
+
+    #[test]
+    fn deleted_files_are_retracted() {
+        let mut library = Library::new();
+        library.write("a.flac", "Opener");
+        library.write("b.flac", "Closer");
+        library.rescan();
+        let gone = ContentHash::from_file(&library.path("b.flac")).unwrap();
+
+        std::fs::remove_file(library.path("b.flac")).unwrap();
+        let rescan = library.rescan();
+        assert_eq!(rescan.read, 0);
+        assert_eq!(rescan.count(Kind::Retract), 2);
+        assert_eq!(rescan.count(Kind::Assert), 0);
+        assert!(library.state[&gone].0.is_empty());
+        assert!(!library.index.files.contains_key(&library.path("b.flac")));
+    }
+
+    #[test]
+    fn deleting_one_copy_keeps_the_other() {
+        let mut library = Library::new();
+        library.write("a.flac", "Opener");
+        library.write("b.flac", "Opener");
+        library.rescan();
+
+        std::fs::remove_file(library.path("a.flac")).unwrap();
+        let rescan = library.rescan();
+        assert_eq!(
+            changed(&rescan.changes),
+            vec![(Kind::Retract, MusicValue::FilePath(library.path("a.flac")))]
+        );
+        let values = library.values("b.flac");
+        assert!(values.contains(&MusicValue::FilePath(library.path("b.flac"))));
+        assert!(values.contains(&title("Opener")));
+    }
+
+    #[test]
+    fn moved_files_only_change_their_path() {
+        let mut library = Library::new();
+        library.write("a.flac", "Opener");
+        library.rescan();
+
+        std::fs::create_dir(library.path("sorted")).unwrap();
+        std::fs::rename(library.path("a.flac"), library.path("sorted/a.flac")).unwrap();
+        let rescan = library.rescan();
+        assert_eq!(
+            changed(&rescan.changes),
+            vec![
+                (Kind::Retract, MusicValue::FilePath(library.path("a.flac"))),
+                (
+                    Kind::Assert,
+                    MusicValue::FilePath(library.path("sorted/a.flac"))
+                ),
+            ]
+        );
+        assert_eq!(library.values("sorted/a.flac").len(), 2);
+    }
+
+    #[test]
+    fn moved_files_are_not_read_again() {
+        let mut library = Library::new();
+        library.write("a.flac", "Opener");
+        library.write("b.flac", "Closer");
+        library.rescan();
+
+        std::fs::create_dir(library.path("sorted")).unwrap();
+        std::fs::rename(library.path("a.flac"), library.path("sorted/a.flac")).unwrap();
+        let rescan = library.rescan();
+        assert_eq!((rescan.read, rescan.unchanged), (0, 2));
+        assert_eq!(rescan.count(Kind::Retract), 1);
+        assert_eq!(rescan.count(Kind::Assert), 1);
+        assert!(library
+            .index
+            .files
+            .contains_key(&library.path("sorted/a.flac")));
+        let values = library.values("sorted/a.flac");
+        assert_eq!(values.len(), 2);
+        assert!(values.contains(&MusicValue::FilePath(library.path("sorted/a.flac"))));
+    }
+
+    #[test]
+    fn unreadable_files_wait_until_they_change() {
+        let mut library = Library::new();
+        library.write("a.flac", "");
+        let mut rescan = || {
+            let rescan = library.rescan();
+            (rescan.read, rescan.failed.len())
+        };
+
+        assert_eq!(rescan(), (0, 1));
+        assert_eq!(rescan(), (0, 0));
+        library.write("a.flac", "Opener");
+        assert_eq!(library.rescan().read, 1);
+        assert!(library.index.failed.is_empty());
+    }
+
+    #[test]
+    fn renames_tell_apart_files_moved_with_the_same_stamp() {
+        let mut library = Library::new();
+        library.write("a.flac", "Side A");
+        library.write("b.flac", "Side B");
+        let modified = std::fs::metadata(library.path("a.flac"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        let b = std::fs::File::options()
+            .write(true)
+            .open(library.path("b.flac"))
+            .unwrap();
+        b.set_modified(modified).unwrap();
+        library.rescan();
+        let moved = library.index.files[&library.path("b.flac")].hash.clone();
+
+        std::fs::create_dir(library.path("sorted")).unwrap();
+        for name in ["a.flac", "b.flac"] {
+            let to = library.path(&format!("sorted/{}", name));
+            std::fs::rename(library.path(name), to).unwrap();
+        }
+        let scope = Scope {
+            paths: Some(vec![library.dir.path().to_path_buf()]),
+            renamed: vec![(library.path("b.flac"), library.path("sorted/b.flac"))],
+        };
+
+        // Only b is known to have moved, a has to be read to be sure
+        assert_eq!(library.rescan_in(&scope).read, 1);
+        let b = &library.index.files[&library.path("sorted/b.flac")];
+        assert_eq!(b.hash, moved);
+    }
+
+    #[test]
+    fn only_the_scope_is_looked_at() {
+        let mut library = Library::new();
+        library.write("a.flac", "Opener");
+        library.write("b.flac", "Closer");
+        library.rescan();
+
+        library.write("a.flac", "Opener (Extended Mix)");
+        std::fs::remove_file(library.path("b.flac")).unwrap();
+        let scope = Scope {
+            paths: Some(vec![library.path("a.flac")]),
+            ..Default::default()
+        };
+        let rescan = library.rescan_in(&scope);
+
+        assert_eq!((rescan.read, rescan.unchanged), (1, 1));
+        assert!(library.index.files.contains_key(&library.path("b.flac")));
+        assert_eq!(rescan.count(Kind::Retract), 2);
+    }
+
+    #[test]
+    fn tracks_outside_the_library_are_left_alone() {
+        let mut library = Library::new();
+        let elsewhere = ContentHash("sha256:elsewhere".to_string());
+        let mut asserted = Asserted::default();
+        let source = FactSource::new("test", "1.0.0", FactOrigin::Unknown);
+        asserted.assert(&MusicValue::FilePath("/other/x.flac".into()), &source);
+        library.state.insert(elsewhere, asserted);
+
+        assert!(library.rescan().changes.is_empty());
+    }
+}
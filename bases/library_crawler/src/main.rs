@@ -2,22 +2,35 @@ mod fact_generator;
 mod fact_reader;
 mod fact_writer;
 mod hash;
+mod incremental;
+mod pipeline;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod watch;
 
 use chrono::Utc;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use color_eyre::Result;
 use fact_generator::generate_facts;
 use flac_metadata::{discover_all_fields, extract_metadata};
 use hash::compute_content_hash;
+use indicatif::{ProgressBar, ProgressStyle};
 use stainless_facts::{Fact, FactStreamWriter, Operation};
-use std::path::PathBuf;
-use walkdir::WalkDir;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::fact_reader::{read_and_aggregate, AggregatedTrack};
+use crate::fact_reader::{read_and_aggregate, read_asserted, AggregatedTrack};
+use crate::incremental::{index_path, rescan, FileFacts, Kind, ScanIndex, Scope};
+use crate::storage::Storage;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the music library root directory (for scanning)
     library_path: Option<PathBuf>,
 
@@ -33,15 +46,62 @@ struct Args {
     #[arg(long)]
     aggregate: bool,
 
+    /// Only read files that changed since the last scan, and write only what differs
+    ///
+    /// Keeps an index of the files scanned beside the facts file.
+    #[arg(long)]
+    incremental: bool,
+
+    /// Files to read at once, by default as many as the disk the library is on handles well
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
     /// Show progress while scanning
     #[arg(short, long)]
     verbose: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Keep watching a library and write facts for files as they arrive
+    Watch {
+        /// Path to the music library root directory
+        library_path: PathBuf,
+
+        /// Append facts to this file (JSONL format), indexed as with --incremental
+        #[arg(long)]
+        write_facts: PathBuf,
+
+        /// Milliseconds a file has to be left alone before it is read
+        #[arg(long, default_value_t = watch::DEFAULT_QUIET.as_millis() as u64)]
+        debounce_ms: u64,
+
+        /// Files to read at once, by default as many as the disk the library is on handles well
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Show each file as it is read
+        #[arg(short, long)]
+        verbose: bool,
+    },
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
     let args = Args::parse();
 
+    if let Some(Command::Watch {
+        library_path,
+        write_facts,
+        debounce_ms,
+        jobs,
+        verbose,
+    }) = args.command
+    {
+        let quiet = Duration::from_millis(debounce_ms);
+        return watch_and_write_facts(&library_path, &write_facts, quiet, jobs, verbose);
+    }
+
     // Mode 1: Read and aggregate facts
     if let Some(ref facts_path) = args.read_facts {
         return read_and_display_facts(facts_path, args.aggregate);
@@ -50,7 +110,10 @@ fn main() -> Result<()> {
     // Mode 2: Scan library and write facts
     if let Some(ref library_path) = args.library_path {
         if let Some(ref output_path) = args.write_facts {
-            return scan_and_write_facts(library_path, output_path, args.verbose);
+            if args.incremental {
+                return rescan_and_write_facts(library_path, output_path, args.jobs, args.verbose);
+            }
+            return scan_and_write_facts(library_path, output_path, args.jobs, args.verbose);
         } else {
             eprintln!("Error: --write-facts is required when scanning a library");
             eprintln!("Usage: library-crawler <LIBRARY_PATH> --write-facts <OUTPUT>");
@@ -61,13 +124,15 @@ fn main() -> Result<()> {
     eprintln!("Error: Must provide either:");
     eprintln!("  1. <LIBRARY_PATH> --write-facts <OUTPUT>  (to scan and generate facts)");
     eprintln!("  2. --read-facts <INPUT> --aggregate       (to read and display facts)");
+    eprintln!("  3. watch <LIBRARY_PATH> --write-facts <OUTPUT>  (to keep facts up to date)");
     std::process::exit(1);
 }
 
 /// Scan library and write facts to file
 fn scan_and_write_facts(
-    library_path: &PathBuf,
-    output_path: &PathBuf,
+    library_path: &Path,
+    output_path: &Path,
+    jobs: Option<usize>,
     verbose: bool,
 ) -> Result<()> {
     if !library_path.exists() {
@@ -79,6 +144,7 @@ fn scan_and_write_facts(
 
     println!("🎵 Scanning music library: {}", library_path.display());
     println!("📝 Writing facts to: {}", output_path.display());
+    let workers = workers(library_path, jobs);
     println!("{}", "-".repeat(60));
 
     let mut writer = FactStreamWriter::open(output_path)?;
@@ -86,39 +152,36 @@ fn scan_and_write_facts(
     let mut total_facts = 0;
     let mut failed_files = Vec::new();
 
-    for entry in WalkDir::new(library_path)
-        .follow_links(false)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let path = entry.path();
-
-        // Only process FLAC files
-        if path.extension().and_then(|s| s.to_str()) != Some("flac") {
-            continue;
-        }
-
-        if verbose {
-            println!("Processing: {}", path.display());
-        }
-
-        match process_file(path, &mut writer) {
-            Ok(fact_count) => {
+    let progress = ProgressBar::new(0).with_style(ProgressStyle::with_template(
+        "{spinner} [{elapsed_precise}] {wide_bar} {pos}/{len} files, {per_sec}, ETA {eta}",
+    )?);
+    pipeline::scan(library_path, workers, read_file, |scanned| {
+        // Still walking while the first files are read, the total grows with it
+        progress.set_length(scanned.found as u64);
+        progress.inc(1);
+
+        match scanned.facts {
+            Ok(file_facts) => {
+                let fact_count = write_file_facts(&mut writer, file_facts)?;
                 track_count += 1;
                 total_facts += fact_count;
 
                 if verbose {
-                    println!("  ✓ Generated {} facts", fact_count);
+                    let line = format!("  ✓ {}: {} facts", scanned.path.display(), fact_count);
+                    println_above(&progress, line);
                 }
             }
             Err(e) => {
-                failed_files.push((path.to_path_buf(), e.to_string()));
                 if verbose {
-                    eprintln!("  ✗ Error: {}", e);
+                    let line = format!("  ✗ {}: {}", scanned.path.display(), e);
+                    println_above(&progress, line);
                 }
+                failed_files.push((scanned.path, e.to_string()));
             }
         }
-    }
+        Ok(())
+    })?;
+    progress.finish_and_clear();
 
     println!("\n{}", "=".repeat(60));
     println!("SCAN COMPLETE");
@@ -130,6 +193,7 @@ fn scan_and_write_facts(
         total_facts as f64 / track_count as f64
     );
     println!("  Failed files:      {}", failed_files.len());
+    println!("  Time taken:        {:.1?}", progress.elapsed());
 
     if !failed_files.is_empty() {
         println!("\n❌ Failed files:");
@@ -141,8 +205,41 @@ fn scan_and_write_facts(
     Ok(())
 }
 
-/// Process a single FLAC file and write its facts
-fn process_file(path: &std::path::Path, writer: &mut FactStreamWriter) -> Result<usize> {
+/// Files to read at once, `jobs` or else what the disk under `library_path` handles well
+fn workers(library_path: &Path, jobs: Option<usize>) -> usize {
+    let storage = Storage::of(library_path);
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let workers = jobs.unwrap_or_else(|| storage.workers(cpus));
+    println!("💽 Reading with {} workers ({})", workers, storage);
+    workers
+}
+
+/// Print a line above the progress bar, or just print it when there is no bar to show
+fn println_above(progress: &ProgressBar, line: String) {
+    if progress.is_hidden() {
+        println!("{}", line);
+    } else {
+        progress.println(line);
+    }
+}
+
+/// Write the facts of a single FLAC file, all in one batch
+fn write_file_facts(writer: &mut FactStreamWriter, file_facts: FileFacts) -> Result<usize> {
+    let (content_hash, facts_and_sources) = file_facts;
+
+    let now = Utc::now();
+    let facts: Vec<_> = facts_and_sources
+        .into_iter()
+        .map(|(value, source)| {
+            Fact::new(content_hash.clone(), value, now, source, Operation::Assert)
+        })
+        .collect();
+    writer.write_batch(&facts)?;
+    Ok(facts.len())
+}
+
+/// Hash a FLAC file and generate the facts its tags give
+fn read_file(path: &std::path::Path) -> Result<FileFacts> {
     // Compute content hash (entity ID)
     let content_hash = compute_content_hash(path)?;
 
@@ -154,16 +251,120 @@ fn process_file(path: &std::path::Path, writer: &mut FactStreamWriter) -> Result
 
     // Generate facts
     let facts_and_sources = generate_facts(content_hash.clone(), &metadata, &all_fields)?;
+    Ok((content_hash, facts_and_sources))
+}
 
+/// Rescan library against the facts already written, and append only the differences
+fn rescan_and_write_facts(
+    library_path: &Path,
+    output_path: &Path,
+    jobs: Option<usize>,
+    verbose: bool,
+) -> Result<()> {
+    if !library_path.exists() {
+        return Err(color_eyre::eyre::eyre!(
+            "Library path does not exist: {}",
+            library_path.display()
+        ));
+    }
+
+    println!("🎵 Rescanning music library: {}", library_path.display());
+    println!("📝 Updating facts in: {}", output_path.display());
+    let workers = workers(library_path, jobs);
+    println!("{}", "-".repeat(60));
+
+    let index_path = index_path(output_path);
+    let previous = ScanIndex::load(&index_path)?;
+    let state = if output_path.exists() {
+        read_asserted(output_path)?
+    } else {
+        Default::default()
+    };
+
+    let read = |path: &Path| {
+        if verbose {
+            println!("Processing: {}", path.display());
+        }
+        read_file(path)
+    };
+    let scope = Scope::default();
+    let result = rescan(library_path, &scope, &previous, &state, workers, read);
+
+    // Facts first, an index ahead of them would skip files next time
+    let asserted = result.count(Kind::Assert);
+    let retracted = result.count(Kind::Retract);
     let now = Utc::now();
-    let fact_count = facts_and_sources.len();
+    let facts: Vec<_> = result
+        .changes
+        .into_iter()
+        .map(|change| change.into_fact(now))
+        .collect();
+    if !facts.is_empty() {
+        let mut writer = FactStreamWriter::open(output_path)?;
+        writer.write_batch(&facts)?;
+    }
+    result.index.save(&index_path)?;
+
+    println!("\n{}", "=".repeat(60));
+    println!("RESCAN COMPLETE");
+    println!("{}", "=".repeat(60));
+    println!("  Files read:        {}", result.read);
+    println!("  Files unchanged:   {}", result.unchanged);
+    println!("  Facts asserted:    {}", asserted);
+    println!("  Facts retracted:   {}", retracted);
+    println!("  Failed files:      {}", result.failed.len());
+
+    if !result.failed.is_empty() {
+        println!("\n❌ Failed files:");
+        for (path, error) in &result.failed {
+            println!("  {} - {}", path.display(), error);
+        }
+    }
+
+    Ok(())
+}
 
-    // Write facts
-    for (value, source) in facts_and_sources {
-        let fact = Fact::new(content_hash.clone(), value, now, source, Operation::Assert);
-        writer.write_batch(&[fact])?;
+/// Watch library and append facts for whatever changes, until interrupted
+fn watch_and_write_facts(
+    library_path: &Path,
+    output_path: &Path,
+    quiet: Duration,
+    jobs: Option<usize>,
+    verbose: bool,
+) -> Result<()> {
+    if !library_path.is_dir() {
+        return Err(color_eyre::eyre::eyre!(
+            "Library path is not a directory: {}",
+            library_path.display()
+        ));
     }
-    Ok(fact_count)
+
+    println!("👀 Watching music library: {}", library_path.display());
+    println!("📝 Appending facts to: {}", output_path.display());
+    let workers = workers(library_path, jobs);
+    println!("{}", "-".repeat(60));
+
+    let read = |path: &Path| {
+        if verbose {
+            println!("Processing: {}", path.display());
+        }
+        read_file(path)
+    };
+    watch::run(library_path, output_path, quiet, workers, read, |rescan| {
+        if rescan.changes.is_empty() && rescan.failed.is_empty() {
+            return;
+        }
+        println!(
+            "[{}] {} files read, {} facts asserted, {} retracted",
+            Utc::now().format("%H:%M:%S"),
+            rescan.read,
+            rescan.count(Kind::Assert),
+            rescan.count(Kind::Retract)
+        );
+        for (path, error) in &rescan.failed {
+            println!("  ❌ {} - {}", path.display(), error);
+        }
+    })
 }
 
 /// Read facts from file and display them
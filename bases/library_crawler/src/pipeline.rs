@@ -0,0 +1,186 @@
+use crate::incremental::FileFacts;
+use color_eyre::Result;
+use crossbeam::channel;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use walkdir::WalkDir;
+
+/// A file the workers are done with
+pub struct Scanned {
+    pub path: PathBuf,
+    pub facts: Result<FileFacts>,
+    /// FLAC files the walk has found so far, this one included
+    pub found: usize,
+}
+
+/// Read every FLAC file under `root` on `workers` threads, handing each to `write` in turn
+///
+/// The walk, the reading and `write` run at the same time, with only a few
+/// files queued between them so memory stays flat however big the
+/// library. Files come back in the order they finish, each with all of its
+/// facts. An error from `write` stops the scan.
+pub fn scan(
+    root: &Path,
+    workers: usize,
+    read: impl Fn(&Path) -> Result<FileFacts> + Sync,
+    mut write: impl FnMut(Scanned) -> Result<()>,
+) -> Result<()> {
+    let workers = workers.max(1);
+    let (queue, jobs) = channel::bounded::<PathBuf>(workers * 4);
+    let (done, results) = channel::bounded::<(PathBuf, Result<FileFacts>)>(workers * 4);
+    let found = AtomicUsize::new(0);
+
+    std::thread::scope(|scope| {
+        let found = &found;
+        scope.spawn(move || {
+            for entry in WalkDir::new(root)
+                .follow_links(false)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_map(|e| e.ok())
+            {
+                let path = entry.path();
+                if path.extension().and_then(|s| s.to_str()) != Some("flac") {
+                    continue;
+                }
+                found.fetch_add(1, Ordering::Relaxed);
+                if queue.send(path.to_path_buf()).is_err() {
+                    break;
+                }
+            }
+        });
+        for _ in 0..workers {
+            let (jobs, done, read) = (jobs.clone(), done.clone(), &read);
+            scope.spawn(move || {
+                for path in jobs {
+                    let facts = read(&path);
+                    if done.send((path, facts)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // The workers hold the only senders left, the results end when they do
+        drop((jobs, done));
+
+        for (path, facts) in results {
+            let found = found.load(Ordering::Relaxed);
+            write(Scanned { path, facts, found })?;
+        }
+        Ok(())
+    })
+}
+
+/// Read `paths` on `workers` threads, giving back what each held in the order asked for
+pub fn read_all(
+    paths: Vec<PathBuf>,
+    workers: usize,
+    read: impl Fn(&Path) -> Result<FileFacts> + Sync,
+) -> Vec<(PathBuf, Result<FileFacts>)> {
+    let workers = workers.max(1).min(paths.len().max(1));
+    let (queue, jobs) = channel::unbounded();
+    let (done, results) = channel::unbounded();
+    for job in paths.into_iter().enumerate() {
+        queue.send(job).expect("the jobs are still open");
+    }
+    drop(queue);
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let (jobs, done, read) = (jobs.clone(), done.clone(), &read);
+            scope.spawn(move || {
+                for (i, path) in jobs {
+                    let facts = read(&path);
+                    let _ = done.send((i, path, facts));
+                }
+            });
+        }
+    });
+    drop(done);
+
+    let mut read: Vec<_> = results.into_iter().collect();
+    read.sort_by_key(|(i, _, _)| *i);
+    read.into_iter()
+        .map(|(_, path, facts)| (path, facts))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::read_title;
+    use color_eyre::eyre::eyre;
+    use std::collections::BTreeMap;
+
+    fn library(files: usize) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("album")).unwrap();
+        for i in 0..files {
+            let name = format!("album/{i:03}.flac");
+            std::fs::write(dir.path().join(name), format!("Track {i}")).unwrap();
+        }
+        std::fs::write(dir.path().join("album/cover.jpg"), "not music").unwrap();
+        std::fs::write(dir.path().join("empty.flac"), "").unwrap();
+        dir
+    }
+
+    // Title of every file that was read, failures as their error
+    fn titles(dir: &Path, workers: usize) -> BTreeMap<PathBuf, String> {
+        let mut titles = BTreeMap::new();
+        scan(dir, workers, read_title, |scanned| {
+            assert!(scanned.found > titles.len());
+            let title = match scanned.facts {
+                Ok((_, facts)) => format!("{:?}", facts[1].0),
+                Err(e) => e.to_string(),
+            };
+            assert!(titles.insert(scanned.path, title).is_none());
+            Ok(())
+        })
+        .unwrap();
+        titles
+    }
+
+    #[test]
+    fn every_file_is_read_once_whatever_the_workers() {
+        let dir = library(50);
+        let one = titles(dir.path(), 1);
+        assert_eq!(one.len(), 51);
+        assert_eq!(one[&dir.path().join("empty.flac")], "no title");
+        assert_eq!(
+            one[&dir.path().join("album/007.flac")],
+            r#"Title("Track 7")"#
+        );
+        assert_eq!(titles(dir.path(), 8), one);
+    }
+
+    #[test]
+    fn read_all_keeps_the_order_it_was_given() {
+        let dir = library(50);
+        let mut paths: Vec<_> = (0..50)
+            .map(|i| dir.path().join(format!("album/{i:03}.flac")))
+            .collect();
+        paths.reverse();
+        paths.push(dir.path().join("empty.flac"));
+
+        let read = read_all(paths.clone(), 8, read_title);
+        let read_paths: Vec<_> = read.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(read_paths, paths);
+        assert!(read[..50].iter().all(|(_, facts)| facts.is_ok()));
+        assert_eq!(read[50].1.as_ref().unwrap_err().to_string(), "no title");
+    }
+
+    #[test]
+    fn a_failed_write_stops_the_scan() {
+        let dir = library(50);
+        let mut written = 0;
+        let result = scan(dir.path(), 4, read_title, |_| {
+            written += 1;
+            if written == 3 {
+                return Err(eyre!("disk full"));
+            }
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "disk full");
+        assert_eq!(written, 3);
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt::{self, Display};
+use std::path::Path;
+
+/// The kind of disk a library is on, which decides how many files to read at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    Nvme,
+    Ssd,
+    Rotational,
+    SdCard,
+    Unknown,
+}
+
+impl Storage {
+    /// Look the disk holding `path` up in sysfs
+    #[cfg(target_os = "linux")]
+    pub fn of(path: &Path) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return Storage::Unknown;
+        };
+        let dev = metadata.dev();
+        let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+        let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+        let Ok(device) = std::fs::canonicalize(format!("/sys/dev/block/{major}:{minor}")) else {
+            return Storage::Unknown;
+        };
+
+        // A partition has no queue of its own, the disk above it does
+        let Some(disk) = device
+            .ancestors()
+            .take(2)
+            .find(|dir| dir.join("queue").is_dir())
+        else {
+            return Storage::Unknown;
+        };
+        let name = disk
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let rotational = std::fs::read_to_string(disk.join("queue/rotational")).ok();
+        Self::classify(name, rotational.as_deref().map(str::trim))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn of(_path: &Path) -> Self {
+        Storage::Unknown
+    }
+
+    /// From the kernel's name for the disk and its `queue/rotational` flag
+    pub fn classify(name: &str, rotational: Option<&str>) -> Self {
+        if name.starts_with("nvme") {
+            Storage::Nvme
+        } else if name.starts_with("mmcblk") {
+            Storage::SdCard
+        } else {
+            match rotational {
+                Some("1") => Storage::Rotational,
+                Some("0") => Storage::Ssd,
+                _ => Storage::Unknown,
+            }
+        }
+    }
+
+    /// Files to read at once with `cpus` to hash them on
+    ///
+    /// Hashing keeps a core busy per file on fast disks. A spinning disk
+    /// seeks between every file read at once, and an SD card has little
+    /// more than one reader's worth of bandwidth to share.
+    pub fn workers(&self, cpus: usize) -> usize {
+        let cpus = cpus.max(1);
+        match self {
+            Storage::Nvme => cpus,
+            Storage::Ssd | Storage::Unknown => cpus.min(4),
+            Storage::SdCard => cpus.min(2),
+            Storage::Rotational => 1,
+        }
+    }
+}
+
+impl Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Storage::Nvme => "NVMe",
+            Storage::Ssd => "SSD",
+            Storage::Rotational => "hard disk",
+            Storage::SdCard => "SD card",
+            Storage::Unknown => "unknown disk",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disks_are_told_apart_by_name_first() {
+        assert_eq!(Storage::classify("nvme0n1", Some("0")), Storage::Nvme);
+        assert_eq!(Storage::classify("mmcblk0", Some("0")), Storage::SdCard);
+        assert_eq!(Storage::classify("sda", Some("0")), Storage::Ssd);
+        assert_eq!(Storage::classify("sdb", Some("1")), Storage::Rotational);
+        assert_eq!(Storage::classify("loop0", None), Storage::Unknown);
+    }
+
+    #[test]
+    fn slow_disks_get_fewer_workers() {
+        assert_eq!(Storage::Nvme.workers(16), 16);
+        assert_eq!(Storage::Ssd.workers(16), 4);
+        assert_eq!(Storage::SdCard.workers(4), 2);
+        assert_eq!(Storage::Rotational.workers(16), 1);
+        assert_eq!(Storage::Unknown.workers(0), 1);
+    }
+
+    #[test]
+    fn any_path_has_some_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Storage::of(dir.path()).workers(8) >= 1);
+        assert_eq!(Storage::of(&dir.path().join("missing")), Storage::Unknown);
+    }
+}
@@ -0,0 +1,91 @@
+//! Fixtures shared by the crawler's tests
+
+use crate::fact_reader::Asserted;
+use crate::incremental::{rescan, Change, FileFacts, Kind, Rescan, ScanIndex, Scope};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use music_facts::{ContentHash, FactOrigin, FactSource, MusicValue};
+use stainless_facts::FactAggregator;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Stands in for a FLAC file, its title is whatever the file holds
+///
+/// An empty file has no title and can't be read, as a broken FLAC can't.
+pub fn read_title(path: &Path) -> Result<FileFacts> {
+    let title = std::fs::read_to_string(path)?;
+    if title.is_empty() {
+        return Err(eyre!("no title"));
+    }
+    let hash = ContentHash::from_file(path)?;
+    let source = FactSource::new("test", "1.0.0", FactOrigin::Unknown);
+    let facts = vec![
+        (MusicValue::FilePath(path.to_path_buf()), source.clone()),
+        (MusicValue::Title(title), source),
+    ];
+    Ok((hash, facts))
+}
+
+pub fn title(title: &str) -> MusicValue {
+    MusicValue::Title(title.to_string())
+}
+
+/// What each change does, without the track and source it is for
+pub fn changed(changes: &[Change]) -> Vec<(Kind, MusicValue)> {
+    changes
+        .iter()
+        .map(|change| (change.kind, change.value.clone()))
+        .collect()
+}
+
+/// A library in a temporary directory and what rescanning it has found
+pub struct Library {
+    pub dir: TempDir,
+    pub index: ScanIndex,
+    pub state: HashMap<ContentHash, Asserted>,
+}
+
+impl Library {
+    pub fn new() -> Self {
+        Self {
+            dir: tempfile::tempdir().unwrap(),
+            index: ScanIndex::default(),
+            state: HashMap::new(),
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    pub fn write(&self, name: &str, title: &str) {
+        std::fs::write(self.path(name), title).unwrap();
+    }
+
+    pub fn rescan(&mut self) -> Rescan {
+        self.rescan_in(&Scope::default())
+    }
+
+    /// Rescan and take in the changes, as the next run would read them back
+    pub fn rescan_in(&mut self, scope: &Scope) -> Rescan {
+        let (root, index, state) = (self.dir.path(), &self.index, &self.state);
+        let mut rescan = rescan(root, scope, index, state, 4, read_title);
+        for change in &rescan.changes {
+            let asserted = self.state.entry(change.hash.clone()).or_default();
+            match change.kind {
+                Kind::Assert => asserted.assert(&change.value, &change.source),
+                Kind::Retract => asserted.retract(&change.value, &change.source),
+            }
+        }
+        self.index = std::mem::take(&mut rescan.index);
+        rescan
+    }
+
+    /// What is asserted about the track in the file `name`
+    pub fn values(&self, name: &str) -> Vec<MusicValue> {
+        let hash = ContentHash::from_file(&self.path(name)).unwrap();
+        let asserted = &self.state[&hash];
+        asserted.0.iter().map(|(value, _)| value.clone()).collect()
+    }
+}
@@ -0,0 +1,498 @@
+use crate::fact_reader::{read_asserted, Asserted};
+use crate::incremental::{index_path, rescan, FileFacts, Kind, Rescan, ScanIndex, Scope};
+use chrono::Utc;
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use music_facts::ContentHash;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use stainless_facts::{FactAggregator, FactStreamWriter};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long a file has to be left alone before it is read
+pub const DEFAULT_QUIET: Duration = Duration::from_secs(2);
+
+/// Paths that changed, each waiting to go quiet
+///
+/// A download or an unzip writes a file many times over, reading it on
+/// the first write would hash half a track.
+#[derive(Debug)]
+pub struct Debouncer {
+    quiet: Duration,
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Debouncer {
+    pub fn new(quiet: Duration) -> Self {
+        Self {
+            quiet,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn touch(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(path, now);
+    }
+
+    /// Whether `path` changed too recently to be read
+    pub fn is_settling(&self, path: &Path) -> bool {
+        self.pending.contains_key(path)
+    }
+
+    /// Take the paths quiet since `now - quiet`
+    pub fn settle(&mut self, now: Instant) -> Vec<PathBuf> {
+        let quiet = self.quiet;
+        let mut settled = Vec::new();
+        self.pending.retain(|path, touched| {
+            let settling = now.saturating_duration_since(*touched) < quiet;
+            if !settling {
+                settled.push(path.clone());
+            }
+            settling
+        });
+        settled
+    }
+
+    /// When the path touched longest ago goes quiet
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .min()
+            .map(|touched| *touched + self.quiet)
+    }
+}
+
+/// Library under watch, with the facts and index kept up to date as files settle
+///
+/// Settled changes rescan the paths that changed against the index, the
+/// rest of the library is taken to be as it was. A rename keeps the
+/// content hash and only moves the track's path fact, however the events
+/// for it arrive.
+pub struct Watch {
+    root: PathBuf,
+    facts_path: PathBuf,
+    index: ScanIndex,
+    state: HashMap<ContentHash, Asserted>,
+    debouncer: Debouncer,
+    renamed: Vec<(PathBuf, PathBuf)>,
+    everything: bool,
+    workers: usize,
+}
+
+impl Watch {
+    /// Pick up from the facts and index a scan of `root` left behind, reading on `workers` threads
+    pub fn open(root: &Path, facts_path: &Path, quiet: Duration, workers: usize) -> Result<Self> {
+        let index = ScanIndex::load(&index_path(facts_path))?;
+        let state = if facts_path.exists() {
+            read_asserted(facts_path)?
+        } else {
+            Default::default()
+        };
+        Ok(Self {
+            root: root.to_path_buf(),
+            facts_path: facts_path.to_path_buf(),
+            index,
+            state,
+            debouncer: Debouncer::new(quiet),
+            renamed: Vec::new(),
+            everything: false,
+            workers,
+        })
+    }
+
+    /// Note a change at `path`, other files than FLACs and directories are ignored
+    ///
+    /// What is gone may have been either, so it is noted too.
+    pub fn touch(&mut self, path: PathBuf, now: Instant) {
+        let matters = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata.is_dir() || path.extension().is_some_and(|ext| ext == "flac"),
+            Err(_) => true,
+        };
+        if matters {
+            self.debouncer.touch(path, now);
+        }
+    }
+
+    /// Note `from` was renamed to `to`, so what was read there need not be read again
+    pub fn rename(&mut self, from: PathBuf, to: PathBuf, now: Instant) {
+        self.touch(from.clone(), now);
+        self.touch(to.clone(), now);
+        self.renamed.push((from, to));
+    }
+
+    /// Have the next rescan look at every file, for when events went missing
+    pub fn touch_everything(&mut self, now: Instant) {
+        self.everything = true;
+        self.debouncer.touch(self.root.clone(), now);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.debouncer.deadline()
+    }
+
+    /// Rescan what has gone quiet by `now`, if anything has
+    pub fn flush(
+        &mut self,
+        now: Instant,
+        read: impl Fn(&Path) -> Result<FileFacts> + Sync,
+    ) -> Result<Option<Rescan>> {
+        let settled = self.debouncer.settle(now);
+        if settled.is_empty() {
+            return Ok(None);
+        }
+        // Renames wait for both their ends to settle
+        let debouncer = &self.debouncer;
+        let (renamed, pending) = std::mem::take(&mut self.renamed)
+            .into_iter()
+            .partition(|(from, to)| !debouncer.is_settling(from) && !debouncer.is_settling(to));
+        self.renamed = pending;
+        let scope = Scope {
+            paths: (!std::mem::take(&mut self.everything)).then_some(settled),
+            renamed,
+        };
+        self.rescan_in(&scope, read).map(Some)
+    }
+
+    /// Rescan every file now and write what changed, files still settling are left for later
+    pub fn rescan(&mut self, read: impl Fn(&Path) -> Result<FileFacts> + Sync) -> Result<Rescan> {
+        self.rescan_in(&Scope::default(), read)
+    }
+
+    fn rescan_in(
+        &mut self,
+        scope: &Scope,
+        read: impl Fn(&Path) -> Result<FileFacts> + Sync,
+    ) -> Result<Rescan> {
+        let debouncer = &self.debouncer;
+        let read = |path: &Path| {
+            if debouncer.is_settling(path) {
+                bail!("still being written");
+            }
+            read(path)
+        };
+        let (index, state) = (&self.index, &self.state);
+        let mut result = rescan(&self.root, scope, index, state, self.workers, read);
+        // Not unreadable, just not done yet
+        result
+            .failed
+            .retain(|(path, _)| !debouncer.is_settling(path));
+        result
+            .index
+            .failed
+            .retain(|path, _| !debouncer.is_settling(path));
+
+        // Facts first, as for a rescan from the command line
+        if !result.changes.is_empty() {
+            let now = Utc::now();
+            let facts: Vec<_> = result
+                .changes
+                .iter()
+                .map(|change| change.clone().into_fact(now))
+                .collect();
+            let mut writer = FactStreamWriter::open(&self.facts_path)?;
+            writer.write_batch(&facts)?;
+        }
+        for change in &result.changes {
+            let asserted = self.state.entry(change.hash.clone()).or_default();
+            match change.kind {
+                Kind::Assert => asserted.assert(&change.value, &change.source),
+                Kind::Retract => asserted.retract(&change.value, &change.source),
+            }
+        }
+        self.index = std::mem::take(&mut result.index);
+        self.index.save(&index_path(&self.facts_path))?;
+        Ok(result)
+    }
+}
+
+/// Watch `root` until the watcher goes away, reporting each rescan that happens
+///
+/// Catches up on what changed while nobody was watching first. Events the
+/// kernel dropped are made up for by a rescan that looks at every file.
+pub fn run(
+    root: &Path,
+    facts_path: &Path,
+    quiet: Duration,
+    workers: usize,
+    read: impl Fn(&Path) -> Result<FileFacts> + Sync,
+    mut report: impl FnMut(&Rescan),
+) -> Result<()> {
+    let (events, received) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events)?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+
+    let mut watch = Watch::open(root, facts_path, quiet, workers)?;
+    report(&watch.rescan(&read)?);
+
+    loop {
+        let event = match watch.deadline() {
+            Some(deadline) => {
+                received.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => received.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(Ok(event)) if changes_content(&event.kind) => match (event.kind, &event.paths[..]) {
+                (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                    watch.rename(from.clone(), to.clone(), Instant::now());
+                }
+                _ => {
+                    for path in event.paths {
+                        watch.touch(path, Instant::now());
+                    }
+                }
+            },
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(e)) => {
+                eprintln!("⚠️  Watch error, rescanning everything: {}", e);
+                watch.touch_everything(Instant::now());
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        if let Some(rescan) = watch.flush(Instant::now(), &read)? {
+            report(&rescan);
+        }
+    }
+}
+
+// Reading a file is an event too, hashing one must not set off another rescan
+fn changes_content(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{changed, read_title, title, Library};
+    use music_facts::MusicValue;
+    use tempfile::TempDir;
+
+    const QUIET: Duration = Duration::from_millis(500);
+
+    // A library with a watch on it, at times counted from the start
+    struct Watched {
+        files: Library,
+        facts: TempDir,
+        watch: Watch,
+        start: Instant,
+    }
+
+    impl Watched {
+        fn new() -> Self {
+            let files = Library::new();
+            let facts = tempfile::tempdir().unwrap();
+            let facts_path = facts.path().join("facts.jsonl");
+            let watch = Watch::open(files.dir.path(), &facts_path, QUIET, 4).unwrap();
+            Self {
+                files,
+                facts,
+                watch,
+                start: Instant::now(),
+            }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.files.path(name)
+        }
+
+        fn at(&self, millis: u64) -> Instant {
+            self.start + Duration::from_millis(millis)
+        }
+
+        fn write(&mut self, name: &str, title: &str, millis: u64) {
+            self.files.write(name, title);
+            self.watch.touch(self.path(name), self.at(millis));
+        }
+
+        fn flush(&mut self, millis: u64) -> Option<Rescan> {
+            let now = self.at(millis);
+            self.watch.flush(now, read_title).unwrap()
+        }
+
+        fn written(&self) -> HashMap<ContentHash, Asserted> {
+            read_asserted(self.facts.path().join("facts.jsonl")).unwrap()
+        }
+    }
+
+    #[test]
+    fn files_are_read_once_they_go_quiet() {
+        let mut library = Watched::new();
+        library.write("a.flac", "Open", 0);
+        library.write("a.flac", "Opener", 300);
+
+        assert!(library.flush(600).is_none());
+        assert_eq!(library.watch.deadline(), Some(library.at(800)));
+
+        let rescan = library.flush(800).unwrap();
+        assert_eq!(rescan.read, 1);
+        assert_eq!(
+            changed(&rescan.changes),
+            vec![
+                (Kind::Assert, MusicValue::FilePath(library.path("a.flac"))),
+                (Kind::Assert, title("Opener")),
+            ]
+        );
+        assert!(library.flush(2000).is_none());
+
+        // What was written reads back for the next run
+        let hash = ContentHash::from_file(&library.path("a.flac")).unwrap();
+        assert_eq!(library.written()[&hash].0.len(), 2);
+        let reopened = Watch::open(
+            library.files.dir.path(),
+            &library.facts.path().join("facts.jsonl"),
+            QUIET,
+            4,
+        )
+        .unwrap();
+        assert!(reopened.index.files.contains_key(&library.path("a.flac")));
+    }
+
+    #[test]
+    fn files_still_being_written_wait_for_the_next_rescan() {
+        let mut library = Watched::new();
+        library.write("a.flac", "Opener", 0);
+        library.write("b.flac", "Clos", 400);
+
+        let rescan = library.flush(500).unwrap();
+        assert_eq!(rescan.read, 1);
+        assert!(rescan.failed.is_empty());
+        assert!(!library
+            .watch
+            .index
+            .files
+            .contains_key(&library.path("b.flac")));
+
+        library.write("b.flac", "Closer", 700);
+        let rescan = library.flush(1200).unwrap();
+        assert_eq!(rescan.read, 1);
+        assert!(changed(&rescan.changes).contains(&(Kind::Assert, title("Closer"))));
+    }
+
+    #[test]
+    fn renames_move_the_path_fact() {
+        let mut library = Watched::new();
+        library.write("a.flac", "Opener", 0);
+        library.flush(500).unwrap();
+
+        std::fs::create_dir(library.path("sorted")).unwrap();
+        std::fs::rename(library.path("a.flac"), library.path("sorted/a.flac")).unwrap();
+        let now = library.at(1000);
+        library.watch.touch(library.path("a.flac"), now);
+        library.watch.touch(library.path("sorted"), now);
+
+        let rescan = library.flush(1500).unwrap();
+        assert_eq!(
+            changed(&rescan.changes),
+            vec![
+                (Kind::Retract, MusicValue::FilePath(library.path("a.flac"))),
+                (
+                    Kind::Assert,
+                    MusicValue::FilePath(library.path("sorted/a.flac"))
+                ),
+            ]
+        );
+        assert_eq!(library.written().len(), 1);
+    }
+
+    #[test]
+    fn renamed_directories_are_not_read_again() {
+        let mut library = Watched::new();
+        std::fs::create_dir(library.path("album")).unwrap();
+        library.write("album/a.flac", "Side A", 0);
+        library.write("album/b.flac", "Side B", 0);
+        library.flush(500).unwrap();
+
+        std::fs::rename(library.path("album"), library.path("sorted")).unwrap();
+        let (from, to) = (library.path("album"), library.path("sorted"));
+        library.watch.rename(from, to, library.at(1000));
+
+        let rescan = library.flush(1500).unwrap();
+        assert_eq!(rescan.read, 0);
+        assert_eq!(rescan.count(Kind::Retract), 2);
+        assert_eq!(rescan.count(Kind::Assert), 2);
+        assert!(changed(&rescan.changes).contains(&(
+            Kind::Assert,
+            MusicValue::FilePath(library.path("sorted/b.flac"))
+        )));
+    }
+
+    #[test]
+    fn run_follows_the_files_as_they_change() {
+        let library = Library::new();
+        let facts = tempfile::tempdir().unwrap();
+        let root = library.dir.path().to_path_buf();
+        let facts_path = facts.path().join("facts.jsonl");
+        let (reports, reported) = mpsc::channel();
+        std::thread::spawn(move || {
+            run(
+                &root,
+                &facts_path,
+                Duration::from_millis(100),
+                4,
+                read_title,
+                |rescan| {
+                    let _ = reports.send((rescan.read, changed(&rescan.changes)));
+                },
+            )
+        });
+        let next = || reported.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(next(), (0, vec![]));
+
+        library.write("a.flac", "Opener");
+        let (read, changes) = next();
+        assert_eq!(read, 1);
+        assert!(changes.contains(&(Kind::Assert, title("Opener"))));
+
+        std::fs::rename(library.path("a.flac"), library.path("b.flac")).unwrap();
+        let (read, changes) = next();
+        assert_eq!(read, 0);
+        assert_eq!(
+            changes,
+            vec![
+                (Kind::Retract, MusicValue::FilePath(library.path("a.flac"))),
+                (Kind::Assert, MusicValue::FilePath(library.path("b.flac"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_files_do_not_set_off_a_rescan() {
+        let mut library = Watched::new();
+        library.files.write("cover.jpg", "not music");
+        std::fs::create_dir(library.path("album")).unwrap();
+        library
+            .watch
+            .touch(library.path("cover.jpg"), library.at(0));
+        library.watch.touch(library.path("album"), library.at(0));
+        assert_eq!(library.watch.deadline(), Some(library.at(500)));
+        library.flush(500).unwrap();
+
+        library
+            .watch
+            .touch(library.path("cover.jpg"), library.at(600));
+        assert!(library.watch.deadline().is_none());
+        assert!(!changes_content(&EventKind::Access(AccessKind::Read)));
+    }
+
+    #[test]
+    fn albums_with_a_dot_in_their_name_are_scanned() {
+        let mut library = Watched::new();
+        let album = "Mr. Oizo - Analog Worms Attack";
+        std::fs::create_dir(library.path(album)).unwrap();
+        library
+            .files
+            .write(&format!("{album}/01.flac"), "Last Night a DJ");
+
+        // Moved in whole, only the directory itself is heard of
+        library.watch.touch(library.path(album), library.at(0));
+        let rescan = library.flush(500).unwrap();
+        assert_eq!(rescan.read, 1);
+    }
+}
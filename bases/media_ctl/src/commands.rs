@@ -1,50 +1,52 @@
 use clap::Subcommand;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
+use media_protocol::{DeckQueue, DeckState, DeckStats, Recording, Stats};
+use music_library::{Library, LibraryError, LibraryTrack};
 use playback_primitives::Deck;
 use std::path::PathBuf;
 
 #[derive(Subcommand)]
 pub enum Commands {
     /// Load a track from the library
+    ///
+    /// The query is a title/artist substring, a content hash (sha256:...),
+    /// an ISRC or attributes like "bpm 124-128 key 8A"
     Load {
-        /// Path to music library
-        #[arg(long)]
-        library: PathBuf,
+        /// What to load
+        #[arg(required = true, num_args = 1..)]
+        query: Vec<String>,
 
-        /// Artist name
-        #[arg(long)]
-        artist: String,
+        /// Facts file written by library-crawler
+        #[arg(long, default_value = DEFAULT_FACTS_PATH)]
+        facts: PathBuf,
 
-        /// Album name (optional)
+        /// Which of several matching tracks to load (1-based, as listed)
         #[arg(long)]
-        album: Option<String>,
+        pick: Option<usize>,
 
-        /// Song name
-        #[arg(long)]
-        song: String,
-
-        /// Channel (A or B)
+        /// Channel (A to D)
         #[arg(long)]
         channel: char,
     },
 
     /// Play a loaded track
     Play {
-        /// Channel (A or B)
+        /// Channel (A to D)
         #[arg(long)]
         channel: char,
     },
 
     /// Stop a playing track
     Stop {
-        /// Channel (A or B)
+        /// Channel (A to D)
         #[arg(long)]
         channel: char,
     },
 
     /// Set volume for a channel
     Volume {
-        /// Channel (A or B)
+        /// Channel (A to D)
         #[arg(long)]
         channel: char,
 
@@ -55,13 +57,13 @@ pub enum Commands {
 
     /// Unload a track from a channel
     Unload {
-        /// Channel (A or B)
+        /// Channel (A to D)
         #[arg(long)]
         channel: char,
     },
     /// Seek to a position in a track
     Seek {
-        /// Channel (A or B)
+        /// Channel (A to D)
         #[arg(long)]
         channel: char,
 
@@ -72,43 +74,255 @@ pub enum Commands {
 
     /// Get track length
     GetLength {
-        /// Channel (A or B)
+        /// Channel (A to D)
+        #[arg(long)]
+        channel: char,
+    },
+
+    /// Queue a track from the library to play after the current one
+    ///
+    /// An empty channel loads it straight away, like `load`
+    Enqueue {
+        /// What to queue, as for `load`
+        #[arg(required = true, num_args = 1..)]
+        query: Vec<String>,
+
+        /// Facts file written by library-crawler
+        #[arg(long, default_value = DEFAULT_FACTS_PATH)]
+        facts: PathBuf,
+
+        /// Which of several matching tracks to queue (1-based, as listed)
+        #[arg(long)]
+        pick: Option<usize>,
+
+        /// Channel (A to D)
         #[arg(long)]
         channel: char,
     },
+
+    /// Drop everything queued on a channel
+    ClearQueue {
+        /// Channel (A to D)
+        #[arg(long)]
+        channel: char,
+    },
+
+    /// Move a channel on to the next queued track
+    Skip {
+        /// Channel (A to D)
+        #[arg(long)]
+        channel: char,
+    },
+
+    /// List what is queued on a channel
+    Queue {
+        /// Channel (A to D)
+        #[arg(long)]
+        channel: char,
+    },
+
+    /// Overlap queued tracks on a channel
+    Crossfade {
+        /// Channel (A to D)
+        #[arg(long)]
+        channel: char,
+
+        /// Seconds of overlap, 0 for gapless
+        #[arg(long)]
+        seconds: f32,
+    },
+
+    /// Record the master output to FLAC, with a tracklist alongside
+    Record {
+        /// File name in the server's recordings directory, by default one named after the time
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
+
+    /// Finish the recording
+    StopRecording,
+
+    /// Show what every deck is doing
+    Status,
+
+    /// Show underruns and decode errors since the server started
+    Stats,
+
+    /// Run a TOML script of timed commands against the server
+    Run {
+        /// TOML script with the steps to run
+        script: PathBuf,
+
+        /// Print the expanded commands and their offsets without sending them
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Facts file used to resolve load queries
+        #[arg(long, default_value = DEFAULT_FACTS_PATH)]
+        facts: PathBuf,
+    },
+
+    /// Interactive terminal interface showing every deck
+    Tui {
+        /// Facts file written by library-crawler, used by the track picker
+        #[arg(long, default_value = DEFAULT_FACTS_PATH)]
+        facts: PathBuf,
+    },
 }
 
+/// Interleaved samples per second of playback, assuming 48kHz stereo
+pub const SAMPLES_PER_SECOND: usize = 48000 * 2;
+
+/// Where library-crawler keeps the facts on an MDMA unit
+pub const DEFAULT_FACTS_PATH: &str = "/metadata/facts.jsonl";
+
 pub fn parse_channel(c: char) -> Result<Deck> {
-    match c.to_uppercase().next() {
-        Some('A') => Ok(Deck::A),
-        Some('B') => Ok(Deck::B),
-        _ => Err(color_eyre::eyre::eyre!("Invalid channel. Use 'A' or 'B'")),
-    }
+    c.to_string()
+        .parse()
+        .map_err(|_| eyre!("Invalid channel. Use a letter from A to {}", last_deck()))
+}
+
+fn last_deck() -> Deck {
+    Deck::all().last().expect("at least one deck")
 }
 
 pub fn channel_to_string(channel: Deck) -> String {
     format!("{channel}")
 }
 
-pub fn construct_path(
-    library: PathBuf,
-    artist: String,
-    album: Option<String>,
-    song: String,
-) -> Result<PathBuf> {
-    let mut path = library;
-    path.push(artist);
-    if let Some(album) = album {
-        path.push(album);
+/// Print a deck snapshot as answered by the server
+pub fn print_deck_state(state: &DeckState) {
+    let muted = if state.muted { ", muted" } else { "" };
+    println!(
+        "Deck {} ({:.1} dB{}, peak {:.1} dB, rms {:.1} dB)",
+        state.deck, state.volume_db, muted, state.level.peak_db, state.level.rms_db
+    );
+    let Some(track) = &state.track else {
+        println!("  empty");
+        return;
+    };
+
+    let samples_per_second = track.sample_rate as usize * track.channels as usize;
+    let length = track
+        .length
+        .map(|length| format_time(length, samples_per_second))
+        .unwrap_or_else(|| "--:--".to_string());
+    println!("  {}", track.path.display());
+    if let Some(hash) = &track.content_hash {
+        println!("  {}", hash.0);
+    }
+    println!(
+        "  {} {} / {}  {} Hz, {} ch",
+        if track.playing { "playing" } else { "stopped" },
+        format_time(track.position, samples_per_second),
+        length,
+        track.sample_rate,
+        track.channels
+    );
+}
+
+/// Print where a recording is going and how it is doing
+pub fn print_recording(recording: &Recording) {
+    let seconds = recording.seconds as usize;
+    println!(
+        "Recording {} ({:02}:{:02}, started {})",
+        recording.path.display(),
+        seconds / 60,
+        seconds % 60,
+        recording.started.format("%Y-%m-%d %H:%M:%S UTC")
+    );
+    println!("  tracklist {}", recording.tracklist.display());
+    if recording.dropped_samples > 0 {
+        println!("  {} samples lost", recording.dropped_samples);
     }
-    path.push(format!("{}.flac", song));
+}
 
-    if !path.exists() {
-        return Err(color_eyre::eyre::eyre!(
-            "Track not found: {}",
-            path.display()
-        ));
+/// Print a channel's queue, next up first
+pub fn print_queue(channel: Deck, queue: &DeckQueue) {
+    match queue.crossfade_seconds {
+        seconds if seconds > 0.0 => println!(
+            "Channel {}, crossfading over {}s",
+            channel_to_string(channel),
+            seconds
+        ),
+        _ => println!("Channel {}, gapless", channel_to_string(channel)),
+    }
+    if queue.entries.is_empty() {
+        println!("  nothing queued");
+    }
+    for (index, path) in queue.entries.iter().enumerate() {
+        println!("  {}. {}", index + 1, path.display());
     }
+}
+
+/// Print the server's glitch counters, decks first
+pub fn print_stats(stats: &Stats) {
+    for (deck, deck_stats) in &stats.decks {
+        println!("Deck {}  {}", deck, describe_stats(deck_stats));
+    }
+    println!("Total   {}", describe_stats(&stats.totals));
+    println!("Output  {} underruns", stats.output_underruns);
+}
+
+fn describe_stats(stats: &DeckStats) -> String {
+    format!(
+        "{} underruns, {} decode errors, {} late seeks",
+        stats.buffer_underruns, stats.decode_errors, stats.late_seeks
+    )
+}
 
-    Ok(path)
+fn format_time(samples: usize, samples_per_second: usize) -> String {
+    let seconds = samples / samples_per_second.max(1);
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Find the file to load for a query, using `pick` to choose among several matches
+pub fn resolve_track(library: &Library, query: &str, pick: Option<usize>) -> Result<PathBuf> {
+    let track = match (library.resolve(query), pick) {
+        (Ok(track), _) => track.clone(),
+        (Err(LibraryError::Ambiguous { candidates, .. }), Some(pick)) => pick
+            .checked_sub(1)
+            .and_then(|index| candidates.get(index))
+            .cloned()
+            .ok_or_else(|| {
+                eyre!(
+                    "--pick {} is out of range, {} tracks match",
+                    pick,
+                    candidates.len()
+                )
+            })?,
+        (Err(LibraryError::Ambiguous { query, candidates }), None) => {
+            let listing: Vec<String> = candidates
+                .iter()
+                .enumerate()
+                .map(|(index, track)| format!("  {}. {}", index + 1, describe(track)))
+                .collect();
+            return Err(eyre!(
+                "{} tracks match '{}', choose one with --pick:\n{}",
+                candidates.len(),
+                query,
+                listing.join("\n")
+            ));
+        }
+        (Err(e), _) => return Err(e.into()),
+    };
+
+    track
+        .file_path
+        .ok_or_else(|| eyre!("Track has no file to load"))
+}
+
+/// One line summary of a track for disambiguation
+fn describe(track: &LibraryTrack) -> String {
+    let mut line = track.display_name();
+    if let Some(album) = &track.album {
+        line.push_str(&format!(" [{}]", album));
+    }
+    if let Some(bpm) = track.bpm {
+        line.push_str(&format!(" {} bpm", bpm));
+    }
+    if let Some(key) = track.key {
+        line.push_str(&format!(" {}", key.to_camelot()));
+    }
+    line
 }
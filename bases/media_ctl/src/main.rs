@@ -1,6 +1,8 @@
 use clap::Parser;
 use color_eyre::Result;
 mod commands;
+mod script;
+mod tui;
 
 use commands::Commands;
 
@@ -16,21 +18,32 @@ async fn main() -> Result<()> {
     color_eyre::install()?;
     let cli = Cli::parse();
 
-    let client = media_client::MediaClient::connect("ipc:///tmp/mdma-commands")?;
+    // A dry run only reads the script, so it works without a server
+    if let Commands::Run {
+        script,
+        dry_run: true,
+        facts,
+    } = &cli.command
+    {
+        return script::dry_run(&script::Script::from_file(script)?, facts);
+    }
+
+    let client = media_client::MediaClient::connect("ipc:///tmp/mdma-commands").await?;
 
     match cli.command {
         Commands::Load {
-            library,
-            artist,
-            album,
-            song,
+            query,
+            facts,
+            pick,
             channel,
         } => {
             let channel = commands::parse_channel(channel)?;
-            let path = commands::construct_path(library, artist, album, song)?;
-            client.load_track(path, channel)?;
+            let library = music_library::Library::open(&facts)?;
+            let path = commands::resolve_track(&library, &query.join(" "), pick)?;
+            client.load_track(path.clone(), channel).await?;
             println!(
-                "Track loaded on channel {}",
+                "Loaded {} on channel {}",
+                path.display(),
                 commands::channel_to_string(channel)
             );
         }
@@ -38,19 +51,19 @@ async fn main() -> Result<()> {
         Commands::Play { channel } => {
             println!("Play channel {channel}");
             let channel = commands::parse_channel(channel)?;
-            client.play(channel)?;
+            client.play(channel).await?;
             println!("Playing channel {}", commands::channel_to_string(channel));
         }
 
         Commands::Stop { channel } => {
             let channel = commands::parse_channel(channel)?;
-            client.stop(channel)?;
+            client.stop(channel).await?;
             println!("Stopped channel {}", commands::channel_to_string(channel));
         }
 
         Commands::Volume { channel, db } => {
             let channel = commands::parse_channel(channel)?;
-            client.set_volume(channel, db)?;
+            client.set_volume(channel, db).await?;
             println!(
                 "Set volume of channel {} to {}dB",
                 commands::channel_to_string(channel),
@@ -59,7 +72,7 @@ async fn main() -> Result<()> {
         }
         Commands::Unload { channel } => {
             let channel = commands::parse_channel(channel)?;
-            client.unload_track(channel)?;
+            client.unload_track(channel).await?;
             println!(
                 "Unloaded track from channel {}",
                 commands::channel_to_string(channel)
@@ -68,7 +81,7 @@ async fn main() -> Result<()> {
         Commands::Seek { channel, position } => {
             let channel = commands::parse_channel(channel)?;
             let start = std::time::Instant::now();
-            client.seek(channel, position)?;
+            client.seek(channel, position).await?;
             let duration = start.elapsed();
             println!(
                 "Seeked channel {} to position {} in {:?}",
@@ -80,7 +93,7 @@ async fn main() -> Result<()> {
 
         Commands::GetLength { channel } => {
             let channel = commands::parse_channel(channel)?;
-            let length = client.get_length(channel)?;
+            let length = client.get_length(channel).await?;
 
             // Present the length in a more human-readable way
             let sample_rate = 48000; // Assuming 48kHz
@@ -98,6 +111,83 @@ async fn main() -> Result<()> {
                 seconds
             );
         }
+
+        Commands::Enqueue {
+            query,
+            facts,
+            pick,
+            channel,
+        } => {
+            let channel = commands::parse_channel(channel)?;
+            let library = music_library::Library::open(&facts)?;
+            let path = commands::resolve_track(&library, &query.join(" "), pick)?;
+            client.enqueue(channel, path.clone()).await?;
+            println!(
+                "Queued {} on channel {}",
+                path.display(),
+                commands::channel_to_string(channel)
+            );
+        }
+        Commands::ClearQueue { channel } => {
+            let channel = commands::parse_channel(channel)?;
+            client.clear_queue(channel).await?;
+            println!(
+                "Cleared the queue on channel {}",
+                commands::channel_to_string(channel)
+            );
+        }
+        Commands::Skip { channel } => {
+            let channel = commands::parse_channel(channel)?;
+            client.skip(channel).await?;
+            println!(
+                "Skipped to the next track on channel {}",
+                commands::channel_to_string(channel)
+            );
+        }
+        Commands::Queue { channel } => {
+            let channel = commands::parse_channel(channel)?;
+            commands::print_queue(channel, &client.get_queue(channel).await?);
+        }
+        Commands::Crossfade { channel, seconds } => {
+            let channel = commands::parse_channel(channel)?;
+            client.set_crossfade(channel, seconds).await?;
+            println!(
+                "Crossfading channel {} over {}s",
+                commands::channel_to_string(channel),
+                seconds
+            );
+        }
+
+        Commands::Record { path } => {
+            commands::print_recording(&client.start_recording(path).await?);
+        }
+        Commands::StopRecording => {
+            let recording = client.stop_recording().await?;
+            println!("Stopped");
+            commands::print_recording(&recording);
+        }
+
+        Commands::Status => {
+            for state in client.get_state().await? {
+                commands::print_deck_state(&state);
+            }
+            if let Some(recording) = client.get_recording().await? {
+                commands::print_recording(&recording);
+            }
+        }
+
+        Commands::Stats => {
+            commands::print_stats(&client.get_stats().await?);
+        }
+
+        Commands::Run { script, facts, .. } => {
+            let script = script::Script::from_file(&script)?;
+            script::run(&client, &script, &facts).await?;
+        }
+
+        Commands::Tui { facts } => {
+            tui::run(client, &facts).await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,582 @@
+//! Timed command sequences for rehearsing sets
+//!
+//! A script is a TOML file with a list of steps. Each step runs at an
+//! offset from the start of the script, given either in wall-clock time
+//! (`"12.5s"`, `"250ms"`) or in musical ticks (`"3840t"`) at the script's
+//! tempo:
+//!
+//! ```toml
+//! tempo = 124.0
+//!
+//! [[step]]
+//! at = "0s"
+//! command = "load"
+//! deck = "A"
+//! query = "suanne police"
+//!
+//! [[step]]
+//! at = "0s"
+//! command = "play"
+//! deck = "A"
+//!
+//! [[step]]
+//! at = "30720t"
+//! command = "crossfade"
+//! from = "A"
+//! to = "B"
+//! over = "16s"
+//! ```
+//!
+//! Ramps and crossfades are expanded into a series of volume commands
+//! before anything is sent to the server. A crossfade fades the `from`
+//! deck out from the volume it has by then.
+
+mod runner;
+
+pub use runner::{dry_run, run};
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use playback_primitives::Deck;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time_primitives::{Ppqn, Tempo, Ticks};
+
+/// How often volume is updated during ramps and crossfades
+const RAMP_RESOLUTION: Duration = Duration::from_millis(50);
+
+/// Quietest volume the server accepts
+const SILENT_DB: f32 = -96.0;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    /// Tempo in BPM, needed when any offset is given in ticks
+    tempo: Option<f64>,
+    ppqn: Option<u32>,
+    #[serde(rename = "step", default)]
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Step {
+    at: String,
+    #[serde(flatten)]
+    command: StepCommand,
+}
+
+/// What a step does, as written in the script
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum StepCommand {
+    Load {
+        deck: Deck,
+        path: Option<PathBuf>,
+        query: Option<String>,
+    },
+    Play {
+        deck: Deck,
+    },
+    Stop {
+        deck: Deck,
+    },
+    Unload {
+        deck: Deck,
+    },
+    Volume {
+        deck: Deck,
+        db: f32,
+    },
+    Seek {
+        deck: Deck,
+        seconds: f64,
+    },
+    Ramp {
+        deck: Deck,
+        from_db: f32,
+        to_db: f32,
+        over: String,
+    },
+    Crossfade {
+        from: Deck,
+        to: Deck,
+        over: String,
+    },
+}
+
+impl fmt::Display for StepCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepCommand::Load { deck, path, query } => match (path, query) {
+                (Some(path), _) => write!(f, "load {} on {}", path.display(), deck),
+                (None, Some(query)) => write!(f, "load '{}' on {}", query, deck),
+                (None, None) => write!(f, "load on {}", deck),
+            },
+            StepCommand::Play { deck } => write!(f, "play {}", deck),
+            StepCommand::Stop { deck } => write!(f, "stop {}", deck),
+            StepCommand::Unload { deck } => write!(f, "unload {}", deck),
+            StepCommand::Volume { deck, db } => write!(f, "volume {} {}dB", deck, db),
+            StepCommand::Seek { deck, seconds } => write!(f, "seek {} to {}s", deck, seconds),
+            StepCommand::Ramp {
+                deck,
+                from_db,
+                to_db,
+                over,
+            } => write!(f, "ramp {} {}dB → {}dB over {}", deck, from_db, to_db, over),
+            StepCommand::Crossfade { from, to, over } => {
+                write!(f, "crossfade {} → {} over {}", from, to, over)
+            }
+        }
+    }
+}
+
+/// Where a load step gets its track from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Track {
+    Path(PathBuf),
+    Query(String),
+}
+
+/// A single server command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Load(Deck, Track),
+    Play(Deck),
+    Stop(Deck),
+    Unload(Deck),
+    Volume(Deck, f32),
+    Seek(Deck, usize),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Load(deck, Track::Path(path)) => {
+                write!(f, "load {} on {}", path.display(), deck)
+            }
+            Action::Load(deck, Track::Query(query)) => write!(f, "load '{}' on {}", query, deck),
+            Action::Play(deck) => write!(f, "play {}", deck),
+            Action::Stop(deck) => write!(f, "stop {}", deck),
+            Action::Unload(deck) => write!(f, "unload {}", deck),
+            Action::Volume(deck, db) => write!(f, "volume {} {:.1}dB", deck, db),
+            Action::Seek(deck, position) => write!(f, "seek {} to sample {}", deck, position),
+        }
+    }
+}
+
+/// A command with the offset it should be sent at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheduled {
+    pub at: Duration,
+    /// Index of the script step this command came from
+    pub step: usize,
+    pub action: Action,
+}
+
+impl Script {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read script {}", path.display()))?;
+        Self::parse(&content).wrap_err_with(|| format!("Invalid script {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Expand the steps into commands ordered by when they should be sent
+    ///
+    /// Commands at the same offset keep the order they have in the script.
+    /// Every deck is taken to start at 0 dB.
+    pub fn schedule(&self) -> Result<Vec<Scheduled>> {
+        self.schedule_from(&HashMap::new())
+    }
+
+    /// As `schedule`, with the decks at `volumes` when the script starts
+    pub fn schedule_from(&self, volumes: &HashMap<Deck, f32>) -> Result<Vec<Scheduled>> {
+        let timing = self.timing()?;
+        let mut starts = Vec::new();
+        for (index, step) in self.steps.iter().enumerate() {
+            let at = timing
+                .parse(&step.at)
+                .wrap_err_with(|| format!("Step {}: invalid offset", index + 1))?;
+            starts.push((at, index));
+        }
+
+        // Steps in the order they start, so each sees the volumes set before it
+        starts.sort_by_key(|(at, _)| *at);
+        let mut scheduled: Vec<Scheduled> = Vec::new();
+        for (at, index) in starts {
+            let step = &self.steps[index];
+            let volume = |deck| {
+                scheduled
+                    .iter()
+                    .filter(|command| command.at <= at)
+                    .filter_map(|command| match command.action {
+                        Action::Volume(set, db) if set == deck => Some((command.at, db)),
+                        _ => None,
+                    })
+                    .max_by_key(|(set_at, _)| *set_at)
+                    .map(|(_, db)| db)
+                    .unwrap_or_else(|| volumes.get(&deck).copied().unwrap_or(0.0))
+            };
+            let actions = expand(&step.command, &timing, volume)
+                .wrap_err_with(|| format!("Step {}: {}", index + 1, step.command))?;
+
+            scheduled.extend(actions.into_iter().map(|(offset, action)| Scheduled {
+                at: at + offset,
+                step: index,
+                action,
+            }));
+        }
+
+        // Stable sort, so simultaneous commands run in script order
+        scheduled.sort_by_key(|command| (command.at, command.step));
+        Ok(scheduled)
+    }
+
+    fn timing(&self) -> Result<Timing> {
+        let tempo = self
+            .tempo
+            .map(Tempo::new)
+            .transpose()
+            .wrap_err("Invalid tempo")?;
+        let ppqn = match self.ppqn {
+            Some(ppqn) => Ppqn::new(ppqn).wrap_err("Invalid ppqn")?,
+            None => Ppqn::DEFAULT,
+        };
+        Ok(Timing { tempo, ppqn })
+    }
+}
+
+impl Step {
+    pub fn command(&self) -> &StepCommand {
+        &self.command
+    }
+}
+
+/// Turns offsets from the script into wall-clock durations
+struct Timing {
+    tempo: Option<Tempo>,
+    ppqn: Ppqn,
+}
+
+impl Timing {
+    /// Parse "1.5s", "250ms" or "3840t"
+    fn parse(&self, offset: &str) -> Result<Duration> {
+        let offset = offset.trim();
+
+        let seconds = |value: &str, scale: f64| -> Result<Duration> {
+            let value: f64 = value
+                .trim()
+                .parse()
+                .map_err(|_| eyre!("'{}' is not a number", value))?;
+            Duration::try_from_secs_f64(value * scale)
+                .map_err(|_| eyre!("'{}' must be a positive time", offset))
+        };
+
+        if let Some(value) = offset.strip_suffix("ms") {
+            seconds(value, 0.001)
+        } else if let Some(value) = offset.strip_suffix('s') {
+            seconds(value, 1.0)
+        } else if let Some(value) = offset.strip_suffix('t') {
+            let ticks: u64 = value
+                .trim()
+                .parse()
+                .map_err(|_| eyre!("'{}' is not a whole number of ticks", value))?;
+            let tempo = self
+                .tempo
+                .ok_or_else(|| eyre!("'{}' is in ticks but the script has no tempo", offset))?;
+            Ok(Ticks::new(ticks).to_duration(tempo, self.ppqn))
+        } else {
+            bail!(
+                "'{}' needs a unit: s, ms or t (ticks), e.g. \"1.5s\"",
+                offset
+            )
+        }
+    }
+}
+
+/// Commands for one step, with offsets relative to the step
+///
+/// `volume` tells what a deck is set to when the step starts.
+fn expand(
+    command: &StepCommand,
+    timing: &Timing,
+    volume: impl Fn(Deck) -> f32,
+) -> Result<Vec<(Duration, Action)>> {
+    let now = |action| Ok(vec![(Duration::ZERO, action)]);
+
+    match command.clone() {
+        StepCommand::Load { deck, path, query } => match (path, query) {
+            (Some(path), None) => now(Action::Load(deck, Track::Path(path))),
+            (None, Some(query)) => now(Action::Load(deck, Track::Query(query))),
+            _ => bail!("load needs exactly one of path or query"),
+        },
+        StepCommand::Play { deck } => now(Action::Play(deck)),
+        StepCommand::Stop { deck } => now(Action::Stop(deck)),
+        StepCommand::Unload { deck } => now(Action::Unload(deck)),
+        StepCommand::Volume { deck, db } => now(Action::Volume(deck, checked_db(db)?)),
+        StepCommand::Seek { deck, seconds } => {
+            if seconds < 0.0 {
+                bail!("seek position cannot be negative");
+            }
+            let samples = (seconds * crate::commands::SAMPLES_PER_SECOND as f64).round() as usize;
+            now(Action::Seek(deck, samples))
+        }
+        StepCommand::Ramp {
+            deck,
+            from_db,
+            to_db,
+            over,
+        } => {
+            let (from_db, to_db) = (checked_db(from_db)?, checked_db(to_db)?);
+            Ok(ramp(timing.parse(&over)?, |progress| {
+                vec![Action::Volume(deck, from_db + (to_db - from_db) * progress)]
+            }))
+        }
+        StepCommand::Crossfade { from, to, over } => {
+            if from == to {
+                bail!("cannot crossfade a deck into itself");
+            }
+            // Equal power, so the sum stays as loud through the middle
+            let start = db_to_gain(volume(from));
+            Ok(ramp(timing.parse(&over)?, |progress| {
+                let angle = progress * std::f32::consts::FRAC_PI_2;
+                vec![
+                    Action::Volume(from, gain_to_db(start * angle.cos())),
+                    Action::Volume(to, gain_to_db(angle.sin())),
+                ]
+            }))
+        }
+    }
+}
+
+/// Sample `actions_at(progress)` every `RAMP_RESOLUTION` from 0.0 to 1.0
+fn ramp(duration: Duration, actions_at: impl Fn(f32) -> Vec<Action>) -> Vec<(Duration, Action)> {
+    let steps = (duration.as_secs_f64() / RAMP_RESOLUTION.as_secs_f64()).ceil() as u32;
+    let steps = steps.max(1);
+
+    (0..=steps)
+        .flat_map(|step| {
+            let offset = duration.mul_f64(step as f64 / steps as f64);
+            actions_at(step as f32 / steps as f32)
+                .into_iter()
+                .map(move |action| (offset, action))
+        })
+        .collect()
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    if gain <= 0.0 {
+        SILENT_DB
+    } else {
+        (20.0 * gain.log10()).clamp(SILENT_DB, 0.0)
+    }
+}
+
+fn checked_db(db: f32) -> Result<f32> {
+    if !(SILENT_DB..=0.0).contains(&db) {
+        bail!("volume {}dB is outside {}..=0 dB", db, SILENT_DB);
+    }
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(script: &str) -> Result<Vec<Scheduled>> {
+        Script::parse(script)?.schedule()
+    }
+
+    #[test]
+    fn offsets_accept_seconds_milliseconds_and_ticks() {
+        let commands = schedule(
+            r#"
+            tempo = 120.0
+
+            [[step]]
+            at = "1.5s"
+            command = "play"
+            deck = "A"
+
+            [[step]]
+            at = "250ms"
+            command = "play"
+            deck = "B"
+
+            [[step]]
+            at = "1920t"
+            command = "stop"
+            deck = "A"
+            "#,
+        )
+        .unwrap();
+
+        let offsets: Vec<_> = commands.iter().map(|c| (c.at, c.step)).collect();
+        assert_eq!(
+            offsets,
+            vec![
+                (Duration::from_millis(250), 1),
+                (Duration::from_secs(1), 2),
+                (Duration::from_millis(1500), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn ticks_need_a_tempo() {
+        let result = schedule(
+            r#"
+            [[step]]
+            at = "960t"
+            command = "play"
+            deck = "A"
+            "#,
+        );
+
+        assert!(format!("{:?}", result.unwrap_err()).contains("no tempo"));
+    }
+
+    #[test]
+    fn offsets_need_a_unit() {
+        let result = schedule(
+            r#"
+            [[step]]
+            at = "10"
+            command = "play"
+            deck = "A"
+            "#,
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn ramp_expands_into_volume_steps() {
+        let commands = schedule(
+            r#"
+            [[step]]
+            at = "1s"
+            command = "ramp"
+            deck = "A"
+            from_db = -20.0
+            to_db = 0.0
+            over = "200ms"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(commands.len(), 5);
+        assert_eq!(commands[0].at, Duration::from_secs(1));
+        assert_eq!(commands[0].action, Action::Volume(Deck::A, -20.0));
+        assert_eq!(commands[2].action, Action::Volume(Deck::A, -10.0));
+        assert_eq!(commands[4].at, Duration::from_millis(1200));
+        assert_eq!(commands[4].action, Action::Volume(Deck::A, 0.0));
+    }
+
+    #[test]
+    fn crossfade_swaps_the_decks() {
+        let commands = schedule(
+            r#"
+            [[step]]
+            at = "0s"
+            command = "crossfade"
+            from = "A"
+            to = "B"
+            over = "1s"
+            "#,
+        )
+        .unwrap();
+
+        let first = &commands[..2];
+        let last = &commands[commands.len() - 2..];
+        assert_eq!(first[0].action, Action::Volume(Deck::A, 0.0));
+        assert_eq!(first[1].action, Action::Volume(Deck::B, SILENT_DB));
+        assert_eq!(last[0].action, Action::Volume(Deck::A, SILENT_DB));
+        assert_eq!(last[1].action, Action::Volume(Deck::B, 0.0));
+        assert_eq!(last[1].at, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn crossfade_starts_from_the_current_volume() {
+        let script = Script::parse(
+            r#"
+            [[step]]
+            at = "2s"
+            command = "crossfade"
+            from = "A"
+            to = "B"
+            over = "1s"
+
+            [[step]]
+            at = "1s"
+            command = "volume"
+            deck = "A"
+            db = -12.0
+            "#,
+        )
+        .unwrap();
+
+        let commands = script.schedule().unwrap();
+        let fade = commands.iter().find(|c| c.step == 0).unwrap();
+        assert_eq!(fade.action, Action::Volume(Deck::A, -12.0));
+
+        // Without the volume step, from wherever the server has it
+        let script = Script::parse(
+            r#"
+            [[step]]
+            at = "0s"
+            command = "crossfade"
+            from = "A"
+            to = "B"
+            over = "1s"
+            "#,
+        )
+        .unwrap();
+        let commands = script
+            .schedule_from(&HashMap::from([(Deck::A, -6.0)]))
+            .unwrap();
+        assert_eq!(commands[0].action, Action::Volume(Deck::A, -6.0));
+    }
+
+    #[test]
+    fn load_needs_path_or_query() {
+        let result = schedule(
+            r#"
+            [[step]]
+            at = "0s"
+            command = "load"
+            deck = "A"
+            "#,
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        let result = schedule(
+            r#"
+            [[step]]
+            at = "0s"
+            command = "scratch"
+            deck = "A"
+            "#,
+        );
+
+        assert!(result.is_err());
+    }
+}
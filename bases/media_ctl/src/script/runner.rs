@@ -0,0 +1,150 @@
+use super::{Action, Scheduled, Script, Track};
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use media_client::MediaClient;
+use music_library::Library;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Timing of the commands that came from one script step
+///
+/// Drift is how late a command reached the server, taken as half the
+/// round trip after it was sent.
+#[derive(Debug, Default)]
+struct StepReport {
+    commands: usize,
+    total_drift: Duration,
+    max_drift: Duration,
+    max_round_trip: Duration,
+}
+
+/// Print what would be sent and when, without touching the server
+pub fn dry_run(script: &Script, facts: &Path) -> Result<()> {
+    let scheduled = resolve_tracks(script.schedule()?, facts)?;
+
+    for command in &scheduled {
+        println!(
+            "{:>10}  step {:<3} {}",
+            format_offset(command.at),
+            command.step + 1,
+            command.action
+        );
+    }
+    println!(
+        "{} commands from {} steps",
+        scheduled.len(),
+        script.steps().len()
+    );
+    Ok(())
+}
+
+/// Send every command at its offset and report how late each step ran
+///
+/// Crossfades start from the volumes the server has when the script
+/// starts. Stops at the first command the server rejects.
+pub async fn run(client: &MediaClient, script: &Script, facts: &Path) -> Result<()> {
+    let volumes: HashMap<_, _> = client
+        .get_state()
+        .await?
+        .into_iter()
+        .map(|deck| (deck.deck, deck.volume_db))
+        .collect();
+    // Everything that can fail without the server fails before the clock starts
+    let scheduled = resolve_tracks(script.schedule_from(&volumes)?, facts)?;
+    let mut reports: Vec<StepReport> = script.steps().iter().map(|_| Default::default()).collect();
+
+    let start = Instant::now();
+    for command in &scheduled {
+        let deadline = start + command.at;
+        tokio::time::sleep_until(deadline).await;
+        let sent = Instant::now();
+        send(client, &command.action)
+            .await
+            .wrap_err_with(|| format!("Step {}: {} failed", command.step + 1, command.action))?;
+        let round_trip = sent.elapsed();
+        let drift = sent.saturating_duration_since(deadline) + round_trip / 2;
+
+        let report = &mut reports[command.step];
+        if report.commands == 0 {
+            println!(
+                "{:>10}  step {:<3} {}  (drift {})",
+                format_offset(command.at),
+                command.step + 1,
+                script.steps()[command.step].command(),
+                format_drift(drift)
+            );
+        }
+        report.commands += 1;
+        report.total_drift += drift;
+        report.max_drift = report.max_drift.max(drift);
+        report.max_round_trip = report.max_round_trip.max(round_trip);
+    }
+
+    println!();
+    println!(
+        "{:<6} {:>8} {:>12} {:>12} {:>12}  command",
+        "step", "sent", "avg drift", "max drift", "max rtt"
+    );
+    for (index, (step, report)) in script.steps().iter().zip(&reports).enumerate() {
+        let average = report
+            .total_drift
+            .checked_div(report.commands as u32)
+            .unwrap_or_default();
+        println!(
+            "{:<6} {:>8} {:>12} {:>12} {:>12}  {}",
+            index + 1,
+            report.commands,
+            format_drift(average),
+            format_drift(report.max_drift),
+            format_drift(report.max_round_trip),
+            step.command()
+        );
+    }
+    Ok(())
+}
+
+async fn send(client: &MediaClient, action: &Action) -> Result<()> {
+    match action.clone() {
+        Action::Load(deck, Track::Path(path)) => client.load_track(path, deck).await?,
+        Action::Load(_, Track::Query(query)) => {
+            bail!("query '{}' was never resolved to a file", query)
+        }
+        Action::Play(deck) => client.play(deck).await?,
+        Action::Stop(deck) => client.stop(deck).await?,
+        Action::Unload(deck) => client.unload_track(deck).await?,
+        Action::Volume(deck, db) => client.set_volume(deck, db).await?,
+        Action::Seek(deck, position) => client.seek(deck, position).await?,
+    }
+    Ok(())
+}
+
+/// Replace library queries with the paths they resolve to
+///
+/// The library is only read when the script uses queries.
+fn resolve_tracks(mut scheduled: Vec<Scheduled>, facts: &Path) -> Result<Vec<Scheduled>> {
+    let mut library = None;
+
+    for command in scheduled.iter_mut() {
+        if let Action::Load(deck, Track::Query(query)) = &command.action {
+            let library = match &mut library {
+                Some(library) => library,
+                None => library.insert(Library::open(facts)?),
+            };
+            let path: PathBuf = crate::commands::resolve_track(library, query, None)
+                .wrap_err_with(|| format!("Step {}", command.step + 1))?;
+            command.action = Action::Load(*deck, Track::Path(path));
+        }
+    }
+
+    Ok(scheduled)
+}
+
+fn format_offset(offset: Duration) -> String {
+    format!("{:.3}s", offset.as_secs_f64())
+}
+
+fn format_drift(drift: Duration) -> String {
+    format!("{:.1}ms", drift.as_secs_f64() * 1000.0)
+}
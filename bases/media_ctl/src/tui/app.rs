@@ -0,0 +1,356 @@
+use super::picker::Picker;
+use crate::commands::SAMPLES_PER_SECOND;
+use crossterm::event::{KeyCode, KeyEvent};
+use media_protocol::{DeckState, Event, Level};
+use music_library::Library;
+use playback_primitives::Deck;
+use std::path::{Path, PathBuf};
+
+const SEEK_STEP_SECONDS: usize = 10;
+const VOLUME_STEP_DB: f32 = 1.0;
+
+/// What the TUI knows about one deck
+#[derive(Debug, Clone)]
+pub struct DeckView {
+    pub deck: Deck,
+    pub path: Option<PathBuf>,
+    pub title: Option<String>,
+    pub position: Option<usize>,
+    pub length: Option<usize>,
+    pub playing: bool,
+    pub volume_db: f32,
+    pub level: Level,
+    /// Files waiting in the deck's queue
+    pub queued: usize,
+}
+
+impl DeckView {
+    fn new(deck: Deck) -> Self {
+        Self {
+            deck,
+            path: None,
+            title: None,
+            position: None,
+            length: None,
+            playing: false,
+            volume_db: 0.0,
+            level: Level::default(),
+            queued: 0,
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.path.is_some()
+    }
+
+    // The queue belongs to the deck, it outlives the track
+    fn unload(&mut self) {
+        *self = Self {
+            queued: self.queued,
+            ..Self::new(self.deck)
+        };
+    }
+}
+
+/// A command the user asked for, to be sent to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Load(Deck, PathBuf),
+    Play(Deck),
+    Stop(Deck),
+    Seek(Deck, usize),
+    Volume(Deck, f32),
+    Unload(Deck),
+    Skip(Deck),
+    StartRecording,
+    StopRecording,
+}
+
+pub struct App {
+    pub decks: Vec<DeckView>,
+    pub selected: usize,
+    pub picker: Option<Picker>,
+    pub library: Library,
+    pub status: String,
+    pub master: Level,
+    /// Samples the server's limiter caught, since it started
+    pub clips: u64,
+    /// Deck and output underruns on the server, since it started
+    pub underruns: u64,
+    pub recording: bool,
+    pub should_quit: bool,
+}
+
+impl App {
+    pub fn new(library: Library) -> Self {
+        Self {
+            decks: Deck::all().map(DeckView::new).collect(),
+            selected: 0,
+            picker: None,
+            library,
+            status: String::new(),
+            master: Level::default(),
+            clips: 0,
+            underruns: 0,
+            recording: false,
+            should_quit: false,
+        }
+    }
+
+    pub fn selected_deck(&self) -> &DeckView {
+        &self.decks[self.selected]
+    }
+
+    fn deck_mut(&mut self, deck: Deck) -> Option<&mut DeckView> {
+        self.decks.iter_mut().find(|view| view.deck == deck)
+    }
+
+    /// Update the deck views from a server event
+    pub fn apply_event(&mut self, event: Event) {
+        match event {
+            Event::TrackLoaded { deck, path } => {
+                let title = self.title_for(&path);
+                if let Some(view) = self.deck_mut(deck) {
+                    view.unload();
+                    view.title = Some(title);
+                    view.path = Some(path);
+                    view.position = Some(0);
+                }
+            }
+            Event::TrackUnloaded { deck } => {
+                if let Some(view) = self.deck_mut(deck) {
+                    view.unload();
+                }
+            }
+            Event::Playing { deck } => {
+                if let Some(view) = self.deck_mut(deck) {
+                    view.playing = true;
+                }
+            }
+            Event::Stopped { deck } => {
+                if let Some(view) = self.deck_mut(deck) {
+                    view.playing = false;
+                }
+            }
+            Event::VolumeChanged { deck, db } => {
+                if let Some(view) = self.deck_mut(deck) {
+                    view.volume_db = db;
+                }
+            }
+            Event::Seeked { deck, position } => {
+                if let Some(view) = self.deck_mut(deck) {
+                    view.position = Some(position);
+                }
+            }
+            Event::Advanced { deck, path } => {
+                let title = self.title_for(&path);
+                if let Some(view) = self.deck_mut(deck) {
+                    view.title = Some(title);
+                    view.path = Some(path);
+                    view.position = Some(0);
+                    view.length = None;
+                }
+            }
+            Event::QueueChanged { deck, queue } => {
+                if let Some(view) = self.deck_mut(deck) {
+                    view.queued = queue.entries.len();
+                }
+            }
+            Event::RecordingStarted(_) => self.recording = true,
+            Event::RecordingStopped(recording) => {
+                self.recording = false;
+                self.status = format!("Recorded {}", recording.path.display());
+            }
+            Event::Levels(levels) => {
+                for view in self.decks.iter_mut() {
+                    view.level = levels.decks.get(&view.deck).copied().unwrap_or_default();
+                }
+                self.master = levels.master;
+                self.clips = levels.clips;
+            }
+            Event::Stats(stats) => {
+                self.underruns = stats.totals.buffer_underruns + stats.output_underruns;
+            }
+        }
+    }
+
+    /// Update the deck views from what the server says they are doing
+    ///
+    /// Events only tell of changes, this also catches what happened before
+    /// the TUI started or while it missed events.
+    pub fn apply_state(&mut self, states: Vec<DeckState>) {
+        for state in states {
+            let title = state
+                .track
+                .as_ref()
+                .map(|track| self.title_for(&track.path));
+            let Some(view) = self.deck_mut(state.deck) else {
+                continue;
+            };
+            view.volume_db = state.volume_db;
+            match state.track {
+                Some(track) => {
+                    view.title = title;
+                    view.path = Some(track.path);
+                    view.position = Some(track.position);
+                    view.length = track.length;
+                    view.playing = track.playing;
+                }
+                None if view.is_loaded() => view.unload(),
+                None => {}
+            }
+        }
+    }
+
+    /// Name to show for a loaded file, preferring what the library knows
+    fn title_for(&self, path: &Path) -> String {
+        self.library
+            .playable()
+            .find(|track| track.file_path.as_deref() == Some(path))
+            .map(|track| track.display_name())
+            .or_else(|| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+            })
+            .unwrap_or_else(|| path.display().to_string())
+    }
+
+    /// Translate a key press into an action, if it maps to one
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        if self.picker.is_some() {
+            return self.handle_picker_key(key);
+        }
+
+        let view = self.selected_deck().clone();
+        let deck = view.deck;
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => {
+                self.should_quit = true;
+                None
+            }
+            KeyCode::Tab => {
+                self.selected = (self.selected + 1) % self.decks.len();
+                None
+            }
+            KeyCode::Char(digit @ '1'..='9') => {
+                let index = digit as usize - '1' as usize;
+                if index < self.decks.len() {
+                    self.selected = index;
+                }
+                None
+            }
+            KeyCode::Char(' ') if view.playing => Some(Action::Stop(deck)),
+            KeyCode::Char(' ') => Some(Action::Play(deck)),
+            KeyCode::Char('s') => Some(Action::Stop(deck)),
+            KeyCode::Char('u') => Some(Action::Unload(deck)),
+            KeyCode::Char('n') => Some(Action::Skip(deck)),
+            KeyCode::Char('r') if self.recording => Some(Action::StopRecording),
+            KeyCode::Char('r') => Some(Action::StartRecording),
+            KeyCode::Left => {
+                let position = view.position.unwrap_or(0);
+                Some(Action::Seek(
+                    deck,
+                    position.saturating_sub(SEEK_STEP_SECONDS * SAMPLES_PER_SECOND),
+                ))
+            }
+            KeyCode::Right => {
+                let position = view.position.unwrap_or(0) + SEEK_STEP_SECONDS * SAMPLES_PER_SECOND;
+                let position = match view.length {
+                    Some(length) => position.min(length),
+                    None => position,
+                };
+                Some(Action::Seek(deck, position))
+            }
+            KeyCode::Up => Some(Action::Volume(
+                deck,
+                (view.volume_db + VOLUME_STEP_DB).min(0.0),
+            )),
+            KeyCode::Down => Some(Action::Volume(
+                deck,
+                (view.volume_db - VOLUME_STEP_DB).max(-96.0),
+            )),
+            KeyCode::Char('l') | KeyCode::Char('/') => {
+                self.picker = Some(Picker::new(&self.library));
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_picker_key(&mut self, key: KeyEvent) -> Option<Action> {
+        let deck = self.selected_deck().deck;
+        let picker = self.picker.as_mut()?;
+
+        match key.code {
+            KeyCode::Esc => {
+                self.picker = None;
+                None
+            }
+            KeyCode::Enter => {
+                let path = picker.selected_path();
+                self.picker = None;
+                path.map(|path| Action::Load(deck, path))
+            }
+            KeyCode::Up => {
+                picker.previous();
+                None
+            }
+            KeyCode::Down => {
+                picker.next();
+                None
+            }
+            KeyCode::Backspace => {
+                picker.pop_char();
+                None
+            }
+            KeyCode::Char(c) => {
+                picker.push_char(c);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use media_protocol::TrackState;
+
+    #[test]
+    fn server_state_fills_in_what_events_missed() {
+        let mut app = App::new(Library::default());
+        app.apply_state(vec![DeckState {
+            deck: Deck::B,
+            volume_db: -6.0,
+            muted: false,
+            track: Some(TrackState {
+                path: PathBuf::from("/music/Suanne - Police.flac"),
+                content_hash: None,
+                playing: true,
+                position: 96000,
+                length: Some(960000),
+                sample_rate: 48000,
+                channels: 2,
+            }),
+            level: Level::default(),
+        }]);
+
+        let view = app.decks.iter().find(|view| view.deck == Deck::B).unwrap();
+        assert_eq!(view.title.as_deref(), Some("Suanne - Police"));
+        assert_eq!((view.position, view.length), (Some(96000), Some(960000)));
+        assert!(view.playing);
+        assert_eq!(view.volume_db, -6.0);
+
+        app.apply_state(vec![DeckState {
+            deck: Deck::B,
+            volume_db: -6.0,
+            muted: false,
+            track: None,
+            level: Level::default(),
+        }]);
+        let view = app.decks.iter().find(|view| view.deck == Deck::B).unwrap();
+        assert!(!view.is_loaded() && !view.playing);
+    }
+}
@@ -0,0 +1,139 @@
+mod app;
+mod picker;
+mod ui;
+
+use crate::commands::SAMPLES_PER_SECOND;
+use app::{Action, App};
+use color_eyre::Result;
+use crossterm::event::{self, KeyEvent, KeyEventKind};
+use media_client::{EventSubscriber, MediaClient};
+use media_protocol::{ClientError, Event};
+use music_library::Library;
+use ratatui::DefaultTerminal;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Run the interactive interface until the user quits
+pub async fn run(client: MediaClient, facts: &Path) -> Result<()> {
+    let mut app = match Library::open(facts) {
+        Ok(library) => {
+            let mut app = App::new(library);
+            app.status = format!("{} tracks in library", app.library.len());
+            app
+        }
+        Err(e) => {
+            let mut app = App::new(Library::default());
+            app.status = format!("Track picker unavailable: {}", e);
+            app
+        }
+    };
+
+    let (key_tx, key_rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || read_keys(key_tx));
+
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    let subscriber = EventSubscriber::connect("ipc:///tmp/mdma-events")?;
+    tokio::spawn(forward_events(subscriber, event_tx));
+
+    // Whatever was going on before the TUI started, events only tell of changes
+    refresh_from_server(&client, &mut app).await;
+
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &client, &mut app, key_rx, event_rx).await;
+    ratatui::restore();
+
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    client: &MediaClient,
+    app: &mut App,
+    mut keys: mpsc::UnboundedReceiver<KeyEvent>,
+    mut events: mpsc::UnboundedReceiver<Event>,
+) -> Result<()> {
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+
+    while !app.should_quit {
+        terminal.draw(|frame| ui::draw(frame, app))?;
+
+        tokio::select! {
+            Some(key) = keys.recv() => {
+                if let Some(action) = app.handle_key(key) {
+                    perform(client, app, action).await;
+                }
+            }
+            Some(event) = events.recv() => app.apply_event(event),
+            _ = refresh.tick() => refresh_from_server(client, app).await,
+        }
+    }
+
+    Ok(())
+}
+
+async fn perform(client: &MediaClient, app: &mut App, action: Action) {
+    let result = match action.clone() {
+        Action::Load(deck, path) => client.load_track(path, deck).await,
+        Action::Play(deck) => client.play(deck).await,
+        Action::Stop(deck) => client.stop(deck).await,
+        Action::Seek(deck, position) => client.seek(deck, position).await,
+        Action::Volume(deck, db) => client.set_volume(deck, db).await,
+        Action::Unload(deck) => client.unload_track(deck).await,
+        Action::Skip(deck) => client.skip(deck).await,
+        Action::StartRecording => client.start_recording(None).await.map(|_| ()),
+        Action::StopRecording => client.stop_recording().await.map(|_| ()),
+    };
+
+    app.status = match result {
+        Ok(()) => String::new(),
+        Err(e) => format!("{:?} failed: {}", action, e),
+    };
+}
+
+/// Poll what events don't tell us, like the play position, or events that went missing
+async fn refresh_from_server(client: &MediaClient, app: &mut App) {
+    if let Ok(states) = client.get_state().await {
+        app.apply_state(states);
+    }
+}
+
+/// Forward server events until the subscription breaks
+async fn forward_events(subscriber: EventSubscriber, tx: mpsc::UnboundedSender<Event>) {
+    loop {
+        match subscriber.next().await {
+            Ok(event) => {
+                if tx.send(event).is_err() {
+                    break;
+                }
+            }
+            // Skip events we can't parse, e.g. from a newer server
+            Err(ClientError::Protocol(_)) => continue,
+            Err(_) => break,
+        }
+    }
+}
+
+/// Blocking terminal reader, runs on its own thread
+fn read_keys(tx: mpsc::UnboundedSender<KeyEvent>) {
+    while let Ok(event) = event::read() {
+        if let event::Event::Key(key) = event {
+            if key.kind == KeyEventKind::Press && tx.send(key).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Format a sample count as mm:ss, or a placeholder when unknown
+fn format_samples(samples: Option<usize>) -> String {
+    match samples {
+        Some(samples) => {
+            let seconds = samples / SAMPLES_PER_SECOND;
+            format!("{:02}:{:02}", seconds / 60, seconds % 60)
+        }
+        None => "--:--".to_string(),
+    }
+}
@@ -0,0 +1,163 @@
+use music_library::Library;
+use std::path::PathBuf;
+
+/// A loadable track as shown in the picker
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Fuzzy track picker over the playable tracks of the library
+pub struct Picker {
+    candidates: Vec<Candidate>,
+    query: String,
+    matches: Vec<usize>,
+    selected: usize,
+}
+
+impl Picker {
+    pub fn new(library: &Library) -> Self {
+        let candidates = library
+            .playable()
+            .filter_map(|track| {
+                Some(Candidate {
+                    name: track.display_name(),
+                    path: track.file_path.clone()?,
+                })
+            })
+            .collect();
+
+        let mut picker = Self {
+            candidates,
+            query: String::new(),
+            matches: Vec::new(),
+            selected: 0,
+        };
+        picker.refresh();
+        picker
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Candidates matching the query, best match first
+    pub fn matches(&self) -> impl Iterator<Item = &Candidate> {
+        self.matches.iter().map(|&index| &self.candidates[index])
+    }
+
+    pub fn selected_path(&self) -> Option<PathBuf> {
+        let index = *self.matches.get(self.selected)?;
+        Some(self.candidates[index].path.clone())
+    }
+
+    pub fn push_char(&mut self, c: char) {
+        self.query.push(c);
+        self.refresh();
+    }
+
+    pub fn pop_char(&mut self) {
+        self.query.pop();
+        self.refresh();
+    }
+
+    pub fn next(&mut self) {
+        if self.selected + 1 < self.matches.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    fn refresh(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(index, candidate)| {
+                fuzzy_score(&self.query, &candidate.name).map(|score| (score, index))
+            })
+            .collect();
+
+        // Best score first, library order breaks ties
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        self.matches = scored.into_iter().map(|(_, index)| index).collect();
+        self.selected = 0;
+    }
+}
+
+/// Score how well `query` matches `candidate` as a case-insensitive subsequence
+///
+/// Returns `None` when not every query character can be found in order.
+/// Consecutive characters and characters at the start of a word score higher,
+/// so "sp" prefers "Suanne - Police" over "Sunspot".
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+
+    for wanted in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = (position..candidate.len()).find(|&i| candidate[i] == wanted)?;
+
+        score += 1;
+        if previous_match == Some(found.wrapping_sub(1)) {
+            score += 5;
+        }
+        if found == 0 || !candidate[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        // Small penalty for skipping characters
+        score -= (found - position).min(3) as i64;
+
+        previous_match = Some(found);
+        position = found + 1;
+    }
+
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(fuzzy_score("", "Anything"), Some(0));
+    }
+
+    #[test]
+    fn characters_must_appear_in_order() {
+        assert!(fuzzy_score("plc", "Police").is_some());
+        assert!(fuzzy_score("clp", "Police").is_none());
+    }
+
+    #[test]
+    fn matching_is_case_insensitive() {
+        assert!(fuzzy_score("SUANNE", "Suanne - Police").is_some());
+    }
+
+    #[test]
+    fn word_starts_beat_scattered_matches() {
+        let word_starts = fuzzy_score("sp", "Suanne - Police").unwrap();
+        let scattered = fuzzy_score("sp", "Sunspot").unwrap();
+
+        assert!(word_starts > scattered);
+    }
+
+    #[test]
+    fn consecutive_matches_beat_gaps() {
+        let consecutive = fuzzy_score("pol", "Police").unwrap();
+        let gaps = fuzzy_score("pol", "Pink Cola").unwrap();
+
+        assert!(consecutive > gaps);
+    }
+}
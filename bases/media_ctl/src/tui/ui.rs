@@ -0,0 +1,179 @@
+use super::app::{App, DeckView};
+use super::picker::Picker;
+use media_protocol::Level;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Clear, Gauge, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+
+// Bottom of the meter scale, quieter than this reads as empty
+const METER_FLOOR_DB: f32 = -60.0;
+
+const HELP: &str =
+    "Tab/1-4 deck  Space play/stop  s stop  ←/→ seek  ↑/↓ volume  l load  u unload  n next  r record  q quit";
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [decks_area, master_area, status_area, help_area] = Layout::vertical([
+        Constraint::Min(8),
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let deck_areas = Layout::horizontal(
+        app.decks
+            .iter()
+            .map(|_| Constraint::Ratio(1, app.decks.len() as u32)),
+    )
+    .split(decks_area);
+
+    for (index, view) in app.decks.iter().enumerate() {
+        draw_deck(frame, deck_areas[index], view, index == app.selected);
+    }
+
+    let mut master = String::from(if app.recording {
+        "● REC Master"
+    } else {
+        "Master"
+    });
+    if app.clips > 0 {
+        master.push_str(&format!("  {} clipped", app.clips));
+    }
+    if app.underruns > 0 {
+        master.push_str(&format!("  {} underruns", app.underruns));
+    }
+    frame.render_widget(meter(app.master, &master), master_area);
+    frame.render_widget(Paragraph::new(app.status.as_str()).yellow(), status_area);
+    frame.render_widget(Paragraph::new(HELP).dark_gray(), help_area);
+
+    if let Some(picker) = &app.picker {
+        draw_picker(frame, picker, app.selected_deck());
+    }
+}
+
+fn draw_deck(frame: &mut Frame, area: Rect, view: &DeckView, selected: bool) {
+    let border = if selected {
+        Style::new().fg(Color::Yellow)
+    } else {
+        Style::new()
+    };
+    let block = Block::bordered()
+        .title(format!(" Deck {} ", view.deck))
+        .border_style(border);
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [title_area, state_area, volume_area, level_area, _, position_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(inner);
+
+    let title = view.title.as_deref().unwrap_or("— empty —");
+    frame.render_widget(Paragraph::new(title).bold(), title_area);
+
+    let state = match (view.is_loaded(), view.playing) {
+        (false, _) => Span::raw("no track").dark_gray(),
+        (true, true) => Span::raw("▶ PLAYING").green(),
+        (true, false) => Span::raw("■ STOPPED").red(),
+    };
+    let mut state = vec![state];
+    if view.queued > 0 {
+        state.push(Span::raw(format!("  +{} queued", view.queued)).dark_gray());
+    }
+    frame.render_widget(Paragraph::new(Line::from(state)), state_area);
+
+    frame.render_widget(
+        Paragraph::new(format!("Volume {:>6.1} dB", view.volume_db)),
+        volume_area,
+    );
+    frame.render_widget(meter(view.level, "Level"), level_area);
+
+    let ratio = match (view.position, view.length) {
+        (Some(position), Some(length)) if length > 0 => {
+            (position as f64 / length as f64).clamp(0.0, 1.0)
+        }
+        _ => 0.0,
+    };
+    let label = format!(
+        "{} / {}",
+        super::format_samples(view.position),
+        super::format_samples(view.length)
+    );
+    frame.render_widget(
+        Gauge::default()
+            .gauge_style(Style::new().fg(Color::Cyan))
+            .ratio(ratio)
+            .label(label),
+        position_area,
+    );
+}
+
+/// Peak as a bar on a dB scale, turning red near full scale
+fn meter(level: Level, name: &str) -> Gauge<'static> {
+    let ratio = (1.0 - level.peak_db / METER_FLOOR_DB).clamp(0.0, 1.0);
+    let color = match level.peak_db {
+        peak if peak > -1.0 => Color::Red,
+        peak if peak > -9.0 => Color::Yellow,
+        _ => Color::Green,
+    };
+    Gauge::default()
+        .gauge_style(Style::new().fg(color))
+        .ratio(ratio as f64)
+        .label(format!(
+            "{} {:>5.1} dB peak {:>5.1} dB rms",
+            name, level.peak_db, level.rms_db
+        ))
+}
+
+fn draw_picker(frame: &mut Frame, picker: &Picker, deck: &DeckView) {
+    let area = centered(frame.area(), 80, 70);
+    frame.render_widget(Clear, area);
+
+    let block = Block::bordered().title(format!(" Load into deck {} ", deck.deck));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [query_area, list_area] =
+        Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(inner);
+
+    frame.render_widget(
+        Paragraph::new(format!("> {}", picker.query())).bold(),
+        query_area,
+    );
+
+    let items: Vec<ListItem> = picker
+        .matches()
+        .map(|candidate| ListItem::new(candidate.name.as_str()))
+        .collect();
+    let list = List::new(items)
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .highlight_symbol("> ");
+    let mut state = ListState::default().with_selected(Some(picker.selected()));
+    frame.render_stateful_widget(list, list_area, &mut state);
+}
+
+/// A rectangle of the given percentage size in the middle of `area`
+fn centered(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let [_, middle, _] = Layout::vertical([
+        Constraint::Percentage((100 - percent_y) / 2),
+        Constraint::Percentage(percent_y),
+        Constraint::Percentage((100 - percent_y) / 2),
+    ])
+    .areas(area);
+
+    let [_, center, _] = Layout::horizontal([
+        Constraint::Percentage((100 - percent_x) / 2),
+        Constraint::Percentage(percent_x),
+        Constraint::Percentage((100 - percent_x) / 2),
+    ])
+    .areas(middle);
+
+    center
+}
@@ -0,0 +1,249 @@
+use clap::Parser;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use playback_engine::{OutputConfig, SampleFormat};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Playback server configuration, from the config file and command line
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub output: OutputConfig,
+    /// Where deck queues are saved to survive a restart, they aren't unless one is given
+    pub queue_file: Option<PathBuf>,
+    /// Where recordings go when no path is given for them
+    pub recordings_dir: PathBuf,
+    /// Where every command that changes something is logged for replaying, if anywhere
+    pub event_log: Option<PathBuf>,
+    /// Join an Ableton Link session on the LAN, the sync tempo follows it
+    pub link: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            output: OutputConfig::default(),
+            queue_file: None,
+            recordings_dir: PathBuf::from("/music/recordings"),
+            event_log: None,
+            link: false,
+        }
+    }
+}
+
+/// MDMA playback server
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct CliArgs {
+    /// TOML file with an [output] table, flags given here take precedence
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Sample format sent to PipeWire (s16, s24, s32 or f32)
+    #[arg(long)]
+    pub format: Option<SampleFormat>,
+
+    /// Rate to run the device at, the 48 kHz mix is converted to it
+    #[arg(long)]
+    pub rate: Option<u32>,
+
+    /// Output channels, the stereo mix goes to the first two
+    #[arg(long)]
+    pub channels: Option<u16>,
+
+    /// PipeWire node name or serial to play to instead of the default sink
+    #[arg(long)]
+    pub target: Option<String>,
+
+    /// Frames per period, smaller is lower latency but less forgiving
+    #[arg(long)]
+    pub quantum: Option<u32>,
+
+    /// Milliseconds of mixed audio kept ahead of the device
+    #[arg(long)]
+    pub latency_ms: Option<u32>,
+
+    /// File to keep deck queues in between runs
+    #[arg(long)]
+    pub queue_file: Option<PathBuf>,
+
+    /// Don't keep deck queues between runs, whatever the config file says
+    #[arg(long, conflicts_with = "queue_file")]
+    pub no_queue_file: bool,
+
+    /// Directory for recordings started without a path
+    #[arg(long)]
+    pub recordings_dir: Option<PathBuf>,
+
+    /// File to log state changing commands to
+    #[arg(long)]
+    pub event_log: Option<PathBuf>,
+
+    /// Don't log commands, whatever the config file says
+    #[arg(long, conflicts_with = "event_log")]
+    pub no_event_log: bool,
+
+    /// Share tempo and beat with Ableton Link apps on the LAN
+    #[arg(long)]
+    pub link: bool,
+
+    /// Play the commands in an event log to a headless engine, then exit
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+
+    /// Replay commands back to back instead of at the pace they were logged
+    #[arg(long, requires = "replay")]
+    pub replay_fast: bool,
+}
+
+impl Config {
+    /// Read the config file if one was given, then apply the flags over it
+    pub fn from_args(args: CliArgs) -> Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        let output = &mut config.output;
+        if let Some(format) = args.format {
+            output.format = format;
+        }
+        if let Some(rate) = args.rate {
+            output.rate = rate;
+        }
+        if let Some(channels) = args.channels {
+            output.channels = channels;
+        }
+        if args.target.is_some() {
+            output.target = args.target;
+        }
+        if args.quantum.is_some() {
+            output.quantum = args.quantum;
+        }
+        if let Some(latency_ms) = args.latency_ms {
+            output.latency_ms = latency_ms;
+        }
+        if args.queue_file.is_some() || args.no_queue_file {
+            config.queue_file = args.queue_file;
+        }
+        if let Some(recordings_dir) = args.recordings_dir {
+            config.recordings_dir = recordings_dir;
+        }
+        if args.event_log.is_some() || args.no_event_log {
+            config.event_log = args.event_log;
+        }
+        if args.link {
+            config.link = true;
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read config {}", path.display()))?;
+        Self::parse(&content).wrap_err_with(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn missing_settings_keep_their_defaults() {
+        let config = Config::parse(
+            r#"
+            [output]
+            format = "f32"
+            target = "alsa_output.usb-Pioneer_DJM-900NXS2"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.output.format, SampleFormat::F32);
+        assert_eq!(
+            config.output.target.as_deref(),
+            Some("alsa_output.usb-Pioneer_DJM-900NXS2")
+        );
+        assert_eq!(config.output.rate, 48000);
+        assert_eq!(config.output.channels, 2);
+        assert_eq!(config.output.quantum, None);
+        assert_eq!(config.queue_file, None);
+        assert_eq!(config.event_log, None);
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(Config::parse("[output]\nfromat = \"f32\"").is_err());
+        assert!(Config::parse("[output]\nformat = \"u8\"").is_err());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "[output]\nformat = \"s24\"\nrate = 96000\nquantum = 512"
+        )
+        .unwrap();
+
+        let args = CliArgs::parse_from([
+            "playback-server",
+            "--config",
+            file.path().to_str().unwrap(),
+            "--format",
+            "F32",
+            "--quantum",
+            "128",
+            "--latency-ms",
+            "20",
+            "--recordings-dir",
+            "/tmp/sets",
+        ]);
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(config.recordings_dir, PathBuf::from("/tmp/sets"));
+        let output = config.output;
+
+        assert_eq!(output.format, SampleFormat::F32);
+        assert_eq!(output.rate, 96000);
+        assert_eq!(output.quantum, Some(128));
+        assert_eq!(output.latency_ms, 20);
+    }
+
+    #[test]
+    fn queues_and_the_event_log_can_be_turned_off() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "queue_file = \"queues.json\"\nevent_log = \"events.jsonl\""
+        )
+        .unwrap();
+        let config = |flags: &[&str]| {
+            let mut args = vec!["playback-server", "--config", file.path().to_str().unwrap()];
+            args.extend(flags);
+            Config::from_args(CliArgs::parse_from(args)).unwrap()
+        };
+
+        let kept = config(&[]);
+        assert_eq!(kept.queue_file, Some(PathBuf::from("queues.json")));
+        assert_eq!(kept.event_log, Some(PathBuf::from("events.jsonl")));
+        let off = config(&["--no-queue-file", "--no-event-log"]);
+        assert_eq!(off.queue_file, None);
+        assert_eq!(off.event_log, None);
+        assert!(CliArgs::try_parse_from([
+            "playback-server",
+            "--event-log",
+            "events.jsonl",
+            "--no-event-log"
+        ])
+        .is_err());
+    }
+}
@@ -5,6 +5,9 @@ pub enum ServerError {
     #[error("NNG error: {0}")]
     Nng(String),
 
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
 
@@ -0,0 +1,192 @@
+use chrono::{DateTime, Utc};
+use clock::{MusicalClock, TimeSource};
+use media_protocol::Command;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use time_primitives::{Ppqn, Ticks};
+use tracing::warn;
+
+/// A command that changed something, as it was handled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Since the server started logging
+    pub at_seconds: f64,
+    pub time: DateTime<Utc>,
+    /// Where the server's musical clock was, at the default resolution
+    pub tick: Ticks,
+    /// Whether the command succeeded, so a replay can tell when it went differently
+    pub ok: bool,
+    pub command: Command,
+}
+
+/// Whether `command` can change what the engine does, only those are logged
+pub fn is_logged(command: &Command) -> bool {
+    !matches!(
+        command,
+        Command::GetLength { .. }
+            | Command::GetPosition { .. }
+            | Command::GetState
+            | Command::GetLevels
+            | Command::GetStats
+            | Command::GetQueue { .. }
+            | Command::GetRecording
+    )
+}
+
+/// Append-only log of every state changing command, one JSON line each
+///
+/// Lines are flushed as they are written, so a crash loses nothing that
+/// was handled before it. Runs of the server follow one another in the
+/// same file, each starting again from 0 seconds and ticks.
+pub struct EventLog {
+    file: LineWriter<File>,
+    started: Instant,
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: LineWriter::new(file),
+            started: Instant::now(),
+        })
+    }
+
+    /// Add a handled command, if it is one worth keeping, at where `clock` is
+    pub fn record<T: TimeSource>(&mut self, command: &Command, ok: bool, clock: &MusicalClock<T>) {
+        if !is_logged(command) {
+            return;
+        }
+        let entry = Entry {
+            at_seconds: self.started.elapsed().as_secs_f64(),
+            time: Utc::now(),
+            tick: Ticks::from_beats(clock.beats(), Ppqn::DEFAULT),
+            ok,
+            command: command.clone(),
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(self.file, "{line}"));
+        if let Err(e) = written {
+            warn!("Failed to log {:?}: {}", entry.command, e);
+        }
+    }
+}
+
+/// Every entry in the log at `path`, oldest first
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, e),
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// How long to wait after `previous` before replaying `entry`
+///
+/// A new run of the server starts its clock again, its first entry
+/// follows straight on.
+pub fn wait_before(previous: Option<&Entry>, entry: &Entry) -> Duration {
+    let since = previous.map_or(0.0, |previous| entry.at_seconds - previous.at_seconds);
+    Duration::from_secs_f64(since.max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::SystemTimeSource;
+    use media_protocol::Deck;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use time_primitives::Tempo;
+
+    // Moved on by hand
+    #[derive(Clone)]
+    struct ManualTime(Rc<Cell<Instant>>);
+
+    impl TimeSource for ManualTime {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn only_changes_are_logged_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/events.jsonl");
+
+        let clock = MusicalClock::new(SystemTimeSource);
+        let mut log = EventLog::open(&path).unwrap();
+        log.record(&Command::GetState, true, &clock);
+        log.record(&Command::SetSyncTempo { bpm: Some(240.0) }, true, &clock);
+        log.record(&Command::Play { deck: Deck::B }, false, &clock);
+        drop(log);
+        // Another run appends rather than starting over
+        let mut log = EventLog::open(&path).unwrap();
+        log.record(&Command::Stop { deck: Deck::B }, true, &clock);
+
+        let entries = read(&path).unwrap();
+        let commands: Vec<_> = entries.iter().map(|entry| &entry.command).collect();
+        assert!(matches!(
+            commands.as_slice(),
+            [
+                Command::SetSyncTempo { bpm: Some(_) },
+                Command::Play { deck: Deck::B },
+                Command::Stop { deck: Deck::B }
+            ]
+        ));
+        assert!(entries[0].ok && !entries[1].ok);
+        assert!(entries[1].at_seconds >= entries[0].at_seconds);
+        assert!(entries[1].tick >= entries[0].tick);
+        assert_eq!(wait_before(None, &entries[0]), Duration::ZERO);
+
+        let mut restarted = entries[2].clone();
+        restarted.at_seconds = 0.0;
+        assert_eq!(wait_before(Some(&entries[1]), &restarted), Duration::ZERO);
+    }
+
+    #[test]
+    fn ticks_come_from_the_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let time = Rc::new(Cell::new(Instant::now()));
+        let clock = MusicalClock::new(ManualTime(time.clone()));
+
+        // Two beats at 120, then one at 60
+        time.set(time.get() + Duration::from_secs(1));
+        clock.set_tempo(Tempo::new(60.0).unwrap());
+        time.set(time.get() + Duration::from_secs(1));
+        let mut log = EventLog::open(&path).unwrap();
+        log.record(&Command::Play { deck: Deck::A }, true, &clock);
+
+        let entries = read(&path).unwrap();
+        assert_eq!(entries[0].tick, Ticks::from_beats(3.0, Ppqn::DEFAULT));
+    }
+
+    #[test]
+    fn broken_lines_say_where_they_are() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        std::fs::write(&path, "\n{\"not\": \"an entry\"}\n").unwrap();
+
+        let error = read(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2"));
+    }
+}
@@ -1,33 +1,121 @@
+mod config;
 mod error;
+mod event_log;
+mod scheduler;
 mod server;
+mod tracklist;
 
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::Path;
 use std::sync::Arc;
 
+use clap::Parser;
+use clock::link::{self, LinkSession};
+use clock::sync::Running;
+use clock::{MusicalClock, SystemTimeSource};
+use color_eyre::eyre::{bail, WrapErr};
 use color_eyre::Result;
+use config::{CliArgs, Config};
+use event_log::EventLog;
 use nng::{Protocol, Socket};
 use playback_engine::PlaybackEngine;
 use server::Server;
 
 use tokio::runtime::Runtime;
+use tracing::info;
 
 // In playback_server/src/main.rs
 fn main() -> Result<()> {
     // Initialize error handling and logging
     color_eyre::install()?;
     tracing_subscriber::fmt::init();
+    let args = CliArgs::parse();
+    let replay = args.replay.clone().map(|log| (log, args.replay_fast));
+    let config = Config::from_args(args)?;
 
     // Create a Tokio runtime explicitly
     let runtime = Runtime::new()?;
+    if let Some((log, fast)) = replay {
+        return runtime.block_on(replay_log(config, &log, fast));
+    }
 
     // Create the playback engine
-    let engine = Arc::new(tokio::sync::Mutex::new(PlaybackEngine::new()?));
+    let engine = PlaybackEngine::with_output_config(config.output)?;
+    let engine = Arc::new(tokio::sync::Mutex::new(engine));
     // Create NNG socket for receiving commands
     let socket = Socket::new(Protocol::Rep0)?;
     socket.listen("ipc:///tmp/mdma-commands")?;
+    // Create NNG socket for publishing events
+    let events = Socket::new(Protocol::Pub0)?;
+    events.listen("ipc:///tmp/mdma-events")?;
 
-    // Create and run server
-    let server = Server::new(engine, socket);
+    // Create and run server, keeping musical time on the clock Link shares too
+    let clock = Arc::new(MusicalClock::new(SystemTimeSource));
+    let mut server = Server::new(engine, socket, events)
+        .with_recordings_dir(config.recordings_dir)
+        .with_clock(clock.clone());
+    if let Some(queue_file) = config.queue_file {
+        server = server.with_queue_file(queue_file);
+    }
+    if let Some(path) = config.event_log {
+        let log = EventLog::open(&path)
+            .wrap_err_with(|| format!("Failed to open event log {}", path.display()))?;
+        server = server.with_event_log(log);
+    }
+    // Held while the server runs, the session is left when it drops
+    let mut _link = None;
+    if config.link {
+        let (session, running) = join_link(clock)?;
+        server = server.with_link(session);
+        _link = Some(running);
+    }
     runtime.block_on(server.run())?;
 
     Ok(())
 }
+
+// Join whatever Link session is going on the LAN, or start one for others to join
+fn join_link(
+    clock: Arc<MusicalClock<SystemTimeSource>>,
+) -> Result<(Arc<LinkSession<SystemTimeSource>>, Running)> {
+    let session = Arc::new(LinkSession::new(clock, SystemTimeSource));
+    let discovery = link::discovery_socket().wrap_err("Failed to open the Link port")?;
+    let measurement = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let group = (link::LINK_GROUP, link::LINK_PORT).into();
+    let running = link::join(discovery, group, measurement, session.clone())?;
+    info!("Taking part in Ableton Link as {}", session.id());
+    Ok((session, running))
+}
+
+// Reproduce a logged set or bug on an engine that plays to nowhere.
+// Nothing listens on the sockets, and nothing is saved or logged:
+// recordings go to a temporary directory, removed when the replay ends.
+async fn replay_log(config: Config, log: &Path, fast: bool) -> Result<()> {
+    let entries = event_log::read(log)
+        .wrap_err_with(|| format!("Failed to read event log {}", log.display()))?;
+    let engine = PlaybackEngine::headless_with_output_config(config.output)?;
+    let engine = Arc::new(tokio::sync::Mutex::new(engine));
+    let recordings = tempfile::tempdir()?;
+    let server = Server::new(
+        engine,
+        Socket::new(Protocol::Rep0)?,
+        Socket::new(Protocol::Pub0)?,
+    )
+    .with_recordings_dir(recordings.path().to_path_buf());
+
+    info!(
+        "Replaying {} commands from {}",
+        entries.len(),
+        log.display()
+    );
+    let differed = server.replay(&entries, fast).await;
+    if differed > 0 {
+        bail!(
+            "{} of {} commands went differently",
+            differed,
+            entries.len()
+        );
+    }
+    info!("Replayed {} commands", entries.len());
+    Ok(())
+}
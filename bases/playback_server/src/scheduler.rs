@@ -0,0 +1,316 @@
+use clock::protocol::Message;
+use clock::{MusicalClock, TimeSource};
+use playback_engine::{Cue, CueId, MixPosition, PlaybackEngine, PlaybackError};
+use playback_primitives::Db;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use time_primitives::Ticks;
+
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("{0:?} has no tick to be scheduled at")]
+    Untimed(Message),
+
+    #[error("Nothing scheduled as {0}, or it has already happened")]
+    Unknown(u64),
+
+    #[error(transparent)]
+    Playback(#[from] PlaybackError),
+}
+
+/// Messages waiting for their tick, as cues in the mixer
+///
+/// A tick is turned into the frame of the mix heard when the clock gets
+/// there, so the change lands on the same sample however late the mixer
+/// gets round to it. When the clock changes tempo or jumps,
+/// [`follow_clock`](Self::follow_clock) moves the cues to where their
+/// ticks land now.
+pub struct Scheduler<T: TimeSource> {
+    clock: Arc<MusicalClock<T>>,
+    scheduled: HashMap<u64, Pending>,
+    next_id: u64,
+    // Clock revision the cues were placed for
+    revision: u64,
+}
+
+// A message's cues, with the frame they are on
+struct Pending {
+    tick: Ticks,
+    cues: Vec<Cue>,
+    frame: u64,
+    ids: Vec<CueId>,
+}
+
+impl<T: TimeSource> Scheduler<T> {
+    pub fn new(clock: Arc<MusicalClock<T>>) -> Self {
+        let revision = clock.revision();
+        Self {
+            clock,
+            scheduled: HashMap::new(),
+            next_id: 0,
+            revision,
+        }
+    }
+
+    /// Frame of the mix heard when the clock reaches `tick`, or now once it has
+    pub fn frame_at(&self, position: &MixPosition, tick: Ticks) -> u64 {
+        position.heard() + position.frames_in(self.clock.time_until(tick))
+    }
+
+    /// Have `message` applied on its tick, answering the id to cancel it by
+    pub fn schedule(
+        &mut self,
+        engine: &mut PlaybackEngine,
+        message: Message,
+    ) -> Result<u64, ScheduleError> {
+        let (tick, cues) = match message {
+            Message::SetVolume {
+                channel,
+                tick,
+                volume,
+            } => (
+                tick,
+                vec![Cue::SetVolume {
+                    deck: channel,
+                    db: volume.raw(),
+                }],
+            ),
+            Message::SetMute {
+                channel,
+                tick,
+                muted,
+            } => (
+                tick,
+                vec![Cue::SetMute {
+                    deck: channel,
+                    muted,
+                }],
+            ),
+            // Starts at its volume, without a blip of the old one
+            Message::StartTrack {
+                channel,
+                start_position,
+                initial_volume,
+            } => (
+                start_position,
+                vec![
+                    Cue::SetVolume {
+                        deck: channel,
+                        db: initial_volume.raw(),
+                    },
+                    Cue::Play { deck: channel },
+                ],
+            ),
+            Message::LoadTrack { .. } | Message::StopChannel(_) => {
+                return Err(ScheduleError::Untimed(message));
+            }
+        };
+
+        let position = engine.mix_position();
+        self.forget_past(position.frames());
+        let frame = self.frame_at(&position, tick);
+        let ids = place(engine, frame, &cues)?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let pending = Pending {
+            tick,
+            cues,
+            frame,
+            ids,
+        };
+        self.scheduled.insert(id, pending);
+        Ok(id)
+    }
+
+    /// Whether the clock has changed since the cues were placed
+    pub fn is_behind_clock(&self) -> bool {
+        self.clock.revision() != self.revision
+    }
+
+    /// Move the cues waiting for their ticks to where the clock has them now
+    ///
+    /// A message that can't be placed again, a start on a deck unloaded
+    /// since, is dropped and the first such error answered.
+    pub fn follow_clock(&mut self, engine: &mut PlaybackEngine) -> Result<(), ScheduleError> {
+        let position = engine.mix_position();
+        self.forget_past(position.frames());
+        if !self.is_behind_clock() {
+            return Ok(());
+        }
+        self.revision = self.clock.revision();
+
+        let mut failed = None;
+        let mut ids = Vec::new();
+        for (id, pending) in &self.scheduled {
+            let frame = self.frame_at(&position, pending.tick);
+            if frame != pending.frame {
+                ids.push((*id, frame));
+            }
+        }
+        for (id, frame) in ids {
+            let pending = self.scheduled.get_mut(&id).expect("just looked at");
+            for cue in pending.ids.drain(..) {
+                engine.cancel(cue)?;
+            }
+            match place(engine, frame, &pending.cues) {
+                Ok(cues) => {
+                    pending.ids = cues;
+                    pending.frame = frame;
+                }
+                Err(e) => {
+                    self.scheduled.remove(&id);
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        failed.map_or(Ok(()), Err)
+    }
+
+    /// Take back a message that hasn't been applied yet
+    pub fn cancel(&mut self, engine: &mut PlaybackEngine, id: u64) -> Result<(), ScheduleError> {
+        self.forget_past(engine.mix_position().frames());
+        let pending = self
+            .scheduled
+            .remove(&id)
+            .ok_or(ScheduleError::Unknown(id))?;
+        for id in pending.ids {
+            engine.cancel(id)?;
+        }
+        Ok(())
+    }
+
+    // Messages the mixer has got past can't be taken back
+    fn forget_past(&mut self, mixed: u64) {
+        self.scheduled.retain(|_, pending| pending.frame >= mixed);
+    }
+}
+
+// All of a message's cues on `frame`, or none of them
+fn place(
+    engine: &mut PlaybackEngine,
+    frame: u64,
+    cues: &[Cue],
+) -> Result<Vec<CueId>, ScheduleError> {
+    let mut ids = Vec::with_capacity(cues.len());
+    for cue in cues {
+        match engine.schedule(frame, *cue) {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                for id in ids {
+                    engine.cancel(id)?;
+                }
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::protocol::FileRef;
+    use playback_engine::Deck;
+    use playback_primitives::Volume;
+    use std::time::Instant;
+    use time_primitives::Tempo;
+
+    #[derive(Clone)]
+    struct StoppedTime(Instant);
+
+    impl TimeSource for StoppedTime {
+        fn now(&self) -> Instant {
+            self.0
+        }
+    }
+
+    fn scheduler() -> Scheduler<StoppedTime> {
+        let clock = MusicalClock::new(StoppedTime(Instant::now()));
+        Scheduler::new(Arc::new(clock))
+    }
+
+    #[test]
+    fn ticks_land_on_the_frame_heard_then() {
+        let scheduler = scheduler();
+        let position = MixPosition::default();
+
+        // A beat at 120 BPM is half a second, 24000 frames of the mix
+        assert_eq!(scheduler.frame_at(&position, Ticks::new(960)), 24000);
+        assert_eq!(scheduler.frame_at(&position, Ticks::new(0)), 0);
+    }
+
+    #[test]
+    fn messages_without_a_tick_are_refused() {
+        let mut scheduler = scheduler();
+        let mut engine = PlaybackEngine::headless().unwrap();
+
+        let load = Message::LoadTrack {
+            file: FileRef::new("/music/loud.flac"),
+            channel: Deck::A,
+        };
+        assert!(matches!(
+            scheduler.schedule(&mut engine, load),
+            Err(ScheduleError::Untimed(_))
+        ));
+        let start = Message::StartTrack {
+            channel: Deck::A,
+            start_position: Ticks::new(960),
+            initial_volume: Volume::UNITY,
+        };
+        assert!(matches!(
+            scheduler.schedule(&mut engine, start),
+            Err(ScheduleError::Playback(PlaybackError::NoTrackLoaded(_)))
+        ));
+    }
+
+    #[test]
+    fn tempo_changes_move_what_is_waiting() {
+        let clock = Arc::new(MusicalClock::new(StoppedTime(Instant::now())));
+        let mut scheduler = Scheduler::new(clock.clone());
+        let mut engine = PlaybackEngine::headless().unwrap();
+
+        let fade = Message::SetVolume {
+            channel: Deck::B,
+            tick: Ticks::new(960 * 64),
+            volume: Volume::new(-12.0).unwrap(),
+        };
+        let id = scheduler.schedule(&mut engine, fade).unwrap();
+        let frame = scheduler.scheduled[&id].frame;
+        assert!(!scheduler.is_behind_clock());
+
+        clock.set_tempo(Tempo::new(240.0).unwrap());
+        assert!(scheduler.is_behind_clock());
+        scheduler.follow_clock(&mut engine).unwrap();
+        // Twice as fast, the 64 beats ahead take half as long, give or take the mix going on
+        let heard = engine.mix_position().heard();
+        let moved = scheduler.scheduled[&id].frame;
+        assert!(moved < frame);
+        assert!(
+            moved.abs_diff(heard + 32 * 24000) <= 4800,
+            "{}",
+            moved - heard
+        );
+        assert!(!scheduler.is_behind_clock());
+    }
+
+    #[test]
+    fn scheduled_messages_can_be_cancelled_once() {
+        let mut scheduler = scheduler();
+        let mut engine = PlaybackEngine::headless().unwrap();
+
+        // Far enough ahead that the mixer is nowhere near it
+        let fade = Message::SetVolume {
+            channel: Deck::B,
+            tick: Ticks::new(960 * 64),
+            volume: Volume::new(-12.0).unwrap(),
+        };
+        let id = scheduler.schedule(&mut engine, fade).unwrap();
+        scheduler.cancel(&mut engine, id).unwrap();
+        assert!(matches!(
+            scheduler.cancel(&mut engine, id),
+            Err(ScheduleError::Unknown(_))
+        ));
+    }
+}
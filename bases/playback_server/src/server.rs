@@ -1,32 +1,188 @@
 use crate::error::ServerError;
+use crate::event_log::{self, Entry, EventLog};
+use crate::scheduler::Scheduler;
+use crate::tracklist::{tracklist_path, Moment, Tracklist};
+use atomic_file::write_atomically;
+use chrono::Utc;
+use clock::link::LinkSession;
+use clock::{MusicalClock, SystemTimeSource};
 use color_eyre::Result;
-use media_protocol::{Command, Deck as ProtocolChannel, Response, ResponseData};
+use media_protocol::{
+    Command, Deck, DeckQueue, DeckState, DeckStats, Event, Level, Levels, Recording, Response,
+    ResponseData, Stats, TrackState,
+};
+use music_facts::ContentHash;
 use nng::Socket;
-use playback_engine::{self, PlaybackEngine, PlaybackError};
+use playback_engine::{self, Health, Meters, PlaybackEngine, PlaybackError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use time_primitives::Tempo;
 use tokio::sync::Mutex;
 use tracing::{info, warn};
 
+// Often enough for meters to move smoothly
+const LEVELS_INTERVAL: Duration = Duration::from_millis(100);
+// Counters only move when something went wrong, no need to hurry
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+// Soon enough after a queue moves on for a UI to show the new track
+const QUEUE_INTERVAL: Duration = Duration::from_millis(250);
+// Well inside a beat, so a tempo change from a Link peer lands on the next one
+const LINK_INTERVAL: Duration = Duration::from_millis(50);
+// How soon scheduled messages move after the clock changes tempo, under the mixer's lookahead
+const RETIME_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct Server {
     engine: Arc<Mutex<PlaybackEngine>>,
     socket: Socket,
+    events: Socket,
+    // Filled in the background after loads, hashing a track takes a while
+    content_hashes: Arc<parking_lot::Mutex<HashMap<PathBuf, ContentHash>>>,
+    // Where deck queues are kept between runs
+    queue_file: Option<PathBuf>,
+    recordings_dir: PathBuf,
+    tracklist: Tracklist,
+    event_log: Option<Arc<parking_lot::Mutex<EventLog>>>,
+    link: Option<Link>,
+    // Musical time for scheduling and logging, shared with the Link session if there is one
+    clock: Arc<MusicalClock<SystemTimeSource>>,
+    scheduler: Arc<parking_lot::Mutex<Scheduler<SystemTimeSource>>>,
+    // Threads publishing telemetry, stopped and joined when the server goes
+    telemetry: parking_lot::Mutex<Vec<std::thread::JoinHandle<()>>>,
+    stop: Arc<Stop>,
+}
+
+// Tells the telemetry threads to stop, waking them from their sleep
+#[derive(Default)]
+struct Stop {
+    stopped: parking_lot::Mutex<bool>,
+    wake: parking_lot::Condvar,
+}
+
+impl Stop {
+    // Sleep for `interval`, false once told to stop
+    fn sleep(&self, interval: Duration) -> bool {
+        let mut stopped = self.stopped.lock();
+        if !*stopped {
+            self.wake.wait_for(&mut stopped, interval);
+        }
+        !*stopped
+    }
+
+    fn stop(&self) {
+        *self.stopped.lock() = true;
+        self.wake.notify_all();
+    }
+}
+
+// An Ableton Link session the sync tempo follows, unless sync was turned off here
+#[derive(Clone)]
+struct Link {
+    session: Arc<LinkSession<SystemTimeSource>>,
+    following: Arc<AtomicBool>,
+}
+
+impl Link {
+    // A tempo set here goes to the session, sync being turned off stops following it
+    fn share(&self, tempo: Option<Tempo>) {
+        if let Some(tempo) = tempo {
+            self.session.set_tempo(tempo);
+        }
+        self.following.store(tempo.is_some(), Ordering::Relaxed);
+    }
+
+    // Give the engine the session's tempo when it has changed
+    //
+    // Logged as if set here, so a replay changes tempo where the set did.
+    fn follow(
+        &self,
+        engine: &Mutex<PlaybackEngine>,
+        log: Option<&parking_lot::Mutex<EventLog>>,
+        last: &mut Option<Tempo>,
+    ) {
+        let tempo = self.session.tempo();
+        if !self.following.load(Ordering::Relaxed) || *last == Some(tempo) {
+            return;
+        }
+        if let Err(e) = engine.blocking_lock().set_sync_tempo(Some(tempo)) {
+            warn!("Failed to follow the Link tempo: {}", e);
+            return;
+        }
+        *last = Some(tempo);
+        if let Some(log) = log {
+            let command = Command::SetSyncTempo {
+                bpm: Some(tempo.raw()),
+            };
+            log.lock().record(&command, true, self.session.clock());
+        }
+    }
 }
 
 impl Server {
-    // Convert protocol channel to playback channel
-    fn convert_deck(protocol_channel: ProtocolChannel) -> playback_engine::Deck {
-        match protocol_channel {
-            ProtocolChannel::A => playback_engine::Deck::A,
-            ProtocolChannel::B => playback_engine::Deck::B,
+    pub fn new(engine: Arc<Mutex<PlaybackEngine>>, socket: Socket, events: Socket) -> Self {
+        let clock = Arc::new(MusicalClock::new(SystemTimeSource));
+        Self {
+            engine,
+            socket,
+            events,
+            content_hashes: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+            queue_file: None,
+            // Relative to where the server runs, until told otherwise
+            recordings_dir: PathBuf::from("recordings"),
+            tracklist: Tracklist::default(),
+            event_log: None,
+            link: None,
+            scheduler: Arc::new(parking_lot::Mutex::new(Scheduler::new(clock.clone()))),
+            clock,
+            telemetry: parking_lot::Mutex::new(Vec::new()),
+            stop: Arc::new(Stop::default()),
         }
     }
 
-    pub fn new(engine: Arc<Mutex<PlaybackEngine>>, socket: Socket) -> Self {
-        Self { engine, socket }
+    /// Run scheduling and logging on `clock`, along with whatever else keeps time by it
+    pub fn with_clock(mut self, clock: Arc<MusicalClock<SystemTimeSource>>) -> Self {
+        self.scheduler = Arc::new(parking_lot::Mutex::new(Scheduler::new(clock.clone())));
+        self.clock = clock;
+        self
+    }
+
+    /// Follow the tempo of `session` while sync is on, and share the sync tempo set here
+    ///
+    /// Scheduling and logging run on the session's clock.
+    pub fn with_link(mut self, session: Arc<LinkSession<SystemTimeSource>>) -> Self {
+        self = self.with_clock(session.clock().clone());
+        self.link = Some(Link {
+            session,
+            following: Arc::new(AtomicBool::new(true)),
+        });
+        self
+    }
+
+    /// Log the commands that change something to `log`, so they can be replayed
+    pub fn with_event_log(mut self, log: EventLog) -> Self {
+        self.event_log = Some(Arc::new(parking_lot::Mutex::new(log)));
+        self
+    }
+
+    /// Record to new files in `dir` unless told where
+    pub fn with_recordings_dir(mut self, dir: PathBuf) -> Self {
+        self.recordings_dir = dir;
+        self
+    }
+
+    /// Save deck queues to `path` as they change, and pick them up from it on start
+    pub fn with_queue_file(mut self, path: PathBuf) -> Self {
+        self.queue_file = Some(path);
+        self
     }
 
     pub async fn run(&self) -> Result<(), ServerError> {
         info!("Playback server starting...");
+        self.restore_queues().await;
+        self.publish_telemetry().await;
 
         loop {
             // Receive command
@@ -36,9 +192,19 @@ impl Server {
             info!("Received command: {:?}", command);
 
             // Process command
+            let event = Self::event_for(&command);
+            let logged = self.event_log.is_some().then(|| command.clone());
             let response = self.handle_command(command).await;
+            if let Some(command) = logged {
+                self.log(&command, response.success);
+            }
 
             info!("Handled command, response {:?}", response);
+            if let (true, Some(event)) = (response.success, event) {
+                let moment = Moment::of(&*self.engine.lock().await);
+                self.tracklist.note(&moment, &event);
+                self.publish(event);
+            }
             // Send response
             let response_data = serde_json::to_vec(&response)?;
             self.socket
@@ -53,61 +219,571 @@ impl Server {
             Command::LoadTrack { path, deck } => {
                 info!("Loading track {:?} on deck {:?}", path, deck);
                 // Use .await to acquire the lock asynchronously
-                let result = self
-                    .engine
-                    .lock()
-                    .await
-                    .load_track(Self::convert_deck(deck), &path)
-                    .await;
+                let result = self.engine.lock().await.load_track(deck, &path).await;
                 info!("Track loaded");
+                if result.is_ok() {
+                    self.hash_in_background(path);
+                }
                 self.create_response(result, None)
             } // For non-async operations, keep the original pattern
             Command::Play { deck } => {
                 info!("About to play deck {:?}", deck);
-                let result = self.engine.lock().await.play(Self::convert_deck(deck));
+                let result = self.engine.lock().await.play(deck);
                 info!("Play command completed for deck {:?}: {:?}", deck, result);
                 self.create_response(result, None)
             }
             Command::Stop { deck } => {
                 info!("Stopping deck {:?}", deck);
-                let result = self.engine.lock().await.stop(Self::convert_deck(deck));
+                let result = self.engine.lock().await.stop(deck);
                 self.create_response(result, None)
             }
             Command::SetVolume { deck, db } => {
                 info!("Setting volume on deck {:?} to {}dB", deck, db);
-                let result = self
-                    .engine
-                    .lock()
-                    .await
-                    .set_volume(Self::convert_deck(deck), db);
+                let result = self.engine.lock().await.set_volume(deck, db);
                 self.create_response(result, None)
             }
             Command::Unload { deck } => {
                 info!("Unloading deck {:?}", deck);
+                let result = self.engine.lock().await.unload_track(deck);
+                self.create_response(result, None)
+            }
+            Command::Seek { deck, position } => {
+                info!("Seeking deck {:?} to position {}", deck, position);
+                let result = self.engine.lock().await.seek(deck, position).await; // Now awaiting the seek operation
+                self.create_response(result, None)
+            }
+            Command::GetLength { deck } => {
+                info!("Getting length for deck {:?}", deck);
+                let state = self.deck_state(deck).await;
+                match state.track.map(|track| track.length) {
+                    Some(Some(length)) => {
+                        self.create_response(Ok(()), Some(ResponseData::Length(length)))
+                    }
+                    Some(None) => Response {
+                        success: false,
+                        error_message: format!("Length of the track on deck {} is unknown", deck),
+                        data: None,
+                    },
+                    None => self.create_response(Err(PlaybackError::NoTrackLoaded(deck)), None),
+                }
+            }
+            Command::GetPosition { deck } => {
+                info!("Getting position for deck {:?}", deck);
+                let state = self.deck_state(deck).await;
+                match state.track {
+                    Some(track) => {
+                        self.create_response(Ok(()), Some(ResponseData::Position(track.position)))
+                    }
+                    None => self.create_response(Err(PlaybackError::NoTrackLoaded(deck)), None),
+                }
+            }
+            Command::GetState => {
+                let mut states = Vec::with_capacity(Deck::MAX as usize);
+                for deck in Deck::all() {
+                    states.push(self.deck_state(deck).await);
+                }
+                self.create_response(Ok(()), Some(ResponseData::DeckState(states)))
+            }
+            Command::LoadPad { pad, clip } => {
+                info!(
+                    "Loading {:?} onto pad {} as {:?}",
+                    clip.path, pad, clip.mode
+                );
                 let result = self
                     .engine
                     .lock()
                     .await
-                    .unload_track(Self::convert_deck(deck));
+                    .load_pad(pad, &clip.path, clip.mode, clip.gain_db)
+                    .await;
                 self.create_response(result, None)
             }
-            Command::Seek { deck, position } => {
-                info!("Seeking deck {:?} to position {}", deck, position);
+            Command::UnloadPad { pad } => {
+                info!("Unloading pad {}", pad);
+                let result = self.engine.lock().await.unload_pad(pad);
+                self.create_response(result, None)
+            }
+            Command::TriggerPad { pad } => {
+                let result = self.engine.lock().await.trigger_pad(pad);
+                self.create_response(result, None)
+            }
+            Command::ReleasePad { pad } => {
+                let result = self.engine.lock().await.release_pad(pad);
+                self.create_response(result, None)
+            }
+            Command::StopPad { pad } => {
+                let result = self.engine.lock().await.stop_pad(pad);
+                self.create_response(result, None)
+            }
+            Command::SetSyncTempo { bpm } => {
+                info!("Setting sync tempo to {:?}", bpm);
+                match bpm.map(Tempo::new).transpose() {
+                    Ok(tempo) => {
+                        let mut engine = self.engine.lock().await;
+                        let result = engine.set_sync_tempo(tempo);
+                        // Sync going off leaves the beat where it is
+                        if let (Ok(()), Some(tempo)) = (&result, tempo) {
+                            self.clock.set_tempo(tempo);
+                            Self::retime(&self.scheduler, &mut engine);
+                        }
+                        drop(engine);
+                        if let (Ok(()), Some(link)) = (&result, &self.link) {
+                            link.share(tempo);
+                        }
+                        self.create_response(result, None)
+                    }
+                    Err(e) => Response {
+                        success: false,
+                        error_message: e.to_string(),
+                        data: None,
+                    },
+                }
+            }
+            Command::SetEffect(change) => {
                 let result = self
                     .engine
                     .lock()
                     .await
-                    .seek(Self::convert_deck(deck), position)
-                    .await; // Now awaiting the seek operation
+                    .set_effect(change.target, change.settings);
                 self.create_response(result, None)
             }
-            Command::GetLength { deck } => {
-                info!("Getting length for deck {:?}", deck);
-                todo!("get length of track, or remove opportunity")
+            Command::SetDeckBpm { deck, bpm } => {
+                info!("Deck {} is at {} BPM", deck, bpm);
+                let result = self.engine.lock().await.set_deck_bpm(deck, bpm);
+                self.create_response(result, None)
+            }
+            Command::GetLevels => {
+                let levels = convert_levels(self.engine.lock().await.levels());
+                self.create_response(Ok(()), Some(ResponseData::Levels(levels)))
+            }
+            Command::GetStats => {
+                let stats = convert_stats(self.engine.lock().await.stats());
+                self.create_response(Ok(()), Some(ResponseData::Stats(stats)))
+            }
+            Command::Enqueue { deck, path } => {
+                info!("Queueing {:?} on deck {:?}", path, deck);
+                let result = self.engine.lock().await.enqueue(deck, &path).await;
+                if result.is_ok() {
+                    self.hash_in_background(path);
+                }
+                self.create_response(result, None)
+            }
+            Command::ClearQueue { deck } => {
+                self.engine.lock().await.clear_queue(deck);
+                self.create_response(Ok(()), None)
+            }
+            Command::Skip { deck } => {
+                info!("Skipping on deck {:?}", deck);
+                let result = self.engine.lock().await.skip(deck);
+                self.create_response(result, None)
+            }
+            Command::GetQueue { deck } => {
+                let queue = convert_queue(self.engine.lock().await.queue_state(deck));
+                self.create_response(Ok(()), Some(ResponseData::Queue(queue)))
+            }
+            Command::SetCrossfade { deck, seconds } => {
+                info!("Crossfading deck {:?} over {}s", deck, seconds);
+                let result = self.engine.lock().await.set_crossfade(deck, seconds);
+                self.create_response(result, None)
+            }
+            Command::StartRecording { path } => {
+                let path = match self.recording_path(path) {
+                    Ok(path) => path,
+                    Err(e) => return self.create_response(Err(e), None),
+                };
+                let mut engine = self.engine.lock().await;
+                if let Err(e) = engine.start_recording(&path) {
+                    return self.create_response(Err(e), None);
+                }
+                let moment = Moment::of(&engine);
+                let recording = engine.recording().map(convert_recording);
+                drop(engine);
+
+                // A recording without its tracklist is still worth having
+                if let Err(e) = self.tracklist.start(&tracklist_path(&path), &moment) {
+                    warn!("No tracklist for {}: {}", path.display(), e);
+                }
+                if let Some(recording) = &recording {
+                    self.publish(Event::RecordingStarted(recording.clone()));
+                }
+                self.create_response(Ok(()), Some(ResponseData::Recording(recording)))
+            }
+            Command::StopRecording => {
+                let ended = self.engine.lock().await.end_recording();
+                self.tracklist.stop();
+                // The writer may have seconds of mix to flush, not with the engine held
+                let result = match ended {
+                    Ok(recording) => tokio::task::spawn_blocking(|| recording.finish())
+                        .await
+                        .unwrap_or(Err(PlaybackError::TaskCancelled)),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(state) => {
+                        let recording = convert_recording(state);
+                        self.publish(Event::RecordingStopped(recording.clone()));
+                        let data = ResponseData::Recording(Some(recording));
+                        self.create_response(Ok(()), Some(data))
+                    }
+                    Err(e) => self.create_response(Err(e), None),
+                }
+            }
+            Command::GetRecording => {
+                let recording = self.engine.lock().await.recording().map(convert_recording);
+                self.create_response(Ok(()), Some(ResponseData::Recording(recording)))
+            }
+            Command::Schedule(message) => {
+                info!("Scheduling {:?}", message);
+                let mut engine = self.engine.lock().await;
+                match self.scheduler.lock().schedule(&mut engine, message) {
+                    Ok(id) => self.create_response(Ok(()), Some(ResponseData::Scheduled(id))),
+                    Err(e) => Response {
+                        success: false,
+                        error_message: e.to_string(),
+                        data: None,
+                    },
+                }
+            }
+            Command::CancelScheduled { id } => {
+                info!("Cancelling scheduled message {}", id);
+                let mut engine = self.engine.lock().await;
+                match self.scheduler.lock().cancel(&mut engine, id) {
+                    Ok(()) => self.create_response(Ok(()), None),
+                    Err(e) => Response {
+                        success: false,
+                        error_message: e.to_string(),
+                        data: None,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Handle the commands of an event log again, at the pace they were logged unless `fast`
+    ///
+    /// Returns how many went differently, failing where they had succeeded
+    /// or the other way round.
+    pub async fn replay(&self, entries: &[Entry], fast: bool) -> usize {
+        let mut differed = 0;
+        let mut previous = None;
+        for entry in entries {
+            if !fast {
+                tokio::time::sleep(event_log::wait_before(previous, entry)).await;
+            }
+            previous = Some(entry);
+
+            let response = self.handle_command(entry.command.clone()).await;
+            if response.success != entry.ok {
+                differed += 1;
+                warn!(
+                    "{:?} at tick {} {} this time: {}",
+                    entry.command,
+                    entry.tick.raw(),
+                    if response.success {
+                        "succeeded"
+                    } else {
+                        "failed"
+                    },
+                    response.error_message
+                );
+            }
+        }
+        differed
+    }
+
+    fn log(&self, command: &Command, ok: bool) {
+        if let Some(log) = &self.event_log {
+            log.lock().record(command, ok, &self.clock);
+        }
+    }
+
+    async fn deck_state(&self, deck: Deck) -> DeckState {
+        let state = self.engine.lock().await.state(deck);
+        let hashes = self.content_hashes.lock();
+
+        DeckState {
+            deck,
+            volume_db: state.volume_db,
+            muted: state.muted,
+            track: state.track.map(|track| TrackState {
+                content_hash: hashes.get(&track.path).cloned(),
+                path: track.path,
+                playing: track.playing,
+                position: track.position,
+                length: track.length,
+                sample_rate: track.sample_rate,
+                channels: track.channels,
+            }),
+            level: convert_level(state.level),
+        }
+    }
+
+    // Meters, glitch counters and queues change without any command, so
+    // they go out on a timer rather than after commands. Nothing is sent
+    // while they sit still.
+    async fn publish_telemetry(&self) {
+        let engine = self.engine.lock().await;
+        let (meters, health) = (engine.meters(), engine.health());
+        drop(engine);
+
+        let mut last = None;
+        self.publish_every(LEVELS_INTERVAL, move || {
+            Self::levels_event(&meters, &mut last)
+        });
+        let mut last = None;
+        self.publish_every(STATS_INTERVAL, move || {
+            Self::stats_event(&health, &mut last)
+        });
+        let (engine, queue_file) = (self.engine.clone(), self.queue_file.clone());
+        let tracklist = self.tracklist.clone();
+        let mut last = QueueWatch::default();
+        let mut saved = BTreeMap::new();
+        self.publish_every(QUEUE_INTERVAL, move || {
+            let (events, moment) = {
+                let engine = engine.blocking_lock();
+                (Self::queue_events(&engine, &mut last), Moment::of(&engine))
+            };
+            // Written with the engine unlocked, the disk can take its time
+            for event in &events {
+                tracklist.note(&moment, event);
+            }
+            // Loads and unloads are saved too, they change no queue
+            let decks = last.saved();
+            if let (Some(path), true) = (&queue_file, decks != saved) {
+                save_queues(path, &decks);
+                saved = decks;
+            }
+            events
+        });
+        let (engine, scheduler) = (self.engine.clone(), self.scheduler.clone());
+        // Link and MIDI move the clock from their own threads
+        self.publish_every(RETIME_INTERVAL, move || {
+            if scheduler.lock().is_behind_clock() {
+                let mut engine = engine.blocking_lock();
+                Self::retime(&scheduler, &mut engine);
+            }
+            None
+        });
+        if let Some(link) = self.link.clone() {
+            let (engine, event_log) = (self.engine.clone(), self.event_log.clone());
+            let mut last = None;
+            // Nothing to publish, it only runs on the same kind of schedule
+            self.publish_every(LINK_INTERVAL, move || {
+                link.follow(&engine, event_log.as_deref(), &mut last);
+                None
+            });
+        }
+    }
+
+    // Scheduled messages to where their ticks land since the clock changed
+    fn retime(
+        scheduler: &parking_lot::Mutex<Scheduler<SystemTimeSource>>,
+        engine: &mut PlaybackEngine,
+    ) {
+        if let Err(e) = scheduler.lock().follow_clock(engine) {
+            warn!("Dropped a scheduled message the clock moved: {}", e);
+        }
+    }
+
+    fn publish_every<I>(
+        &self,
+        interval: Duration,
+        mut next_events: impl FnMut() -> I + Send + 'static,
+    ) where
+        I: IntoIterator<Item = Event>,
+    {
+        let (events, stop) = (self.events.clone(), self.stop.clone());
+        let thread = std::thread::spawn(move || {
+            while stop.sleep(interval) {
+                for event in next_events() {
+                    send_event(&events, event);
+                }
+            }
+        });
+        self.telemetry.lock().push(thread);
+    }
+
+    fn levels_event(meters: &Meters, last: &mut Option<Levels>) -> Option<Event> {
+        let levels = convert_levels(meters.levels());
+        if last.as_ref() == Some(&levels) {
+            return None;
+        }
+        *last = Some(levels.clone());
+        Some(Event::Levels(levels))
+    }
+
+    fn stats_event(health: &Health, last: &mut Option<Stats>) -> Option<Event> {
+        let stats = convert_stats(health.stats());
+        if last.as_ref() == Some(&stats) {
+            return None;
+        }
+        *last = Some(stats.clone());
+        Some(Event::Stats(stats))
+    }
+
+    // Decks that moved on to their next file, and queues that changed
+    fn queue_events(engine: &PlaybackEngine, last: &mut QueueWatch) -> Vec<Event> {
+        let mut events = Vec::new();
+        for deck in Deck::all() {
+            match engine.state(deck).track {
+                Some(track) => {
+                    // A fresh load starts counting from 0 again
+                    if last
+                        .advances
+                        .get(&deck)
+                        .is_some_and(|n| *n < track.advances)
+                    {
+                        events.push(Event::Advanced {
+                            deck,
+                            path: track.path.clone(),
+                        });
+                    }
+                    last.advances.insert(deck, track.advances);
+                    last.loaded.insert(deck, track.path);
+                }
+                None => {
+                    last.advances.remove(&deck);
+                    last.loaded.remove(&deck);
+                }
+            }
+
+            let queue = convert_queue(engine.queue_state(deck));
+            if last.queues.get(&deck).unwrap_or(&DeckQueue::default()) != &queue {
+                events.push(Event::QueueChanged {
+                    deck,
+                    queue: queue.clone(),
+                });
+                if queue == DeckQueue::default() {
+                    last.queues.remove(&deck);
+                } else {
+                    last.queues.insert(deck, queue);
+                }
+            }
+        }
+        events
+    }
+
+    // Load and queue up again whatever the decks had when the server last
+    // stopped. The decks are empty, so each one loads the first file it is
+    // given.
+    async fn restore_queues(&self) {
+        let Some(path) = &self.queue_file else {
+            return;
+        };
+        let queues = match load_queues(path) {
+            Ok(queues) => queues,
+            Err(e) => {
+                warn!("Not restoring queues from {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        let mut engine = self.engine.lock().await;
+        for (deck, SavedDeck { loaded, queue }) in queues {
+            info!(
+                "Restoring {} queued files on deck {:?}",
+                queue.entries.len(),
+                deck
+            );
+            // Logged like any other command, a replay starts from the same queues
+            let seconds = queue.crossfade_seconds;
+            let result = engine.set_crossfade(deck, seconds);
+            if let Err(e) = &result {
+                warn!("Not restoring crossfade on deck {:?}: {}", deck, e);
+            }
+            self.log(&Command::SetCrossfade { deck, seconds }, result.is_ok());
+            for path in loaded.into_iter().chain(queue.entries) {
+                let result = engine.enqueue(deck, &path).await;
+                if let Err(e) = &result {
+                    warn!("Dropping {} from deck {:?}: {}", path.display(), deck, e);
+                }
+                self.log(&Command::Enqueue { deck, path }, result.is_ok());
             }
         }
     }
 
+    // Hash a loaded file once, so GetState can identify it in the library
+    fn hash_in_background(&self, path: PathBuf) {
+        if self.content_hashes.lock().contains_key(&path) {
+            return;
+        }
+
+        let hashes = self.content_hashes.clone();
+        tokio::task::spawn_blocking(move || match ContentHash::from_file(&path) {
+            Ok(hash) => {
+                hashes.lock().insert(path, hash);
+            }
+            Err(e) => warn!("Failed to hash {}: {}", path.display(), e),
+        });
+    }
+
+    // Event to announce once a command has succeeded
+    fn event_for(command: &Command) -> Option<Event> {
+        match command {
+            Command::LoadTrack { path, deck } => Some(Event::TrackLoaded {
+                deck: *deck,
+                path: path.clone(),
+            }),
+            Command::Play { deck } => Some(Event::Playing { deck: *deck }),
+            Command::Stop { deck } => Some(Event::Stopped { deck: *deck }),
+            Command::SetVolume { deck, db } => Some(Event::VolumeChanged {
+                deck: *deck,
+                db: *db,
+            }),
+            Command::Unload { deck } => Some(Event::TrackUnloaded { deck: *deck }),
+            Command::Seek { deck, position } => Some(Event::Seeked {
+                deck: *deck,
+                position: *position,
+            }),
+            Command::GetLength { .. } | Command::GetPosition { .. } | Command::GetState => None,
+            // Pads are fire and forget, nothing to follow
+            Command::LoadPad { .. }
+            | Command::UnloadPad { .. }
+            | Command::TriggerPad { .. }
+            | Command::ReleasePad { .. }
+            | Command::StopPad { .. }
+            | Command::SetSyncTempo { .. } => None,
+            Command::SetEffect(_) | Command::SetDeckBpm { .. } => None,
+            // Levels and stats are published on their own
+            Command::GetLevels | Command::GetStats => None,
+            // So are queues, which also move when a track ends
+            Command::Enqueue { .. }
+            | Command::ClearQueue { .. }
+            | Command::Skip { .. }
+            | Command::GetQueue { .. }
+            | Command::SetCrossfade { .. } => None,
+            // Recordings are announced by their handlers, which know the path
+            Command::StartRecording { .. } | Command::StopRecording | Command::GetRecording => None,
+            // Nothing has changed yet, the change happens on its tick
+            Command::Schedule(_) | Command::CancelScheduled { .. } => None,
+        }
+    }
+
+    fn publish(&self, event: Event) {
+        send_event(&self.events, event);
+    }
+
+    /// Where to record, always in the recordings directory
+    ///
+    /// Clients only choose the file name, a new one named after the time
+    /// if they don't.
+    fn recording_path(&self, name: Option<PathBuf>) -> Result<PathBuf, PlaybackError> {
+        let name = match name {
+            Some(name) => {
+                let mut components = name.components();
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(file)), None) => PathBuf::from(file),
+                    _ => return Err(PlaybackError::RecordingName(name)),
+                }
+            }
+            None => Utc::now()
+                .format("set-%Y-%m-%d_%H-%M-%S.flac")
+                .to_string()
+                .into(),
+        };
+        std::fs::create_dir_all(&self.recordings_dir)?;
+        Ok(self.recordings_dir.join(name))
+    }
+
     // Add a helper method to create responses
     fn create_response(
         &self,
@@ -135,39 +811,166 @@ impl Server {
     }
 }
 
+impl Drop for Server {
+    // The threads hold the engine and event socket, they go with the server
+    fn drop(&mut self) {
+        self.stop.stop();
+        for thread in self.telemetry.get_mut().drain(..) {
+            if thread.join().is_err() {
+                warn!("A telemetry thread panicked");
+            }
+        }
+    }
+}
+
+/// What the queue watcher last saw of the decks
+#[derive(Default)]
+struct QueueWatch {
+    // Times the track on each deck had moved on
+    advances: HashMap<Deck, u64>,
+    // File heard on each deck that has one
+    loaded: BTreeMap<Deck, PathBuf>,
+    // Only decks with something queued or a crossfade set
+    queues: BTreeMap<Deck, DeckQueue>,
+}
+
+impl QueueWatch {
+    // What to save of the decks, so a restart picks up where they were
+    fn saved(&self) -> BTreeMap<Deck, SavedDeck> {
+        let decks = self.loaded.keys().chain(self.queues.keys());
+        decks
+            .map(|deck| {
+                let saved = SavedDeck {
+                    loaded: self.loaded.get(deck).cloned(),
+                    queue: self.queues.get(deck).cloned().unwrap_or_default(),
+                };
+                (*deck, saved)
+            })
+            .collect()
+    }
+}
+
+/// A deck as kept in the queue file
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct SavedDeck {
+    // Loaded again before the queue, files saved without it only have a queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    loaded: Option<PathBuf>,
+    #[serde(flatten)]
+    queue: DeckQueue,
+}
+
+fn load_queues(path: &Path) -> Result<BTreeMap<Deck, SavedDeck>, ServerError> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_queues(path: &Path, queues: &BTreeMap<Deck, SavedDeck>) {
+    let write = || -> Result<(), ServerError> {
+        write_atomically(path, &serde_json::to_vec_pretty(queues)?)?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        warn!("Failed to save queues to {}: {}", path.display(), e);
+    }
+}
+
+fn send_event(events: &Socket, event: Event) {
+    let data = match serde_json::to_vec(&event) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Failed to serialize event {:?}: {}", event, e);
+            return;
+        }
+    };
+
+    if let Err((_, e)) = events.send(&data) {
+        warn!("Failed to publish event {:?}: {}", event, e);
+    }
+}
+
+fn convert_level(level: playback_engine::Level) -> Level {
+    Level {
+        peak_db: level.peak_db,
+        rms_db: level.rms_db,
+    }
+}
+
+fn convert_levels(levels: playback_engine::Levels) -> Levels {
+    Levels {
+        decks: levels
+            .decks
+            .into_iter()
+            .map(|(deck, level)| (deck, convert_level(level)))
+            .collect(),
+        master: convert_level(levels.master),
+        clips: levels.clips,
+        reduction_db: levels.reduction_db,
+    }
+}
+
+fn convert_queue(queue: playback_engine::QueueState) -> DeckQueue {
+    DeckQueue {
+        entries: queue.entries,
+        crossfade_seconds: queue.crossfade_seconds,
+    }
+}
+
+fn convert_recording(state: playback_engine::RecordingState) -> Recording {
+    Recording {
+        tracklist: tracklist_path(&state.path),
+        path: state.path,
+        started: state.started,
+        seconds: state.seconds,
+        dropped_samples: state.dropped_samples,
+    }
+}
+
+fn convert_deck_stats(stats: playback_engine::DeckStats) -> DeckStats {
+    DeckStats {
+        buffer_underruns: stats.buffer_underruns,
+        decode_errors: stats.decode_errors,
+        late_seeks: stats.late_seeks,
+    }
+}
+
+fn convert_stats(stats: playback_engine::Stats) -> Stats {
+    Stats {
+        decks: stats
+            .decks
+            .into_iter()
+            .map(|(deck, stats)| (deck, convert_deck_stats(stats)))
+            .collect(),
+        totals: convert_deck_stats(stats.totals),
+        output_underruns: stats.output_underruns,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::path::PathBuf;
 
-    #[test]
-    fn test_channel_conversion() {
-        use playback_engine::Deck as PlaybackChannel;
-
-        assert!(matches!(
-            Server::convert_deck(ProtocolChannel::A),
-            PlaybackChannel::A
-        ));
+    fn headless_server() -> Server {
+        let engine = PlaybackEngine::headless().unwrap();
+        let engine = Arc::new(Mutex::new(engine));
 
-        assert!(matches!(
-            Server::convert_deck(ProtocolChannel::B),
-            PlaybackChannel::B
-        ));
+        let socket = nng::Socket::new(nng::Protocol::Rep0).unwrap();
+        let events = nng::Socket::new(nng::Protocol::Pub0).unwrap();
+        Server::new(engine, socket, events)
     }
 
-    #[tokio::test]
-    #[ignore]
+    #[tokio::test(flavor = "multi_thread")]
     async fn test_handle_nonexistent_track() {
-        let engine = PlaybackEngine::new().unwrap();
-        let engine = Arc::new(Mutex::new(engine));
-
-        let socket = nng::Socket::new(nng::Protocol::Rep0).unwrap();
-        let server = Server::new(engine, socket);
+        let server = headless_server();
 
         let nonexistent_path = PathBuf::from("/this/file/does/not/exist.flac");
         let command = Command::LoadTrack {
             path: nonexistent_path.clone(),
-            deck: ProtocolChannel::A,
+            deck: Deck::A,
         };
 
         let response = server.handle_command(command).await;
@@ -180,4 +983,501 @@ mod tests {
         );
         assert!(response.data.is_none());
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_state_of_empty_decks() {
+        let server = headless_server();
+
+        let response = server.handle_command(Command::GetState).await;
+        assert!(response.success);
+        let Some(ResponseData::DeckState(states)) = response.data else {
+            panic!("expected deck state, got {:?}", response.data);
+        };
+        assert_eq!(states.len(), Deck::MAX as usize);
+        assert_eq!(states[0].deck, Deck::A);
+        assert_eq!(states[3].deck, Deck::D);
+        assert!(states.iter().all(|state| state.track.is_none()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_position_without_track() {
+        let server = headless_server();
+
+        let response = server
+            .handle_command(Command::GetPosition { deck: Deck::A })
+            .await;
+        assert!(!response.success);
+        assert!(response.data.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pad_commands_need_a_clip() {
+        let server = headless_server();
+        let pad = media_protocol::Pad::new(0).unwrap();
+
+        let response = server.handle_command(Command::TriggerPad { pad }).await;
+        assert!(!response.success);
+        assert!(response.error_message.contains("pad 0"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_tempo_is_validated() {
+        let server = headless_server();
+
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: Some(128.0) })
+            .await;
+        assert!(response.success);
+
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: Some(1000.0) })
+            .await;
+        assert!(!response.success);
+
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: None })
+            .await;
+        assert!(response.success);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_tempo_follows_link() {
+        use clock::MusicalClock;
+
+        let dir = tempfile::tempdir().unwrap();
+        let log_file = dir.path().join("events.jsonl");
+        let clock = Arc::new(MusicalClock::new(SystemTimeSource));
+        let session = Arc::new(LinkSession::new(clock, SystemTimeSource));
+        let server = headless_server()
+            .with_link(session.clone())
+            .with_event_log(EventLog::open(&log_file).unwrap());
+        let link = server.link.clone().unwrap();
+        let (engine, log) = (server.engine.clone(), server.event_log.clone());
+        let follow = move |mut last| {
+            let (link, engine, log) = (link.clone(), engine.clone(), log.clone());
+            tokio::task::spawn_blocking(move || {
+                link.follow(&engine, log.as_deref(), &mut last);
+                last
+            })
+        };
+
+        // A tempo set here is shared with the session
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: Some(128.0) })
+            .await;
+        assert!(response.success);
+        assert!((session.tempo().raw() - 128.0).abs() < 0.001);
+
+        // A peer's change is followed, once
+        session.set_tempo(Tempo::new(140.0).unwrap());
+        let last = follow(None).await.unwrap();
+        assert_eq!(last, Some(session.tempo()));
+        assert_eq!(follow(last).await.unwrap(), last);
+
+        // Not while sync is off here
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: None })
+            .await;
+        assert!(response.success);
+        session.set_tempo(Tempo::new(150.0).unwrap());
+        assert_eq!(follow(last).await.unwrap(), last);
+
+        // Followed changes are logged as if they were made here
+        let logged: Vec<_> = event_log::read(&log_file)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.command)
+            .collect();
+        assert!(matches!(
+            logged.as_slice(),
+            [Command::SetSyncTempo { bpm: Some(bpm) }] if (*bpm - 140.0).abs() < 0.001
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scheduled_messages_answer_an_id() {
+        use clock::protocol::Message;
+        use time_primitives::Ticks;
+
+        let server = headless_server();
+        let response = server
+            .handle_command(Command::Schedule(Message::StopChannel(Deck::A)))
+            .await;
+        assert!(!response.success);
+        assert!(response.error_message.contains("no tick"));
+
+        let mute = Message::SetMute {
+            channel: Deck::A,
+            tick: Ticks::new(960 * 64),
+            muted: true,
+        };
+        let response = server.handle_command(Command::Schedule(mute)).await;
+        let Some(ResponseData::Scheduled(id)) = response.data else {
+            panic!("expected an id, got {:?}", response.data);
+        };
+        let response = server.handle_command(Command::CancelScheduled { id }).await;
+        assert!(response.success, "{}", response.error_message);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scheduled_changes_show_in_deck_state() {
+        use clock::protocol::Message;
+        use playback_primitives::Volume;
+        use time_primitives::Ticks;
+
+        let server = headless_server();
+        let quieten = Message::SetVolume {
+            channel: Deck::B,
+            tick: Ticks::ZERO,
+            volume: Volume::new(-12.0).unwrap(),
+        };
+        let mute = Message::SetMute {
+            channel: Deck::B,
+            tick: Ticks::ZERO,
+            muted: true,
+        };
+        for message in [quieten, mute] {
+            let response = server.handle_command(Command::Schedule(message)).await;
+            assert!(response.success, "{}", response.error_message);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let state = server.deck_state(Deck::B).await;
+        assert_eq!(state.volume_db, -12.0);
+        assert!(state.muted);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scheduling_runs_on_the_sync_tempo() {
+        use time_primitives::Ticks;
+
+        let server = headless_server();
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: Some(60.0) })
+            .await;
+        assert!(response.success);
+
+        // A beat at 60 BPM is a second of the mix, give or take a block
+        let position = server.engine.lock().await.mix_position();
+        let (first, second) = {
+            let scheduler = server.scheduler.lock();
+            let first = scheduler.frame_at(&position, Ticks::new(960 * 4));
+            (first, scheduler.frame_at(&position, Ticks::new(960 * 5)))
+        };
+        assert!(second.abs_diff(first + 48000) <= 1024, "{}", second - first);
+
+        // Sync going off keeps the beat it had
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: None })
+            .await;
+        assert!(response.success);
+        assert_eq!(server.clock.tempo(), Tempo::new(60.0).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scheduled_messages_keep_to_their_tick_through_tempo_changes() {
+        use clock::protocol::Message;
+        use time_primitives::Ticks;
+
+        let server = headless_server();
+        // Four beats from now, two seconds at 120 BPM
+        let tick = Ticks::new(server.clock.position().raw() + 960 * 4);
+        let mute = Message::SetMute {
+            channel: Deck::A,
+            tick,
+            muted: true,
+        };
+        let response = server.handle_command(Command::Schedule(mute)).await;
+        assert!(response.success, "{}", response.error_message);
+
+        // At 240 BPM they go by in a second
+        let response = server
+            .handle_command(Command::SetSyncTempo { bpm: Some(240.0) })
+            .await;
+        assert!(response.success, "{}", response.error_message);
+        tokio::time::sleep(Duration::from_millis(1400)).await;
+
+        assert!(server.deck_state(Deck::A).await.muted);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_effect_settings_are_validated() {
+        use media_protocol::{EffectChange, EffectParams, EffectSettings, EffectTarget};
+
+        let server = headless_server();
+        let mut change = EffectChange {
+            target: EffectTarget::Deck(Deck::A),
+            settings: EffectSettings {
+                enabled: true,
+                wet: 1.0,
+                params: EffectParams::Filter { amount: -0.5 },
+            },
+        };
+
+        let response = server
+            .handle_command(Command::SetEffect(change.clone()))
+            .await;
+        assert!(response.success, "{}", response.error_message);
+
+        change.settings.params = EffectParams::Filter { amount: 3.0 };
+        let response = server.handle_command(Command::SetEffect(change)).await;
+        assert!(!response.success);
+        assert!(response.error_message.contains("amount"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_telemetry_stops_with_the_server() {
+        let server = headless_server();
+        let engine = server.engine.clone();
+        server.publish_telemetry().await;
+        assert_eq!(server.telemetry.lock().len(), 4);
+        assert!(Arc::strong_count(&engine) > 2);
+
+        // Without waiting out the slowest interval
+        let start = std::time::Instant::now();
+        drop(server);
+        assert!(start.elapsed() < STATS_INTERVAL);
+        assert_eq!(Arc::strong_count(&engine), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_levels_are_only_published_when_they_move() {
+        let server = headless_server();
+        let meters = server.engine.lock().await.meters();
+
+        let response = server.handle_command(Command::GetLevels).await;
+        assert!(matches!(
+            response.data,
+            Some(ResponseData::Levels(levels)) if levels.decks.is_empty() && levels.clips == 0
+        ));
+
+        let mut last = None;
+        assert!(matches!(
+            Server::levels_event(&meters, &mut last),
+            Some(Event::Levels(_))
+        ));
+        assert!(Server::levels_event(&meters, &mut last).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_queues_are_saved_and_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue_file = dir.path().join("queues.json");
+        let server = headless_server().with_queue_file(queue_file.clone());
+
+        let response = server
+            .handle_command(Command::Enqueue {
+                deck: Deck::A,
+                path: PathBuf::from("/this/file/does/not/exist.flac"),
+            })
+            .await;
+        assert!(!response.success);
+        let response = server.handle_command(Command::Skip { deck: Deck::A }).await;
+        assert!(!response.success);
+
+        let response = server
+            .handle_command(Command::SetCrossfade {
+                deck: Deck::B,
+                seconds: 6.0,
+            })
+            .await;
+        assert!(response.success, "{}", response.error_message);
+
+        let mut last = QueueWatch::default();
+        let events = Server::queue_events(&*server.engine.lock().await, &mut last);
+        assert!(matches!(
+            events.as_slice(),
+            [Event::QueueChanged { deck: Deck::B, queue }] if queue.crossfade_seconds == 6.0
+        ));
+        assert!(Server::queue_events(&*server.engine.lock().await, &mut last).is_empty());
+        save_queues(&queue_file, &last.saved());
+
+        let restarted = headless_server().with_queue_file(queue_file);
+        restarted.restore_queues().await;
+        let response = restarted
+            .handle_command(Command::GetQueue { deck: Deck::B })
+            .await;
+        assert!(matches!(
+            response.data,
+            Some(ResponseData::Queue(queue)) if queue.crossfade_seconds == 6.0 && queue.entries.is_empty()
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recordings_keep_a_tracklist() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = headless_server().with_recordings_dir(dir.path().to_path_buf());
+
+        let response = server.handle_command(Command::StopRecording).await;
+        assert!(!response.success);
+
+        let response = server
+            .handle_command(Command::StartRecording { path: None })
+            .await;
+        assert!(response.success, "{}", response.error_message);
+        let Some(ResponseData::Recording(Some(recording))) = response.data else {
+            panic!("expected a recording, got {:?}", response.data);
+        };
+        assert!(recording.path.starts_with(dir.path()));
+        let response = server
+            .handle_command(Command::StartRecording { path: None })
+            .await;
+        assert!(!response.success);
+
+        let loaded = Event::TrackLoaded {
+            deck: Deck::C,
+            path: PathBuf::from("/music/opener.flac"),
+        };
+        let moment = Moment::of(&*server.engine.lock().await);
+        server.tracklist.note(&moment, &loaded);
+        server
+            .tracklist
+            .note(&moment, &Event::Playing { deck: Deck::C });
+
+        let response = server.handle_command(Command::StopRecording).await;
+        assert!(response.success, "{}", response.error_message);
+        assert!(recording.path.exists());
+        let response = server.handle_command(Command::GetRecording).await;
+        assert!(matches!(response.data, Some(ResponseData::Recording(None))));
+
+        let tracklist = std::fs::read_to_string(&recording.tracklist).unwrap();
+        let cues: Vec<serde_json::Value> = tracklist
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[0]["deck"], "C");
+        assert_eq!(cues[0]["action"], "loaded");
+        assert_eq!(cues[0]["path"], "/music/opener.flac");
+        assert_eq!(cues[1]["action"], "playing");
+        assert!(cues[1]["at_seconds"].as_f64().unwrap() >= 0.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recordings_stay_in_their_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let recordings = dir.path().join("recordings");
+        let server = headless_server().with_recordings_dir(recordings.clone());
+
+        for path in ["../escaped.flac", "/tmp/elsewhere.flac", "sets/nested.flac"] {
+            let path = Some(PathBuf::from(path));
+            let response = server
+                .handle_command(Command::StartRecording { path })
+                .await;
+            assert!(!response.success, "recorded outside the directory");
+        }
+        assert!(!dir.path().join("escaped.flac").exists());
+        assert!(!recordings.join("sets").exists());
+
+        let path = Some(PathBuf::from("named.flac"));
+        let response = server
+            .handle_command(Command::StartRecording { path })
+            .await;
+        assert!(response.success, "{}", response.error_message);
+        let response = server.handle_command(Command::StopRecording).await;
+        let Some(ResponseData::Recording(Some(recording))) = response.data else {
+            panic!("expected a recording, got {:?}", response.data);
+        };
+        assert_eq!(recording.path, recordings.join("named.flac"));
+        assert!(recording.path.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loaded_tracks_are_restored_before_the_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue_file = dir.path().join("queues.json");
+        let log_file = dir.path().join("events.jsonl");
+        let last = QueueWatch {
+            loaded: BTreeMap::from([(Deck::A, PathBuf::from("/music/playing.flac"))]),
+            queues: BTreeMap::from([(
+                Deck::A,
+                DeckQueue {
+                    entries: vec![PathBuf::from("/music/next.flac")],
+                    crossfade_seconds: 0.0,
+                },
+            )]),
+            ..QueueWatch::default()
+        };
+        save_queues(&queue_file, &last.saved());
+        assert_eq!(load_queues(&queue_file).unwrap(), last.saved());
+
+        let server = headless_server()
+            .with_queue_file(queue_file)
+            .with_event_log(EventLog::open(&log_file).unwrap());
+        server.restore_queues().await;
+        let enqueued: Vec<_> = event_log::read(&log_file)
+            .unwrap()
+            .into_iter()
+            .filter_map(|entry| match entry.command {
+                Command::Enqueue { path, .. } => Some(path),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            enqueued,
+            [
+                PathBuf::from("/music/playing.flac"),
+                PathBuf::from("/music/next.flac")
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replays_follow_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue_file = dir.path().join("queues.json");
+        let log_file = dir.path().join("events.jsonl");
+        let queued = SavedDeck {
+            loaded: None,
+            queue: DeckQueue {
+                entries: vec![],
+                crossfade_seconds: 4.0,
+            },
+        };
+        save_queues(&queue_file, &BTreeMap::from([(Deck::D, queued)]));
+
+        // Restored queues are logged, so a replay starts from them too
+        let server = headless_server()
+            .with_queue_file(queue_file)
+            .with_event_log(EventLog::open(&log_file).unwrap());
+        server.restore_queues().await;
+        server.log(&Command::Play { deck: Deck::A }, false);
+        server.log(&Command::GetState, true);
+
+        let entries = event_log::read(&log_file).unwrap();
+        assert_eq!(entries.len(), 2);
+        let replayed = headless_server();
+        assert_eq!(replayed.replay(&entries, true).await, 0);
+        let response = replayed
+            .handle_command(Command::GetQueue { deck: Deck::D })
+            .await;
+        assert!(matches!(
+            response.data,
+            Some(ResponseData::Queue(queue)) if queue.crossfade_seconds == 4.0
+        ));
+
+        // Play worked when logged but has nothing to play now
+        let mut entries = entries;
+        entries[1].ok = true;
+        assert_eq!(headless_server().replay(&entries, false).await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stats_start_clean_and_are_published_once() {
+        let server = headless_server();
+        let health = server.engine.lock().await.health();
+
+        let response = server.handle_command(Command::GetStats).await;
+        assert!(matches!(
+            response.data,
+            Some(ResponseData::Stats(stats)) if stats.decks.is_empty() && stats.totals == DeckStats::default()
+        ));
+
+        let mut last = None;
+        assert!(matches!(
+            Server::stats_event(&health, &mut last),
+            Some(Event::Stats(_))
+        ));
+        assert!(Server::stats_event(&health, &mut last).is_none());
+    }
 }
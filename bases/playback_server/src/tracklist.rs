@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use media_protocol::{Deck, Event};
+use parking_lot::Mutex;
+use playback_engine::PlaybackEngine;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Where the tracklist of the recording at `recording` goes
+pub fn tracklist_path(recording: &Path) -> PathBuf {
+    recording.with_extension("tracklist.jsonl")
+}
+
+/// One thing that happened on a deck while recording
+#[derive(Debug, Serialize)]
+struct Cue<'a> {
+    /// Into the recording
+    at_seconds: f64,
+    time: DateTime<Utc>,
+    deck: Deck,
+    action: &'static str,
+    path: Option<&'a Path>,
+}
+
+/// What the tracklist needs of the engine, copied while it is locked
+///
+/// Notes are written from the snapshot, so the disk is never waited on
+/// with the engine held.
+#[derive(Debug, Default)]
+pub struct Moment {
+    /// Into the recording, `None` when nothing is being recorded
+    seconds: Option<f64>,
+    tracks: Vec<(Deck, PathBuf, bool)>,
+}
+
+impl Moment {
+    pub fn of(engine: &PlaybackEngine) -> Self {
+        let tracks = Deck::all()
+            .filter_map(|deck| {
+                let track = engine.state(deck).track?;
+                Some((deck, track.path, track.playing))
+            })
+            .collect();
+        Self {
+            seconds: engine.recording().map(|recording| recording.seconds),
+            tracks,
+        }
+    }
+
+    fn path(&self, deck: Deck) -> Option<PathBuf> {
+        let (_, path, _) = self.tracks.iter().find(|(on, ..)| *on == deck)?;
+        Some(path.clone())
+    }
+}
+
+/// The sidecar of a recording, saying what was played when
+///
+/// Each cue is a JSON line, written as it happens so a crash keeps
+/// everything up to it. Shared by everything that publishes deck events.
+#[derive(Clone, Default)]
+pub struct Tracklist {
+    file: Arc<Mutex<Option<LineWriter<File>>>>,
+}
+
+impl Tracklist {
+    /// Begin a new list, starting with what the decks already have
+    pub fn start(&self, path: &Path, moment: &Moment) -> io::Result<()> {
+        *self.file.lock() = Some(LineWriter::new(File::create_new(path)?));
+
+        for (deck, path, playing) in &moment.tracks {
+            let (deck, path) = (*deck, path.clone());
+            self.note(moment, &Event::TrackLoaded { deck, path });
+            if *playing {
+                self.note(moment, &Event::Playing { deck });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        *self.file.lock() = None;
+    }
+
+    /// Add a deck event to the list, if a recording is running
+    pub fn note(&self, moment: &Moment, event: &Event) {
+        let mut file = self.file.lock();
+        let (Some(out), Some(seconds)) = (file.as_mut(), moment.seconds) else {
+            return;
+        };
+
+        let (deck, action, path) = match event {
+            Event::TrackLoaded { deck, path } => (*deck, "loaded", Some(path.clone())),
+            Event::Advanced { deck, path } => (*deck, "advanced", Some(path.clone())),
+            Event::Playing { deck } => (*deck, "playing", moment.path(*deck)),
+            Event::Stopped { deck } => (*deck, "stopped", moment.path(*deck)),
+            Event::TrackUnloaded { deck } => (*deck, "unloaded", None),
+            _ => return,
+        };
+        let cue = Cue {
+            at_seconds: seconds,
+            time: Utc::now(),
+            deck,
+            action,
+            path: path.as_deref(),
+        };
+
+        let written = serde_json::to_string(&cue)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(out, "{line}"));
+        if let Err(e) = written {
+            warn!("Failed to add {:?} to the tracklist: {}", cue, e);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Files that are replaced whole, for state kept between runs
+//!
+//! A crash or power cut part way through a write leaves either the old
+//! contents or the new ones, never half of each.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Write `contents` to `path` so a crash leaves either the old file or the new one
+///
+/// The contents go beside it with a "partial" extension first, reach the
+/// disk, and only then are renamed over it. The directory is synced after
+/// the rename so the new name survives a power cut too.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    let mut file = File::create(&partial)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    sync_directory(path)
+}
+
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Directories can't be opened as files here, the rename is as far as it goes
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_new_contents_replace_the_old() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queues.json");
+        write_atomically(&path, b"old").unwrap();
+        write_atomically(&path, b"new").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!path.with_extension("partial").exists());
+    }
+
+    #[test]
+    fn a_missing_directory_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gone").join("queues.json");
+
+        assert!(write_atomically(&path, b"new").is_err());
+    }
+}
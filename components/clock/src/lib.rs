@@ -2,8 +2,12 @@ use parking_lot::RwLock;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
+use time_primitives::{Ppqn, Tempo, TempoMap, TempoPoint, Ticks};
 
+pub mod link;
+pub mod midi;
 pub mod protocol;
+pub mod sync;
 
 #[derive(Debug, Error)]
 pub enum ClockError {
@@ -25,55 +29,165 @@ impl TimeSource for SystemTimeSource {
 }
 
 pub struct ClockState {
-    ticks: u64,
-    tempo: f64,
-    last_tick_time: Instant,
+    map: TempoMap,
+    // Where on the map the clock was at `anchor`, it runs on from there
+    anchor: Instant,
+    anchor_time: Duration,
+    // Bumped whenever ticks start landing at other times
+    revision: u64,
 }
 
 impl ClockState {
-    fn new(time_source: &dyn TimeSource) -> Self {
+    fn new(time_source: &dyn TimeSource, map: TempoMap) -> Self {
         Self {
-            ticks: 0,
-            tempo: 120.0,
-            last_tick_time: time_source.now(),
+            map,
+            anchor: time_source.now(),
+            anchor_time: Duration::ZERO,
+            revision: 0,
         }
     }
+
+    // Time along the map, from tick 0
+    fn time_at(&self, now: Instant) -> Duration {
+        self.anchor_time + now.saturating_duration_since(self.anchor)
+    }
+
+    fn beats_at(&self, now: Instant) -> f64 {
+        self.map.beats_at(self.time_at(now))
+    }
+
+    fn reanchor(&mut self, now: Instant, beats: f64) {
+        self.anchor = now;
+        self.anchor_time = self.map.time_at_beats(beats);
+        self.revision += 1;
+    }
 }
 
+/// Musical time running on its own from the time source, along a tempo map
+///
+/// Position is never stored as a count, it is worked out from the time
+/// since the clock last moved. Ticks land where the map says, however
+/// rarely the clock is read.
 pub struct MusicalClock<T: TimeSource> {
     state: Arc<RwLock<ClockState>>,
     time_source: T,
 }
 
 impl<T: TimeSource> MusicalClock<T> {
+    /// Clock at 120 BPM and the default resolution, starting from tick 0 now
     pub fn new(time_source: T) -> Self {
+        Self::with_ppqn(time_source, Ppqn::DEFAULT)
+    }
+
+    pub fn with_ppqn(time_source: T, ppqn: Ppqn) -> Self {
+        Self::with_tempo_map(time_source, TempoMap::new(ppqn, Tempo::DEFAULT))
+    }
+
+    /// Clock following `map`, starting from tick 0 now
+    pub fn with_tempo_map(time_source: T, map: TempoMap) -> Self {
         Self {
-            state: Arc::new(RwLock::new(ClockState::new(&time_source))),
+            state: Arc::new(RwLock::new(ClockState::new(&time_source, map))),
             time_source,
         }
     }
 
-    pub fn tick(&self) -> Result<(), ClockError> {
+    /// Ticks since the start, whole ones only
+    pub fn position(&self) -> Ticks {
+        Ticks::from_beats(self.beats(), self.ppqn())
+    }
+
+    /// Beats since the start, with how far into the current one
+    pub fn beats(&self) -> f64 {
+        self.state.read().beats_at(self.time_source.now())
+    }
+
+    /// Tempo right now, part way along a ramp if the clock is in one
+    pub fn tempo(&self) -> Tempo {
+        self.state.read().map.tempo_at(self.position())
+    }
+
+    pub fn ppqn(&self) -> Ppqn {
+        self.state.read().map.ppqn()
+    }
+
+    pub fn tempo_map(&self) -> TempoMap {
+        self.state.read().map.clone()
+    }
+
+    /// Changes each time the clock is moved or retimed, when times worked out before go stale
+    pub fn revision(&self) -> u64 {
+        self.state.read().revision
+    }
+
+    /// Follow `map` from now on, carrying on from where the beat is
+    pub fn set_tempo_map(&self, map: TempoMap) {
+        let now = self.time_source.now();
         let mut state = self.state.write();
-        state.ticks += 1;
-        state.last_tick_time = self.time_source.now();
-        Ok(())
+        let beats = state.beats_at(now);
+        state.map = map;
+        state.reanchor(now, beats);
     }
 
-    pub fn set_tempo(&self, bpm: f64) -> Result<(), ClockError> {
+    /// Hold a new tempo from now, carrying on from where the beat is
+    ///
+    /// Changes the map had coming are dropped, and so are those already
+    /// passed, however often the tempo is set the map stays small.
+    pub fn set_tempo(&self, tempo: Tempo) {
+        let now = self.time_source.now();
         let mut state = self.state.write();
-        state.tempo = bpm.clamp(20.0, 400.0);
-        Ok(())
+        let beats = state.beats_at(now);
+        let ticks = Ticks::from_beats(beats, state.map.ppqn());
+        state.map.end_at(ticks);
+        state.map.insert(TempoPoint::step(ticks, tempo));
+        state.map.forget_before(ticks);
+        // The change lands on a whole tick, the beat carries on from between ticks
+        state.reanchor(now, beats);
     }
 
-    pub fn get_position(&self) -> (u64, f64) {
-        let state = self.state.read();
-        (state.ticks, state.tempo)
+    /// Jump to `ticks` and hold `tempo` from there, for following an outside clock
+    ///
+    /// Both change together, and whatever the map had is dropped.
+    pub fn follow(&self, ticks: Ticks, tempo: Tempo) {
+        let now = self.time_source.now();
+        let mut state = self.state.write();
+        state.map = TempoMap::new(state.map.ppqn(), tempo);
+        let beats = ticks.raw() as f64 / state.map.ppqn().raw() as f64;
+        state.reanchor(now, beats);
     }
 
-    pub fn time_since_last_tick(&self) -> Duration {
+    /// Jump to `ticks` and carry on from there
+    pub fn locate(&self, ticks: Ticks) {
+        let now = self.time_source.now();
+        let mut state = self.state.write();
+        let beats = ticks.raw() as f64 / state.map.ppqn().raw() as f64;
+        state.reanchor(now, beats);
+    }
+
+    /// How long until the clock reaches `ticks` along the map, zero once it has
+    pub fn time_until(&self, ticks: Ticks) -> Duration {
+        let now = self.time_source.now();
         let state = self.state.read();
-        self.time_source.now().duration_since(state.last_tick_time)
+        state.map.time_of(ticks).saturating_sub(state.time_at(now))
+    }
+
+    /// Wall-clock time `ticks` span at the current tempo
+    pub fn duration_of(&self, ticks: Ticks) -> Duration {
+        ticks.to_duration(self.tempo(), self.ppqn())
+    }
+
+    /// Whole ticks that pass in `duration` at the current tempo
+    pub fn ticks_in(&self, duration: Duration) -> Ticks {
+        Ticks::from_duration(duration, self.tempo(), self.ppqn())
+    }
+
+    /// Sample frames `ticks` span at `rate` and the current tempo
+    pub fn frames_of(&self, ticks: Ticks, rate: u32) -> u64 {
+        ticks.to_frames(self.tempo(), self.ppqn(), rate)
+    }
+
+    /// Whole ticks that pass in `frames` at `rate` and the current tempo
+    pub fn ticks_in_frames(&self, frames: u64, rate: u32) -> Ticks {
+        Ticks::from_frames(frames, self.tempo(), self.ppqn(), rate)
     }
 }
 
@@ -113,23 +227,124 @@ mod tests {
     fn test_clock_creation() {
         let time_source = TimeSourceStub::new();
         let clock = MusicalClock::new(time_source);
-        let (ticks, tempo) = clock.get_position();
 
-        assert_eq!(ticks, 0);
-        assert_eq!(tempo, 120.0);
+        assert_eq!(clock.position(), Ticks::ZERO);
+        assert_eq!(clock.tempo(), Tempo::DEFAULT);
+        assert_eq!(clock.ppqn(), Ppqn::DEFAULT);
     }
 
     #[test]
-    fn test_tick_updates_time() {
+    fn test_clock_runs_from_time_and_tempo() {
         let time_source = TimeSourceStub::new();
         let clock = MusicalClock::new(time_source.clone());
 
-        time_source.advance(Duration::from_millis(10));
-        clock.tick().unwrap();
+        // A beat at 120 BPM is half a second
+        time_source.advance(Duration::from_millis(500));
+        assert_eq!(clock.position(), Ticks::new(960));
+        time_source.advance(Duration::from_millis(1250));
+        assert_eq!(clock.position(), Ticks::new(3360));
+        assert_eq!(clock.beats(), 3.5);
+
+        // Reading it again without time passing changes nothing
+        assert_eq!(clock.position(), Ticks::new(3360));
+    }
 
-        assert_eq!(clock.time_since_last_tick().as_millis(), 0);
+    #[test]
+    fn test_tempo_changes_keep_the_phase() {
+        let time_source = TimeSourceStub::new();
+        let clock = MusicalClock::with_ppqn(time_source.clone(), Ppqn::new(24).unwrap());
 
-        time_source.advance(Duration::from_millis(5));
-        assert_eq!(clock.time_since_last_tick().as_millis(), 5);
+        // A quarter of the way into the second beat, then twice as fast
+        time_source.advance(Duration::from_millis(625));
+        clock.set_tempo(Tempo::new(240.0).unwrap());
+        assert_eq!(clock.position(), Ticks::new(30));
+        assert_eq!(
+            clock.time_until(Ticks::new(48)),
+            Duration::from_millis(187) + Duration::from_micros(500)
+        );
+
+        time_source.advance(Duration::from_millis(375));
+        assert_eq!(clock.beats(), 2.75);
+        assert_eq!(clock.position(), Ticks::new(66));
+        assert_eq!(clock.time_until(Ticks::new(48)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_locating_moves_the_position() {
+        let time_source = TimeSourceStub::new();
+        let clock = MusicalClock::new(time_source.clone());
+
+        time_source.advance(Duration::from_secs(3));
+        let revision = clock.revision();
+        clock.locate(Ticks::new(480));
+        assert_eq!(clock.position(), Ticks::new(480));
+        assert_ne!(clock.revision(), revision);
+        time_source.advance(Duration::from_millis(250));
+        assert_eq!(clock.position(), Ticks::new(960));
+    }
+
+    #[test]
+    fn test_conversions_follow_the_tempo() {
+        let time_source = TimeSourceStub::new();
+        let clock = MusicalClock::new(time_source);
+
+        assert_eq!(
+            clock.duration_of(Ticks::new(960)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(clock.ticks_in(Duration::from_secs(1)), Ticks::new(1920));
+        assert_eq!(clock.frames_of(Ticks::new(960), 48000), 24000);
+        assert_eq!(clock.ticks_in_frames(44100, 44100), Ticks::new(1920));
+
+        clock.set_tempo(Tempo::new(90.0).unwrap());
+        assert_eq!(clock.frames_of(Ticks::new(960), 48000), 32000);
+        assert_eq!(clock.ticks_in(Duration::from_secs(2)), Ticks::new(2880));
+    }
+
+    #[test]
+    fn test_clock_follows_its_tempo_map() {
+        let time_source = TimeSourceStub::new();
+        let tempo = |bpm| Tempo::new(bpm).unwrap();
+        // 4 beats at 120, then 4 slowing to 60, then 60 from there on
+        let mut map = TempoMap::new(Ppqn::DEFAULT, tempo(120.0));
+        map.insert(TempoPoint::ramp(
+            Ticks::new(4 * 960),
+            tempo(120.0),
+            tempo(60.0),
+        ));
+        map.insert(TempoPoint::step(Ticks::new(8 * 960), tempo(60.0)));
+        let clock = MusicalClock::with_tempo_map(time_source.clone(), map.clone());
+
+        time_source.advance(Duration::from_secs(2));
+        assert_eq!(clock.position(), Ticks::new(4 * 960));
+        assert_eq!(clock.tempo(), tempo(120.0));
+        let end_of_ramp = map.time_of(Ticks::new(8 * 960)) - Duration::from_secs(2);
+        assert_eq!(clock.time_until(Ticks::new(8 * 960)), end_of_ramp);
+
+        time_source.advance(end_of_ramp + Duration::from_secs(1));
+        assert_eq!(clock.position(), Ticks::new(9 * 960));
+        assert_eq!(clock.tempo(), tempo(60.0));
+
+        // A live change drops what the map had coming
+        clock.locate(Ticks::new(6 * 960));
+        assert_eq!(clock.tempo(), tempo(90.0));
+        clock.set_tempo(tempo(100.0));
+        time_source.advance(Duration::from_secs(3));
+        assert_eq!(clock.position(), Ticks::new(11 * 960));
+        // What was passed is forgotten along with it
+        assert_eq!(clock.tempo_map().points().len(), 1);
+    }
+
+    #[test]
+    fn test_tempo_changes_keep_the_map_small() {
+        let time_source = TimeSourceStub::new();
+        let clock = MusicalClock::new(time_source.clone());
+
+        for step in 0..1000 {
+            time_source.advance(Duration::from_millis(5));
+            clock.set_tempo(Tempo::new(120.0 + (step % 10) as f64).unwrap());
+        }
+        assert_eq!(clock.tempo_map().points().len(), 1);
+        assert_eq!(clock.tempo(), Tempo::new(129.0).unwrap());
     }
 }
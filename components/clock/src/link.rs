@@ -0,0 +1,1063 @@
+//! Joining an Ableton Link session, to share tempo and beat phase with
+//! the laptops on the LAN
+//!
+//! Link peers multicast who they are every so often, with the session
+//! they are in and its timeline, which places the session's beats on its
+//! ghost time. Ghost time is a clock each session shares, and a peer
+//! finds it by timing pings to someone already in the session. When two
+//! sessions meet they merge into the one that has run longest.
+//!
+//! The local [`MusicalClock`] takes the session's tempo and is nudged to
+//! its phase within a quantum, so bars line up without the beat count
+//! jumping to the session's.
+
+use crate::sync::{spawn_loop, Running};
+use crate::{MusicalClock, TimeSource};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use time_primitives::{Tempo, Ticks};
+use tracing::{info, warn};
+
+/// Multicast group Link peers announce themselves to
+pub const LINK_GROUP: Ipv4Addr = Ipv4Addr::new(224, 76, 78, 75);
+pub const LINK_PORT: u16 = 20808;
+/// Beats the phase is kept over, a bar of four
+pub const DEFAULT_QUANTUM: f64 = 4.0;
+
+// Seconds a peer is remembered after it last announced itself
+const TTL: u8 = 5;
+// Well inside the time to live, as Link does
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(250);
+// Timing points taken before a session's ghost time is settled on
+const MEASUREMENTS: usize = 20;
+const PING_TIMEOUT: Duration = Duration::from_millis(50);
+// Give up on a peer that stops answering pings
+const MEASURE_TIMEOUT: Duration = Duration::from_secs(1);
+// How long a session that was not joined is left before timing it again
+const REMEASURE_INTERVAL: Duration = Duration::from_secs(30);
+// Microseconds, sessions closer in age than this are taken as the same age
+const SESSION_EPSILON: i64 = 500_000;
+// How long the Link threads wait for a packet before checking what is due
+const POLL: Duration = Duration::from_millis(5);
+// A clock this close to the session's phase is left alone, timing jitters by about as much
+const PHASE_WITHIN: Duration = Duration::from_millis(1);
+const MAX_PACKET: usize = 512;
+
+const DISCOVERY_HEADER: &[u8; 8] = b"_asdp_v\x01";
+const MEASUREMENT_HEADER: &[u8; 8] = b"_link_v\x01";
+const ALIVE: u8 = 1;
+const RESPONSE: u8 = 2;
+const BYE_BYE: u8 = 3;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+
+const TIMELINE: [u8; 4] = *b"tmln";
+const SESSION: [u8; 4] = *b"sess";
+const START_STOP: [u8; 4] = *b"stst";
+const ENDPOINT_V4: [u8; 4] = *b"mep4";
+const HOST_TIME: [u8; 4] = *b"__ht";
+const GHOST_TIME: [u8; 4] = *b"__gt";
+const PREV_GHOST_TIME: [u8; 4] = *b"_pgt";
+
+/// A peer, or a session by the peer that started it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub [u8; 8]);
+
+impl NodeId {
+    // Printable, as Link makes them
+    fn random() -> Self {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        hasher.write_u128(since.as_nanos());
+        Self(hasher.finish().to_be_bytes().map(|byte| 33 + byte % 94))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.0))
+    }
+}
+
+/// Where a session's beats fall on its ghost time, in Link's units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeline {
+    pub micros_per_beat: i64,
+    /// The beat at `time_origin`, in millionths of a beat
+    pub beat_origin: i64,
+    /// Ghost time in microseconds
+    pub time_origin: i64,
+}
+
+impl Timeline {
+    fn new(tempo: Tempo, beats: f64, ghost_time: i64) -> Self {
+        Self {
+            micros_per_beat: (60e6 / tempo.raw()).round() as i64,
+            beat_origin: (beats * 1e6).round() as i64,
+            time_origin: ghost_time,
+        }
+    }
+
+    /// Nothing if it is outside the tempos a clock here runs at
+    pub fn tempo(&self) -> Option<Tempo> {
+        if self.micros_per_beat <= 0 {
+            return None;
+        }
+        Tempo::new(60e6 / self.micros_per_beat as f64).ok()
+    }
+
+    pub fn beats_at(&self, ghost_time: i64) -> f64 {
+        let since = (ghost_time - self.time_origin) as f64;
+        self.beat_origin as f64 / 1e6 + since / self.micros_per_beat as f64
+    }
+}
+
+// What a peer says about itself when it announces
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PeerState {
+    session: NodeId,
+    timeline: Timeline,
+    // Where it answers pings
+    endpoint: Option<SocketAddrV4>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Discovery {
+    Alive(NodeId, PeerState),
+    Response(NodeId, PeerState),
+    ByeBye(NodeId),
+}
+
+impl Discovery {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, ttl, ident, state) = match self {
+            Self::Alive(ident, state) => (ALIVE, TTL, ident, Some(state)),
+            Self::Response(ident, state) => (RESPONSE, TTL, ident, Some(state)),
+            Self::ByeBye(ident) => (BYE_BYE, 0, ident, None),
+        };
+        let mut packet = DISCOVERY_HEADER.to_vec();
+        // No session groups, they were never used
+        packet.extend([kind, ttl, 0, 0]);
+        packet.extend(ident.0);
+        if let Some(state) = state {
+            let timeline = state.timeline;
+            let timeline = [
+                timeline.micros_per_beat,
+                timeline.beat_origin,
+                timeline.time_origin,
+            ];
+            entry(
+                &mut packet,
+                TIMELINE,
+                &timeline.map(i64::to_be_bytes).concat(),
+            );
+            entry(&mut packet, SESSION, &state.session.0);
+            // Start and stop is not shared, this is what Link sends with it off
+            entry(&mut packet, START_STOP, &[0; 17]);
+            if let Some(endpoint) = state.endpoint {
+                let port = endpoint.port().to_be_bytes();
+                entry(
+                    &mut packet,
+                    ENDPOINT_V4,
+                    &[&endpoint.ip().octets()[..], &port].concat(),
+                );
+            }
+        }
+        packet
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        let rest = packet.strip_prefix(DISCOVERY_HEADER)?;
+        let (header, payload) = rest.split_at_checked(12)?;
+        let ident = NodeId(header[4..].try_into().ok()?);
+        if header[0] == BYE_BYE {
+            return Some(Self::ByeBye(ident));
+        }
+
+        let (mut timeline, mut session, mut endpoint) = (None, None, None);
+        for (key, value) in entries(payload)? {
+            match key {
+                TIMELINE => {
+                    let &[micros_per_beat, beat_origin, time_origin] = int64s(value)?.as_slice()
+                    else {
+                        return None;
+                    };
+                    timeline = Some(Timeline {
+                        micros_per_beat,
+                        beat_origin,
+                        time_origin,
+                    });
+                }
+                SESSION => session = Some(NodeId(value.try_into().ok()?)),
+                ENDPOINT_V4 => {
+                    let [a, b, c, d, port @ ..]: [u8; 6] = value.try_into().ok()?;
+                    let ip = Ipv4Addr::new(a, b, c, d);
+                    endpoint = Some(SocketAddrV4::new(ip, u16::from_be_bytes(port)));
+                }
+                _ => {}
+            }
+        }
+        let state = PeerState {
+            session: session?,
+            timeline: timeline?,
+            endpoint,
+        };
+        match header[0] {
+            ALIVE => Some(Self::Alive(ident, state)),
+            RESPONSE => Some(Self::Response(ident, state)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Measurement {
+    Ping {
+        host_time: i64,
+        prev_ghost_time: Option<i64>,
+    },
+    /// Carries back what the ping had, with the session's ghost time on answering
+    Pong {
+        session: NodeId,
+        ghost_time: i64,
+        host_time: i64,
+        prev_ghost_time: Option<i64>,
+    },
+}
+
+impl Measurement {
+    fn encode(&self) -> Vec<u8> {
+        let mut packet = MEASUREMENT_HEADER.to_vec();
+        let (host_time, prev_ghost_time) = match *self {
+            Self::Ping {
+                host_time,
+                prev_ghost_time,
+            } => {
+                packet.push(PING);
+                (host_time, prev_ghost_time)
+            }
+            Self::Pong {
+                session,
+                ghost_time,
+                host_time,
+                prev_ghost_time,
+            } => {
+                packet.push(PONG);
+                entry(&mut packet, SESSION, &session.0);
+                entry(&mut packet, GHOST_TIME, &ghost_time.to_be_bytes());
+                (host_time, prev_ghost_time)
+            }
+        };
+        entry(&mut packet, HOST_TIME, &host_time.to_be_bytes());
+        if let Some(prev_ghost_time) = prev_ghost_time {
+            entry(&mut packet, PREV_GHOST_TIME, &prev_ghost_time.to_be_bytes());
+        }
+        packet
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        let rest = packet.strip_prefix(MEASUREMENT_HEADER)?;
+        let (&kind, payload) = rest.split_first()?;
+        let (mut session, mut ghost_time, mut host_time, mut prev_ghost_time) =
+            (None, None, None, None);
+        for (key, value) in entries(payload)? {
+            match key {
+                SESSION => session = Some(NodeId(value.try_into().ok()?)),
+                GHOST_TIME => ghost_time = Some(int64(value)?),
+                HOST_TIME => host_time = Some(int64(value)?),
+                PREV_GHOST_TIME => prev_ghost_time = Some(int64(value)?),
+                _ => {}
+            }
+        }
+        match kind {
+            PING => Some(Self::Ping {
+                host_time: host_time?,
+                prev_ghost_time,
+            }),
+            PONG => Some(Self::Pong {
+                session: session?,
+                ghost_time: ghost_time?,
+                host_time: host_time?,
+                prev_ghost_time,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn entry(packet: &mut Vec<u8>, key: [u8; 4], value: &[u8]) {
+    packet.extend(key);
+    packet.extend((value.len() as u32).to_be_bytes());
+    packet.extend(value);
+}
+
+// Payload entries by key, nothing if one runs past the end
+fn entries(mut payload: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut found = Vec::new();
+    while !payload.is_empty() {
+        let (header, rest) = payload.split_at_checked(8)?;
+        let size = u32::from_be_bytes(header[4..].try_into().ok()?) as usize;
+        let (value, rest) = rest.split_at_checked(size)?;
+        found.push((header[..4].try_into().ok()?, value));
+        payload = rest;
+    }
+    Some(found)
+}
+
+fn int64(value: &[u8]) -> Option<i64> {
+    Some(i64::from_be_bytes(value.try_into().ok()?))
+}
+
+fn int64s(value: &[u8]) -> Option<Vec<i64>> {
+    value.chunks(8).map(int64).collect()
+}
+
+struct Peer {
+    state: PeerState,
+    expires: Instant,
+}
+
+// Pinging a peer in another session to find its ghost time
+struct Measuring {
+    session: NodeId,
+    to: SocketAddr,
+    timeline: Timeline,
+    // Ghost time less host time, in microseconds
+    points: Vec<f64>,
+    prev_ghost_time: Option<i64>,
+    last_ping: Option<Instant>,
+    started: Instant,
+}
+
+struct LinkState {
+    session: NodeId,
+    // Ghost time is host time plus this, in microseconds
+    ghost_offset: i64,
+    timeline: Timeline,
+    peers: HashMap<NodeId, Peer>,
+    measuring: Option<Measuring>,
+    // When each session was last timed
+    measured: HashMap<NodeId, Instant>,
+    endpoint: Option<SocketAddrV4>,
+    // Something changed that peers should hear about straight away
+    announce: bool,
+}
+
+impl LinkState {
+    fn peer_state(&self) -> PeerState {
+        PeerState {
+            session: self.session,
+            timeline: self.timeline,
+            endpoint: self.endpoint,
+        }
+    }
+}
+
+/// This unit as a Link peer, keeping a [`MusicalClock`] with the session
+pub struct LinkSession<T: TimeSource> {
+    id: NodeId,
+    clock: Arc<MusicalClock<T>>,
+    time_source: T,
+    epoch: Instant,
+    quantum: f64,
+    state: Mutex<LinkState>,
+}
+
+impl<T: TimeSource> LinkSession<T> {
+    /// Start a session at the clock's tempo and beat, until a longer running one turns up
+    pub fn new(clock: Arc<MusicalClock<T>>, time_source: T) -> Self {
+        Self::with_quantum(clock, time_source, DEFAULT_QUANTUM)
+    }
+
+    /// Keep the phase over `quantum` beats rather than a bar of four
+    pub fn with_quantum(clock: Arc<MusicalClock<T>>, time_source: T, quantum: f64) -> Self {
+        let id = NodeId::random();
+        let epoch = time_source.now();
+        // Ghost time starts now, along with the session
+        let timeline = Timeline::new(clock.tempo(), clock.beats(), 0);
+        Self {
+            id,
+            clock,
+            time_source,
+            epoch,
+            quantum,
+            state: Mutex::new(LinkState {
+                session: id,
+                ghost_offset: 0,
+                timeline,
+                peers: HashMap::new(),
+                measuring: None,
+                measured: HashMap::new(),
+                endpoint: None,
+                announce: true,
+            }),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// The clock kept with the session
+    pub fn clock(&self) -> &Arc<MusicalClock<T>> {
+        &self.clock
+    }
+
+    pub fn session_id(&self) -> NodeId {
+        self.state.lock().session
+    }
+
+    pub fn tempo(&self) -> Tempo {
+        // Timelines without a tempo here are never taken on
+        self.state.lock().timeline.tempo().unwrap_or(Tempo::DEFAULT)
+    }
+
+    /// The session's beat right now
+    pub fn beats(&self) -> f64 {
+        let host_time = self.host_time(self.time_source.now());
+        let state = self.state.lock();
+        state.timeline.beats_at(host_time + state.ghost_offset)
+    }
+
+    /// Peers heard from lately, in this session or another
+    pub fn peers(&self) -> usize {
+        let now = self.time_source.now();
+        let mut state = self.state.lock();
+        state.peers.retain(|_, peer| peer.expires > now);
+        state.peers.len()
+    }
+
+    /// Change the session's tempo from now, carrying on from where the beat is
+    pub fn set_tempo(&self, tempo: Tempo) {
+        let host_time = self.host_time(self.time_source.now());
+        let mut state = self.state.lock();
+        let ghost_time = host_time + state.ghost_offset;
+        let beats = state.timeline.beats_at(ghost_time);
+        state.timeline = Timeline::new(tempo, beats, ghost_time);
+        state.announce = true;
+    }
+
+    /// Where peers can ping this unit to join its session
+    pub fn set_endpoint(&self, endpoint: SocketAddrV4) {
+        self.state.lock().endpoint = Some(endpoint);
+    }
+
+    pub fn alive(&self) -> Vec<u8> {
+        Discovery::Alive(self.id, self.state.lock().peer_state()).encode()
+    }
+
+    pub fn bye_bye(&self) -> Vec<u8> {
+        Discovery::ByeBye(self.id).encode()
+    }
+
+    /// Take in a peer's announcement, answering one that says it is alive
+    pub fn handle_discovery(&self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let message = Discovery::decode(packet)?;
+        let now = self.time_source.now();
+        let mut state = self.state.lock();
+        state.peers.retain(|_, peer| peer.expires > now);
+        let (ident, mut peer, answer) = match message {
+            Discovery::Alive(ident, peer) => (ident, peer, true),
+            Discovery::Response(ident, peer) => (ident, peer, false),
+            Discovery::ByeBye(ident) => {
+                state.peers.remove(&ident);
+                return None;
+            }
+        };
+        // Announcements to the group come back to whoever sent them
+        if ident == self.id {
+            return None;
+        }
+        // A peer that could not tell its own address is reached at the one it sent from
+        if let (Some(endpoint), SocketAddr::V4(from)) = (&mut peer.endpoint, from) {
+            if endpoint.ip().is_unspecified() {
+                endpoint.set_ip(*from.ip());
+            }
+        }
+
+        let expires = now + Duration::from_secs(TTL.into());
+        state.peers.insert(
+            ident,
+            Peer {
+                state: peer,
+                expires,
+            },
+        );
+        if peer.timeline.tempo().is_some() {
+            if peer.session == state.session {
+                // A change starts its timeline at the beat it was made on, so the latest is furthest on
+                if peer.timeline.beat_origin > state.timeline.beat_origin {
+                    state.timeline = peer.timeline;
+                }
+            } else {
+                Self::consider(&mut state, peer, now);
+            }
+        }
+        answer.then(|| Discovery::Response(self.id, state.peer_state()).encode())
+    }
+
+    /// The next ping timing another session and where it goes, when one is due
+    pub fn ping(&self) -> Option<(Vec<u8>, SocketAddr)> {
+        let now = self.time_source.now();
+        let host_time = self.host_time(now);
+        let mut state = self.state.lock();
+        let measuring = state.measuring.as_mut()?;
+        if now.duration_since(measuring.started) > MEASURE_TIMEOUT {
+            let session = measuring.session;
+            warn!(
+                "Gave up joining Link session {}, its peer stopped answering",
+                session
+            );
+            state.measuring = None;
+            state.measured.insert(session, now);
+            return None;
+        }
+        if measuring
+            .last_ping
+            .is_some_and(|at| now.duration_since(at) < PING_TIMEOUT)
+        {
+            return None;
+        }
+
+        measuring.last_ping = Some(now);
+        let ping = Measurement::Ping {
+            host_time,
+            prev_ghost_time: measuring.prev_ghost_time,
+        };
+        Some((ping.encode(), measuring.to))
+    }
+
+    /// Answer a ping with this session's ghost time, or take in the answer to one
+    pub fn handle_measurement(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let message = Measurement::decode(packet)?;
+        let now = self.time_source.now();
+        let received = self.host_time(now);
+        let mut state = self.state.lock();
+        match message {
+            Measurement::Ping {
+                host_time,
+                prev_ghost_time,
+            } => {
+                let pong = Measurement::Pong {
+                    session: state.session,
+                    ghost_time: received + state.ghost_offset,
+                    host_time,
+                    prev_ghost_time,
+                };
+                Some(pong.encode())
+            }
+            Measurement::Pong {
+                session,
+                ghost_time,
+                host_time,
+                prev_ghost_time,
+            } => {
+                let measuring = state.measuring.as_mut().filter(|m| m.session == session)?;
+                // Ghost time was read halfway through the round trip, near enough
+                let halfway = (host_time + received) as f64 / 2.0;
+                measuring.points.push(ghost_time as f64 - halfway);
+                if let Some(prev_ghost_time) = prev_ghost_time {
+                    let halfway = (ghost_time + prev_ghost_time) as f64 / 2.0;
+                    measuring.points.push(halfway - host_time as f64);
+                }
+                measuring.prev_ghost_time = Some(ghost_time);
+                measuring.last_ping = None;
+                if measuring.points.len() >= MEASUREMENTS {
+                    Self::settle(&mut state, now);
+                }
+                None
+            }
+        }
+    }
+
+    /// Bring the clock to the session's tempo and phase, its beat count stays near where it was
+    ///
+    /// A new tempo is taken keeping the beat where it is, and the clock is
+    /// only moved once it is noticeably out of phase. Called every few
+    /// milliseconds, this leaves the clock be nearly all of the time.
+    pub fn sync_clock(&self) {
+        let tempo = self.tempo();
+        if self.clock.tempo() != tempo {
+            self.clock.set_tempo(tempo);
+        }
+        let session_beats = self.beats();
+        let beats = self.clock.beats();
+        let mut target = beats + phase_difference(session_beats - beats, self.quantum);
+        if target < 0.0 {
+            target += self.quantum;
+        }
+        let within = PHASE_WITHIN.as_secs_f64() * tempo.raw() / 60.0;
+        if (target - beats).abs() > within {
+            self.clock
+                .locate(Ticks::from_beats(target, self.clock.ppqn()));
+        }
+    }
+
+    fn take_announce(&self) -> bool {
+        std::mem::take(&mut self.state.lock().announce)
+    }
+
+    // Start timing a session this unit is not in, unless busy or it was timed lately
+    fn consider(state: &mut LinkState, peer: PeerState, now: Instant) {
+        let Some(endpoint) = peer.endpoint else {
+            return;
+        };
+        let lately = state
+            .measured
+            .get(&peer.session)
+            .is_some_and(|at| now.duration_since(*at) < REMEASURE_INTERVAL);
+        if state.measuring.is_some() || lately {
+            return;
+        }
+        state.measuring = Some(Measuring {
+            session: peer.session,
+            to: endpoint.into(),
+            timeline: peer.timeline,
+            points: Vec::new(),
+            prev_ghost_time: None,
+            last_ping: None,
+            started: now,
+        });
+    }
+
+    // Enough timing is in, join the other session if it has run longer
+    fn settle(state: &mut LinkState, now: Instant) {
+        let Some(mut measuring) = state.measuring.take() else {
+            return;
+        };
+        state.measured.insert(measuring.session, now);
+        measuring.points.sort_by(f64::total_cmp);
+        let offset = measuring.points[measuring.points.len() / 2].round() as i64;
+        let older = offset - state.ghost_offset;
+        let joins = older > SESSION_EPSILON
+            || (older.abs() < SESSION_EPSILON && measuring.session < state.session);
+        if !joins {
+            return;
+        }
+
+        info!("Joining Link session {}", measuring.session);
+        let timeline = state
+            .peers
+            .values()
+            .filter(|peer| peer.state.session == measuring.session)
+            .map(|peer| peer.state.timeline)
+            .max_by_key(|timeline| timeline.beat_origin)
+            .unwrap_or(measuring.timeline);
+        state.session = measuring.session;
+        state.ghost_offset = offset;
+        state.timeline = timeline;
+        state.announce = true;
+    }
+
+    // Microseconds since this unit joined Link
+    fn host_time(&self, now: Instant) -> i64 {
+        now.saturating_duration_since(self.epoch).as_micros() as i64
+    }
+}
+
+// Nearest way to go from one beat to another with the same phase, within half a quantum
+fn phase_difference(beats: f64, quantum: f64) -> f64 {
+    beats - quantum * (beats / quantum).round()
+}
+
+/// Socket on the Link port in its multicast group, shared with other Link apps here
+pub fn discovery_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LINK_PORT)).into())?;
+    socket.join_multicast_v4(&LINK_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into())
+}
+
+/// Take part in Link, announcing to `announce_to` and timing sessions over `measurement`
+///
+/// On a LAN `announce_to` is the Link group and `discovery` comes from
+/// [`discovery_socket`]. The clock is kept with the session until this stops.
+pub fn join<T>(
+    discovery: UdpSocket,
+    announce_to: SocketAddr,
+    measurement: UdpSocket,
+    session: Arc<LinkSession<T>>,
+) -> io::Result<Running>
+where
+    T: TimeSource + Send + Sync + 'static,
+{
+    let endpoint = match measurement.local_addr()? {
+        SocketAddr::V4(endpoint) if endpoint.ip().is_unspecified() => {
+            let ip = local_ip_towards(announce_to).unwrap_or(Ipv4Addr::UNSPECIFIED);
+            SocketAddrV4::new(ip, endpoint.port())
+        }
+        SocketAddr::V4(endpoint) => endpoint,
+        SocketAddr::V6(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Link measurement needs an IPv4 socket",
+            ))
+        }
+    };
+    session.set_endpoint(endpoint);
+    discovery.set_read_timeout(Some(POLL))?;
+    measurement.set_read_timeout(Some(POLL))?;
+
+    spawn_loop("link", move |stop| {
+        thread::scope(|scope| {
+            scope.spawn(|| measure(&measurement, &session, stop));
+            announce(&discovery, announce_to, &session, stop);
+        })
+    })
+}
+
+fn announce<T: TimeSource>(
+    socket: &UdpSocket,
+    to: SocketAddr,
+    session: &LinkSession<T>,
+    stop: &AtomicBool,
+) {
+    let mut buffer = [0; MAX_PACKET];
+    let mut next_alive = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        if session.take_announce() || Instant::now() >= next_alive {
+            send(socket, &session.alive(), to);
+            next_alive = Instant::now() + ANNOUNCE_INTERVAL;
+        }
+        if let Ok((length, from)) = socket.recv_from(&mut buffer) {
+            if let Some(reply) = session.handle_discovery(&buffer[..length], from) {
+                send(socket, &reply, from);
+            }
+        }
+        session.sync_clock();
+    }
+    send(socket, &session.bye_bye(), to);
+}
+
+// Pongs are timed as soon as they come, so this runs on its own
+fn measure<T: TimeSource>(socket: &UdpSocket, session: &LinkSession<T>, stop: &AtomicBool) {
+    let mut buffer = [0; MAX_PACKET];
+    while !stop.load(Ordering::Relaxed) {
+        if let Ok((length, from)) = socket.recv_from(&mut buffer) {
+            if let Some(reply) = session.handle_measurement(&buffer[..length]) {
+                send(socket, &reply, from);
+            }
+        }
+        if let Some((ping, to)) = session.ping() {
+            send(socket, &ping, to);
+        }
+    }
+}
+
+// The address this machine has on the way to `to`
+fn local_ip_towards(to: SocketAddr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(to).ok()?;
+    match socket.local_addr().ok()? {
+        SocketAddr::V4(local) if !local.ip().is_unspecified() => Some(*local.ip()),
+        _ => None,
+    }
+}
+
+fn send(socket: &UdpSocket, packet: &[u8], to: SocketAddr) {
+    if let Err(e) = socket.send_to(packet, to) {
+        warn!("Failed to send to Link peer {}: {}", to, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TimeSourceStub;
+    use crate::SystemTimeSource;
+
+    fn bpm(bpm: f64) -> Tempo {
+        Tempo::new(bpm).unwrap()
+    }
+
+    fn session(time_source: &TimeSourceStub, tempo: f64) -> LinkSession<TimeSourceStub> {
+        let clock = Arc::new(MusicalClock::new(time_source.clone()));
+        clock.set_tempo(bpm(tempo));
+        LinkSession::new(clock, time_source.clone())
+    }
+
+    fn assert_in_phase(beats: f64, other: f64, within: f64) {
+        let apart = phase_difference(beats - other, DEFAULT_QUANTUM);
+        assert!(
+            apart.abs() < within,
+            "{beats} and {other} are {apart} beats out"
+        );
+    }
+
+    #[test]
+    fn announcements_are_laid_out_as_link_sends_them() {
+        let time_source = TimeSourceStub::new();
+        let session = session(&time_source, 120.0);
+        session.set_endpoint(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5000));
+        let id = session.id().0;
+
+        let mut expected = b"_asdp_v\x01\x01\x05\x00\x00".to_vec();
+        expected.extend(id);
+        expected.extend(b"tmln\x00\x00\x00\x18");
+        expected.extend(500_000i64.to_be_bytes());
+        expected.extend([0; 16]);
+        expected.extend(b"sess\x00\x00\x00\x08");
+        expected.extend(id);
+        expected.extend(b"stst\x00\x00\x00\x11");
+        expected.extend([0; 17]);
+        expected.extend(b"mep4\x00\x00\x00\x06\x7f\x00\x00\x01\x13\x88");
+        assert_eq!(session.alive(), expected);
+
+        // A peer hearing it keeps it, and answers
+        let other = self::session(&time_source, 90.0);
+        let from = SocketAddr::from((Ipv4Addr::LOCALHOST, LINK_PORT));
+        let response = other.handle_discovery(&expected, from).unwrap();
+        assert_eq!(response[8], RESPONSE);
+        assert_eq!(other.peers(), 1);
+        assert_eq!(session.handle_discovery(&response, from), None);
+        assert_eq!(session.peers(), 1);
+
+        // Its own announcements and anything garbled are passed over
+        assert_eq!(session.handle_discovery(&session.alive(), from), None);
+        assert_eq!(session.handle_discovery(&expected[..40], from), None);
+        other.handle_discovery(&session.bye_bye(), from);
+        assert_eq!(other.peers(), 0);
+    }
+
+    #[test]
+    fn sessions_merge_into_the_one_running_longest() {
+        let (older_time, newer_time) = (TimeSourceStub::new(), TimeSourceStub::new());
+        let older = session(&older_time, 133.0);
+        older.set_endpoint(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+        older_time.advance(Duration::from_secs(10));
+        let newer = session(&newer_time, 90.0);
+        newer.set_endpoint(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2));
+        let from = SocketAddr::from((Ipv4Addr::LOCALHOST, LINK_PORT));
+
+        // Pings each way, 1.5 ms out and 0.5 ms back
+        let pass = |time: Duration| {
+            older_time.advance(time);
+            newer_time.advance(time);
+        };
+        let time_pings = |pinging: &LinkSession<TimeSourceStub>, other: &LinkSession<_>| {
+            while let Some((ping, _)) = pinging.ping() {
+                pass(Duration::from_micros(1500));
+                let pong = other.handle_measurement(&ping).unwrap();
+                pass(Duration::from_micros(500));
+                pinging.handle_measurement(&pong);
+            }
+        };
+        // Both clocks well into their count, away from beat 0
+        pass(Duration::from_secs(5));
+
+        // The older one hears of the newer and stays put
+        older.handle_discovery(&newer.alive(), from);
+        time_pings(&older, &newer);
+        assert_eq!(older.session_id(), older.id());
+
+        newer.handle_discovery(&older.alive(), from);
+        time_pings(&newer, &older);
+        assert_eq!(newer.session_id(), older.id());
+        assert_eq!(newer.tempo(), older.tempo());
+        assert!((newer.tempo().raw() - 133.0).abs() < 0.001);
+        // Half the difference in the two ways, 0.5 ms, cannot be seen from either end
+        assert!((newer.beats() - older.beats()).abs() < 0.002);
+
+        // Its clock keeps its own count, in phase with the session
+        let clock_beats = newer.clock.beats();
+        newer.sync_clock();
+        assert!((newer.clock.beats() - clock_beats).abs() <= 2.0);
+        assert_in_phase(newer.clock.beats(), older.beats(), 0.003);
+        assert_eq!(newer.clock.tempo(), newer.tempo());
+
+        // A change from anyone in the session is taken up by the rest
+        pass(Duration::from_millis(700));
+        newer.set_tempo(bpm(100.0));
+        older.handle_discovery(&newer.alive(), from);
+        assert_eq!(older.ping(), None);
+        assert_eq!(older.tempo(), bpm(100.0));
+        assert!((newer.beats() - older.beats()).abs() < 0.002);
+
+        // Syncing over and over leaves the clock be once it is in step
+        older.sync_clock();
+        let synced = older.clock.beats();
+        for _ in 0..100 {
+            pass(Duration::from_millis(5));
+            older.sync_clock();
+        }
+        let beats = synced + 0.5 * 100.0 / 60.0;
+        assert!((older.clock.beats() - beats).abs() < 1e-6);
+        assert_eq!(older.clock.tempo_map().points().len(), 1);
+    }
+
+    // A Link peer put together byte by byte from the protocol, sharing
+    // nothing with the code above. Its session started long before.
+    struct ReferencePeer {
+        ident: [u8; 8],
+        discovery: UdpSocket,
+        measurement: UdpSocket,
+        started: Instant,
+        // Announcements heard, as session, micros per beat and message type
+        heard: Mutex<Vec<([u8; 8], i64, u8)>>,
+    }
+
+    const REFERENCE_MICROS_PER_BEAT: i64 = 451_128;
+    const REFERENCE_AGE: i64 = 30_000_000;
+
+    impl ReferencePeer {
+        fn new() -> Self {
+            let bind = || {
+                let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+                socket
+                    .set_read_timeout(Some(Duration::from_millis(1)))
+                    .unwrap();
+                socket
+            };
+            Self {
+                ident: *b"refpeer!",
+                discovery: bind(),
+                measurement: bind(),
+                started: Instant::now(),
+                heard: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn ghost_time(&self) -> i64 {
+            self.started.elapsed().as_micros() as i64 + REFERENCE_AGE
+        }
+
+        // Beat 0 at ghost time 0, so the beat is just ghost time over the beat length
+        fn beats(&self) -> f64 {
+            self.ghost_time() as f64 / REFERENCE_MICROS_PER_BEAT as f64
+        }
+
+        fn alive(&self) -> Vec<u8> {
+            let SocketAddr::V4(endpoint) = self.measurement.local_addr().unwrap() else {
+                unreachable!()
+            };
+            let mut packet = b"_asdp_v\x01".to_vec();
+            packet.extend([1, 5, 0, 0]);
+            packet.extend(self.ident);
+            packet.extend(b"tmln");
+            packet.extend(24u32.to_be_bytes());
+            packet.extend(REFERENCE_MICROS_PER_BEAT.to_be_bytes());
+            packet.extend([0; 16]);
+            packet.extend(b"sess");
+            packet.extend(8u32.to_be_bytes());
+            packet.extend(self.ident);
+            packet.extend(b"mep4");
+            packet.extend(6u32.to_be_bytes());
+            packet.extend(endpoint.ip().octets());
+            packet.extend(endpoint.port().to_be_bytes());
+            packet
+        }
+
+        fn run(&self, to: SocketAddr, stop: &AtomicBool) {
+            let mut buffer = [0; 512];
+            let mut next_alive = Instant::now();
+            while !stop.load(Ordering::Relaxed) {
+                if Instant::now() >= next_alive {
+                    self.discovery.send_to(&self.alive(), to).unwrap();
+                    next_alive += Duration::from_millis(100);
+                }
+                if let Ok((length, from)) = self.measurement.recv_from(&mut buffer) {
+                    let ghost_time = self.ghost_time();
+                    let ping = &buffer[..length];
+                    if ping.starts_with(b"_link_v\x01") && ping[8] == 1 {
+                        // The ping's own entries go back as they came
+                        let mut pong = b"_link_v\x01\x02sess\x00\x00\x00\x08".to_vec();
+                        pong.extend(self.ident);
+                        pong.extend(b"__gt\x00\x00\x00\x08");
+                        pong.extend(ghost_time.to_be_bytes());
+                        pong.extend(&ping[9..]);
+                        self.measurement.send_to(&pong, from).unwrap();
+                    }
+                }
+                if let Ok((length, _)) = self.discovery.recv_from(&mut buffer) {
+                    self.hear(&buffer[..length]);
+                }
+            }
+        }
+
+        fn hear(&self, packet: &[u8]) {
+            if !packet.starts_with(b"_asdp_v\x01") || packet.len() < 20 {
+                return;
+            }
+            let (mut session, mut micros_per_beat) = ([0; 8], 0);
+            let mut at = 20;
+            while at + 8 <= packet.len() {
+                let size = u32::from_be_bytes(packet[at + 4..at + 8].try_into().unwrap()) as usize;
+                let value = &packet[at + 8..at + 8 + size];
+                match &packet[at..at + 4] {
+                    b"sess" => session = value.try_into().unwrap(),
+                    b"tmln" => micros_per_beat = i64::from_be_bytes(value[..8].try_into().unwrap()),
+                    _ => {}
+                }
+                at += 8 + size;
+            }
+            self.heard
+                .lock()
+                .push((session, micros_per_beat, packet[8]));
+        }
+
+        fn has_heard(&self, wanted: impl Fn(&([u8; 8], i64, u8)) -> bool) -> bool {
+            self.heard.lock().iter().any(wanted)
+        }
+    }
+
+    fn wait_for(what: impl Fn() -> bool) -> bool {
+        let until = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < until {
+            if what() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn joins_a_reference_peer_over_loopback() {
+        let reference = Arc::new(ReferencePeer::new());
+        let clock = Arc::new(MusicalClock::new(SystemTimeSource));
+        let session = Arc::new(LinkSession::new(clock.clone(), SystemTimeSource));
+        let discovery = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let measurement = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let ours = discovery.local_addr().unwrap();
+        let theirs = reference.discovery.local_addr().unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let peer = thread::spawn({
+            let (reference, stop) = (reference.clone(), stop.clone());
+            move || reference.run(ours, &stop)
+        });
+        let running = join(discovery, theirs, measurement, session.clone()).unwrap();
+
+        assert!(wait_for(|| session.session_id().0 == reference.ident));
+        assert!((session.tempo().raw() - 133.0).abs() < 0.001);
+        thread::sleep(Duration::from_millis(50));
+        assert_in_phase(clock.beats(), reference.beats(), 0.01);
+        assert_eq!(session.peers(), 1);
+
+        // The reference hears the change made here, in its own session
+        session.set_tempo(bpm(100.0));
+        assert!(wait_for(|| reference.has_heard(
+            |&(session, tempo, _)| session == reference.ident && tempo == 600_000
+        )));
+
+        drop(running);
+        assert!(wait_for(
+            || reference.has_heard(|&(_, _, kind)| kind == BYE_BYE)
+        ));
+        stop.store(true, Ordering::Relaxed);
+        peer.join().unwrap();
+    }
+}
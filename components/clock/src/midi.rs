@@ -0,0 +1,603 @@
+//! MIDI beat clock, to run outside gear from a [`MusicalClock`] or follow it
+//!
+//! Clock goes out at 24 pulses a beat along with start, stop, continue
+//! and song position. Coming in, the tempo is worked out from how the
+//! pulses are spaced and the clock is kept in step with them by nudging
+//! its tempo, it is only moved when the song position is.
+//!
+//! Ports are plain byte streams. A raw MIDI device such as ALSA's
+//! `/dev/snd/midiC1D0` opened as a file is one, [`virtual_port`] is another.
+
+use crate::sync::{spawn_loop, Running};
+use crate::{MusicalClock, TimeSource};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use time_primitives::{Tempo, Ticks};
+use tracing::warn;
+
+/// Clock pulses in a beat
+pub const MIDI_PPQN: u32 = 24;
+// Song position counts sixteenths, six pulses each
+const PULSES_PER_SIXTEENTH: u64 = 6;
+// Furthest a song position pointer goes, it is 14 bits
+const MAX_SONG_POSITION: u64 = 0x3fff;
+
+// Pulses sent at once when the clock has jumped ahead, rather than a flood
+const MAX_BURST: u64 = 4;
+// Longest the output thread sleeps, so tempo changes are picked up
+const MAX_WAIT: Duration = Duration::from_millis(5);
+// Pulses the tempo is worked out over, a beat's worth of spacing
+const TEMPO_WINDOW: usize = MIDI_PPQN as usize + 1;
+// Longer than a pulse at 20 BPM, the clock stopped and started again
+const MAX_PULSE_GAP: Duration = Duration::from_millis(250);
+// How much of each new reading goes into the tempo, the rest is what it was
+const TEMPO_SMOOTHING: f64 = 0.2;
+// Share of a beat out of step made up over the next beat, by running fast or slow
+const CATCH_UP: f64 = 0.1;
+// Tempo changes smaller than this are jitter, not worth moving the clock for
+const TEMPO_DEADBAND: f64 = 0.05;
+
+const CLOCK: u8 = 0xf8;
+const START: u8 = 0xfa;
+const CONTINUE: u8 = 0xfb;
+const STOP: u8 = 0xfc;
+const SONG_POSITION: u8 = 0xf2;
+
+/// The MIDI messages that carry timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiClockMessage {
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    /// Sixteenths from the start of the song
+    SongPosition(u16),
+}
+
+impl MidiClockMessage {
+    pub fn write_to(self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Self::Clock => out.write_all(&[CLOCK]),
+            Self::Start => out.write_all(&[START]),
+            Self::Continue => out.write_all(&[CONTINUE]),
+            Self::Stop => out.write_all(&[STOP]),
+            Self::SongPosition(sixteenths) => out.write_all(&[
+                SONG_POSITION,
+                (sixteenths & 0x7f) as u8,
+                (sixteenths >> 7 & 0x7f) as u8,
+            ]),
+        }
+    }
+}
+
+/// Picks timing messages out of a MIDI byte stream, skipping everything else
+///
+/// Real-time bytes may arrive in the middle of other messages and are
+/// taken as they come.
+#[derive(Debug, Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: Vec<u8>,
+}
+
+impl MidiParser {
+    pub fn push(&mut self, byte: u8) -> Option<MidiClockMessage> {
+        match byte {
+            CLOCK => Some(MidiClockMessage::Clock),
+            START => Some(MidiClockMessage::Start),
+            CONTINUE => Some(MidiClockMessage::Continue),
+            STOP => Some(MidiClockMessage::Stop),
+            // Other real-time messages leave whatever is in progress alone
+            0xf9 | 0xfd..=0xff => None,
+            0x80..=0xf7 => {
+                self.status = Some(byte);
+                self.data.clear();
+                None
+            }
+            _ if self.status == Some(SONG_POSITION) => {
+                self.data.push(byte);
+                if self.data.len() < 2 {
+                    return None;
+                }
+                let sixteenths = self.data[0] as u16 | (self.data[1] as u16) << 7;
+                self.status = None;
+                Some(MidiClockMessage::SongPosition(sixteenths))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Sends MIDI clock following a [`MusicalClock`]
+///
+/// Pulses go out whether or not the transport is running, so gear can
+/// follow the tempo while stopped.
+pub struct MidiClockOutput<W: Write> {
+    port: W,
+    // Not known until the first poll, which starts from the pulse after
+    next_pulse: Option<u64>,
+}
+
+impl<W: Write> MidiClockOutput<W> {
+    pub fn new(port: W) -> Self {
+        Self {
+            port,
+            next_pulse: None,
+        }
+    }
+
+    /// Send the pulses that are due, and say how long until the next one
+    pub fn poll<T: TimeSource>(&mut self, clock: &MusicalClock<T>) -> io::Result<Duration> {
+        let due = pulses_at(clock.beats());
+        let next = self.next_pulse.get_or_insert(due + 1);
+        // The clock jumped back, or far ahead
+        if *next > due + 1 {
+            *next = due + 1;
+        } else if due >= *next + MAX_BURST {
+            *next = due;
+        }
+        while *next <= due {
+            MidiClockMessage::Clock.write_to(&mut self.port)?;
+            *next += 1;
+        }
+        self.port.flush()?;
+        Ok(clock.time_until(pulse_ticks(*next, clock)))
+    }
+
+    /// Start gear from the top, with the next pulse the clock has due
+    ///
+    /// The clock itself is left where it is, others may be running from it.
+    pub fn start<T: TimeSource>(&mut self, clock: &MusicalClock<T>) -> io::Result<()> {
+        let pulses = clock.beats() * MIDI_PPQN as f64;
+        self.next_pulse = Some((pulses - 1e-6).ceil().max(0.0) as u64);
+        self.send(MidiClockMessage::Start)
+    }
+
+    pub fn stop(&mut self) -> io::Result<()> {
+        self.send(MidiClockMessage::Stop)
+    }
+
+    /// Carry on playing from `ticks`, moved back to the sixteenth it falls in
+    pub fn continue_from<T: TimeSource>(
+        &mut self,
+        clock: &MusicalClock<T>,
+        ticks: Ticks,
+    ) -> io::Result<()> {
+        self.locate(clock, ticks)?;
+        self.send(MidiClockMessage::Continue)
+    }
+
+    /// Move to `ticks` without playing, moved back to the sixteenth it falls in
+    pub fn locate<T: TimeSource>(
+        &mut self,
+        clock: &MusicalClock<T>,
+        ticks: Ticks,
+    ) -> io::Result<()> {
+        let pulses = ticks_to_pulses(ticks, clock);
+        let sixteenths = (pulses / PULSES_PER_SIXTEENTH).min(MAX_SONG_POSITION);
+        let pulse = sixteenths * PULSES_PER_SIXTEENTH;
+        clock.locate(pulse_ticks(pulse, clock));
+        self.next_pulse = Some(pulse);
+        self.send(MidiClockMessage::SongPosition(sixteenths as u16))
+    }
+
+    fn send(&mut self, message: MidiClockMessage) -> io::Result<()> {
+        message.write_to(&mut self.port)?;
+        self.port.flush()
+    }
+}
+
+/// Follows incoming MIDI clock with a [`MusicalClock`]
+///
+/// While the transport runs the clock takes the tempo the pulses work
+/// out to, nudged to stay in step with them. It is only moved to a pulse
+/// on the first one after a start or a song position.
+pub struct MidiClockInput<T: TimeSource> {
+    clock: Arc<MusicalClock<T>>,
+    time_source: T,
+    parser: MidiParser,
+    arrivals: VecDeque<Instant>,
+    tempo: Option<Tempo>,
+    playing: bool,
+    // Where the next pulse puts the song, in pulses
+    position: u64,
+    // The song was moved, the next pulse moves the clock along with it
+    relocate: bool,
+}
+
+impl<T: TimeSource> MidiClockInput<T> {
+    /// Lock `clock` to what comes in, timing arrivals by `time_source`
+    pub fn new(clock: Arc<MusicalClock<T>>, time_source: T) -> Self {
+        Self {
+            clock,
+            time_source,
+            parser: MidiParser::default(),
+            arrivals: VecDeque::with_capacity(TEMPO_WINDOW),
+            tempo: None,
+            playing: false,
+            position: 0,
+            relocate: true,
+        }
+    }
+
+    /// Take in bytes as soon as they are read from the port
+    pub fn receive(&mut self, bytes: &[u8]) {
+        let now = self.time_source.now();
+        for &byte in bytes {
+            if let Some(message) = self.parser.push(byte) {
+                self.handle(message, now);
+            }
+        }
+    }
+
+    /// Tempo of the incoming clock, once it has sent a few pulses
+    pub fn tempo(&self) -> Option<Tempo> {
+        self.tempo
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    fn handle(&mut self, message: MidiClockMessage, now: Instant) {
+        match message {
+            MidiClockMessage::Clock => {
+                self.time_pulse(now);
+                if self.playing {
+                    self.keep_in_step();
+                    self.position += 1;
+                }
+            }
+            MidiClockMessage::Start => {
+                self.playing = true;
+                self.position = 0;
+                self.relocate = true;
+            }
+            MidiClockMessage::Continue => self.playing = true,
+            MidiClockMessage::Stop => self.playing = false,
+            MidiClockMessage::SongPosition(sixteenths) => {
+                self.position = sixteenths as u64 * PULSES_PER_SIXTEENTH;
+                self.relocate = true;
+            }
+        }
+    }
+
+    // Bring the clock along with a pulse that just came in
+    fn keep_in_step(&mut self) {
+        if std::mem::take(&mut self.relocate) {
+            self.clock.locate(pulse_ticks(self.position, &self.clock));
+        }
+        let Some(tempo) = self.tempo else {
+            return;
+        };
+        let behind =
+            (self.position as f64 / MIDI_PPQN as f64 - self.clock.beats()).clamp(-1.0, 1.0);
+        let target = tempo.raw() * (1.0 + CATCH_UP * behind);
+        if (target - self.clock.tempo().raw()).abs() > TEMPO_DEADBAND {
+            if let Ok(target) = Tempo::new(target) {
+                self.clock.set_tempo(target);
+            }
+        }
+    }
+
+    fn time_pulse(&mut self, now: Instant) {
+        if self
+            .arrivals
+            .back()
+            .is_some_and(|last| now.saturating_duration_since(*last) > MAX_PULSE_GAP)
+        {
+            self.arrivals.clear();
+        }
+        if self.arrivals.len() == TEMPO_WINDOW {
+            self.arrivals.pop_front();
+        }
+        self.arrivals.push_back(now);
+
+        let (Some(first), true) = (self.arrivals.front(), self.arrivals.len() > 2) else {
+            return;
+        };
+        let pulses = (self.arrivals.len() - 1) as f64;
+        let seconds = now.saturating_duration_since(*first).as_secs_f64();
+        // Anything outside what a tempo can be is noise, keep the last good one
+        let Ok(reading) = Tempo::new(60.0 * pulses / (MIDI_PPQN as f64 * seconds)) else {
+            return;
+        };
+        let smoothed = self.tempo.map_or(reading.raw(), |tempo| {
+            tempo.raw() + (reading.raw() - tempo.raw()) * TEMPO_SMOOTHING
+        });
+        self.tempo = Tempo::new(smoothed).ok();
+    }
+}
+
+/// Send clock as `clock` runs, until the returned handle is dropped
+pub fn drive_output<T, W>(
+    output: Arc<Mutex<MidiClockOutput<W>>>,
+    clock: Arc<MusicalClock<T>>,
+) -> io::Result<Running>
+where
+    T: TimeSource + Send + Sync + 'static,
+    W: Write + Send + 'static,
+{
+    spawn_loop("midi-clock-out", move |stop| {
+        while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+            let wait = match output.lock().poll(&clock) {
+                Ok(wait) => wait,
+                Err(e) => {
+                    warn!("Failed to send MIDI clock: {}", e);
+                    MAX_WAIT
+                }
+            };
+            thread::sleep(wait.min(MAX_WAIT));
+        }
+    })
+}
+
+/// Feed what comes from `port` to `input`, until the port closes
+pub fn drive_input<T, R>(
+    mut port: R,
+    input: Arc<Mutex<MidiClockInput<T>>>,
+) -> io::Result<JoinHandle<io::Result<()>>>
+where
+    T: TimeSource + Send + Sync + 'static,
+    R: Read + Send + 'static,
+{
+    thread::Builder::new()
+        .name("midi-clock-in".into())
+        .spawn(move || {
+            let mut buffer = [0; 64];
+            loop {
+                let read = port.read(&mut buffer)?;
+                if read == 0 {
+                    return Ok(());
+                }
+                input.lock().receive(&buffer[..read]);
+            }
+        })
+}
+
+/// An in-memory MIDI cable, what is written to one end is read from the other
+pub fn virtual_port() -> (VirtualOut, VirtualIn) {
+    let (sender, receiver) = mpsc::channel();
+    let input = VirtualIn {
+        receiver,
+        pending: VecDeque::new(),
+    };
+    (VirtualOut(sender), input)
+}
+
+pub struct VirtualOut(mpsc::Sender<Vec<u8>>);
+
+impl Write for VirtualOut {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(bytes.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reading blocks until something is written, and ends once the other end is dropped
+pub struct VirtualIn {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+impl VirtualIn {
+    /// Everything written so far, without waiting for more
+    pub fn drain(&mut self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.pending.drain(..).collect();
+        while let Ok(more) = self.receiver.try_recv() {
+            bytes.extend(more);
+        }
+        bytes
+    }
+}
+
+impl Read for VirtualIn {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.receiver.recv() {
+                Ok(bytes) => self.pending.extend(bytes),
+                Err(_) => return Ok(0),
+            }
+        }
+        let count = buffer.len().min(self.pending.len());
+        for (slot, byte) in buffer.iter_mut().zip(self.pending.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+// Pulses whose time has come by `beats`
+fn pulses_at(beats: f64) -> u64 {
+    (beats * MIDI_PPQN as f64 + 1e-6).max(0.0) as u64
+}
+
+fn ticks_to_pulses<T: TimeSource>(ticks: Ticks, clock: &MusicalClock<T>) -> u64 {
+    pulses_at(ticks.raw() as f64 / clock.ppqn().raw() as f64)
+}
+
+fn pulse_ticks<T: TimeSource>(pulse: u64, clock: &MusicalClock<T>) -> Ticks {
+    Ticks::from_beats(pulse as f64 / MIDI_PPQN as f64, clock.ppqn())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TimeSourceStub;
+    use crate::SystemTimeSource;
+
+    fn bpm(bpm: f64) -> Tempo {
+        Tempo::new(bpm).unwrap()
+    }
+
+    #[test]
+    fn timing_is_picked_out_of_other_midi() {
+        let mut parser = MidiParser::default();
+        // Note on, song position with a clock in the middle of it, stop
+        let bytes = [0x90, 60, 100, SONG_POSITION, 5, CLOCK, 1, 0x40, STOP];
+        let messages: Vec<_> = bytes.iter().filter_map(|b| parser.push(*b)).collect();
+
+        assert_eq!(
+            messages,
+            [
+                MidiClockMessage::Clock,
+                MidiClockMessage::SongPosition(133),
+                MidiClockMessage::Stop
+            ]
+        );
+
+        let mut bytes = Vec::new();
+        MidiClockMessage::SongPosition(133)
+            .write_to(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, [SONG_POSITION, 5, 1]);
+    }
+
+    #[test]
+    fn output_sends_24_pulses_a_beat() {
+        let time_source = TimeSourceStub::new();
+        let clock = MusicalClock::new(time_source.clone());
+        let (port, mut cable) = virtual_port();
+        let mut output = MidiClockOutput::new(port);
+
+        output.start(&clock).unwrap();
+        let mut wait = output.poll(&clock).unwrap();
+        assert_eq!(wait, clock.tempo_map().time_of(Ticks::new(40)));
+        assert_eq!(cable.drain(), [START, CLOCK]);
+
+        // Polled when told to, a beat later it has sent a beat's worth
+        for _ in 0..24 {
+            time_source.advance(wait);
+            wait = output.poll(&clock).unwrap();
+        }
+        assert_eq!(cable.drain(), [CLOCK; 24]);
+        assert_eq!(clock.position(), Ticks::new(960));
+
+        output.stop().unwrap();
+        assert_eq!(cable.drain(), [STOP]);
+
+        // 2.3 beats in is in the tenth sixteenth
+        output.continue_from(&clock, Ticks::new(2208)).unwrap();
+        assert_eq!(cable.drain(), [SONG_POSITION, 9, 0, CONTINUE]);
+        assert_eq!(clock.position(), Ticks::new(2160));
+        output.poll(&clock).unwrap();
+        assert_eq!(cable.drain(), [CLOCK]);
+    }
+
+    #[test]
+    fn starting_gear_leaves_the_clock_alone() {
+        let time_source = TimeSourceStub::new();
+        let clock = MusicalClock::new(time_source.clone());
+        let (port, mut cable) = virtual_port();
+        let mut output = MidiClockOutput::new(port);
+
+        // Part way into the 50th pulse
+        time_source.advance(Duration::from_millis(1030));
+        output.start(&clock).unwrap();
+        assert_eq!(clock.position(), Ticks::new(1977));
+        output.poll(&clock).unwrap();
+        assert_eq!(cable.drain(), [START]);
+        // The 51st is due 1041.7 ms in
+        time_source.advance(Duration::from_millis(12));
+        output.poll(&clock).unwrap();
+        assert_eq!(cable.drain(), [CLOCK]);
+    }
+
+    #[test]
+    fn output_does_not_flood_after_a_jump() {
+        let time_source = TimeSourceStub::new();
+        let clock = MusicalClock::new(time_source.clone());
+        let (port, mut cable) = virtual_port();
+        let mut output = MidiClockOutput::new(port);
+        output.poll(&clock).unwrap();
+
+        clock.locate(Ticks::new(960 * 16));
+        output.poll(&clock).unwrap();
+        assert_eq!(cable.drain(), [CLOCK]);
+
+        clock.locate(Ticks::ZERO);
+        output.poll(&clock).unwrap();
+        time_source.advance(Duration::from_millis(21));
+        output.poll(&clock).unwrap();
+        assert_eq!(cable.drain(), [CLOCK]);
+    }
+
+    #[test]
+    fn input_locks_to_incoming_pulses() {
+        let time_source = TimeSourceStub::new();
+        let clock = Arc::new(MusicalClock::new(time_source.clone()));
+        let mut input = MidiClockInput::new(clock.clone(), time_source.clone());
+
+        // 128 BPM with a little jitter on every other pulse
+        let pulse = Duration::from_secs_f64(60.0 / 128.0 / 24.0);
+        input.receive(&[SONG_POSITION, 8, 0, CONTINUE]);
+        for n in 0..48 {
+            let jitter = Duration::from_micros(if n % 2 == 0 { 300 } else { 0 });
+            time_source.advance(jitter);
+            input.receive(&[CLOCK]);
+            time_source.advance(pulse - jitter);
+        }
+
+        assert!(input.is_playing());
+        let tempo = input.tempo().unwrap().raw();
+        assert!((tempo - 128.0).abs() < 0.5, "{tempo} BPM");
+        // From the second beat, two beats of pulses go by, within a few ticks
+        let apart = clock.position().raw().abs_diff(4 * 960);
+        assert!(apart <= 10, "{apart} ticks out");
+        assert!((clock.tempo().raw() - 128.0).abs() < 0.5);
+        // Jitter doesn't pile up tempo changes
+        assert_eq!(clock.tempo_map().points().len(), 1);
+
+        input.receive(&[STOP]);
+        let stopped = clock.position();
+        input.receive(&[CLOCK]);
+        assert!(!input.is_playing());
+        assert_eq!(clock.position(), stopped);
+
+        // Tempo starts over after a long pause
+        time_source.advance(Duration::from_secs(2));
+        input.receive(&[CLOCK]);
+        time_source.advance(pulse * 2);
+        input.receive(&[CLOCK]);
+        assert!((input.tempo().unwrap().raw() - 128.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn gear_follows_over_a_virtual_cable() {
+        let master = Arc::new(MusicalClock::new(SystemTimeSource));
+        master.set_tempo(bpm(140.0));
+        let slave = Arc::new(MusicalClock::new(SystemTimeSource));
+
+        let (port, cable) = virtual_port();
+        let output = Arc::new(Mutex::new(MidiClockOutput::new(port)));
+        let input = Arc::new(Mutex::new(MidiClockInput::new(
+            slave.clone(),
+            SystemTimeSource,
+        )));
+        let reading = drive_input(cable, input.clone()).unwrap();
+        output.lock().start(&master).unwrap();
+        let sending = drive_output(output.clone(), master.clone()).unwrap();
+
+        thread::sleep(Duration::from_millis(600));
+        drop(sending);
+        drop(output);
+        reading.join().unwrap().unwrap();
+
+        let tempo = input.lock().tempo().unwrap().raw();
+        assert!((tempo - 140.0).abs() < 5.0, "{tempo} BPM");
+        // Within a pulse or two, a busy machine sleeps late now and then
+        let apart = master.position().raw().abs_diff(slave.position().raw());
+        assert!(apart <= 2 * 40 + 40, "{} ticks apart", apart);
+    }
+}
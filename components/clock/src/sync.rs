@@ -0,0 +1,703 @@
+//! Keeping the clocks of several units together over the network
+//!
+//! The leader broadcasts beacons saying where its musical clock is. Each
+//! follower also times round trips to the leader, PTP style, and fits a
+//! line through the least delayed of them to track how far the leader's
+//! clock is ahead and how fast it drifts. Beacons are then placed on the
+//! follower's own clock, and [`SyncedTimeSource`] runs at the leader's pace.
+
+use crate::{MusicalClock, TimeSource};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use time_primitives::{Ppqn, Tempo, Ticks};
+use tracing::warn;
+
+/// Port units sync on
+pub const SYNC_PORT: u16 = 9303;
+/// How often the leader says where its clock is
+pub const BEACON_INTERVAL: Duration = Duration::from_millis(100);
+/// How often followers time a round trip to the leader
+pub const DELAY_INTERVAL: Duration = Duration::from_millis(250);
+
+// Round trips kept, at the delay interval about 16 seconds of them
+const WINDOW: usize = 64;
+// Drift only shows over time, until the samples span this it is taken as none
+const DRIFT_SPAN: Duration = Duration::from_secs(4);
+// Drift beyond this is a bad fit rather than a real clock, crystals are within 100 ppm
+const MAX_DRIFT: f64 = 500e-6;
+// How long the sync threads wait for a packet before checking what is due
+const POLL: Duration = Duration::from_millis(5);
+// Fastest synced time runs ahead or behind to take up a refit, far too little to hear
+const SLEW: f64 = 0.01;
+// A clock this close to the leader's is left alone, beacons jitter by about as much
+const ALIGN_WITHIN: Duration = Duration::from_millis(2);
+
+/// Where the leader's clock was when it sent this
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Beacon {
+    /// Leader time, in nanoseconds since it started leading
+    pub sent: u64,
+    pub ticks: Ticks,
+    pub tempo: Tempo,
+    pub ppqn: Ppqn,
+}
+
+/// Everything units send each other to stay in sync
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SyncMessage {
+    Beacon(Beacon),
+    /// From a follower, `sent` by its own clock
+    DelayRequest {
+        sent: u64,
+    },
+    /// The leader's answer, `received` and `replied` by its clock
+    DelayResponse {
+        sent: u64,
+        received: u64,
+        replied: u64,
+    },
+}
+
+/// The unit everyone else follows
+pub struct Leader<T: TimeSource> {
+    clock: Arc<MusicalClock<T>>,
+    time_source: T,
+    epoch: Instant,
+}
+
+impl<T: TimeSource> Leader<T> {
+    /// Lead with `clock`, telling time by `time_source` as the clock does
+    pub fn new(clock: Arc<MusicalClock<T>>, time_source: T) -> Self {
+        let epoch = time_source.now();
+        Self {
+            clock,
+            time_source,
+            epoch,
+        }
+    }
+
+    pub fn beacon(&self) -> SyncMessage {
+        SyncMessage::Beacon(Beacon {
+            sent: self.nanos(),
+            ticks: self.clock.position(),
+            tempo: self.clock.tempo(),
+            ppqn: self.clock.ppqn(),
+        })
+    }
+
+    /// Answer a message from a follower, if it needs one
+    pub fn handle(&self, message: &SyncMessage) -> Option<SyncMessage> {
+        match message {
+            SyncMessage::DelayRequest { sent } => {
+                let received = self.nanos();
+                Some(SyncMessage::DelayResponse {
+                    sent: *sent,
+                    received,
+                    replied: self.nanos(),
+                })
+            }
+            SyncMessage::Beacon(_) | SyncMessage::DelayResponse { .. } => None,
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        self.time_source.now().duration_since(self.epoch).as_nanos() as u64
+    }
+}
+
+/// How the leader's clock runs against a follower's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// Nanoseconds the leader is ahead, at `at` on the follower's clock
+    pub offset: f64,
+    /// Nanoseconds the leader gains for every one of the follower's
+    pub drift: f64,
+    /// Shortest round trip seen, the best any one sample could be trusted to
+    pub round_trip: Duration,
+    at: i64,
+}
+
+impl Estimate {
+    fn leader_nanos(&self, local: i64) -> f64 {
+        local as f64 + self.offset + self.drift * (local - self.at) as f64
+    }
+}
+
+// One timed round trip, all in nanoseconds on the follower's clock
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    // Halfway between sending and hearing back
+    local: i64,
+    offset: i64,
+    round_trip: i64,
+}
+
+#[derive(Default)]
+struct Estimator {
+    samples: VecDeque<Sample>,
+}
+
+impl Estimator {
+    // Queueing only ever adds delay, so the quickest round trips are the
+    // most even ones. The line is fitted through the quickest quarter.
+    fn add(&mut self, sample: Sample) -> Estimate {
+        self.samples.push_back(sample);
+        if self.samples.len() > WINDOW {
+            self.samples.pop_front();
+        }
+
+        let mut best: Vec<Sample> = self.samples.iter().copied().collect();
+        best.sort_by_key(|sample| sample.round_trip);
+        best.truncate(best.len().div_ceil(4));
+
+        let count = best.len() as f64;
+        let at = (best.iter().map(|s| s.local as f64).sum::<f64>() / count) as i64;
+        let offset = best.iter().map(|s| s.offset as f64).sum::<f64>() / count;
+        let first = best.iter().map(|s| s.local).min().unwrap_or(at);
+        let last = best.iter().map(|s| s.local).max().unwrap_or(at);
+        let (mut spread, mut together) = (0.0, 0.0);
+        for sample in &best {
+            let x = (sample.local - at) as f64;
+            spread += x * x;
+            together += x * (sample.offset as f64 - offset);
+        }
+        let drift = if last - first >= DRIFT_SPAN.as_nanos() as i64 {
+            (together / spread).clamp(-MAX_DRIFT, MAX_DRIFT)
+        } else {
+            0.0
+        };
+
+        Estimate {
+            offset,
+            drift,
+            round_trip: Duration::from_nanos(best[0].round_trip as u64),
+            at,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FollowerState {
+    estimator: Estimator,
+    estimate: Option<Estimate>,
+    beacon: Option<Beacon>,
+    served: Option<Served>,
+}
+
+// The time `SyncedTimeSource` last handed out, all in nanoseconds
+#[derive(Debug, Clone, Copy)]
+struct Served {
+    // On the follower's clock
+    local: i64,
+    time: i64,
+    // How far the leader's time is from the time handed out, fixed once
+    // synced so that only its pace is followed
+    gap: Option<f64>,
+}
+
+/// A unit keeping time with a leader
+pub struct Follower<T: TimeSource> {
+    time_source: T,
+    epoch: Instant,
+    state: Mutex<FollowerState>,
+}
+
+impl<T: TimeSource> Follower<T> {
+    pub fn new(time_source: T) -> Self {
+        let epoch = time_source.now();
+        Self {
+            time_source,
+            epoch,
+            state: Mutex::new(FollowerState::default()),
+        }
+    }
+
+    pub fn delay_request(&self) -> SyncMessage {
+        SyncMessage::DelayRequest {
+            sent: self.nanos() as u64,
+        }
+    }
+
+    /// Take in a message from the leader, as soon as it arrives
+    pub fn handle(&self, message: &SyncMessage) {
+        let arrived = self.nanos();
+        let mut state = self.state.lock();
+        match *message {
+            SyncMessage::Beacon(beacon) => state.beacon = Some(beacon),
+            SyncMessage::DelayResponse {
+                sent,
+                received,
+                replied,
+            } => {
+                let (sent, received, replied) = (sent as i64, received as i64, replied as i64);
+                let round_trip = (arrived - sent) - (replied - received);
+                // Not an answer to anything this follower sent
+                if round_trip < 0 || sent > arrived {
+                    return;
+                }
+                let sample = Sample {
+                    local: sent + (arrived - sent) / 2,
+                    offset: ((received - sent) + (replied - arrived)) / 2,
+                    round_trip,
+                };
+                state.estimate = Some(state.estimator.add(sample));
+            }
+            SyncMessage::DelayRequest { .. } => {}
+        }
+    }
+
+    /// The current fit, once a round trip has been timed
+    pub fn estimate(&self) -> Option<Estimate> {
+        self.state.lock().estimate
+    }
+
+    /// The leader's time right now, by its own clock
+    pub fn leader_time(&self) -> Option<Duration> {
+        let estimate = self.estimate()?;
+        let nanos = estimate.leader_nanos(self.nanos());
+        Some(Duration::from_nanos(nanos.max(0.0) as u64))
+    }
+
+    /// Where the leader's clock is now, carried on from its last beacon
+    ///
+    /// Carried on at the tempo the beacon had, so along a ramp it is only
+    /// as close as the beacons are frequent.
+    pub fn leader_position(&self) -> Option<(Ticks, Tempo)> {
+        let (beacon, since) = self.since_beacon()?;
+        let ticks = beacon.ticks + Ticks::from_duration(since, beacon.tempo, beacon.ppqn);
+        Some((ticks, beacon.tempo))
+    }
+
+    /// Bring `clock` to the leader's tempo and position, once synced
+    ///
+    /// A new tempo is taken keeping the beat where it is. The position is
+    /// only moved once it is further out than beacons can be trusted, so
+    /// their jitter doesn't nudge the clock back and forth.
+    pub fn sync_clock<U: TimeSource>(&self, clock: &MusicalClock<U>) {
+        let Some((beacon, since)) = self.since_beacon() else {
+            return;
+        };
+        if clock.tempo() != beacon.tempo {
+            clock.set_tempo(beacon.tempo);
+        }
+        let beats = beacon.ticks.raw() as f64 / beacon.ppqn.raw() as f64
+            + since.as_secs_f64() * beacon.tempo.raw() / 60.0;
+        let within = ALIGN_WITHIN.as_secs_f64() * beacon.tempo.raw() / 60.0;
+        if (clock.beats() - beats).abs() > within {
+            clock.locate(Ticks::from_beats(beats, clock.ppqn()));
+        }
+    }
+
+    // The last beacon, and how long ago by the leader's time it was sent
+    fn since_beacon(&self) -> Option<(Beacon, Duration)> {
+        let beacon = self.state.lock().beacon?;
+        let now = self.leader_time()?;
+        Some((
+            beacon,
+            now.saturating_sub(Duration::from_nanos(beacon.sent)),
+        ))
+    }
+
+    // Time handed out to clocks, following the leader's pace without jumps
+    //
+    // Until the first round trip it is the follower's own time. After that
+    // it keeps to the leader's, and when a refit moves the estimate it
+    // takes the change up by running up to `SLEW` fast or slow rather than
+    // jumping, so it never goes backwards.
+    fn synced_nanos(&self) -> i64 {
+        let local = self.nanos();
+        let mut state = self.state.lock();
+        let leader = state.estimate.map(|estimate| estimate.leader_nanos(local));
+        let served = match state.served {
+            None => Served {
+                local,
+                time: local,
+                gap: leader.map(|leader| leader - local as f64),
+            },
+            Some(last) => {
+                let elapsed = (local - last.local).max(0);
+                let mut time = last.time + elapsed;
+                let gap = match (leader, last.gap) {
+                    (Some(leader), Some(gap)) => {
+                        let most = elapsed as f64 * SLEW;
+                        time += (leader - gap - time as f64).clamp(-most, most) as i64;
+                        Some(gap)
+                    }
+                    (Some(leader), None) => Some(leader - time as f64),
+                    (None, gap) => gap,
+                };
+                Served {
+                    local,
+                    time: time.max(last.time),
+                    gap,
+                }
+            }
+        };
+        state.served = Some(served);
+        served.time
+    }
+
+    /// Time that runs at the leader's pace once synced, at the local one until then
+    pub fn time_source(self: &Arc<Self>) -> SyncedTimeSource<T> {
+        SyncedTimeSource(self.clone())
+    }
+
+    fn nanos(&self) -> i64 {
+        self.time_source.now().duration_since(self.epoch).as_nanos() as i64
+    }
+}
+
+/// A follower's view of the leader's time, for running clocks in step with it
+pub struct SyncedTimeSource<T: TimeSource>(Arc<Follower<T>>);
+
+impl<T: TimeSource> Clone for SyncedTimeSource<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: TimeSource> TimeSource for SyncedTimeSource<T> {
+    fn now(&self) -> Instant {
+        let nanos = self.0.synced_nanos().max(0) as u64;
+        self.0.epoch + Duration::from_nanos(nanos)
+    }
+}
+
+/// A clock loop on its own thread, stopped when this is dropped
+pub struct Running {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Send beacons to `beacons_to`, a broadcast address on a LAN, and answer followers
+pub fn lead<T>(socket: UdpSocket, beacons_to: SocketAddr, leader: Leader<T>) -> io::Result<Running>
+where
+    T: TimeSource + Send + Sync + 'static,
+{
+    socket.set_broadcast(true)?;
+    socket.set_read_timeout(Some(POLL))?;
+    spawn_loop("clock-leader", move |stop| {
+        let mut next_beacon = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            if Instant::now() >= next_beacon {
+                send(&socket, &leader.beacon(), beacons_to);
+                next_beacon += BEACON_INTERVAL;
+            }
+            if let Some((message, from)) = receive(&socket) {
+                if let Some(reply) = leader.handle(&message) {
+                    send(&socket, &reply, from);
+                }
+            }
+        }
+    })
+}
+
+/// Listen for a leader's beacons and time round trips to whoever sends them
+///
+/// Every beacon brings `clock` back in line with the leader's.
+pub fn follow<T, U>(
+    socket: UdpSocket,
+    follower: Arc<Follower<T>>,
+    clock: Arc<MusicalClock<U>>,
+) -> io::Result<Running>
+where
+    T: TimeSource + Send + Sync + 'static,
+    U: TimeSource + Send + Sync + 'static,
+{
+    socket.set_read_timeout(Some(POLL))?;
+    spawn_loop("clock-follower", move |stop| {
+        let mut leader = None;
+        let mut next_request = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            if let Some((message, from)) = receive(&socket) {
+                follower.handle(&message);
+                if matches!(message, SyncMessage::Beacon(_)) {
+                    leader = Some(from);
+                    follower.sync_clock(&clock);
+                }
+            }
+            if let (Some(leader), true) = (leader, Instant::now() >= next_request) {
+                send(&socket, &follower.delay_request(), leader);
+                next_request = Instant::now() + DELAY_INTERVAL;
+            }
+        }
+    })
+}
+
+pub(crate) fn spawn_loop(
+    name: &str,
+    run: impl FnOnce(&AtomicBool) + Send + 'static,
+) -> io::Result<Running> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let thread = thread::Builder::new()
+        .name(name.into())
+        .spawn(move || run(&thread_stop))?;
+    Ok(Running {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+fn send(socket: &UdpSocket, message: &SyncMessage, to: SocketAddr) {
+    let result = serde_json::to_vec(message)
+        .map_err(io::Error::from)
+        .and_then(|data| socket.send_to(&data, to));
+    if let Err(e) = result {
+        warn!("Failed to send {:?} to {}: {}", message, to, e);
+    }
+}
+
+// Nothing if the wait ran out, or what came was not a sync message
+fn receive(socket: &UdpSocket) -> Option<(SyncMessage, SocketAddr)> {
+    let mut buffer = [0; 512];
+    let (length, from) = socket.recv_from(&mut buffer).ok()?;
+    let message = serde_json::from_slice(&buffer[..length]).ok()?;
+    Some((message, from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TimeSourceStub;
+
+    // Time on both ends, with the leader's clock running fast by `drift`
+    struct Link {
+        leader_time: TimeSourceStub,
+        follower_time: TimeSourceStub,
+        drift: f64,
+        // xorshift, so every run jitters the same way
+        seed: u64,
+    }
+
+    impl Link {
+        fn new(drift: f64) -> Self {
+            Self {
+                leader_time: TimeSourceStub::new(),
+                follower_time: TimeSourceStub::new(),
+                drift,
+                seed: 0x2545_f491_4f6c_dd1d,
+            }
+        }
+
+        fn pass(&self, time: Duration) {
+            self.follower_time.advance(time);
+            self.leader_time.advance(time.mul_f64(1.0 + self.drift));
+        }
+
+        // 1 ms each way, up to 1 ms more queued, and now and then a spike
+        fn one_way(&mut self) -> Duration {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            let jitter = Duration::from_micros(self.seed % 1000);
+            let spike = if self.seed.is_multiple_of(10) {
+                Duration::from_millis(20)
+            } else {
+                Duration::ZERO
+            };
+            Duration::from_millis(1) + jitter + spike
+        }
+
+        fn exchange<T: TimeSource, U: TimeSource>(
+            &mut self,
+            leader: &Leader<T>,
+            follower: &Follower<U>,
+        ) {
+            let request = follower.delay_request();
+            let there = self.one_way();
+            self.pass(there);
+            let response = leader.handle(&request).unwrap();
+            let back = self.one_way();
+            self.pass(back);
+            follower.handle(&response);
+        }
+    }
+
+    fn error_nanos<T: TimeSource, U: TimeSource>(
+        leader: &Leader<T>,
+        follower: &Follower<U>,
+    ) -> f64 {
+        let estimated = follower.leader_time().unwrap().as_nanos() as f64;
+        (estimated - leader.nanos() as f64).abs()
+    }
+
+    #[test]
+    fn followers_track_offset_and_drift_over_a_jittery_link() {
+        let mut link = Link::new(80e-6);
+        let clock = Arc::new(MusicalClock::new(link.leader_time.clone()));
+        let leader = Leader::new(clock, link.leader_time.clone());
+        // Leading for a while before the follower starts
+        link.leader_time.advance(Duration::from_secs(5));
+        let follower = Follower::new(link.follower_time.clone());
+        assert!(follower.leader_time().is_none());
+
+        for _ in 0..160 {
+            link.exchange(&leader, &follower);
+            link.pass(DELAY_INTERVAL);
+        }
+
+        let estimate = follower.estimate().unwrap();
+        assert!(
+            (estimate.drift - 80e-6).abs() < 10e-6,
+            "drift {}",
+            estimate.drift
+        );
+        assert!(estimate.round_trip < Duration::from_millis(3));
+        let error = error_nanos(&leader, &follower);
+        assert!(error < 200_000.0, "{error} ns out");
+
+        // Drift carries it through a second with no news
+        link.pass(Duration::from_secs(1));
+        let error = error_nanos(&leader, &follower);
+        assert!(error < 250_000.0, "{error} ns out after a second");
+    }
+
+    #[test]
+    fn beacons_say_where_the_leader_is() {
+        let mut link = Link::new(0.0);
+        let clock = Arc::new(MusicalClock::new(link.leader_time.clone()));
+        clock.set_tempo(Tempo::new(128.0).unwrap());
+        let leader = Leader::new(clock.clone(), link.leader_time.clone());
+        let follower = Arc::new(Follower::new(link.follower_time.clone()));
+        for _ in 0..8 {
+            link.exchange(&leader, &follower);
+        }
+        assert!(follower.leader_position().is_none());
+
+        let beacon = leader.beacon();
+        let on_the_way = link.one_way();
+        link.pass(on_the_way);
+        follower.handle(&beacon);
+        link.pass(Duration::from_millis(700));
+
+        let (ticks, tempo) = follower.leader_position().unwrap();
+        assert_eq!(tempo, Tempo::new(128.0).unwrap());
+        let apart = clock.position().raw().abs_diff(ticks.raw());
+        // A tick at 128 BPM is about half a millisecond
+        assert!(apart <= 2, "{} ticks apart", apart);
+
+        // The synced time source keeps the leader's pace
+        let synced = follower.time_source();
+        let before = synced.now();
+        link.pass(Duration::from_secs(2));
+        let passed = synced.now() - before;
+        assert!(passed.abs_diff(Duration::from_secs(2)) < Duration::from_micros(10));
+
+        // And a clock on it is brought to the leader's tempo and position
+        let following = MusicalClock::new(follower.time_source());
+        follower.sync_clock(&following);
+        assert_eq!(following.tempo(), Tempo::new(128.0).unwrap());
+        let apart = clock.position().raw().abs_diff(following.position().raw());
+        assert!(apart <= 2, "{} ticks apart", apart);
+    }
+
+    // Answer a follower's request as a leader `ahead` of it, over a link taking `round_trip`
+    fn answer(
+        follower: &Follower<TimeSourceStub>,
+        time: &TimeSourceStub,
+        ahead: i64,
+        round_trip: Duration,
+    ) {
+        let SyncMessage::DelayRequest { sent } = follower.delay_request() else {
+            unreachable!("followers only ever ask for delays");
+        };
+        time.advance(round_trip);
+        let received = (sent as i64 + ahead + round_trip.as_nanos() as i64 / 2) as u64;
+        follower.handle(&SyncMessage::DelayResponse {
+            sent,
+            received,
+            replied: received,
+        });
+    }
+
+    #[test]
+    fn synced_time_takes_up_refits_without_jumping() {
+        let time = TimeSourceStub::new();
+        let follower = Arc::new(Follower::new(time.clone()));
+        let synced = follower.time_source();
+        let start = synced.now();
+
+        // The first estimate is far from local time, only its pace is taken
+        time.advance(Duration::from_secs(1));
+        answer(&follower, &time, 5_000_000_000, Duration::from_millis(4));
+        let first = synced.now();
+        assert_eq!(first - start, Duration::from_millis(1004));
+
+        // A quicker round trip puts the leader 50 ms further back, taken up slowly
+        answer(&follower, &time, 4_950_000_000, Duration::from_millis(2));
+        let before = synced.now();
+        time.advance(Duration::from_millis(100));
+        let after = synced.now();
+        assert_eq!(after - before, Duration::from_millis(99));
+
+        time.advance(Duration::from_secs(10));
+        let caught_up = synced.now() - first;
+        let expected = Duration::from_millis(10_102 - 50);
+        assert!(caught_up.abs_diff(expected) < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn strays_are_ignored() {
+        let follower = Follower::new(TimeSourceStub::new());
+        follower.handle(&SyncMessage::DelayResponse {
+            sent: 10_000,
+            received: 0,
+            replied: 0,
+        });
+        assert!(follower.estimate().is_none());
+
+        let leader = Leader::new(
+            Arc::new(MusicalClock::new(TimeSourceStub::new())),
+            TimeSourceStub::new(),
+        );
+        assert!(leader.handle(&leader.beacon()).is_none());
+    }
+
+    #[test]
+    fn units_sync_over_udp() {
+        let leader_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let follower_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let follower_address = follower_socket.local_addr().unwrap();
+
+        let clock = Arc::new(MusicalClock::new(crate::SystemTimeSource));
+        clock.set_tempo(Tempo::new(128.0).unwrap());
+        let leader = Leader::new(clock.clone(), crate::SystemTimeSource);
+        let follower = Arc::new(Follower::new(crate::SystemTimeSource));
+        let following = Arc::new(MusicalClock::new(follower.time_source()));
+        let _leading = lead(leader_socket, follower_address, leader).unwrap();
+        let _following = follow(follower_socket, follower.clone(), following.clone()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while follower.leader_position().is_none() {
+            assert!(Instant::now() < deadline, "never synced");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (ticks, _) = follower.leader_position().unwrap();
+        // Loopback is quick, a few ms either way covers a busy test machine
+        let apart = clock.position().raw().abs_diff(ticks.raw());
+        assert!(apart < 20, "{} ticks apart", apart);
+
+        // The next beacon lines the follower's clock up too
+        thread::sleep(BEACON_INTERVAL * 2);
+        assert_eq!(following.tempo(), clock.tempo());
+        let apart = clock.position().raw().abs_diff(following.position().raw());
+        assert!(apart < 20, "{} ticks apart", apart);
+    }
+}
@@ -0,0 +1,179 @@
+use media_protocol::ClientError;
+use nng::{Protocol, Socket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Connection settings for a `MediaClient`
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Address of the playback server, e.g. `ipc:///tmp/mdma-commands`
+    pub url: String,
+
+    /// How long to wait for a reply before giving up on a request
+    pub request_timeout: Duration,
+
+    /// How many times a failed request is retried on a fresh socket
+    ///
+    /// A request the server may have carried out is only retried when it
+    /// is read only, sending a `Skip` again would skip another track.
+    pub max_retries: u32,
+
+    /// Pause before reconnecting after a failed request
+    pub reconnect_delay: Duration,
+}
+
+impl ClientConfig {
+    pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+    pub const DEFAULT_MAX_RETRIES: u32 = 2;
+    pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            request_timeout: Self::DEFAULT_REQUEST_TIMEOUT,
+            max_retries: Self::DEFAULT_MAX_RETRIES,
+            reconnect_delay: Self::DEFAULT_RECONNECT_DELAY,
+        }
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    pub fn with_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self
+    }
+}
+
+/// A Req0 socket that is replaced whenever a request fails
+///
+/// Req0 only allows one outstanding request per socket, so requests are
+/// serialized through the mutex.
+pub(crate) struct Connection {
+    config: ClientConfig,
+    socket: Mutex<Socket>,
+}
+
+impl Connection {
+    pub(crate) fn open(config: ClientConfig) -> Result<Self, ClientError> {
+        let socket = new_socket()?;
+        socket
+            .dial(&config.url)
+            .map_err(|e| ClientError::Connection(format!("Dial error: {:?}", e)))?;
+
+        Ok(Self {
+            config,
+            socket: Mutex::new(socket),
+        })
+    }
+
+    /// Send a request and wait for its reply, reconnecting on failure
+    ///
+    /// Retried if it never got sent, or if it got sent and is `read_only`.
+    pub(crate) async fn request(
+        &self,
+        payload: Vec<u8>,
+        read_only: bool,
+    ) -> Result<Vec<u8>, ClientError> {
+        let mut socket = self.socket.lock().await;
+        let mut attempt = 0;
+
+        loop {
+            let sent = Arc::new(AtomicBool::new(false));
+            let result = tokio::time::timeout(
+                self.config.request_timeout,
+                exchange(socket.clone(), payload.clone(), sent.clone()),
+            )
+            .await
+            .unwrap_or(Err(ClientError::Timeout(self.config.request_timeout)));
+
+            let error = match result {
+                Ok(reply) => return Ok(reply),
+                Err(error) => error,
+            };
+
+            // Closing the old socket wakes up a blocked exchange, if any,
+            // left waiting it would take the reply to the next request
+            socket.close();
+
+            let unsafe_to_repeat = sent.load(Ordering::SeqCst) && !read_only;
+            if attempt >= self.config.max_retries || unsafe_to_repeat {
+                warn!("Request failed after {} attempts: {}", attempt + 1, error);
+                // Failing that, the next request finds it closed and reconnects
+                if let Ok(fresh) = self.reconnect() {
+                    *socket = fresh;
+                }
+                return Err(error);
+            }
+            attempt += 1;
+
+            warn!(
+                "Request failed ({}), reconnecting (retry {}/{})",
+                error, attempt, self.config.max_retries
+            );
+
+            tokio::time::sleep(self.config.reconnect_delay).await;
+            *socket = self.reconnect()?;
+        }
+    }
+
+    fn reconnect(&self) -> Result<Socket, ClientError> {
+        let socket = new_socket()?;
+
+        // Dial in the background so a server that is still restarting
+        // shows up as a timeout on the next attempt rather than an error here
+        socket
+            .dial_async(&self.config.url)
+            .map_err(|e| ClientError::Connection(format!("Dial error: {:?}", e)))?;
+
+        debug!("Reconnected to {}", self.config.url);
+        Ok(socket)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.socket.get_mut().close();
+    }
+}
+
+fn new_socket() -> Result<Socket, ClientError> {
+    Socket::new(Protocol::Req0).map_err(|e| ClientError::Connection(format!("{:?}", e)))
+}
+
+/// Run one send/receive round trip on a blocking thread
+///
+/// `sent` is set once the request is on its way, from then on the server
+/// may carry it out whether or not a reply makes it back.
+async fn exchange(
+    socket: Socket,
+    payload: Vec<u8>,
+    sent: Arc<AtomicBool>,
+) -> Result<Vec<u8>, ClientError> {
+    tokio::task::spawn_blocking(move || {
+        debug!("Sending {} bytes to server", payload.len());
+        socket
+            .send(payload.as_slice())
+            .map_err(|(_, e)| ClientError::Connection(format!("Send error: {:?}", e)))?;
+        sent.store(true, Ordering::SeqCst);
+
+        let msg = socket
+            .recv()
+            .map_err(|e| ClientError::Connection(format!("Receive error: {:?}", e)))?;
+        debug!("Received response of {} bytes", msg.len());
+
+        Ok(msg.to_vec())
+    })
+    .await
+    .map_err(|e| ClientError::Connection(format!("Request task failed: {}", e)))?
+}
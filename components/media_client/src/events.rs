@@ -0,0 +1,47 @@
+use media_protocol::{ClientError, Event};
+use nng::options::{protocol::pubsub::Subscribe, Options};
+use nng::{Protocol, Socket};
+
+/// Receives `Event`s published by the playback server
+pub struct EventSubscriber {
+    socket: Socket,
+}
+
+impl EventSubscriber {
+    /// Subscribe to all events
+    ///
+    /// The dial happens in the background, so subscribing succeeds even if
+    /// the server is not up yet; events start flowing once it is.
+    pub fn connect(url: &str) -> Result<Self, ClientError> {
+        let socket =
+            Socket::new(Protocol::Sub0).map_err(|e| ClientError::Connection(format!("{:?}", e)))?;
+
+        socket
+            .set_opt::<Subscribe>(vec![])
+            .map_err(|e| ClientError::Connection(format!("Subscribe error: {:?}", e)))?;
+
+        socket
+            .dial_async(url)
+            .map_err(|e| ClientError::Connection(format!("Dial error: {:?}", e)))?;
+
+        Ok(Self { socket })
+    }
+
+    /// Wait for the next event
+    pub async fn next(&self) -> Result<Event, ClientError> {
+        let socket = self.socket.clone();
+
+        let msg = tokio::task::spawn_blocking(move || socket.recv())
+            .await
+            .map_err(|e| ClientError::Connection(format!("Receive task failed: {}", e)))?
+            .map_err(|e| ClientError::Connection(format!("Receive error: {:?}", e)))?;
+
+        serde_json::from_slice(&msg).map_err(|e| ClientError::Protocol(e.to_string()))
+    }
+}
+
+impl Drop for EventSubscriber {
+    fn drop(&mut self) {
+        self.socket.close();
+    }
+}
@@ -1,108 +1,273 @@
-use media_protocol::{ClientError, Command, Deck, Response, ResponseData};
-use nng::{Protocol, Socket};
+mod connection;
+mod events;
+
+pub use connection::ClientConfig;
+use connection::Connection;
+pub use events::EventSubscriber;
+use media_protocol::{
+    Bpm, ClientError, Command, Deck, DeckQueue, DeckState, EffectChange, EffectSettings,
+    EffectTarget, Levels, Message, Pad, PadClip, PadMode, Recording, Response, ResponseData, Stats,
+};
 use std::path::PathBuf;
 
 pub struct MediaClient {
-    socket: Socket,
+    connection: Connection,
 }
 
 impl MediaClient {
-    pub fn connect(url: &str) -> Result<Self, ClientError> {
-        let socket =
-            Socket::new(Protocol::Req0).map_err(|e| ClientError::Connection(format!("{:?}", e)))?;
-
-        socket
-            .dial(url)
-            .map_err(|e| ClientError::Connection(format!("{:?}", e)))?;
+    /// Connect with default timeouts and retries
+    pub async fn connect(url: &str) -> Result<Self, ClientError> {
+        Self::connect_with(ClientConfig::new(url)).await
+    }
 
-        Ok(Self { socket })
+    pub async fn connect_with(config: ClientConfig) -> Result<Self, ClientError> {
+        tracing::debug!("Client: Connecting to {}", config.url);
+        let connection = Connection::open(config)?;
+        Ok(Self { connection })
     }
 
-    pub fn load_track(&self, path: PathBuf, deck: Deck) -> Result<(), ClientError> {
+    pub async fn load_track(&self, path: PathBuf, deck: Deck) -> Result<(), ClientError> {
         let cmd = Command::LoadTrack { path, deck };
-        self.send_command(cmd)
+        self.send_command(cmd).await
     }
 
-    pub fn stop(&self, deck: Deck) -> Result<(), ClientError> {
+    pub async fn stop(&self, deck: Deck) -> Result<(), ClientError> {
         let cmd = Command::Stop { deck };
-        self.send_command(cmd)
+        self.send_command(cmd).await
     }
 
-    pub fn set_volume(&self, deck: Deck, db: f32) -> Result<(), ClientError> {
+    pub async fn set_volume(&self, deck: Deck, db: f32) -> Result<(), ClientError> {
         let cmd = Command::SetVolume { deck, db };
-        self.send_command(cmd)
+        self.send_command(cmd).await
     }
 
-    pub fn unload_track(&self, deck: Deck) -> Result<(), ClientError> {
+    pub async fn unload_track(&self, deck: Deck) -> Result<(), ClientError> {
         let cmd = Command::Unload { deck };
-        self.send_command(cmd)
+        self.send_command(cmd).await
     }
 
-    pub fn play(&self, deck: Deck) -> Result<(), ClientError> {
+    pub async fn play(&self, deck: Deck) -> Result<(), ClientError> {
         tracing::info!("Client: Sending Play command for deck {:?}", deck);
         let cmd = Command::Play { deck };
-        let result = self.send_command(cmd);
+        let result = self.send_command(cmd).await;
         tracing::info!("Client: Play command result: {:?}", result);
         result
     }
 
-    fn send_command(&self, cmd: Command) -> Result<(), ClientError> {
-        println!("Client: Serializing command: {:?}", cmd);
-        let data = serde_json::to_vec(&cmd).map_err(|e| ClientError::Protocol(e.to_string()))?;
-        println!("Client: Sending {} bytes to server", data.len());
+    pub async fn seek(&self, deck: Deck, position: usize) -> Result<(), ClientError> {
+        let cmd = Command::Seek { deck, position };
+        self.send_command(cmd).await
+    }
 
-        match self.socket.send(&data) {
-            Ok(_) => println!("Client: Command sent successfully"),
-            Err(e) => return Err(ClientError::Connection(format!("Send error: {:?}", e))),
-        }
+    pub async fn get_length(&self, deck: Deck) -> Result<usize, ClientError> {
+        let cmd = Command::GetLength { deck };
+
+        self.send_command_with_response(cmd, |data| {
+            if let ResponseData::Length(len) = data {
+                Some(len)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    pub async fn get_position(&self, deck: Deck) -> Result<usize, ClientError> {
+        let cmd = Command::GetPosition { deck };
+
+        self.send_command_with_response(cmd, |data| {
+            if let ResponseData::Position(position) = data {
+                Some(position)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// What every deck on the server is doing right now
+    pub async fn get_state(&self) -> Result<Vec<DeckState>, ClientError> {
+        self.send_command_with_response(Command::GetState, |data| {
+            if let ResponseData::DeckState(states) = data {
+                Some(states)
+            } else {
+                None
+            }
+        })
+        .await
+    }
 
-        println!("Client: Waiting for response...");
-        let msg = match self.socket.recv() {
-            Ok(msg) => {
-                println!("Client: Received response of {} bytes", msg.len());
-                msg
+    /// Current meter readings, for a one off look rather than following events
+    pub async fn get_levels(&self) -> Result<Levels, ClientError> {
+        self.send_command_with_response(Command::GetLevels, |data| {
+            if let ResponseData::Levels(levels) = data {
+                Some(levels)
+            } else {
+                None
             }
-            Err(e) => return Err(ClientError::Connection(format!("Receive error: {:?}", e))),
+        })
+        .await
+    }
+
+    /// Underrun and error counts, to see whether the server is keeping up
+    pub async fn get_stats(&self) -> Result<Stats, ClientError> {
+        self.send_command_with_response(Command::GetStats, |data| {
+            if let ResponseData::Stats(stats) = data {
+                Some(stats)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Put a short clip on a sampler pad, decoded fully on the server
+    pub async fn load_pad(
+        &self,
+        pad: Pad,
+        path: PathBuf,
+        mode: PadMode,
+        gain_db: f32,
+    ) -> Result<(), ClientError> {
+        let clip = PadClip {
+            path,
+            mode,
+            gain_db,
         };
+        self.send_command(Command::LoadPad { pad, clip }).await
+    }
 
-        println!("Client: Deserializing response");
-        let response: Response =
-            serde_json::from_slice(&msg).map_err(|e| ClientError::Protocol(e.to_string()))?;
+    pub async fn unload_pad(&self, pad: Pad) -> Result<(), ClientError> {
+        self.send_command(Command::UnloadPad { pad }).await
+    }
 
-        if !response.success {
-            return Err(ClientError::Command(response.error_message));
-        }
+    pub async fn trigger_pad(&self, pad: Pad) -> Result<(), ClientError> {
+        self.send_command(Command::TriggerPad { pad }).await
+    }
 
-        Ok(())
+    pub async fn release_pad(&self, pad: Pad) -> Result<(), ClientError> {
+        self.send_command(Command::ReleasePad { pad }).await
     }
-    pub fn seek(&self, deck: Deck, position: usize) -> Result<(), ClientError> {
-        let cmd = Command::Seek { deck, position };
-        self.send_command(cmd)
+
+    pub async fn stop_pad(&self, pad: Pad) -> Result<(), ClientError> {
+        self.send_command(Command::StopPad { pad }).await
+    }
+
+    /// Quantise looped pads to `bpm`, or stop quantising with `None`
+    pub async fn set_sync_tempo(&self, bpm: Option<f64>) -> Result<(), ClientError> {
+        self.send_command(Command::SetSyncTempo { bpm }).await
     }
 
-    // New helper method for commands that return data
-    fn send_command_with_response<T>(
+    /// Add an effect to a deck or master, or change one already there
+    pub async fn set_effect(
         &self,
-        cmd: Command,
-        extract: fn(ResponseData) -> Option<T>,
-    ) -> Result<T, ClientError> {
-        let data = serde_json::to_vec(&cmd).map_err(|e| ClientError::Protocol(e.to_string()))?;
+        target: EffectTarget,
+        settings: EffectSettings,
+    ) -> Result<(), ClientError> {
+        let change = EffectChange { target, settings };
+        self.send_command(Command::SetEffect(change)).await
+    }
 
-        self.socket
-            .send(&data)
-            .map_err(|e| ClientError::Connection(format!("{:?}", e)))?;
+    /// Tell the server a deck's tempo, for its beat synced effects
+    pub async fn set_deck_bpm(&self, deck: Deck, bpm: Bpm) -> Result<(), ClientError> {
+        self.send_command(Command::SetDeckBpm { deck, bpm }).await
+    }
 
-        let msg = self
-            .socket
-            .recv()
-            .map_err(|e| ClientError::Connection(format!("{:?}", e)))?;
+    /// Play `path` after the deck's queue, or load it if the deck is empty
+    pub async fn enqueue(&self, deck: Deck, path: PathBuf) -> Result<(), ClientError> {
+        self.send_command(Command::Enqueue { deck, path }).await
+    }
 
-        let response: Response =
-            serde_json::from_slice(&msg).map_err(|e| ClientError::Protocol(e.to_string()))?;
+    pub async fn clear_queue(&self, deck: Deck) -> Result<(), ClientError> {
+        self.send_command(Command::ClearQueue { deck }).await
+    }
 
-        if !response.success {
-            return Err(ClientError::Command(response.error_message));
-        }
+    /// Move a deck on to the next file in its queue
+    pub async fn skip(&self, deck: Deck) -> Result<(), ClientError> {
+        self.send_command(Command::Skip { deck }).await
+    }
+
+    pub async fn get_queue(&self, deck: Deck) -> Result<DeckQueue, ClientError> {
+        self.send_command_with_response(Command::GetQueue { deck }, |data| {
+            if let ResponseData::Queue(queue) = data {
+                Some(queue)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Overlap the deck's queued files by `seconds`, 0 plays them gaplessly
+    pub async fn set_crossfade(&self, deck: Deck, seconds: f32) -> Result<(), ClientError> {
+        self.send_command(Command::SetCrossfade { deck, seconds })
+            .await
+    }
+
+    /// Record the master output, to a new file named after the time unless given a file name
+    pub async fn start_recording(&self, path: Option<PathBuf>) -> Result<Recording, ClientError> {
+        self.send_command_with_response(Command::StartRecording { path }, |data| {
+            if let ResponseData::Recording(recording) = data {
+                recording
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Finish the recording, answering once the file is complete
+    pub async fn stop_recording(&self) -> Result<Recording, ClientError> {
+        self.send_command_with_response(Command::StopRecording, |data| {
+            if let ResponseData::Recording(recording) = data {
+                recording
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// The recording in progress, if there is one
+    pub async fn get_recording(&self) -> Result<Option<Recording>, ClientError> {
+        self.send_command_with_response(Command::GetRecording, |data| {
+            if let ResponseData::Recording(recording) = data {
+                Some(recording)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Have the server apply `message` when the musical clock reaches its tick
+    pub async fn schedule(&self, message: Message) -> Result<u64, ClientError> {
+        self.send_command_with_response(Command::Schedule(message), |data| {
+            if let ResponseData::Scheduled(id) = data {
+                Some(id)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Take back a scheduled message that hasn't been applied yet
+    pub async fn cancel_scheduled(&self, id: u64) -> Result<(), ClientError> {
+        self.send_command(Command::CancelScheduled { id }).await
+    }
+
+    async fn send_command(&self, cmd: Command) -> Result<(), ClientError> {
+        self.request(cmd).await.map(|_| ())
+    }
+
+    // Helper method for commands that return data
+    async fn send_command_with_response<T>(
+        &self,
+        cmd: Command,
+        extract: fn(ResponseData) -> Option<T>,
+    ) -> Result<T, ClientError> {
+        let response = self.request(cmd).await?;
 
         match response.data {
             Some(data) => {
@@ -118,15 +283,214 @@ impl MediaClient {
         }
     }
 
-    pub fn get_length(&self, deck: Deck) -> Result<usize, ClientError> {
-        let cmd = Command::GetLength { deck };
+    async fn request(&self, cmd: Command) -> Result<Response, ClientError> {
+        tracing::debug!("Client: Sending command: {:?}", cmd);
+        let data = serde_json::to_vec(&cmd).map_err(|e| ClientError::Protocol(e.to_string()))?;
 
-        self.send_command_with_response(cmd, |data| {
-            if let ResponseData::Length(len) = data {
-                Some(len)
-            } else {
-                None
-            }
-        })
+        let msg = self.connection.request(data, cmd.is_read_only()).await?;
+
+        let response: Response =
+            serde_json::from_slice(&msg).map_err(|e| ClientError::Protocol(e.to_string()))?;
+
+        if !response.success {
+            return Err(ClientError::Command(response.error_message));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nng::{Protocol, Socket};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// How the mock server treats incoming requests
+    #[derive(Clone, Copy)]
+    enum Behavior {
+        /// Reply to every request immediately
+        Reply,
+        /// Swallow the first `n` requests, then reply
+        DropFirst(usize),
+        /// Wait before replying
+        Delay(Duration),
+        /// Never reply
+        Silent,
+        /// Wait before the first reply, and answer each request with its number as a length
+        SlowFirst(Duration),
+    }
+
+    /// A Rep0 server answering every command with a canned response
+    struct MockServer {
+        url: String,
+        received: Arc<AtomicUsize>,
+    }
+
+    impl MockServer {
+        fn start(name: &str, behavior: Behavior, data: Option<ResponseData>) -> Self {
+            let url = format!("inproc://media-client-test-{}", name);
+            let socket = Socket::new(Protocol::Rep0).unwrap();
+            socket.listen(&url).unwrap();
+
+            let received = Arc::new(AtomicUsize::new(0));
+            let counter = received.clone();
+
+            std::thread::spawn(move || {
+                while let Ok(_msg) = socket.recv() {
+                    let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+
+                    match behavior {
+                        Behavior::Reply => {}
+                        Behavior::DropFirst(n) if count <= n => continue,
+                        Behavior::DropFirst(_) => {}
+                        Behavior::Delay(delay) => std::thread::sleep(delay),
+                        Behavior::Silent => continue,
+                        Behavior::SlowFirst(delay) if count == 1 => std::thread::sleep(delay),
+                        Behavior::SlowFirst(_) => {}
+                    }
+
+                    let data = match behavior {
+                        Behavior::SlowFirst(_) => Some(ResponseData::Length(count)),
+                        _ => data.clone(),
+                    };
+                    let response = Response {
+                        success: true,
+                        error_message: String::new(),
+                        data,
+                    };
+                    let reply = serde_json::to_vec(&response).unwrap();
+                    if socket.send(reply.as_slice()).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Self { url, received }
+        }
+
+        fn received(&self) -> usize {
+            self.received.load(Ordering::SeqCst)
+        }
+
+        fn config(&self) -> ClientConfig {
+            ClientConfig::new(&self.url)
+                .with_request_timeout(Duration::from_millis(200))
+                .with_reconnect_delay(Duration::from_millis(10))
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_are_returned() {
+        let server = MockServer::start("reply", Behavior::Reply, None);
+        let client = MediaClient::connect_with(server.config()).await.unwrap();
+
+        client.play(Deck::A).await.unwrap();
+
+        assert_eq!(server.received(), 1);
+    }
+
+    #[tokio::test]
+    async fn response_data_is_extracted() {
+        let server =
+            MockServer::start("length", Behavior::Reply, Some(ResponseData::Length(96000)));
+        let client = MediaClient::connect_with(server.config()).await.unwrap();
+
+        assert_eq!(client.get_length(Deck::B).await.unwrap(), 96000);
+    }
+
+    #[tokio::test]
+    async fn unexpected_response_data_is_a_protocol_error() {
+        let server = MockServer::start("mismatch", Behavior::Reply, Some(ResponseData::Length(1)));
+        let client = MediaClient::connect_with(server.config()).await.unwrap();
+
+        let result = client.get_position(Deck::A).await;
+
+        assert!(matches!(result, Err(ClientError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn dropped_reply_is_retried_on_new_socket() {
+        let server = MockServer::start(
+            "drop",
+            Behavior::DropFirst(1),
+            Some(ResponseData::Length(1)),
+        );
+        let client = MediaClient::connect_with(server.config()).await.unwrap();
+
+        client.get_length(Deck::A).await.unwrap();
+
+        assert_eq!(server.received(), 2);
+    }
+
+    #[tokio::test]
+    async fn slow_reply_within_timeout_succeeds() {
+        let server = MockServer::start("slow", Behavior::Delay(Duration::from_millis(50)), None);
+        let client = MediaClient::connect_with(server.config().with_max_retries(0))
+            .await
+            .unwrap();
+
+        client.set_volume(Deck::A, -6.0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delayed_reply_times_out() {
+        let server = MockServer::start("delay", Behavior::Delay(Duration::from_secs(2)), None);
+        let client = MediaClient::connect_with(server.config().with_max_retries(0))
+            .await
+            .unwrap();
+
+        let result = client.play(Deck::A).await;
+
+        assert!(matches!(result, Err(ClientError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let server = MockServer::start("silent", Behavior::Silent, None);
+        let client = MediaClient::connect_with(server.config().with_max_retries(2))
+            .await
+            .unwrap();
+
+        let result = client.get_state().await;
+
+        assert!(matches!(result, Err(ClientError::Timeout(_))));
+        assert_eq!(server.received(), 3);
+    }
+
+    #[tokio::test]
+    async fn commands_the_server_may_have_run_are_not_sent_again() {
+        let late = Behavior::SlowFirst(Duration::from_millis(300));
+        let server = MockServer::start("once", late, None);
+        let client = MediaClient::connect_with(server.config()).await.unwrap();
+
+        let result = client.skip(Deck::A).await;
+
+        assert!(matches!(result, Err(ClientError::Timeout(_))));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(server.received(), 1);
+    }
+
+    #[tokio::test]
+    async fn late_reply_does_not_answer_the_next_request() {
+        let late = Behavior::SlowFirst(Duration::from_millis(300));
+        let server = MockServer::start("late", late, None);
+        let client = MediaClient::connect_with(server.config().with_max_retries(0))
+            .await
+            .unwrap();
+
+        let result = client.get_length(Deck::A).await;
+        assert!(matches!(result, Err(ClientError::Timeout(_))));
+
+        assert_eq!(client.get_length(Deck::A).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn connect_fails_without_server() {
+        let result = MediaClient::connect("inproc://media-client-test-nobody").await;
+
+        assert!(matches!(result, Err(ClientError::Connection(_))));
     }
 }
@@ -10,4 +10,7 @@ pub enum ClientError {
 
     #[error("Protocol error: {0}")]
     Protocol(String),
+
+    #[error("Request timed out after {0:?}")]
+    Timeout(std::time::Duration),
 }
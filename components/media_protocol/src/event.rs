@@ -0,0 +1,48 @@
+use crate::protocol::{DeckQueue, Levels, Recording, Stats};
+use playback_primitives::Deck;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// State changes published by the playback server
+///
+/// Events are broadcast to every subscriber after a command succeeded, so a
+/// UI can follow the decks without polling for every change. `Levels` and
+/// `Stats` are the exception, they are published on a timer when they
+/// change. So are `Advanced` and `QueueChanged`, which follow the decks
+/// moving through their queues.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    TrackLoaded { deck: Deck, path: PathBuf },
+    TrackUnloaded { deck: Deck },
+    Playing { deck: Deck },
+    Stopped { deck: Deck },
+    VolumeChanged { deck: Deck, db: f32 },
+    Seeked { deck: Deck, position: usize },
+    Advanced { deck: Deck, path: PathBuf },
+    QueueChanged { deck: Deck, queue: DeckQueue },
+    RecordingStarted(Recording),
+    RecordingStopped(Recording),
+    Levels(Levels),
+    Stats(Stats),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_serialization() {
+        let event = Event::VolumeChanged {
+            deck: Deck::B,
+            db: -6.0,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        let decoded: Event = serde_json::from_str(&json).unwrap();
+
+        assert!(matches!(
+            decoded,
+            Event::VolumeChanged { deck: Deck::B, db } if db == -6.0
+        ));
+    }
+}
@@ -1,6 +1,15 @@
 mod error;
+mod event;
 mod protocol;
 
+pub use clock::protocol::{FileRef, Message};
 pub use error::ClientError;
-pub use playback_primitives::Deck;
-pub use protocol::{Command, Response, ResponseData};
+pub use event::Event;
+pub use music_primitives::Bpm;
+pub use playback_primitives::{
+    Deck, EffectKind, EffectParams, EffectSettings, EffectTarget, Pad, PadMode,
+};
+pub use protocol::{
+    Command, DeckQueue, DeckState, DeckStats, EffectChange, Level, Levels, PadClip, Recording,
+    Response, ResponseData, Stats, TrackState,
+};
@@ -1,5 +1,10 @@
-use playback_primitives::Deck;
+use chrono::{DateTime, Utc};
+use clock::protocol::Message;
+use music_facts::ContentHash;
+use music_primitives::Bpm;
+use playback_primitives::{Deck, EffectSettings, EffectTarget, Pad, PadMode};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,6 +17,59 @@ pub enum Command {
     Unload { deck: Deck },
     Seek { deck: Deck, position: usize },
     GetLength { deck: Deck },
+    GetPosition { deck: Deck },
+    GetState,
+    LoadPad { pad: Pad, clip: PadClip },
+    UnloadPad { pad: Pad },
+    TriggerPad { pad: Pad },
+    ReleasePad { pad: Pad },
+    StopPad { pad: Pad },
+    SetSyncTempo { bpm: Option<f64> },
+    SetEffect(EffectChange),
+    SetDeckBpm { deck: Deck, bpm: Bpm },
+    GetLevels,
+    GetStats,
+    Enqueue { deck: Deck, path: PathBuf },
+    ClearQueue { deck: Deck },
+    Skip { deck: Deck },
+    GetQueue { deck: Deck },
+    SetCrossfade { deck: Deck, seconds: f32 },
+    StartRecording { path: Option<PathBuf> },
+    StopRecording,
+    GetRecording,
+    Schedule(Message),
+    CancelScheduled { id: u64 },
+}
+
+impl Command {
+    /// Whether running it twice leaves the server as running it once does
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::GetLength { .. }
+                | Command::GetPosition { .. }
+                | Command::GetState
+                | Command::GetLevels
+                | Command::GetStats
+                | Command::GetQueue { .. }
+                | Command::GetRecording
+        )
+    }
+}
+
+/// Add or change an effect on a deck or on master
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EffectChange {
+    pub target: EffectTarget,
+    pub settings: EffectSettings,
+}
+
+/// What to put on a sampler pad
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PadClip {
+    pub path: PathBuf,
+    pub mode: PadMode,
+    pub gain_db: f32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,11 +84,121 @@ pub struct Response {
 pub enum ResponseData {
     Position(usize),
     Length(usize),
+    DeckState(Vec<DeckState>),
+    Levels(Levels),
+    Stats(Stats),
+    Queue(DeckQueue),
+    Recording(Option<Recording>),
+    /// Id for taking back a `Schedule`d message
+    Scheduled(u64),
+}
+
+/// Snapshot of one deck, as answered to `GetState`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeckState {
+    pub deck: Deck,
+    pub volume_db: f32,
+    /// Silenced by a scheduled mute, the volume is kept for when it lifts
+    #[serde(default)]
+    pub muted: bool,
+    /// `None` when the deck is empty
+    pub track: Option<TrackState>,
+    #[serde(default)]
+    pub level: Level,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackState {
+    pub path: PathBuf,
+    /// Hash of the file, once the server has hashed it
+    pub content_hash: Option<ContentHash>,
+    pub playing: bool,
+    /// Interleaved samples played so far
+    pub position: usize,
+    /// Total interleaved samples, when known
+    pub length: Option<usize>,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Meter reading in dBFS, -96 is the floor
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+    pub peak_db: f32,
+    pub rms_db: f32,
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Self {
+            peak_db: -96.0,
+            rms_db: -96.0,
+        }
+    }
+}
+
+/// All the meters, as answered to `GetLevels` and published as they move
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Levels {
+    pub decks: BTreeMap<Deck, Level>,
+    /// Output after the limiter
+    pub master: Level,
+    /// Samples that would have clipped, since the server started
+    pub clips: u64,
+    /// Gain the limiter is taking off right now
+    pub reduction_db: f32,
+}
+
+/// Glitches on one deck, or on all of them together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct DeckStats {
+    /// Blocks the deck was playing but had run out of decoded audio
+    pub buffer_underruns: u64,
+    pub decode_errors: u64,
+    /// Seeks the decoder was slow to carry out
+    pub late_seeks: u64,
+}
+
+/// Audio health since the server started, as answered to `GetStats`
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub decks: BTreeMap<Deck, DeckStats>,
+    pub totals: DeckStats,
+    /// Times the device played silence because the mixer fell behind
+    pub output_underruns: u64,
+}
+
+/// What a deck plays after its current track, as answered to `GetQueue`
+///
+/// `Enqueue` on an empty deck loads the file straight away, after that
+/// files follow each other without a gap, or overlapping by
+/// `crossfade_seconds` when that is set.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct DeckQueue {
+    pub entries: Vec<PathBuf>,
+    pub crossfade_seconds: f32,
+}
+
+/// A recording of the master output, as answered to the recording commands
+///
+/// Recordings always go in the server's recordings directory, `StartRecording`
+/// only names the file. Without a name it is named after the time. The tracklist sidecar gets
+/// a JSON line for every track loaded, played, stopped or moved on to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub path: PathBuf,
+    pub tracklist: PathBuf,
+    pub started: DateTime<Utc>,
+    /// Length recorded so far, or in all once stopped
+    pub seconds: f64,
+    /// Samples lost because the disk couldn't keep up
+    pub dropped_samples: u64,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use playback_primitives::{EffectKind, EffectParams};
 
     #[test]
     fn test_play_command_serialization() {
@@ -40,4 +208,172 @@ mod tests {
 
         assert!(matches!(decoded, Command::Play { deck: Deck::A }));
     }
+
+    #[test]
+    fn test_pad_command_serialization() {
+        let cmd = Command::LoadPad {
+            pad: Pad::new(2).unwrap(),
+            clip: PadClip {
+                path: PathBuf::from("/samples/airhorn.flac"),
+                mode: PadMode::OneShot,
+                gain_db: -6.0,
+            },
+        };
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert!(json.contains("\"mode\":\"one_shot\""), "{}", json);
+
+        let decoded: Command = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            decoded,
+            Command::LoadPad { pad, clip } if pad.index() == 2 && clip.mode == PadMode::OneShot
+        ));
+    }
+
+    #[test]
+    fn test_effect_command_serialization() {
+        let cmd = Command::SetEffect(EffectChange {
+            target: EffectTarget::Master,
+            settings: EffectSettings {
+                enabled: true,
+                wet: 0.3,
+                params: EffectParams::Reverb {
+                    room_size: 0.8,
+                    damping: 0.2,
+                },
+            },
+        });
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert!(
+            json.starts_with(r#"{"set_effect":{"target":"master""#),
+            "{}",
+            json
+        );
+
+        let decoded: Command = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            decoded,
+            Command::SetEffect(change) if change.settings.params.kind() == EffectKind::Reverb
+        ));
+    }
+
+    #[test]
+    fn test_deck_state_round_trip() {
+        let data = ResponseData::DeckState(vec![
+            DeckState {
+                deck: Deck::A,
+                volume_db: -3.0,
+                muted: false,
+                track: Some(TrackState {
+                    path: PathBuf::from("/music/police.flac"),
+                    content_hash: Some(ContentHash("sha256:abc".to_string())),
+                    playing: true,
+                    position: 96000,
+                    length: Some(960000),
+                    sample_rate: 48000,
+                    channels: 2,
+                }),
+                level: Level {
+                    peak_db: -4.5,
+                    rms_db: -12.0,
+                },
+            },
+            DeckState {
+                deck: Deck::B,
+                volume_db: 0.0,
+                muted: true,
+                track: None,
+                level: Level::default(),
+            },
+        ]);
+
+        let json = serde_json::to_string(&data).unwrap();
+        let decoded: ResponseData = serde_json::from_str(&json).unwrap();
+
+        assert!(matches!(decoded, ResponseData::DeckState(states) if states.len() == 2));
+    }
+
+    #[test]
+    fn test_levels_round_trip() {
+        let levels = Levels {
+            decks: BTreeMap::from([(
+                Deck::C,
+                Level {
+                    peak_db: -1.0,
+                    rms_db: -9.5,
+                },
+            )]),
+            master: Level::default(),
+            clips: 12,
+            reduction_db: 2.5,
+        };
+
+        let json = serde_json::to_string(&ResponseData::Levels(levels.clone())).unwrap();
+        assert!(json.contains(r#""decks":{"C":{"#), "{}", json);
+
+        let decoded: ResponseData = serde_json::from_str(&json).unwrap();
+        assert!(matches!(decoded, ResponseData::Levels(decoded) if decoded == levels));
+    }
+
+    #[test]
+    fn test_stats_round_trip() {
+        let deck = DeckStats {
+            buffer_underruns: 3,
+            decode_errors: 1,
+            late_seeks: 0,
+        };
+        let stats = Stats {
+            decks: BTreeMap::from([(Deck::B, deck)]),
+            totals: deck,
+            output_underruns: 7,
+        };
+
+        let json = serde_json::to_string(&ResponseData::Stats(stats.clone())).unwrap();
+        assert!(json.starts_with(r#"{"type":"stats","value":{"#), "{}", json);
+
+        let decoded: ResponseData = serde_json::from_str(&json).unwrap();
+        assert!(matches!(decoded, ResponseData::Stats(decoded) if decoded == stats));
+    }
+
+    #[test]
+    fn test_queue_round_trip() {
+        let queue = DeckQueue {
+            entries: vec![PathBuf::from("/music/one.flac")],
+            crossfade_seconds: 4.0,
+        };
+
+        let json = serde_json::to_string(&ResponseData::Queue(queue.clone())).unwrap();
+        let decoded: ResponseData = serde_json::from_str(&json).unwrap();
+        assert!(matches!(decoded, ResponseData::Queue(decoded) if decoded == queue));
+    }
+
+    #[test]
+    fn test_recording_round_trip() {
+        let recording = Recording {
+            path: PathBuf::from("/music/recordings/set.flac"),
+            tracklist: PathBuf::from("/music/recordings/set.tracklist.jsonl"),
+            started: "2024-06-01T22:00:00Z".parse().unwrap(),
+            seconds: 3600.5,
+            dropped_samples: 0,
+        };
+
+        let json =
+            serde_json::to_string(&ResponseData::Recording(Some(recording.clone()))).unwrap();
+        assert!(
+            json.contains(r#""started":"2024-06-01T22:00:00Z""#),
+            "{}",
+            json
+        );
+
+        let decoded: ResponseData = serde_json::from_str(&json).unwrap();
+        assert!(matches!(decoded, ResponseData::Recording(Some(decoded)) if decoded == recording));
+    }
+
+    #[test]
+    fn test_deck_state_without_level_still_parses() {
+        let json = r#"{"deck":"A","volume_db":0.0,"track":null}"#;
+        let state: DeckState = serde_json::from_str(json).unwrap();
+
+        assert_eq!(state.level, Level::default());
+        assert!(!state.muted);
+    }
 }
@@ -7,7 +7,7 @@ use std::path::PathBuf;
 /// 
 /// Each variant represents a single fact that can be asserted or retracted
 /// about a track. Facts are stored in the stainless-facts stream.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "t", content = "v")]
 pub enum MusicValue {
     // ========================================================================
@@ -0,0 +1,280 @@
+//! Read-only view of the music library built from the fact stream
+//!
+//! `library_crawler` writes facts about every track it finds. This crate
+//! replays that stream into one `LibraryTrack` per `ContentHash` so tools
+//! like `media_ctl` can browse and pick tracks without touching the files.
+
+mod query;
+mod track;
+
+use music_facts::{ContentHash, FactSource, MusicValue};
+use stainless_facts::{aggregate_facts, Fact, FactStreamReader};
+use std::collections::HashMap;
+use std::path::Path;
+use thiserror::Error;
+
+pub use query::Query;
+pub use track::LibraryTrack;
+
+#[derive(Error, Debug)]
+pub enum LibraryError {
+    #[error("Facts file does not exist: {0}")]
+    NotFound(std::path::PathBuf),
+
+    #[error("Failed to read facts: {0}")]
+    Facts(String),
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    #[error("No playable track matches '{0}'")]
+    NoMatch(String),
+
+    #[error("{} tracks match '{query}'", candidates.len())]
+    Ambiguous {
+        query: String,
+        candidates: Vec<LibraryTrack>,
+    },
+}
+
+/// All tracks known from a fact stream
+#[derive(Debug, Default)]
+pub struct Library {
+    tracks: Vec<LibraryTrack>,
+}
+
+impl Library {
+    /// Read and aggregate a facts file written by `library_crawler`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LibraryError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(LibraryError::NotFound(path.to_path_buf()));
+        }
+
+        let mut reader =
+            FactStreamReader::open(path).map_err(|e| LibraryError::Facts(e.to_string()))?;
+
+        let mut facts = Vec::new();
+        while let Some(result) = reader.next() {
+            let fact: Fact<ContentHash, MusicValue, FactSource> =
+                result.map_err(|e| LibraryError::Facts(e.to_string()))?;
+            facts.push(fact);
+        }
+
+        let aggregated: HashMap<ContentHash, LibraryTrack> = aggregate_facts(facts);
+
+        let tracks = aggregated
+            .into_iter()
+            .map(|(content_hash, mut track)| {
+                track.content_hash = Some(content_hash);
+                track
+            })
+            .collect();
+
+        Ok(Self::from_tracks(tracks))
+    }
+
+    /// Build a library from already aggregated tracks, sorted by display name
+    pub fn from_tracks(mut tracks: Vec<LibraryTrack>) -> Self {
+        tracks.sort_by_key(|track| track.display_name());
+        Self { tracks }
+    }
+
+    pub fn tracks(&self) -> &[LibraryTrack] {
+        &self.tracks
+    }
+
+    /// Tracks that still have a file on disk to load
+    pub fn playable(&self) -> impl Iterator<Item = &LibraryTrack> {
+        self.tracks.iter().filter(|track| track.file_path.is_some())
+    }
+
+    /// Playable tracks matching a query, in library order
+    pub fn search(&self, query: &Query) -> Vec<&LibraryTrack> {
+        self.playable()
+            .filter(|track| query.matches(track))
+            .collect()
+    }
+
+    /// Resolve a query to exactly one playable track
+    ///
+    /// When several tracks match, all of them are returned in
+    /// `LibraryError::Ambiguous` so the caller can let the user choose.
+    pub fn resolve(&self, query: &str) -> Result<&LibraryTrack, LibraryError> {
+        let mut matches = self.search(&Query::parse(query)?);
+
+        match matches.len() {
+            0 => Err(LibraryError::NoMatch(query.to_string())),
+            1 => Ok(matches.remove(0)),
+            _ => Err(LibraryError::Ambiguous {
+                query: query.to_string(),
+                candidates: matches.into_iter().cloned().collect(),
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use music_facts::FactOrigin;
+    use music_primitives::Bpm;
+    use stainless_facts::{FactStreamWriter, Operation};
+    use std::path::PathBuf;
+    use tempfile::NamedTempFile;
+
+    fn fact(
+        hash: &str,
+        value: MusicValue,
+        operation: Operation,
+    ) -> Fact<ContentHash, MusicValue, FactSource> {
+        Fact::new(
+            ContentHash(hash.to_string()),
+            value,
+            Utc::now(),
+            FactSource::new("test", "1.0.0", FactOrigin::Unknown),
+            operation,
+        )
+    }
+
+    fn write_facts(facts: &[Fact<ContentHash, MusicValue, FactSource>]) -> NamedTempFile {
+        let temp = NamedTempFile::new().unwrap();
+        let mut writer = FactStreamWriter::open(temp.path()).unwrap();
+        writer.write_batch(facts).unwrap();
+        temp
+    }
+
+    #[test]
+    fn tracks_are_aggregated_per_content_hash() {
+        let temp = write_facts(&[
+            fact("a", MusicValue::Title("Police".into()), Operation::Assert),
+            fact("a", MusicValue::Artist("Suanne".into()), Operation::Assert),
+            fact(
+                "a",
+                MusicValue::Bpm(Bpm::from_u32(128).unwrap()),
+                Operation::Assert,
+            ),
+            fact(
+                "b",
+                MusicValue::FilePath(PathBuf::from("/music/b.flac")),
+                Operation::Assert,
+            ),
+        ]);
+
+        let library = Library::open(temp.path()).unwrap();
+
+        assert_eq!(library.len(), 2);
+        let track = library
+            .tracks()
+            .iter()
+            .find(|t| t.content_hash == Some(ContentHash("a".into())))
+            .unwrap();
+        assert_eq!(track.display_name(), "Suanne - Police");
+        assert_eq!(track.bpm, Some(Bpm::from_u32(128).unwrap()));
+    }
+
+    #[test]
+    fn retracted_path_is_not_playable() {
+        let path = PathBuf::from("/music/gone.flac");
+        let temp = write_facts(&[
+            fact("a", MusicValue::FilePath(path.clone()), Operation::Assert),
+            fact("a", MusicValue::FilePath(path), Operation::Retract),
+        ]);
+
+        let library = Library::open(temp.path()).unwrap();
+
+        assert_eq!(library.len(), 1);
+        assert_eq!(library.playable().count(), 0);
+    }
+
+    #[test]
+    fn duplicate_copies_stay_playable_until_the_last_goes() {
+        let first = PathBuf::from("/music/police.flac");
+        let copy = PathBuf::from("/music/backup/police.flac");
+        let temp = write_facts(&[
+            fact("a", MusicValue::FilePath(first.clone()), Operation::Assert),
+            fact("a", MusicValue::FilePath(copy.clone()), Operation::Assert),
+            fact("a", MusicValue::FilePath(first.clone()), Operation::Retract),
+        ]);
+
+        let library = Library::open(temp.path()).unwrap();
+        let track = library.playable().next().unwrap();
+        assert_eq!(track.file_path, Some(copy.clone()));
+
+        let temp = write_facts(&[
+            fact("a", MusicValue::FilePath(first.clone()), Operation::Assert),
+            fact("a", MusicValue::FilePath(copy.clone()), Operation::Assert),
+            fact("a", MusicValue::FilePath(copy), Operation::Retract),
+            fact("a", MusicValue::FilePath(first.clone()), Operation::Retract),
+        ]);
+
+        let library = Library::open(temp.path()).unwrap();
+        assert_eq!(library.playable().count(), 0);
+    }
+
+    #[test]
+    fn resolve_picks_the_single_match() {
+        let library = Library::from_tracks(vec![
+            LibraryTrack {
+                title: Some("Police".into()),
+                file_path: Some(PathBuf::from("/music/police.flac")),
+                ..Default::default()
+            },
+            LibraryTrack {
+                title: Some("Sunspot".into()),
+                file_path: Some(PathBuf::from("/music/sunspot.flac")),
+                ..Default::default()
+            },
+        ]);
+
+        let track = library.resolve("police").unwrap();
+
+        assert_eq!(track.file_path, Some(PathBuf::from("/music/police.flac")));
+    }
+
+    #[test]
+    fn resolve_reports_every_candidate_when_ambiguous() {
+        let library = Library::from_tracks(vec![
+            LibraryTrack {
+                title: Some("Police".into()),
+                file_path: Some(PathBuf::from("/music/police.flac")),
+                ..Default::default()
+            },
+            LibraryTrack {
+                title: Some("Police (Dub)".into()),
+                file_path: Some(PathBuf::from("/music/police-dub.flac")),
+                ..Default::default()
+            },
+            // Not playable, so never a candidate
+            LibraryTrack {
+                title: Some("Police (Lost)".into()),
+                ..Default::default()
+            },
+        ]);
+
+        match library.resolve("police") {
+            Err(LibraryError::Ambiguous { candidates, .. }) => assert_eq!(candidates.len(), 2),
+            other => panic!("expected ambiguous result, got {:?}", other),
+        }
+        assert!(matches!(
+            library.resolve("sunspot"),
+            Err(LibraryError::NoMatch(_))
+        ));
+    }
+
+    #[test]
+    fn missing_file_is_reported() {
+        let result = Library::open("/this/file/does/not/exist.jsonl");
+
+        assert!(matches!(result, Err(LibraryError::NotFound(_))));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{LibraryError, LibraryTrack};
+use music_primitives::Key;
+
+const HASH_PREFIX: &str = "sha256:";
+
+/// What the user asked for when looking up a track
+///
+/// Parsed from free text:
+/// - `sha256:…` matches content hashes by prefix
+/// - a twelve character ISRC, with or without dashes, matches exactly
+/// - `bpm 124-128`, `bpm 128` and `key 8A` filter on analysed attributes
+///   and can be combined
+/// - anything else matches when every word is found in the artist,
+///   title or album, "key largo" included
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Hash(String),
+    Isrc(String),
+    Attributes {
+        bpm: Option<(u32, u32)>,
+        key: Option<Key>,
+    },
+    Text(Vec<String>),
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Self, LibraryError> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Err(LibraryError::InvalidQuery("empty query".to_string()));
+        }
+
+        if input.starts_with(HASH_PREFIX) {
+            return Ok(Query::Hash(input.to_string()));
+        }
+
+        if let Some(isrc) = normalize_isrc(input) {
+            return Ok(Query::Isrc(isrc));
+        }
+
+        let words: Vec<&str> = input.split_whitespace().collect();
+        let first = words[0].to_lowercase();
+        if first == "bpm" || first == "key" {
+            // Titles start with these words too
+            if let Some(attributes) = parse_attributes(&words) {
+                return Ok(attributes);
+            }
+        }
+
+        Ok(Query::Text(
+            words.into_iter().map(|word| word.to_lowercase()).collect(),
+        ))
+    }
+
+    pub fn matches(&self, track: &LibraryTrack) -> bool {
+        match self {
+            Query::Hash(prefix) => track
+                .content_hash
+                .as_ref()
+                .is_some_and(|hash| hash.0.starts_with(prefix.as_str())),
+            Query::Isrc(isrc) => {
+                track.isrc.as_deref().and_then(normalize_isrc).as_ref() == Some(isrc)
+            }
+            Query::Attributes { bpm, key } => {
+                let bpm_matches = match bpm {
+                    Some((low, high)) => track
+                        .bpm
+                        .is_some_and(|bpm| (*low..=*high).contains(&bpm.as_u32())),
+                    None => true,
+                };
+                let key_matches = match key {
+                    Some(key) => track.key.as_ref() == Some(key),
+                    None => true,
+                };
+                bpm_matches && key_matches
+            }
+            Query::Text(words) => {
+                let haystack = [&track.artist, &track.title, &track.album]
+                    .into_iter()
+                    .flatten()
+                    .map(|s| s.to_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                words.iter().all(|word| haystack.contains(word.as_str()))
+            }
+        }
+    }
+}
+
+/// Uppercase ISRC without dashes, if `input` looks like one
+///
+/// An ISRC is a country code of two letters, a three character registrant,
+/// two year digits and a five digit designation, e.g. `USRC17607839`.
+fn normalize_isrc(input: &str) -> Option<String> {
+    let isrc: String = input
+        .chars()
+        .filter(|c| *c != '-')
+        .collect::<String>()
+        .to_uppercase();
+    let chars: Vec<char> = isrc.chars().collect();
+
+    let valid = chars.len() == 12
+        && chars[..2].iter().all(|c| c.is_ascii_uppercase())
+        && chars[2..5].iter().all(|c| c.is_ascii_alphanumeric())
+        && chars[5..].iter().all(|c| c.is_ascii_digit());
+
+    valid.then_some(isrc)
+}
+
+// None unless every word pairs up as a bpm range or a Camelot key
+fn parse_attributes(words: &[&str]) -> Option<Query> {
+    let mut bpm = None;
+    let mut key = None;
+
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        let value = words.next()?;
+        match word.to_lowercase().as_str() {
+            "bpm" => bpm = Some(parse_bpm_range(value)?),
+            "key" => key = Some(Key::from_camelot(value).ok()?),
+            _ => return None,
+        }
+    }
+
+    Some(Query::Attributes { bpm, key })
+}
+
+fn parse_bpm_range(value: &str) -> Option<(u32, u32)> {
+    match value.split_once('-') {
+        Some((low, high)) => {
+            let (low, high) = (low.parse().ok()?, high.parse().ok()?);
+            (low <= high).then_some((low, high))
+        }
+        None => {
+            let bpm = value.parse().ok()?;
+            Some((bpm, bpm))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use music_facts::ContentHash;
+    use music_primitives::Bpm;
+
+    fn track() -> LibraryTrack {
+        LibraryTrack {
+            content_hash: Some(ContentHash("sha256:abcdef0123".into())),
+            title: Some("Police".into()),
+            artist: Some("Suanne".into()),
+            album: Some("Night Shift".into()),
+            bpm: Some(Bpm::from_f32(125.6).unwrap()),
+            key: Some(Key::from_camelot("8A").unwrap()),
+            isrc: Some("SE-ABC-24-00001".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn content_hash_matches_by_prefix() {
+        let query = Query::parse("sha256:abcd").unwrap();
+
+        assert_eq!(query, Query::Hash("sha256:abcd".into()));
+        assert!(query.matches(&track()));
+        assert!(!Query::parse("sha256:ffff").unwrap().matches(&track()));
+    }
+
+    #[test]
+    fn isrc_ignores_dashes_and_case() {
+        let query = Query::parse("seabc2400001").unwrap();
+
+        assert_eq!(query, Query::Isrc("SEABC2400001".into()));
+        assert!(query.matches(&track()));
+    }
+
+    #[test]
+    fn text_requires_every_word() {
+        assert!(Query::parse("suanne pol").unwrap().matches(&track()));
+        assert!(Query::parse("night").unwrap().matches(&track()));
+        assert!(!Query::parse("suanne sunspot").unwrap().matches(&track()));
+    }
+
+    #[test]
+    fn bpm_range_and_key_combine() {
+        let query = Query::parse("bpm 124-128 key 8A").unwrap();
+
+        assert_eq!(
+            query,
+            Query::Attributes {
+                bpm: Some((124, 128)),
+                key: Some(Key::from_camelot("8A").unwrap()),
+            }
+        );
+        assert!(query.matches(&track()));
+        assert!(!Query::parse("bpm 128 key 8A").unwrap().matches(&track()));
+        assert!(!Query::parse("key 9A").unwrap().matches(&track()));
+    }
+
+    #[test]
+    fn words_that_are_not_filters_are_searched_for() {
+        for input in [
+            "key largo",
+            "Key to the City",
+            "bpm",
+            "bpm fast",
+            "bpm 130-120",
+            "key 13A",
+            "bpm 128 mood dark",
+        ] {
+            let words = input.split_whitespace().map(str::to_lowercase).collect();
+            assert_eq!(Query::parse(input).unwrap(), Query::Text(words), "{input}");
+        }
+
+        let largo = LibraryTrack {
+            title: Some("Key Largo".into()),
+            ..Default::default()
+        };
+        assert!(Query::parse("key largo").unwrap().matches(&largo));
+    }
+}
@@ -0,0 +1,97 @@
+use music_facts::{ContentHash, FactSource, MusicValue};
+use music_primitives::{Bpm, Key};
+use stainless_facts::FactAggregator;
+use std::path::PathBuf;
+
+/// The parts of a track's facts needed to find and load it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LibraryTrack {
+    pub content_hash: Option<ContentHash>,
+    pub file_path: Option<PathBuf>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub bpm: Option<Bpm>,
+    pub key: Option<Key>,
+    pub isrc: Option<String>,
+    pub duration_seconds: Option<u32>,
+    /// Every copy's path not retracted yet, newest last
+    pub(crate) paths: Vec<PathBuf>,
+}
+
+impl LibraryTrack {
+    /// "Artist - Title", falling back to whatever is known
+    pub fn display_name(&self) -> String {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (Some(artist), None) => artist.clone(),
+            (None, Some(title)) => title.clone(),
+            (None, None) => match (&self.file_path, &self.content_hash) {
+                (Some(path), _) => path.display().to_string(),
+                (None, Some(hash)) => hash.0.clone(),
+                (None, None) => "Unknown".to_string(),
+            },
+        }
+    }
+}
+
+impl FactAggregator<ContentHash, MusicValue, FactSource> for LibraryTrack {
+    fn assert(&mut self, value: &MusicValue, _source: &FactSource) {
+        use MusicValue::*;
+
+        match value {
+            FilePath(path) => {
+                self.paths.retain(|copy| copy != path);
+                self.paths.push(path.clone());
+                self.file_path = Some(path.clone());
+            }
+            Title(s) => self.title = Some(s.clone()),
+            Artist(s) => self.artist = Some(s.clone()),
+            Album(s) => self.album = Some(s.clone()),
+            Bpm(bpm) => self.bpm = Some(*bpm),
+            Key(key) => self.key = Some(*key),
+            Isrc(isrc) => self.isrc = Some(isrc.0.clone()),
+            DurationSeconds(ds) => self.duration_seconds = Some(ds.0),
+            // Everything else is not needed to pick a track
+            _ => {}
+        }
+    }
+
+    fn retract(&mut self, value: &MusicValue, _source: &FactSource) {
+        use MusicValue::*;
+
+        match value {
+            // Another copy of the same content can still be played
+            FilePath(path) => {
+                self.paths.retain(|copy| copy != path);
+                self.file_path = self.paths.last().cloned();
+            }
+            Title(_) => self.title = None,
+            Artist(_) => self.artist = None,
+            Album(_) => self.album = None,
+            Bpm(_) => self.bpm = None,
+            Key(_) => self.key = None,
+            Isrc(_) => self.isrc = None,
+            DurationSeconds(_) => self.duration_seconds = None,
+            _ => {}
+        }
+    }
+
+    fn assert_unknown(
+        &mut self,
+        _attribute: &str,
+        _value: &serde_json::Value,
+        _source: &FactSource,
+    ) {
+        // Unknown attributes are gracefully ignored for forward compatibility
+    }
+
+    fn retract_unknown(
+        &mut self,
+        _attribute: &str,
+        _value: &serde_json::Value,
+        _source: &FactSource,
+    ) {
+        // Unknown attributes are gracefully ignored
+    }
+}
@@ -231,6 +231,28 @@ impl Key {
         format!("{}{}", number, letter)
     }
 
+    /// Parse from Camelot notation (e.g., "8A", "12B")
+    ///
+    /// # Examples
+    /// ```
+    /// # use music_primitives::{Key, KeyError};
+    /// let key = Key::from_camelot("8A")?;
+    /// assert_eq!(key.to_traditional_sharp(), "A Minor");
+    ///
+    /// let key = Key::from_camelot("8b")?;
+    /// assert_eq!(key.to_traditional_sharp(), "C Major");
+    /// # Ok::<(), KeyError>(())
+    /// ```
+    pub fn from_camelot(s: &str) -> Result<Self, KeyError> {
+        let wanted = s.trim().to_uppercase();
+
+        // The wheel has only 24 positions, so search it instead of keeping
+        // a second mapping table in sync with to_camelot
+        Self::all()
+            .find(|key| key.to_camelot() == wanted)
+            .ok_or_else(|| KeyError::InvalidNotation(s.to_string()))
+    }
+
     /// Get Open Key notation (alternative DJ notation)
     ///
     /// Open Key uses numbers 1-12 and letters d (major) or m (minor)
@@ -263,6 +285,15 @@ impl Key {
         format!("{}{}", open_key_num, letter)
     }
 
+    /// All 24 major and minor keys
+    fn all() -> impl Iterator<Item = Key> {
+        use PitchClass::*;
+
+        [C, CSharp, D, DSharp, E, F, FSharp, G, GSharp, A, ASharp, B]
+            .into_iter()
+            .flat_map(|pitch| [Key::new(pitch, Mode::Major), Key::new(pitch, Mode::Minor)])
+    }
+
     pub fn pitch(&self) -> PitchClass {
         self.pitch
     }
@@ -335,6 +366,27 @@ mod tests {
         assert_eq!(Key::from_traditional("E Minor").unwrap().to_camelot(), "9A");
     }
 
+    #[test]
+    fn parse_camelot_notation() {
+        assert_eq!(
+            Key::from_camelot("8B").unwrap(),
+            Key::from_traditional("C Major").unwrap()
+        );
+        assert_eq!(
+            Key::from_camelot("12a").unwrap(),
+            Key::from_traditional("C# Minor").unwrap()
+        );
+        assert!(Key::from_camelot("13A").is_err());
+        assert!(Key::from_camelot("8C").is_err());
+    }
+
+    #[test]
+    fn camelot_notation_round_trip() {
+        for key in Key::all() {
+            assert_eq!(Key::from_camelot(&key.to_camelot()).unwrap(), key);
+        }
+    }
+
     #[test]
     fn open_key_conversion() {
         assert_eq!(
@@ -0,0 +1,98 @@
+//! CPU use and output underruns of the mix thread under load
+//!
+//! Four decks play through a filter and an echo each, with reverb on
+//! master, into the headless output for a few seconds per latency setting.
+//! Run with `cargo bench --bench mix_load`.
+
+use playback_engine::{
+    Deck, EffectParams, EffectSettings, EffectTarget, FnSource, OutputConfig, PlaybackEngine,
+    PlaybackError,
+};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const RUN_TIME: Duration = Duration::from_secs(5);
+const LATENCIES_MS: [u32; 4] = [10, 20, 40, 100];
+
+/// CPU time used by this process so far, from /proc
+fn cpu_time() -> Duration {
+    // Clock ticks are 100 Hz on every Linux we run on
+    const TICKS_PER_SECOND: u64 = 100;
+
+    let stat = std::fs::read_to_string("/proc/self/stat").expect("Linux only");
+    // Fields after the command name, which is in parentheses and may hold spaces
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..]
+        .split_whitespace()
+        .collect();
+    // utime and stime are fields 14 and 15 of the whole line
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    Duration::from_millis(ticks * 1000 / TICKS_PER_SECOND)
+}
+
+fn effect(params: EffectParams) -> EffectSettings {
+    EffectSettings {
+        enabled: true,
+        wet: 0.5,
+        params,
+    }
+}
+
+fn run(rt: &Runtime, latency_ms: u32) -> Result<(f64, u64), PlaybackError> {
+    let config = OutputConfig {
+        latency_ms,
+        ..OutputConfig::default()
+    };
+    let mut engine = PlaybackEngine::headless_with_output_config(config)?;
+
+    rt.block_on(async {
+        for (index, deck) in Deck::all().enumerate() {
+            // Endless sine, decoded on demand like a file would be
+            let frequency = 220.0 * (index + 1) as f32;
+            let source = FnSource::new(move |frame| {
+                0.25 * (2.0 * std::f32::consts::PI * frequency * frame as f32 / 48000.0).sin()
+            });
+            engine
+                .load_source(deck, Path::new("/bench/sine.flac"), source)
+                .await?;
+            let target = EffectTarget::Deck(deck);
+            engine.set_effect(target, effect(EffectParams::Filter { amount: -0.3 }))?;
+            let echo = EffectParams::Echo {
+                beats: 0.75,
+                feedback: 0.4,
+            };
+            engine.set_effect(target, effect(echo))?;
+        }
+        let reverb = EffectParams::Reverb {
+            room_size: 0.7,
+            damping: 0.3,
+        };
+        engine.set_effect(EffectTarget::Master, effect(reverb))?;
+        Ok::<_, PlaybackError>(())
+    })?;
+
+    // Let the decks fill their buffers before counting
+    std::thread::sleep(Duration::from_millis(500));
+    for deck in Deck::all() {
+        engine.play(deck)?;
+    }
+    let underruns_before = engine.output_underruns();
+    let cpu_before = cpu_time();
+    let start = Instant::now();
+
+    std::thread::sleep(RUN_TIME);
+
+    let cpu = (cpu_time() - cpu_before).as_secs_f64() / start.elapsed().as_secs_f64();
+    Ok((cpu * 100.0, engine.output_underruns() - underruns_before))
+}
+
+fn main() {
+    let rt = Runtime::new().unwrap();
+
+    println!("4 decks with filter and echo, reverb on master");
+    println!("{:>10} {:>8} {:>10}", "latency", "cpu", "underruns");
+    for latency_ms in LATENCIES_MS {
+        let (cpu, underruns) = run(&rt, latency_ms).expect("engine failed");
+        println!("{:>7} ms {:>7.1}% {:>10}", latency_ms, cpu, underruns);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::output_config::MIX_RATE;
+use playback_primitives::Deck;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A change the mixer makes on an exact frame of the mix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cue {
+    SetVolume { deck: Deck, db: f32 },
+    SetMute { deck: Deck, muted: bool },
+    Play { deck: Deck },
+}
+
+/// Names a cue, for taking it back before it happens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CueId(pub(crate) u64);
+
+/// Frames the mixer has rendered, the mix's own clock
+///
+/// Runs ahead of what is heard by the output latency, and moves a block
+/// at a time.
+#[derive(Debug, Clone, Default)]
+pub struct MixPosition {
+    mixed: Arc<AtomicU64>,
+    latency: u64,
+}
+
+impl MixPosition {
+    pub(crate) fn with_latency(latency: u64) -> Self {
+        Self {
+            mixed: Arc::default(),
+            latency,
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.mixed.load(Ordering::Acquire)
+    }
+
+    /// Frame of the mix being heard about now, the output latency behind
+    pub fn heard(&self) -> u64 {
+        self.frames().saturating_sub(self.latency)
+    }
+
+    /// Frames of the mix in `duration`
+    pub fn frames_in(&self, duration: Duration) -> u64 {
+        (duration.as_secs_f64() * MIX_RATE as f64).round() as u64
+    }
+
+    pub(crate) fn set(&self, frames: u64) {
+        self.mixed.store(frames, Ordering::Release);
+    }
+}
+
+// Cues waiting for their frame, soonest first
+#[derive(Debug, Default)]
+pub(crate) struct Cues(VecDeque<(u64, CueId, Cue)>);
+
+impl Cues {
+    pub fn add(&mut self, frame: u64, id: CueId, cue: Cue) {
+        // Cues on the same frame happen in the order they came
+        let at = self.0.partition_point(|(waiting, ..)| *waiting <= frame);
+        self.0.insert(at, (frame, id, cue));
+    }
+
+    pub fn cancel(&mut self, id: CueId) {
+        self.0.retain(|(_, waiting, _)| *waiting != id);
+    }
+
+    /// Take the next cue due before `frame`, with the frame it was for
+    pub fn next_before(&mut self, frame: u64) -> Option<(u64, Cue)> {
+        if self.0.front()?.0 >= frame {
+            return None;
+        }
+        self.0.pop_front().map(|(frame, _, cue)| (frame, cue))
+    }
+}
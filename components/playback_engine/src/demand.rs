@@ -0,0 +1,81 @@
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// What the audio output tells the mix thread
+///
+/// The output wakes the mixer every time it takes audio, so the mixer only
+/// runs when there is room to fill, and reports when it found too little.
+#[derive(Default)]
+pub struct Demand {
+    woken: Mutex<bool>,
+    condvar: Condvar,
+    underruns: AtomicU64,
+}
+
+impl Demand {
+    /// Ask the mix thread for more, from the output or after a command
+    pub fn wake(&self) {
+        *self.woken.lock() = true;
+        self.condvar.notify_one();
+    }
+
+    /// Sleep until woken, or `timeout` passes without a word
+    pub fn wait(&self, timeout: Duration) {
+        let mut woken = self.woken.lock();
+        if !*woken {
+            self.condvar.wait_for(&mut woken, timeout);
+        }
+        *woken = false;
+    }
+
+    /// The output had to play silence for want of mixed audio
+    pub fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn wake_before_wait_is_not_lost() {
+        let demand = Demand::default();
+        demand.wake();
+
+        let start = Instant::now();
+        demand.wait(Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn wait_returns_when_woken_from_another_thread() {
+        let demand = Arc::new(Demand::default());
+        let waker = demand.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            waker.wake();
+        });
+
+        let start = Instant::now();
+        demand.wait(Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn wait_gives_up_after_the_timeout() {
+        let demand = Demand::default();
+
+        let start = Instant::now();
+        demand.wait(Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
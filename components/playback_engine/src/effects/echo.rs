@@ -0,0 +1,135 @@
+use super::{Effect, CHANNELS, DEFAULT_BPM, SAMPLE_RATE};
+use playback_primitives::EffectParams;
+
+// Four beats at 60 BPM, the longest echo asked for at sane tempos
+const MAX_DELAY_SECONDS: f32 = 4.0;
+
+/// Tempo synced delay, the repeats land on a division of the beat
+pub struct Echo {
+    beats: f32,
+    feedback: f32,
+    bpm: f32,
+    // Interleaved ring of past input plus fed back repeats
+    line: Vec<f32>,
+    delay: usize,
+    write: usize,
+}
+
+impl Echo {
+    pub fn new() -> Self {
+        let frames = (SAMPLE_RATE * MAX_DELAY_SECONDS) as usize;
+        let mut echo = Self {
+            beats: 0.5,
+            feedback: 0.4,
+            bpm: DEFAULT_BPM,
+            line: vec![0.0; frames * CHANNELS],
+            delay: 0,
+            write: 0,
+        };
+        echo.update_delay();
+        echo
+    }
+
+    fn update_delay(&mut self) {
+        let seconds = self.beats * 60.0 / self.bpm;
+        let frames = (seconds * SAMPLE_RATE).round() as usize;
+        let max_frames = self.line.len() / CHANNELS;
+        self.delay = frames.clamp(1, max_frames) * CHANNELS;
+    }
+}
+
+impl Default for Echo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Effect for Echo {
+    fn process(&mut self, buffer: &mut [f32]) {
+        let length = self.line.len();
+        for sample in buffer.iter_mut() {
+            let read = (self.write + length - self.delay) % length;
+            let delayed = self.line[read];
+            self.line[self.write] = *sample + delayed * self.feedback;
+            self.write = (self.write + 1) % length;
+            *sample = delayed;
+        }
+    }
+
+    fn configure(&mut self, params: &EffectParams) {
+        if let EffectParams::Echo { beats, feedback } = *params {
+            self.beats = beats;
+            self.feedback = feedback.clamp(0.0, 0.95);
+            self.update_delay();
+        }
+    }
+
+    fn set_bpm(&mut self, bpm: f32) {
+        self.bpm = bpm;
+        self.update_delay();
+    }
+
+    fn reset(&mut self) {
+        self.line.fill(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_signals::*;
+    use super::*;
+
+    fn echo(beats: f32, feedback: f32, bpm: f32) -> Echo {
+        let mut echo = Echo::new();
+        echo.set_bpm(bpm);
+        echo.configure(&EffectParams::Echo { beats, feedback });
+        echo
+    }
+
+    // Frames where the left channel is audible
+    fn repeats(buffer: &[f32]) -> Vec<(usize, f32)> {
+        buffer
+            .chunks_exact(CHANNELS)
+            .enumerate()
+            .filter(|(_, frame)| frame[0].abs() > 0.001)
+            .map(|(index, frame)| (index, frame[0]))
+            .collect()
+    }
+
+    #[test]
+    fn delay_follows_the_beat() {
+        // Half a beat at 120 BPM is a quarter second
+        assert_eq!(echo(0.5, 0.0, 120.0).delay / CHANNELS, 12000);
+        // A whole beat at 128 BPM
+        assert_eq!(echo(1.0, 0.0, 128.0).delay / CHANNELS, 22500);
+
+        let mut echo = echo(0.25, 0.0, 120.0);
+        echo.set_bpm(60.0);
+        assert_eq!(echo.delay / CHANNELS, 12000);
+    }
+
+    #[test]
+    fn impulse_repeats_with_feedback() {
+        let mut echo = echo(0.5, 0.5, 120.0);
+        let mut buffer = impulse(1.0);
+        render(&mut echo, &mut buffer);
+
+        assert_eq!(
+            repeats(&buffer),
+            [(12000, 1.0), (24000, 0.5), (36000, 0.25)]
+        );
+    }
+
+    #[test]
+    fn reset_clears_the_tail() {
+        let mut echo = echo(0.5, 0.5, 120.0);
+        let mut buffer = impulse(0.1);
+        render(&mut echo, &mut buffer);
+
+        echo.reset();
+        let mut silence = vec![0.0; 48000 * CHANNELS];
+        render(&mut echo, &mut silence);
+
+        assert!(repeats(&silence).is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use super::{Effect, CHANNELS, SAMPLE_RATE};
+use playback_primitives::EffectParams;
+
+// Range the cutoff sweeps over
+const MIN_CUTOFF: f32 = 20.0;
+const MAX_CUTOFF: f32 = 20000.0;
+
+// Amounts this close to the middle leave the filter open
+const DEAD_ZONE: f32 = 0.01;
+
+// Butterworth, no resonance peak
+const Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct History {
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+/// One knob DJ filter: low pass below the middle, high pass above
+pub struct SweepFilter {
+    amount: f32,
+    // Biquad coefficients, normalised by a0
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    history: [History; CHANNELS],
+}
+
+impl SweepFilter {
+    pub fn new() -> Self {
+        let mut filter = Self {
+            amount: 0.0,
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            history: [History::default(); CHANNELS],
+        };
+        filter.set_amount(0.0);
+        filter
+    }
+
+    fn set_amount(&mut self, amount: f32) {
+        self.amount = amount.clamp(-1.0, 1.0);
+        if self.amount.abs() < DEAD_ZONE {
+            return;
+        }
+
+        // Sweep exponentially, so the knob feels even across octaves
+        let range = MAX_CUTOFF / MIN_CUTOFF;
+        let (cutoff, high_pass) = if self.amount < 0.0 {
+            (MAX_CUTOFF / range.powf(-self.amount), false)
+        } else {
+            (MIN_CUTOFF * range.powf(self.amount), true)
+        };
+
+        // Coefficients from the RBJ audio EQ cookbook
+        let omega = 2.0 * std::f32::consts::PI * cutoff.min(SAMPLE_RATE * 0.45) / SAMPLE_RATE;
+        let alpha = omega.sin() / (2.0 * Q);
+        let cos = omega.cos();
+        let a0 = 1.0 + alpha;
+        let (b0, b1) = if high_pass {
+            ((1.0 + cos) / 2.0, -(1.0 + cos))
+        } else {
+            ((1.0 - cos) / 2.0, 1.0 - cos)
+        };
+
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b0 / a0;
+        self.a1 = -2.0 * cos / a0;
+        self.a2 = (1.0 - alpha) / a0;
+    }
+}
+
+impl Default for SweepFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Effect for SweepFilter {
+    fn process(&mut self, buffer: &mut [f32]) {
+        if self.amount.abs() < DEAD_ZONE {
+            return;
+        }
+
+        for frame in buffer.chunks_exact_mut(CHANNELS) {
+            for (sample, history) in frame.iter_mut().zip(self.history.iter_mut()) {
+                let x = *sample;
+                let y = self.b0 * x + self.b1 * history.x1 + self.b2 * history.x2
+                    - self.a1 * history.y1
+                    - self.a2 * history.y2;
+                *history = History {
+                    x1: x,
+                    x2: history.x1,
+                    y1: y,
+                    y2: history.y1,
+                };
+                *sample = y;
+            }
+        }
+    }
+
+    fn configure(&mut self, params: &EffectParams) {
+        if let EffectParams::Filter { amount } = *params {
+            self.set_amount(amount);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.history = [History::default(); CHANNELS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_signals::*;
+    use super::*;
+
+    fn filtered_rms(amount: f32, frequency: f32) -> f32 {
+        let mut filter = SweepFilter::new();
+        filter.configure(&EffectParams::Filter { amount });
+        let mut buffer = sine(frequency, 0.5);
+        render(&mut filter, &mut buffer);
+        // Skip the settling at the start
+        rms(&buffer[buffer.len() / 2..])
+    }
+
+    #[test]
+    fn centre_is_transparent() {
+        let input = sine(1000.0, 0.1);
+        let mut buffer = input.clone();
+        let mut filter = SweepFilter::new();
+        filter.configure(&EffectParams::Filter { amount: 0.0 });
+        render(&mut filter, &mut buffer);
+
+        assert_eq!(buffer, input);
+    }
+
+    #[test]
+    fn low_pass_keeps_bass_and_cuts_highs() {
+        let unfiltered = rms(&sine(100.0, 0.1));
+
+        let bass = filtered_rms(-0.6, 100.0);
+        let highs = filtered_rms(-0.6, 8000.0);
+
+        assert!((bass - unfiltered).abs() < 0.05, "bass rms {}", bass);
+        assert!(highs < 0.02, "highs rms {}", highs);
+    }
+
+    #[test]
+    fn high_pass_keeps_highs_and_cuts_bass() {
+        let unfiltered = rms(&sine(8000.0, 0.1));
+
+        let bass = filtered_rms(0.5, 60.0);
+        let highs = filtered_rms(0.5, 8000.0);
+
+        assert!(bass < 0.02, "bass rms {}", bass);
+        assert!((highs - unfiltered).abs() < 0.05, "highs rms {}", highs);
+    }
+
+    #[test]
+    fn sweeping_further_cuts_more() {
+        let gentle = filtered_rms(-0.3, 2000.0);
+        let hard = filtered_rms(-0.8, 2000.0);
+
+        assert!(hard < gentle, "{} should be below {}", hard, gentle);
+    }
+}
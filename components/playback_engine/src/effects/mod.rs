@@ -0,0 +1,212 @@
+mod echo;
+mod filter;
+mod reverb;
+
+pub use echo::Echo;
+pub use filter::SweepFilter;
+use playback_primitives::{EffectKind, EffectParams, EffectSettings};
+pub use reverb::Reverb;
+
+// Effects run inside the mixer, at its format
+const SAMPLE_RATE: f32 = 48000.0;
+const CHANNELS: usize = 2;
+
+/// Tempo for beat-timed effects until a deck tells us its own
+pub const DEFAULT_BPM: f32 = 120.0;
+
+/// An audio effect on interleaved stereo
+///
+/// Effects always render fully wet; on/off and the wet/dry balance are
+/// handled by the chain holding them.
+pub trait Effect: Send {
+    fn process(&mut self, buffer: &mut [f32]);
+
+    /// Take new parameters, ignoring ones meant for another kind of effect
+    fn configure(&mut self, params: &EffectParams);
+
+    /// Tempo in beats per minute, for effects timed in beats
+    fn set_bpm(&mut self, _bpm: f32) {}
+
+    /// Forget tails and filter history
+    fn reset(&mut self);
+}
+
+/// Create the effect `params` describe
+pub fn build(params: &EffectParams, bpm: f32) -> Box<dyn Effect> {
+    let mut effect: Box<dyn Effect> = match params.kind() {
+        EffectKind::Filter => Box::new(SweepFilter::new()),
+        EffectKind::Echo => Box::new(Echo::new()),
+        EffectKind::Reverb => Box::new(Reverb::new()),
+    };
+    effect.set_bpm(bpm);
+    effect.configure(params);
+    effect
+}
+
+struct Slot {
+    kind: EffectKind,
+    enabled: bool,
+    wet: f32,
+    effect: Box<dyn Effect>,
+}
+
+/// Effects applied in the order they were first set
+pub struct EffectChain {
+    slots: Vec<Slot>,
+    bpm: f32,
+    dry: Vec<f32>,
+}
+
+impl Default for EffectChain {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            bpm: DEFAULT_BPM,
+            dry: Vec::new(),
+        }
+    }
+}
+
+impl EffectChain {
+    /// Update an effect in the chain, adding it at the end if it is new
+    pub fn apply(&mut self, settings: EffectSettings) {
+        let kind = settings.params.kind();
+        match self.slots.iter_mut().find(|slot| slot.kind == kind) {
+            Some(slot) => {
+                if settings.enabled && !slot.enabled {
+                    // Don't bring back the tail from when it was switched off
+                    slot.effect.reset();
+                }
+                slot.enabled = settings.enabled;
+                slot.wet = settings.wet;
+                slot.effect.configure(&settings.params);
+            }
+            None => self.slots.push(Slot {
+                kind,
+                enabled: settings.enabled,
+                wet: settings.wet,
+                effect: build(&settings.params, self.bpm),
+            }),
+        }
+    }
+
+    /// Whether any effect in the chain is switched on
+    pub fn is_active(&self) -> bool {
+        self.slots.iter().any(|slot| slot.enabled)
+    }
+
+    pub fn set_bpm(&mut self, bpm: f32) {
+        self.bpm = bpm;
+        for slot in &mut self.slots {
+            slot.effect.set_bpm(bpm);
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for slot in self.slots.iter_mut().filter(|slot| slot.enabled) {
+            if slot.wet >= 1.0 {
+                slot.effect.process(buffer);
+                continue;
+            }
+
+            self.dry.clear();
+            self.dry.extend_from_slice(buffer);
+            slot.effect.process(buffer);
+            for (sample, dry) in buffer.iter_mut().zip(&self.dry) {
+                *sample = dry * (1.0 - slot.wet) + *sample * slot.wet;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_signals {
+    use super::{CHANNELS, SAMPLE_RATE};
+
+    /// Stereo sine at `frequency`, both channels the same
+    pub fn sine(frequency: f32, seconds: f32) -> Vec<f32> {
+        let frames = (SAMPLE_RATE * seconds) as usize;
+        (0..frames)
+            .flat_map(|frame| {
+                let phase = 2.0 * std::f32::consts::PI * frequency * frame as f32 / SAMPLE_RATE;
+                [phase.sin(); CHANNELS]
+            })
+            .collect()
+    }
+
+    /// A single full scale frame followed by silence
+    pub fn impulse(seconds: f32) -> Vec<f32> {
+        let mut buffer = vec![0.0; (SAMPLE_RATE * seconds) as usize * CHANNELS];
+        buffer[0] = 1.0;
+        buffer[1] = 1.0;
+        buffer
+    }
+
+    pub fn rms(buffer: &[f32]) -> f32 {
+        (buffer.iter().map(|s| s * s).sum::<f32>() / buffer.len() as f32).sqrt()
+    }
+
+    /// Render in mixer sized blocks, like the engine does
+    pub fn render(effect: &mut dyn super::Effect, buffer: &mut [f32]) {
+        for block in buffer.chunks_mut(1920 * CHANNELS) {
+            effect.process(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_signals::*;
+    use super::*;
+
+    fn filter(amount: f32, wet: f32, enabled: bool) -> EffectSettings {
+        EffectSettings {
+            enabled,
+            wet,
+            params: EffectParams::Filter { amount },
+        }
+    }
+
+    #[test]
+    fn disabled_effects_leave_the_signal_alone() {
+        let mut chain = EffectChain::default();
+        chain.apply(filter(-0.9, 1.0, false));
+
+        let input = sine(5000.0, 0.1);
+        let mut output = input.clone();
+        chain.process(&mut output);
+
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn wet_balances_dry_and_effect() {
+        let input = sine(5000.0, 0.2);
+        let render = |wet: f32| {
+            let mut chain = EffectChain::default();
+            chain.apply(filter(-0.9, wet, true));
+            let mut output = input.clone();
+            chain.process(&mut output);
+            rms(&output[output.len() / 2..])
+        };
+
+        let (dry, half, wet) = (render(0.0), render(0.5), render(1.0));
+        assert!((dry - rms(&input)).abs() < 0.001);
+        assert!(wet < 0.05, "fully filtered rms {}", wet);
+        assert!(half > wet && half < dry, "half wet rms {}", half);
+    }
+
+    #[test]
+    fn settings_update_the_existing_effect() {
+        let mut chain = EffectChain::default();
+        chain.apply(filter(-0.9, 1.0, true));
+        chain.apply(filter(0.0, 1.0, true));
+
+        let input = sine(5000.0, 0.1);
+        let mut output = input.clone();
+        chain.process(&mut output);
+
+        assert_eq!(chain.slots.len(), 1);
+        assert_eq!(output, input);
+    }
+}
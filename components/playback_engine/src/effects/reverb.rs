@@ -0,0 +1,213 @@
+use super::{Effect, CHANNELS, SAMPLE_RATE};
+use playback_primitives::EffectParams;
+
+// Freeverb's tunings, given in samples at 44.1 kHz
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+// Right channel delays are this much longer, for width
+const STEREO_SPREAD: usize = 23;
+const TUNING_RATE: f32 = 44100.0;
+
+// Keeps eight summed combs from clipping
+const INPUT_GAIN: f32 = 0.015;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+fn scaled(length: usize) -> usize {
+    (length as f32 * SAMPLE_RATE / TUNING_RATE).round() as usize
+}
+
+/// Feedback delay with a low pass in the loop
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; length],
+            index: 0,
+            filter_store: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.buffer[self.index] = input + self.filter_store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.filter_store = 0.0;
+    }
+}
+
+/// Diffuses the comb output without colouring it
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; length],
+            index: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * ALLPASS_FEEDBACK;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+struct Channel {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Channel {
+    fn new(spread: usize) -> Self {
+        Self {
+            combs: COMB_TUNING
+                .iter()
+                .map(|length| Comb::new(scaled(length + spread)))
+                .collect(),
+            allpasses: ALLPASS_TUNING
+                .iter()
+                .map(|length| Allpass::new(scaled(length + spread)))
+                .collect(),
+        }
+    }
+}
+
+/// Schroeder style reverb after Jezar's Freeverb
+pub struct Reverb {
+    feedback: f32,
+    damping: f32,
+    channels: [Channel; CHANNELS],
+}
+
+impl Reverb {
+    pub fn new() -> Self {
+        let mut reverb = Self {
+            feedback: 0.0,
+            damping: 0.0,
+            channels: [Channel::new(0), Channel::new(STEREO_SPREAD)],
+        };
+        reverb.configure(&EffectParams::Reverb {
+            room_size: 0.5,
+            damping: 0.5,
+        });
+        reverb
+    }
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, buffer: &mut [f32]) {
+        for frame in buffer.chunks_exact_mut(CHANNELS) {
+            // Both sides are fed the mono sum, like Freeverb
+            let input = frame.iter().sum::<f32>() * INPUT_GAIN;
+            for (sample, channel) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                let mut output: f32 = channel
+                    .combs
+                    .iter_mut()
+                    .map(|comb| comb.process(input, self.feedback, self.damping))
+                    .sum();
+                for allpass in channel.allpasses.iter_mut() {
+                    output = allpass.process(output);
+                }
+                *sample = output;
+            }
+        }
+    }
+
+    fn configure(&mut self, params: &EffectParams) {
+        if let EffectParams::Reverb { room_size, damping } = *params {
+            self.feedback = 0.7 + 0.28 * room_size.clamp(0.0, 1.0);
+            self.damping = 0.4 * damping.clamp(0.0, 1.0);
+        }
+    }
+
+    fn reset(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.combs.iter_mut().for_each(Comb::clear);
+            channel.allpasses.iter_mut().for_each(Allpass::clear);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_signals::*;
+    use super::*;
+
+    fn reverb(room_size: f32, damping: f32) -> Reverb {
+        let mut reverb = Reverb::new();
+        reverb.configure(&EffectParams::Reverb { room_size, damping });
+        reverb
+    }
+
+    // RMS of each tenth of a second
+    fn envelope(buffer: &[f32]) -> Vec<f32> {
+        buffer.chunks(4800 * CHANNELS).map(rms).collect()
+    }
+
+    #[test]
+    fn impulse_leaves_a_decaying_tail() {
+        let mut reverb = reverb(0.5, 0.5);
+        let mut buffer = impulse(2.0);
+        render(&mut reverb, &mut buffer);
+
+        let envelope = envelope(&buffer);
+        assert!(envelope[1] > 0.0001, "no tail: {:?}", envelope);
+        assert!(
+            envelope[19] < envelope[1] / 10.0,
+            "no decay: {:?}",
+            envelope
+        );
+        assert!(buffer.iter().all(|sample| sample.abs() < 1.0));
+    }
+
+    #[test]
+    fn bigger_rooms_ring_longer() {
+        let tail = |room_size: f32| {
+            let mut reverb = reverb(room_size, 0.5);
+            let mut buffer = impulse(2.0);
+            render(&mut reverb, &mut buffer);
+            rms(&buffer[48000 * CHANNELS..])
+        };
+
+        assert!(tail(0.9) > tail(0.1) * 2.0);
+    }
+
+    #[test]
+    fn channels_differ_for_width() {
+        let mut reverb = reverb(0.5, 0.5);
+        let mut buffer = impulse(0.5);
+        render(&mut reverb, &mut buffer);
+
+        let differing = buffer
+            .chunks_exact(CHANNELS)
+            .filter(|frame| (frame[0] - frame[1]).abs() > 0.0001)
+            .count();
+        assert!(differing > 1000);
+    }
+}
@@ -27,4 +27,34 @@ pub enum PlaybackError {
 
     #[error("Track is not ready for playback")]
     TrackNotReady,
+
+    #[error("No clip loaded on pad {0}")]
+    NoPadLoaded(playback_primitives::Pad),
+
+    #[error("Clip is longer than {0} seconds")]
+    ClipTooLong(usize),
+
+    #[error("Clip is {0} Hz with {1} channels, pads play 48000 Hz stereo")]
+    ClipFormat(u32, u16),
+
+    #[error("Invalid effect settings: {0}")]
+    InvalidEffect(String),
+
+    #[error("Invalid output config: {0}")]
+    InvalidOutput(String),
+
+    #[error("Nothing queued on deck {0:?}")]
+    QueueEmpty(crate::Deck),
+
+    #[error("Invalid crossfade: {0}s, use 0 to {max}s", max = crate::MAX_CROSSFADE_SECONDS)]
+    InvalidCrossfade(f32),
+
+    #[error("Already recording to {0}")]
+    AlreadyRecording(std::path::PathBuf),
+
+    #[error("Not recording")]
+    NotRecording,
+
+    #[error("Recordings are named, not placed: {0} is not a file name")]
+    RecordingName(std::path::PathBuf),
 }
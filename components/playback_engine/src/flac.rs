@@ -0,0 +1,482 @@
+//! Just enough of a FLAC encoder to record the mix
+//!
+//! Every channel of a frame is coded on its own with whichever of the fixed
+//! predictors leaves the smallest residual, Rice coded in one partition.
+//! That gets most of the way to `flac -5` on music without any of the LPC
+//! search, cheap enough to keep up with the mixer on one core.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Frames per FLAC block, the reference encoder's default
+pub const BLOCK_FRAMES: usize = 4096;
+
+// Longest Rice parameter the 4 bit coding method can carry, 15 is the escape
+const MAX_RICE_PARAMETER: u32 = 14;
+const MAX_FIXED_ORDER: usize = 4;
+// Offset of STREAMINFO's body, after "fLaC" and the block header
+const STREAMINFO_OFFSET: u64 = 8;
+
+/// Writes interleaved float audio as a FLAC stream
+///
+/// STREAMINFO goes out with the length unknown and is filled in by
+/// `finish`, so a stream that was never finished still plays.
+pub struct FlacWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    channels: usize,
+    bits: u32,
+    // Interleaved samples waiting for a whole block
+    pending: Vec<i32>,
+    blocks_written: u64,
+    // Samples of each channel
+    frames_written: u64,
+    min_frame_bytes: u32,
+    max_frame_bytes: u32,
+    // Reused for every frame
+    frame: BitWriter,
+    channel: Vec<i64>,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    /// Start a stream, `comments` become its Vorbis comments as `KEY=value`
+    pub fn new(
+        out: W,
+        sample_rate: u32,
+        channels: usize,
+        bits: u32,
+        comments: &[(&str, String)],
+    ) -> io::Result<Self> {
+        if !(1..=8).contains(&channels) || !matches!(bits, 16 | 24) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't encode {channels} channels of {bits} bits"),
+            ));
+        }
+
+        let mut writer = Self {
+            out,
+            sample_rate,
+            channels,
+            bits,
+            pending: Vec::with_capacity(BLOCK_FRAMES * channels),
+            blocks_written: 0,
+            frames_written: 0,
+            min_frame_bytes: 0,
+            max_frame_bytes: 0,
+            frame: BitWriter::default(),
+            channel: Vec::with_capacity(BLOCK_FRAMES),
+        };
+
+        let streaminfo = writer.streaminfo();
+        let comments = vorbis_comments(comments);
+        let length = comments.len() as u32;
+        let out = &mut writer.out;
+        out.write_all(b"fLaC")?;
+        out.write_all(&[0x00, 0x00, 0x00, 34])?;
+        out.write_all(&streaminfo)?;
+        // The last metadata block
+        out.write_all(&[
+            0x84,
+            (length >> 16) as u8,
+            (length >> 8) as u8,
+            length as u8,
+        ])?;
+        out.write_all(&comments)?;
+
+        Ok(writer)
+    }
+
+    /// Encode samples in [-1, 1], whole blocks go out as they fill
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        // Decoders divide by a power of two, full scale positive is a step short
+        let scale = (1i64 << (self.bits - 1)) as f32;
+        let largest = (1i64 << (self.bits - 1)) - 1;
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * scale).round() as i64;
+            self.pending.push(sample.min(largest) as i32);
+            if self.pending.len() == BLOCK_FRAMES * self.channels {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the last partial block and fill in STREAMINFO
+    pub fn finish(mut self) -> io::Result<W> {
+        // Half a frame can't be coded, the mixer never sends one anyway
+        self.pending
+            .truncate(self.pending.len() / self.channels * self.channels);
+        if !self.pending.is_empty() {
+            self.write_block()?;
+        }
+
+        let streaminfo = self.streaminfo();
+        self.out.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.out.write_all(&streaminfo)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn streaminfo(&self) -> [u8; 34] {
+        let mut info = BitWriter::default();
+        info.write(BLOCK_FRAMES as u64, 16);
+        info.write(BLOCK_FRAMES as u64, 16);
+        info.write(self.min_frame_bytes as u64, 24);
+        info.write(self.max_frame_bytes as u64, 24);
+        info.write(self.sample_rate as u64, 20);
+        info.write(self.channels as u64 - 1, 3);
+        info.write(self.bits as u64 - 1, 5);
+        info.write(self.frames_written >> 32, 4);
+        info.write(self.frames_written, 32);
+        // No MD5, all zeros means it wasn't worked out
+        for _ in 0..4 {
+            info.write(0, 32);
+        }
+        info.bytes.try_into().expect("STREAMINFO is 34 bytes")
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let frames = self.pending.len() / self.channels;
+        let frame = &mut self.frame;
+        frame.clear();
+
+        // Sync code, fixed block size
+        frame.write(0xfff8, 16);
+        frame.write(0b0111, 4);
+        frame.write(sample_rate_code(self.sample_rate), 4);
+        frame.write(self.channels as u64 - 1, 4);
+        frame.write(if self.bits == 16 { 0b100 } else { 0b110 }, 3);
+        frame.write(0, 1);
+        frame.write_utf8(self.blocks_written);
+        frame.write(frames as u64 - 1, 16);
+        frame.write(crc8(&frame.bytes) as u64, 8);
+
+        for channel in 0..self.channels {
+            self.channel.clear();
+            self.channel.extend(
+                self.pending[channel..]
+                    .iter()
+                    .step_by(self.channels)
+                    .map(|sample| *sample as i64),
+            );
+            write_subframe(frame, &self.channel, self.bits);
+        }
+
+        frame.align();
+        let crc = crc16(&frame.bytes);
+        frame.write(crc as u64, 16);
+        self.out.write_all(&frame.bytes)?;
+
+        let size = frame.bytes.len() as u32;
+        self.min_frame_bytes = match self.blocks_written {
+            0 => size,
+            _ => self.min_frame_bytes.min(size),
+        };
+        self.max_frame_bytes = self.max_frame_bytes.max(size);
+        self.blocks_written += 1;
+        self.frames_written += frames as u64;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+// The rates a frame header can name itself, anything else is in STREAMINFO
+fn sample_rate_code(rate: u32) -> u64 {
+    match rate {
+        44100 => 0b1001,
+        48000 => 0b1010,
+        96000 => 0b1011,
+        _ => 0b0000,
+    }
+}
+
+fn vorbis_comments(comments: &[(&str, String)]) -> Vec<u8> {
+    const VENDOR: &str = "mdma playback-engine";
+
+    let mut block = Vec::new();
+    block.extend((VENDOR.len() as u32).to_le_bytes());
+    block.extend(VENDOR.as_bytes());
+    block.extend((comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let comment = format!("{key}={value}");
+        block.extend((comment.len() as u32).to_le_bytes());
+        block.extend(comment.as_bytes());
+    }
+    block
+}
+
+fn write_subframe(frame: &mut BitWriter, samples: &[i64], bits: u32) {
+    if samples.iter().all(|sample| *sample == samples[0]) {
+        frame.write(0b0000000, 8);
+        frame.write_signed(samples[0], bits);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bits as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let parameter = rice_parameter(&residual);
+            let cost = order as u64 * bits as u64 + rice_bits(&residual, parameter);
+            (cost, order, parameter, residual)
+        })
+        .min_by_key(|(cost, ..)| *cost);
+
+    match best {
+        Some((cost, order, parameter, residual)) if cost < verbatim_bits => {
+            frame.write(0b0001000 | order as u64, 7);
+            frame.write(0, 1);
+            for sample in &samples[..order] {
+                frame.write_signed(*sample, bits);
+            }
+            // Rice with 4 bit parameters, one partition
+            frame.write(0b00, 2);
+            frame.write(0, 4);
+            frame.write(parameter as u64, 4);
+            for value in residual {
+                frame.write_rice(zigzag(value), parameter);
+            }
+        }
+        _ => {
+            frame.write(0b0000010, 8);
+            for sample in samples {
+                frame.write_signed(*sample, bits);
+            }
+        }
+    }
+}
+
+// What's left once the fixed polynomial predictor of `order` has had its go
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let s = samples;
+    (order..s.len())
+        .map(|n| match order {
+            0 => s[n],
+            1 => s[n] - s[n - 1],
+            2 => s[n] - 2 * s[n - 1] + s[n - 2],
+            3 => s[n] - 3 * s[n - 1] + 3 * s[n - 2] - s[n - 3],
+            _ => s[n] - 4 * s[n - 1] + 6 * s[n - 2] - 4 * s[n - 3] + s[n - 4],
+        })
+        .collect()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Near the log of the mean is near optimal, try either side of it
+fn rice_parameter(residual: &[i64]) -> u32 {
+    let sum: u64 = residual.iter().map(|value| zigzag(*value)).sum();
+    let mean = sum / residual.len().max(1) as u64;
+    let guess = (64 - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+    (guess.saturating_sub(1)..=(guess + 1).min(MAX_RICE_PARAMETER))
+        .min_by_key(|parameter| rice_bits(residual, *parameter))
+        .unwrap_or(0)
+}
+
+fn rice_bits(residual: &[i64], parameter: u32) -> u64 {
+    residual
+        .iter()
+        .map(|value| (zigzag(*value) >> parameter) + 1 + parameter as u64)
+        .sum()
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits not yet making up a whole byte, in the low end
+    partial: u64,
+    partial_bits: u32,
+}
+
+impl BitWriter {
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.partial = 0;
+        self.partial_bits = 0;
+    }
+
+    /// The low `bits` of `value`, most significant first, at most 32
+    fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        let mask = (1u64 << bits) - 1;
+        self.partial = (self.partial << bits) | (value & mask);
+        self.partial_bits += bits;
+        while self.partial_bits >= 8 {
+            self.partial_bits -= 8;
+            self.bytes.push((self.partial >> self.partial_bits) as u8);
+        }
+        self.partial &= (1u64 << self.partial_bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_rice(&mut self, value: u64, parameter: u32) {
+        let mut quotient = value >> parameter;
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, quotient as u32 + 1);
+        self.write(value, parameter);
+    }
+
+    /// Frame numbers are coded like UTF-8, stretched to 36 bits
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        let continuation = match value {
+            0..=0x7ff => 1,
+            0x800..=0xffff => 2,
+            0x1_0000..=0x1f_ffff => 3,
+            0x20_0000..=0x3ff_ffff => 4,
+            0x400_0000..=0x7fff_ffff => 5,
+            _ => 6,
+        };
+        // As many leading ones as bytes, then what's left of the value
+        let lead = (0xffu64 << (7 - continuation)) & 0xff;
+        self.write(lead | (value >> (6 * continuation)), 8);
+        for index in (0..continuation).rev() {
+            self.write(0x80 | ((value >> (6 * index)) & 0x3f), 8);
+        }
+    }
+
+    fn align(&mut self) {
+        if self.partial_bits > 0 {
+            self.write(0, 8 - self.partial_bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    fn decode(flac: Vec<u8>) -> (Option<u64>, Vec<f32>) {
+        let stream = MediaSourceStream::new(Box::new(Cursor::new(flac)), Default::default());
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &Hint::new(),
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+        let params = format.default_track().unwrap().codec_params.clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .unwrap();
+
+        let mut samples = Vec::new();
+        while let Ok(packet) = format.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+        (params.n_frames, samples)
+    }
+
+    // Stereo with a bit of everything: silence, a ramp, a tone and noise
+    fn test_signal(frames: usize) -> Vec<f32> {
+        let mut noise = 0x1234_5678u32;
+        (0..frames)
+            .flat_map(|frame| {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                let left = match frame / BLOCK_FRAMES {
+                    0 => 0.0,
+                    1 => frame as f32 / frames as f32,
+                    _ => (frame as f32 * 0.05).sin() * 0.8,
+                };
+                let right = (noise as f32 / u32::MAX as f32) * 2.0 - 1.0;
+                [left, right]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streams_decode_to_what_was_written() {
+        for bits in [16, 24] {
+            let signal = test_signal(BLOCK_FRAMES * 3 + 100);
+            let mut writer = FlacWriter::new(Cursor::new(Vec::new()), 48000, 2, bits, &[]).unwrap();
+            // In odd sized pieces, like the mixer's blocks
+            for piece in signal.chunks(1922) {
+                writer.write(piece).unwrap();
+            }
+            let flac = writer.finish().unwrap().into_inner();
+
+            let (frames, decoded) = decode(flac);
+            assert_eq!(frames, Some(signal.len() as u64 / 2));
+            assert_eq!(decoded.len(), signal.len());
+            let step = 1.0 / (1u32 << (bits - 1)) as f32;
+            for (written, read) in signal.iter().zip(&decoded) {
+                assert!((written - read).abs() <= step, "{written} read as {read}");
+            }
+        }
+    }
+
+    #[test]
+    fn predictable_audio_is_compressed() {
+        let tone: Vec<f32> = (0..BLOCK_FRAMES * 2)
+            .map(|i| ((i / 2) as f32 * 0.01).sin() * 0.5)
+            .collect();
+        let mut writer = FlacWriter::new(Cursor::new(Vec::new()), 48000, 2, 24, &[]).unwrap();
+        writer.write(&tone).unwrap();
+        let flac = writer.finish().unwrap().into_inner();
+
+        assert!(flac.len() < tone.len() * 3 / 2, "{} bytes", flac.len());
+    }
+
+    #[test]
+    fn frame_numbers_are_coded_like_utf8() {
+        for value in [0x41u32, 0x80, 0x7ff, 0x800, 0xffff, 0x1_0000, 0x10_ffff] {
+            let mut bits = BitWriter::default();
+            bits.write_utf8(value as u64);
+            let expected = char::from_u32(value).unwrap().to_string();
+            assert_eq!(bits.bytes, expected.as_bytes(), "{value:#x}");
+        }
+    }
+}
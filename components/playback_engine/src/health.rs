@@ -0,0 +1,131 @@
+use crate::demand::Demand;
+use crate::state::{DeckStats, Stats};
+use parking_lot::Mutex;
+use playback_primitives::Deck;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Default)]
+struct Counters {
+    buffer_underruns: AtomicU64,
+    decode_errors: AtomicU64,
+    late_seeks: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> DeckStats {
+        DeckStats {
+            buffer_underruns: self.buffer_underruns.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            late_seeks: self.late_seeks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Where a deck's decoder and mixer input report trouble
+///
+/// Everything counts for the deck and for the engine as a whole. The
+/// default isn't attached to an engine, for tracks played on their own.
+#[derive(Clone, Default)]
+pub(crate) struct DeckHealth {
+    deck: Arc<Counters>,
+    total: Arc<Counters>,
+}
+
+impl DeckHealth {
+    /// The deck was playing but its buffer couldn't fill a block
+    pub fn buffer_underrun(&self) {
+        self.count(|counters| &counters.buffer_underruns);
+    }
+
+    pub fn decode_error(&self) {
+        self.count(|counters| &counters.decode_errors);
+    }
+
+    /// The decoder got to a seek too late for it to sound seamless
+    pub fn late_seek(&self) {
+        self.count(|counters| &counters.late_seeks);
+    }
+
+    fn count(&self, counter: impl Fn(&Counters) -> &AtomicU64) {
+        counter(&self.deck).fetch_add(1, Ordering::Relaxed);
+        counter(&self.total).fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Glitch counters for every deck and the output, readable from any thread
+///
+/// Counts run from engine start. A deck keeps its counts when tracks are
+/// swapped, so a struggling deck stands out over a whole set.
+#[derive(Clone)]
+pub struct Health {
+    decks: Arc<Mutex<HashMap<Deck, Arc<Counters>>>>,
+    total: Arc<Counters>,
+    // Counts the output's underruns
+    demand: Arc<Demand>,
+}
+
+impl Health {
+    pub(crate) fn new(demand: Arc<Demand>) -> Self {
+        Self {
+            decks: Arc::default(),
+            total: Arc::default(),
+            demand,
+        }
+    }
+
+    /// Counters for whatever gets loaded on `deck`
+    pub(crate) fn deck(&self, deck: Deck) -> DeckHealth {
+        DeckHealth {
+            deck: self.decks.lock().entry(deck).or_default().clone(),
+            total: self.total.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            decks: self
+                .decks
+                .lock()
+                .iter()
+                .map(|(deck, counters)| (*deck, counters.snapshot()))
+                .collect(),
+            totals: self.total.snapshot(),
+            output_underruns: self.demand.underruns(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deck_counts_add_up_to_the_totals() {
+        let demand = Arc::new(Demand::default());
+        let health = Health::new(demand.clone());
+
+        let a = health.deck(Deck::A);
+        a.buffer_underrun();
+        a.late_seek();
+        // A new track on the same deck carries on counting
+        health.deck(Deck::A).buffer_underrun();
+        health.deck(Deck::B).decode_error();
+        demand.underrun();
+
+        let stats = health.stats();
+        assert_eq!(stats.decks[&Deck::A].buffer_underruns, 2);
+        assert_eq!(stats.decks[&Deck::A].late_seeks, 1);
+        assert_eq!(stats.decks[&Deck::B].decode_errors, 1);
+        assert_eq!(
+            stats.totals,
+            DeckStats {
+                buffer_underruns: 2,
+                decode_errors: 1,
+                late_seeks: 1,
+            }
+        );
+        assert_eq!(stats.output_underruns, 1);
+    }
+}
@@ -1,129 +1,530 @@
+mod cue;
+mod demand;
+mod effects;
 mod error;
+mod flac;
+mod health;
+mod limiter;
+mod meter;
 mod mixer;
+mod null_output;
+mod output_config;
 mod pipewire_output;
+mod queue;
+mod recorder;
+mod sampler;
 mod source;
+mod state;
 mod track;
 
 use std::{
     collections::HashMap,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{mpsc, Arc},
 };
 
+pub use cue::{Cue, CueId, MixPosition};
+use demand::Demand;
+
 pub use error::PlaybackError;
-use mixer::Mixer;
+pub use health::Health;
+pub use meter::Meters;
+use mixer::{Faders, Mixer, MixerInput};
+use music_primitives::Bpm;
+use null_output::NullOutput;
+use output_config::MIX_CHANNELS;
+pub use output_config::{OutputConfig, SampleFormat};
 use parking_lot::RwLock;
 use pipewire_output::PipewireOutput;
-pub use playback_primitives::Deck;
+pub use playback_primitives::{
+    Deck, EffectKind, EffectParams, EffectSettings, EffectTarget, Pad, PadMode,
+};
+pub use queue::MAX_CROSSFADE_SECONDS;
+use queue::{open_flac, OpenSource, Queue};
+pub use recorder::Recording;
+use recorder::Tap;
 use ringbuf::{HeapConsumer, HeapRb};
-pub use source::{FlacSource, Source};
+use sampler::PadClip;
+pub use sampler::MAX_CLIP_SECONDS;
+pub use source::{FlacSource, FnSource, Source};
+pub use state::{
+    DeckState, DeckStats, Level, Levels, QueueState, RecordingState, Stats, TrackState,
+};
+use time_primitives::Tempo;
 use tracing::info;
 pub use track::Track;
 
-type Decks = Arc<RwLock<HashMap<Deck, Arc<RwLock<Track>>>>>;
+type Decks = Arc<RwLock<HashMap<Deck, LoadedTrack>>>;
+
+/// Keeps an audio backend running for as long as the engine lives
+trait AudioOutput: Send {}
+
+impl AudioOutput for PipewireOutput {}
+impl AudioOutput for NullOutput {}
+
+struct LoadedTrack {
+    track: Arc<RwLock<Track>>,
+}
 
 pub struct PlaybackEngine {
     decks: Decks,
-    _audio_output: PipewireOutput,
+    // Volumes and mutes, set here straight away and by cues on the mix thread
+    faders: Faders,
+    // Where each loaded pad's clip came from
+    pads: HashMap<Pad, PathBuf>,
+    // Kept when tracks are loaded or unloaded, a deck's queue outlives them
+    queues: HashMap<Deck, Queue>,
+    open_source: OpenSource,
+    // Interleaved samples each deck's buffer holds, enough for the latency
+    deck_buffer: usize,
+    recording: Option<Recording>,
+    _audio_output: Box<dyn AudioOutput>,
     command_sender: mpsc::Sender<MixerCommand>,
+    // Shared with the output and mix thread, commands wake the mixer too
+    demand: Arc<Demand>,
+    mix_position: MixPosition,
+    next_cue: u64,
+    meters: Meters,
+    health: Health,
     _mix_task: Option<std::thread::JoinHandle<()>>,
 }
 enum MixerCommand {
-    RegisterTrack {
-        deck: Deck,
-        consumer: HeapConsumer<f32>,
-    },
-    SetVolume {
-        deck: Deck,
-        db: f32,
-    },
+    RegisterTrack { deck: Deck, input: MixerInput },
+    UnregisterTrack { deck: Deck },
+    SetVolume { deck: Deck, db: f32 },
+    LoadPad { pad: Pad, clip: PadClip },
+    UnloadPad { pad: Pad },
+    TriggerPad { pad: Pad },
+    ReleasePad { pad: Pad },
+    StopPad { pad: Pad },
+    SetSyncTempo { tempo: Option<Tempo> },
+    SetEffect(EffectTarget, EffectSettings),
+    SetBpm(EffectTarget, f32),
+    Record(Option<Tap>),
+    Cue { frame: u64, id: CueId, cue: Cue },
+    CancelCue(CueId),
 }
 impl PlaybackEngine {
+    /// Engine playing through PipeWire to the default sink
     pub fn new() -> Result<Self, PlaybackError> {
+        Self::with_output_config(OutputConfig::default())
+    }
+
+    /// Engine playing through PipeWire in the format and to the device asked for
+    pub fn with_output_config(config: OutputConfig) -> Result<Self, PlaybackError> {
+        config.validate().map_err(PlaybackError::InvalidOutput)?;
+        Self::with_output(config.clone(), |consumer, demand| {
+            // Need to add conversion from pipewire::Error to PlaybackError
+            info!("spawn pipewire output");
+            match PipewireOutput::new(consumer, config, demand) {
+                Ok(output) => Ok(Box::new(output)),
+                Err(e) => Err(PlaybackError::AudioDevice(format!("PipeWire error: {}", e))),
+            }
+        })
+    }
+
+    /// Engine that mixes in real time but sends the result nowhere
+    ///
+    /// For tests and machines without a sound server.
+    pub fn headless() -> Result<Self, PlaybackError> {
+        Self::headless_with_output_config(OutputConfig::default())
+    }
+
+    /// Headless engine, only the latency of `config` matters without a device
+    pub fn headless_with_output_config(config: OutputConfig) -> Result<Self, PlaybackError> {
+        config.validate().map_err(PlaybackError::InvalidOutput)?;
+        Self::with_output(config, |consumer, demand| {
+            Ok(Box::new(NullOutput::new(consumer, demand)))
+        })
+    }
+
+    fn with_output(
+        config: OutputConfig,
+        create_output: impl FnOnce(
+            HeapConsumer<f32>,
+            Arc<Demand>,
+        ) -> Result<Box<dyn AudioOutput>, PlaybackError>,
+    ) -> Result<Self, PlaybackError> {
         // Create a channel for mixer commands - std::sync::mpsc doesn't take a capacity
         let (command_sender, command_receiver) = std::sync::mpsc::channel();
 
-        // Create ringbuffer for mixer output
+        // Create ringbuffer for mixer output, room for the longest latency and a block
         const MIXER_BUFFER_SIZE: usize = 32768;
         let mixer_rb = HeapRb::<f32>::new(MIXER_BUFFER_SIZE);
         let (mixer_producer, mixer_consumer) = mixer_rb.split();
 
-        // Create PipeWire audio output with consumer
-        // Need to add conversion from pipewire::Error to PlaybackError
-        info!("spawn pipewire output");
-        let audio_output = match PipewireOutput::new(mixer_consumer) {
-            Ok(output) => output,
-            Err(e) => return Err(PlaybackError::AudioDevice(format!("PipeWire error: {}", e))),
-        };
+        // Create the audio output with consumer, it wakes the mixer as it plays
+        let demand = Arc::new(Demand::default());
+        let audio_output = create_output(mixer_consumer, demand.clone())?;
+        let mix_demand = demand.clone();
+        let health = Health::new(demand.clone());
 
         // Start the mix thread with command receiver
+        let meters = Meters::default();
+        let mixer_meters = meters.clone();
+        let latency_frames = config.latency_samples() / MIX_CHANNELS;
+        let deck_buffer = config.deck_buffer_samples();
+        let mix_position = MixPosition::with_latency(latency_frames as u64);
+        let mixer_position = mix_position.clone();
+        let faders = Faders::default();
+        let mixer_faders = faders.clone();
         let mix_task = std::thread::spawn(move || {
-            let mut mixer = Mixer::new(mixer_producer);
-            let mut consumers = HashMap::<Deck, HeapConsumer<f32>>::new();
-            let mut temp_buffer = vec![0.0; 1920 * 2];
+            let mut mixer = Mixer::new(
+                mixer_producer,
+                mixer_meters.clone(),
+                mixer_position,
+                mixer_faders,
+            );
+            let mut inputs = HashMap::<Deck, MixerInput>::new();
+            let latency_samples = config.latency_samples();
+            let block = config.block_samples();
+            let mut temp_buffer = vec![0.0; block];
 
-            tracing::info!("MIX THREAD: Started, will process audio");
+            tracing::info!(
+                "MIX THREAD: Started, keeping {} ms ahead of the output",
+                config.latency_ms
+            );
 
             loop {
-                // Process any pending commands
-                while let Ok(cmd) = command_receiver.try_recv() {
+                // Process any pending commands, until the engine is dropped
+                loop {
+                    let cmd = match command_receiver.try_recv() {
+                        Ok(cmd) => cmd,
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => {
+                            tracing::info!("MIX THREAD: Engine gone, stopping");
+                            return;
+                        }
+                    };
                     match cmd {
-                        MixerCommand::RegisterTrack { deck, consumer } => {
+                        MixerCommand::RegisterTrack { deck, input } => {
                             tracing::info!("MIX THREAD: Registering track for deck {:?}", deck);
-                            consumers.insert(deck, consumer);
+                            inputs.insert(deck, input);
+                        }
+                        MixerCommand::UnregisterTrack { deck } => {
+                            tracing::info!("MIX THREAD: Removing track for deck {:?}", deck);
+                            inputs.remove(&deck);
+                            mixer_meters.remove(deck);
                         }
                         MixerCommand::SetVolume { deck, db } => {
                             mixer.set_volume(deck, db);
                         }
+                        MixerCommand::LoadPad { pad, clip } => {
+                            tracing::info!("MIX THREAD: Loading pad {}", pad);
+                            mixer.sampler().load(pad, clip);
+                        }
+                        MixerCommand::UnloadPad { pad } => mixer.sampler().unload(pad),
+                        MixerCommand::TriggerPad { pad } => mixer.sampler().trigger(pad),
+                        MixerCommand::ReleasePad { pad } => mixer.sampler().release(pad),
+                        MixerCommand::StopPad { pad } => mixer.sampler().stop(pad),
+                        MixerCommand::SetSyncTempo { tempo } => {
+                            mixer.sampler().set_sync_tempo(tempo);
+                        }
+                        MixerCommand::SetEffect(target, settings) => {
+                            mixer.set_effect(target, settings);
+                        }
+                        MixerCommand::SetBpm(target, bpm) => mixer.set_bpm(target, bpm),
+                        MixerCommand::Record(tap) => mixer.set_tap(tap),
+                        MixerCommand::Cue { frame, id, cue } => mixer.cue(frame, id, cue),
+                        MixerCommand::CancelCue(id) => mixer.cancel_cue(id),
                     }
                 }
-                let l = temp_buffer.len();
 
-                // Mix audio
-                if let Err(e) = mixer.mix(&mut temp_buffer, l, &mut consumers) {
-                    tracing::error!("MIX THREAD: Error mixing: {}", e);
+                // Top the output up to the latency, then sleep until it takes some
+                while mixer.buffered() < latency_samples {
+                    if let Err(e) = mixer.mix(&mut temp_buffer, block, &mut inputs) {
+                        tracing::error!("MIX THREAD: Error mixing: {}", e);
+                    }
                 }
-
-                // Sleep briefly
-                std::thread::sleep(std::time::Duration::from_micros(500)); // 0.5ms instead of 5ms
+                mix_demand.wait(config.latency());
             }
         });
 
         // Return the engine
         Ok(Self {
             decks: Arc::new(RwLock::new(HashMap::new())),
+            faders,
+            pads: HashMap::new(),
+            queues: HashMap::new(),
+            open_source: open_flac(),
+            deck_buffer,
+            recording: None,
             _audio_output: audio_output,
             command_sender,
+            demand,
+            mix_position,
+            next_cue: 0,
+            meters,
+            health,
             _mix_task: Some(mix_task),
         })
     }
 
     pub async fn load_track(&mut self, deck: Deck, path: &Path) -> Result<(), PlaybackError> {
         tracing::info!("Starting track load for deck {:?}", deck);
+        self.load_source(deck, path, FlacSource::new(path)?).await
+    }
 
+    /// Load any source, with `path` recorded as where it came from
+    pub async fn load_source<S: Source + Send + Sync + 'static>(
+        &mut self,
+        deck: Deck,
+        path: &Path,
+        source: S,
+    ) -> Result<(), PlaybackError> {
         // Create ringbuffer for this deck
-        const BUFFER_SIZE: usize = 16384;
-        let rb = HeapRb::<f32>::new(BUFFER_SIZE);
+        let rb = HeapRb::<f32>::new(self.deck_buffer);
         let (producer, consumer) = rb.split();
 
         // Create new track with producer
-        let track = Track::new(FlacSource::new(path)?, producer).await?;
+        let health = self.health.deck(deck);
+        let queue = self.queue(deck);
+        let track = Track::start(
+            path.to_path_buf(),
+            Box::new(source),
+            producer,
+            health.clone(),
+            queue,
+        )
+        .await?;
         tracing::info!("Track is ready for playback");
+        let playhead = track.playhead();
 
         // Store the track - no lock conflicts possible with mix thread now
         let mut decks = self.decks.write();
-        decks.insert(deck, Arc::new(RwLock::new(track)));
+        decks.insert(
+            deck,
+            LoadedTrack {
+                track: Arc::new(RwLock::new(track)),
+            },
+        );
         drop(decks);
 
         // Send consumer to mix thread via command - using standard send, not try_send
-        self.command_sender
-            .send(MixerCommand::RegisterTrack { deck, consumer })
-            .map_err(|_| PlaybackError::TaskCancelled)?;
+        let input = MixerInput {
+            consumer,
+            playhead,
+            played: 0,
+            health,
+        };
+        self.send_to_mixer(MixerCommand::RegisterTrack { deck, input })?;
 
         tracing::info!("Loaded track from {:?} into deck {:?}", path, deck);
         Ok(())
     }
 
+    /// Put a file on the end of a deck's queue
+    ///
+    /// An empty deck loads it straight away instead. The file is opened
+    /// here too, so a bad path fails now rather than when its turn comes.
+    pub async fn enqueue(&mut self, deck: Deck, path: &Path) -> Result<(), PlaybackError> {
+        let source = (self.open_source)(path)?;
+        if self.find_track(deck).is_none() {
+            return self.load_source(deck, path, source).await;
+        }
+
+        tracing::info!("Queued {:?} on deck {:?}", path, deck);
+        self.queue(deck).push(path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn clear_queue(&mut self, deck: Deck) {
+        self.queue(deck).clear();
+    }
+
+    /// Stop the current entry and go on to the next one in the queue
+    pub fn skip(&mut self, deck: Deck) -> Result<(), PlaybackError> {
+        let track = self
+            .find_track(deck)
+            .ok_or(PlaybackError::NoTrackLoaded(deck))?;
+        let track = track.read();
+        if self.queue(deck).is_empty() && !track.playhead().is_waiting() {
+            return Err(PlaybackError::QueueEmpty(deck));
+        }
+        track.skip()
+    }
+
+    /// Overlap between a deck's queue entries, 0 joins them gaplessly
+    pub fn set_crossfade(&mut self, deck: Deck, seconds: f32) -> Result<(), PlaybackError> {
+        if !(0.0..=MAX_CROSSFADE_SECONDS).contains(&seconds) {
+            return Err(PlaybackError::InvalidCrossfade(seconds));
+        }
+        self.queue(deck).set_crossfade_seconds(seconds);
+        Ok(())
+    }
+
+    /// What a deck plays after the current track
+    pub fn queue_state(&self, deck: Deck) -> QueueState {
+        self.queues.get(&deck).map(Queue::state).unwrap_or_default()
+    }
+
+    fn queue(&mut self, deck: Deck) -> Queue {
+        let open_source = &self.open_source;
+        self.queues
+            .entry(deck)
+            .or_insert_with(|| Queue::new(open_source.clone()))
+            .clone()
+    }
+
+    /// Decode a short clip into memory and put it on a pad
+    pub async fn load_pad(
+        &mut self,
+        pad: Pad,
+        path: &Path,
+        mode: PadMode,
+        gain_db: f32,
+    ) -> Result<(), PlaybackError> {
+        self.load_pad_source(pad, path, FlacSource::new(path)?, mode, gain_db)
+            .await
+    }
+
+    /// Put any source on a pad, with `path` recorded as where it came from
+    pub async fn load_pad_source<S: Source + 'static>(
+        &mut self,
+        pad: Pad,
+        path: &Path,
+        source: S,
+        mode: PadMode,
+        gain_db: f32,
+    ) -> Result<(), PlaybackError> {
+        if !(-96.0..=0.0).contains(&gain_db) {
+            return Err(PlaybackError::InvalidVolume(gain_db));
+        }
+
+        let samples = tokio::task::spawn_blocking(move || sampler::load_clip(&source))
+            .await
+            .map_err(|_| PlaybackError::TaskCancelled)??;
+        tracing::info!(
+            "Loaded {} samples from {:?} onto pad {}",
+            samples.len(),
+            path,
+            pad
+        );
+
+        let clip = PadClip {
+            samples,
+            mode,
+            gain_db,
+        };
+        self.send_to_mixer(MixerCommand::LoadPad { pad, clip })?;
+        self.pads.insert(pad, path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn unload_pad(&mut self, pad: Pad) -> Result<(), PlaybackError> {
+        if self.pads.remove(&pad).is_some() {
+            self.send_to_mixer(MixerCommand::UnloadPad { pad })
+        } else {
+            Ok(()) // Like decks, an empty pad is already unloaded
+        }
+    }
+
+    pub fn trigger_pad(&mut self, pad: Pad) -> Result<(), PlaybackError> {
+        self.require_pad(pad)?;
+        self.send_to_mixer(MixerCommand::TriggerPad { pad })
+    }
+
+    /// Let go of a pad, only gated pads stop
+    pub fn release_pad(&mut self, pad: Pad) -> Result<(), PlaybackError> {
+        self.require_pad(pad)?;
+        self.send_to_mixer(MixerCommand::ReleasePad { pad })
+    }
+
+    pub fn stop_pad(&mut self, pad: Pad) -> Result<(), PlaybackError> {
+        self.require_pad(pad)?;
+        self.send_to_mixer(MixerCommand::StopPad { pad })
+    }
+
+    /// Tempo looped pads are quantised to, `None` turns sync off
+    ///
+    /// Beat timed effects on master follow it too.
+    pub fn set_sync_tempo(&mut self, tempo: Option<Tempo>) -> Result<(), PlaybackError> {
+        let bpm = tempo.map_or(effects::DEFAULT_BPM, |tempo| tempo.raw() as f32);
+        self.send_to_mixer(MixerCommand::SetBpm(EffectTarget::Master, bpm))?;
+        self.send_to_mixer(MixerCommand::SetSyncTempo { tempo })
+    }
+
+    /// Add an effect to a deck or master, or change one already there
+    pub fn set_effect(
+        &mut self,
+        target: EffectTarget,
+        settings: EffectSettings,
+    ) -> Result<(), PlaybackError> {
+        settings.validate().map_err(PlaybackError::InvalidEffect)?;
+        tracing::info!("Setting {:?} on {:?}", settings, target);
+        self.send_to_mixer(MixerCommand::SetEffect(target, settings))
+    }
+
+    /// Tempo of the track on a deck, which its beat timed effects follow
+    pub fn set_deck_bpm(&mut self, deck: Deck, bpm: Bpm) -> Result<(), PlaybackError> {
+        self.send_to_mixer(MixerCommand::SetBpm(EffectTarget::Deck(deck), bpm.as_f32()))
+    }
+
+    /// Record the master output, after the limiter, to a new FLAC file
+    ///
+    /// Encoding and writing happen off the mix thread, a slow disk loses
+    /// samples from the recording rather than holding up playback.
+    pub fn start_recording(&mut self, path: &Path) -> Result<(), PlaybackError> {
+        if let Some(recording) = &self.recording {
+            return Err(PlaybackError::AlreadyRecording(recording.state().path));
+        }
+        let (recording, tap) = Recording::start(path)?;
+        self.send_to_mixer(MixerCommand::Record(Some(tap)))?;
+        info!("Recording to {}", path.display());
+        self.recording = Some(recording);
+        Ok(())
+    }
+
+    /// Finish the recording, returning once the file is complete
+    pub fn stop_recording(&mut self) -> Result<RecordingState, PlaybackError> {
+        self.end_recording()?.finish()
+    }
+
+    /// Stop capturing without waiting for the file
+    ///
+    /// The writer may still have seconds to flush, `Recording::finish`
+    /// waits for it and can be left to a thread that may block.
+    pub fn end_recording(&mut self) -> Result<Recording, PlaybackError> {
+        let recording = self.recording.take().ok_or(PlaybackError::NotRecording)?;
+        self.send_to_mixer(MixerCommand::Record(None))?;
+        Ok(recording)
+    }
+
+    pub fn recording(&self) -> Option<RecordingState> {
+        self.recording.as_ref().map(Recording::state)
+    }
+
+    fn require_pad(&self, pad: Pad) -> Result<(), PlaybackError> {
+        if self.pads.contains_key(&pad) {
+            Ok(())
+        } else {
+            Err(PlaybackError::NoPadLoaded(pad))
+        }
+    }
+
+    fn send_to_mixer(&self, command: MixerCommand) -> Result<(), PlaybackError> {
+        self.command_sender
+            .send(command)
+            .map_err(|_| PlaybackError::TaskCancelled)?;
+        // Don't leave it until the output next asks for audio
+        self.demand.wake();
+        Ok(())
+    }
+
+    /// Times the output found less mixed audio than it needed
+    pub fn output_underruns(&self) -> u64 {
+        self.demand.underruns()
+    }
+
+    /// Handle on the glitch counters, for watching them from another thread
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.health.stats()
+    }
+
     pub fn set_volume(&mut self, deck: Deck, db: f32) -> Result<(), PlaybackError> {
         // Validate the volume value first
         if !(-96.0..=0.0).contains(&db) {
@@ -131,12 +532,10 @@ impl PlaybackEngine {
         }
 
         // Send the volume command through the channel - use send instead of try_send
-        match self
-            .command_sender
-            .send(MixerCommand::SetVolume { deck, db })
-        {
+        match self.send_to_mixer(MixerCommand::SetVolume { deck, db }) {
             Ok(_) => {
                 tracing::info!("Setting volume for deck {:?} to {}dB", deck, db);
+                self.faders.set_volume(deck, db);
                 Ok(())
             }
             Err(_) => {
@@ -146,9 +545,76 @@ impl PlaybackEngine {
         }
     }
 
+    /// Have the mixer make `cue` on frame `frame` of the mix
+    ///
+    /// Frames count from when the engine started, see [`Self::mix_position`].
+    /// A cue has to arrive before the mixer renders its frame, about the
+    /// output latency ahead of what is heard, or it is made a block late.
+    pub fn schedule(&mut self, frame: u64, cue: Cue) -> Result<CueId, PlaybackError> {
+        match cue {
+            Cue::SetVolume { db, .. } if !(-96.0..=0.0).contains(&db) => {
+                return Err(PlaybackError::InvalidVolume(db));
+            }
+            Cue::Play { deck } if self.find_track(deck).is_none() => {
+                return Err(PlaybackError::NoTrackLoaded(deck));
+            }
+            _ => {}
+        }
+
+        let id = CueId(self.next_cue);
+        self.next_cue += 1;
+        self.send_to_mixer(MixerCommand::Cue { frame, id, cue })?;
+        Ok(id)
+    }
+
+    /// Take back a cue, one already made is left as it is
+    pub fn cancel(&mut self, id: CueId) -> Result<(), PlaybackError> {
+        self.send_to_mixer(MixerCommand::CancelCue(id))
+    }
+
+    /// Handle on how far the mix has got, for placing cues from another thread
+    pub fn mix_position(&self) -> MixPosition {
+        self.mix_position.clone()
+    }
+
     fn find_track(&self, deck: Deck) -> Option<Arc<RwLock<Track>>> {
         let decks = self.decks.read();
-        decks.get(&deck).cloned()
+        decks.get(&deck).map(|loaded| loaded.track.clone())
+    }
+
+    /// Handle on the meters that stays live, for reading levels off another thread
+    pub fn meters(&self) -> Meters {
+        self.meters.clone()
+    }
+
+    pub fn levels(&self) -> Levels {
+        self.meters.levels()
+    }
+
+    /// Snapshot of what a deck is doing right now
+    pub fn state(&self, deck: Deck) -> DeckState {
+        let decks = self.decks.read();
+        let track = decks.get(&deck).map(|loaded| {
+            let track = loaded.track.read();
+            TrackState {
+                path: track.path(),
+                playing: track.is_playing(),
+                position: track.position(),
+                length: track.length(),
+                sample_rate: track.sample_rate(),
+                channels: track.channels(),
+                advances: track.advances(),
+            }
+        });
+
+        let fader = self.faders.get(deck);
+        DeckState {
+            deck,
+            volume_db: fader.db,
+            muted: fader.muted,
+            track,
+            level: self.meters.deck(deck),
+        }
     }
 
     pub fn play(&mut self, deck: Deck) -> Result<(), PlaybackError> {
@@ -182,7 +648,7 @@ impl PlaybackEngine {
         match decks.remove(&deck) {
             Some(_) => {
                 tracing::info!("Unloaded track from deck {:?}", deck);
-                Ok(())
+                self.send_to_mixer(MixerCommand::UnregisterTrack { deck })
             }
             None => {
                 tracing::info!("No track to unload from deck {:?}", deck);
@@ -204,3 +670,330 @@ impl PlaybackEngine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::TestSource;
+    use std::time::Duration;
+
+    async fn engine_with_track(deck: Deck) -> PlaybackEngine {
+        let mut engine = PlaybackEngine::headless().unwrap();
+        engine
+            .load_source(
+                deck,
+                Path::new("/music/sine.flac"),
+                TestSource::new_with_pattern("sine", 2.0),
+            )
+            .await
+            .unwrap();
+        engine
+    }
+
+    fn position(engine: &PlaybackEngine, deck: Deck) -> usize {
+        engine.state(deck).track.unwrap().position
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_deck_has_no_track() {
+        let engine = PlaybackEngine::headless().unwrap();
+
+        let state = engine.state(Deck::B);
+
+        assert_eq!(state.deck, Deck::B);
+        assert_eq!(state.volume_db, 0.0);
+        assert!(state.track.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn loaded_track_is_described() {
+        let engine = engine_with_track(Deck::A).await;
+
+        let track = engine.state(Deck::A).track.unwrap();
+
+        assert_eq!(track.path, PathBuf::from("/music/sine.flac"));
+        assert!(!track.playing);
+        assert_eq!(track.sample_rate, 48000);
+        assert_eq!(track.channels, 2);
+        // Two seconds of stereo, rounded up to whole segments
+        let samples = 2 * 48000 * 2;
+        let length = track.length.unwrap();
+        assert!((samples..samples + source::SEGMENT_SIZE).contains(&length));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn position_only_advances_while_playing() {
+        let mut engine = engine_with_track(Deck::A).await;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(position(&engine, Deck::A), 0);
+
+        engine.play(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(engine.state(Deck::A).track.unwrap().playing);
+        assert!(position(&engine, Deck::A) > 0);
+
+        engine.stop(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stopped_at = position(&engine, Deck::A);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(position(&engine, Deck::A), stopped_at);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn volume_seek_and_unload_are_reflected() {
+        let mut engine = engine_with_track(Deck::A).await;
+
+        engine.set_volume(Deck::A, -6.0).unwrap();
+        engine.seek(Deck::A, 48000).await.unwrap();
+
+        let state = engine.state(Deck::A);
+        assert_eq!(state.volume_db, -6.0);
+        assert_eq!(state.track.unwrap().position, 48000);
+
+        engine.unload_track(Deck::A).unwrap();
+
+        let state = engine.state(Deck::A);
+        assert!(state.track.is_none());
+        assert_eq!(state.volume_db, -6.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn playing_decks_show_up_on_the_meters() {
+        let mut engine = engine_with_track(Deck::A).await;
+        assert_eq!(engine.state(Deck::A).level, Level::SILENT);
+
+        engine.play(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let level = engine.state(Deck::A).level;
+        assert!(level.peak_db > -20.0, "deck peak {}", level.peak_db);
+        assert!(level.rms_db < level.peak_db);
+        assert!(engine.levels().master.peak_db > -20.0);
+
+        engine.unload_track(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!engine.levels().decks.contains_key(&Deck::A));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn loaded_decks_are_counted_in_the_stats() {
+        let mut engine = engine_with_track(Deck::A).await;
+        assert!(engine.stats().decks.contains_key(&Deck::A));
+        assert!(!engine.stats().decks.contains_key(&Deck::B));
+
+        // Playing past the end of the track isn't an underrun
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        engine.play(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+
+        let stats = engine.stats();
+        assert_eq!(stats.decks[&Deck::A].buffer_underruns, 0);
+        assert_eq!(stats.totals, stats.decks[&Deck::A]);
+        assert_eq!(stats.output_underruns, engine.output_underruns());
+    }
+
+    // Every file opened for the queue is a short sine, bar the missing ones
+    fn short_tracks(engine: &mut PlaybackEngine) {
+        engine.open_source = Arc::new(|path| {
+            if path.starts_with("/missing") {
+                return Err(PlaybackError::TrackNotFound(path.to_path_buf()));
+            }
+            Ok(Box::new(TestSource::new_with_pattern("sine", 0.3)))
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queues_fill_empty_decks_and_move_on_when_tracks_end() {
+        let mut engine = PlaybackEngine::headless().unwrap();
+        short_tracks(&mut engine);
+
+        let one = Path::new("/music/one.flac");
+        let two = Path::new("/music/two.flac");
+        engine.enqueue(Deck::A, one).await.unwrap();
+        engine.enqueue(Deck::A, two).await.unwrap();
+        assert!(engine
+            .enqueue(Deck::A, Path::new("/missing/three.flac"))
+            .await
+            .is_err());
+
+        assert_eq!(engine.state(Deck::A).track.unwrap().path, one);
+        assert_eq!(engine.queue_state(Deck::A).entries, [two]);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        engine.play(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(450)).await;
+
+        let track = engine.state(Deck::A).track.unwrap();
+        assert_eq!(track.path, two);
+        assert_eq!(track.advances, 1);
+        assert!(track.playing);
+        assert!(engine.queue_state(Deck::A).entries.is_empty());
+        assert_eq!(engine.stats().totals.buffer_underruns, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skipping_moves_on_even_when_stopped() {
+        let mut engine = PlaybackEngine::headless().unwrap();
+        short_tracks(&mut engine);
+
+        assert!(matches!(
+            engine.skip(Deck::A),
+            Err(PlaybackError::NoTrackLoaded(Deck::A))
+        ));
+        engine
+            .enqueue(Deck::A, Path::new("/music/one.flac"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            engine.skip(Deck::A),
+            Err(PlaybackError::QueueEmpty(Deck::A))
+        ));
+
+        let two = Path::new("/music/two.flac");
+        engine.enqueue(Deck::A, two).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        engine.skip(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let track = engine.state(Deck::A).track.unwrap();
+        assert_eq!(track.path, two);
+        assert_eq!(track.position, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queues_outlive_their_tracks() {
+        let mut engine = engine_with_track(Deck::B).await;
+        short_tracks(&mut engine);
+
+        engine.set_crossfade(Deck::B, 4.0).unwrap();
+        assert!(matches!(
+            engine.set_crossfade(Deck::B, MAX_CROSSFADE_SECONDS + 1.0),
+            Err(PlaybackError::InvalidCrossfade(_))
+        ));
+        engine
+            .enqueue(Deck::B, Path::new("/music/next.flac"))
+            .await
+            .unwrap();
+        engine.unload_track(Deck::B).unwrap();
+
+        let queue = engine.queue_state(Deck::B);
+        assert_eq!(queue.entries, [Path::new("/music/next.flac")]);
+        assert_eq!(queue.crossfade_seconds, 4.0);
+
+        engine.clear_queue(Deck::B);
+        assert!(engine.queue_state(Deck::B).entries.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recordings_capture_the_master_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("set.flac");
+        let mut engine = engine_with_track(Deck::A).await;
+
+        assert!(matches!(
+            engine.stop_recording(),
+            Err(PlaybackError::NotRecording)
+        ));
+        engine.start_recording(&path).unwrap();
+        assert!(matches!(
+            engine.start_recording(&dir.path().join("other.flac")),
+            Err(PlaybackError::AlreadyRecording(_))
+        ));
+        engine.play(Deck::A).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let state = engine.stop_recording().unwrap();
+        assert!(engine.recording().is_none());
+        assert_eq!(state.path, path);
+        assert!(state.seconds > 0.2, "only {}s recorded", state.seconds);
+        assert_eq!(state.dropped_samples, 0);
+        assert_eq!(engine.stats().output_underruns, 0);
+
+        let recorded = FlacSource::new(&path).unwrap();
+        let samples = (state.seconds * 96000.0).round() as usize;
+        assert_eq!(recorded.length(), Some(samples));
+        let mut loudest = 0.0f32;
+        while let Ok(block) = source::decode_samples(&recorded) {
+            if block.is_empty() {
+                break;
+            }
+            loudest = block
+                .iter()
+                .fold(loudest, |peak, sample| peak.max(sample.abs()));
+        }
+        assert!(loudest > 0.1, "recording is silent");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pads_must_be_loaded_before_triggering() {
+        let mut engine = PlaybackEngine::headless().unwrap();
+        let pad = Pad::new(0).unwrap();
+
+        assert!(matches!(
+            engine.trigger_pad(pad),
+            Err(PlaybackError::NoPadLoaded(_))
+        ));
+
+        engine
+            .load_pad_source(
+                pad,
+                Path::new("/samples/horn.flac"),
+                TestSource::new_with_pattern("sine", 0.5),
+                PadMode::OneShot,
+                -3.0,
+            )
+            .await
+            .unwrap();
+        engine.trigger_pad(pad).unwrap();
+        engine.release_pad(pad).unwrap();
+        engine.stop_pad(pad).unwrap();
+
+        engine.unload_pad(pad).unwrap();
+        assert!(engine.trigger_pad(pad).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn long_clips_are_refused() {
+        let mut engine = PlaybackEngine::headless().unwrap();
+
+        let result = engine
+            .load_pad_source(
+                Pad::new(1).unwrap(),
+                Path::new("/music/whole-track.flac"),
+                TestSource::new_with_pattern("sine", MAX_CLIP_SECONDS as f32 + 1.0),
+                PadMode::Loop,
+                0.0,
+            )
+            .await;
+
+        assert!(matches!(result, Err(PlaybackError::ClipTooLong(_))));
+        assert!(engine.trigger_pad(Pad::new(1).unwrap()).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn effects_are_validated() {
+        let mut engine = engine_with_track(Deck::A).await;
+        let mut settings = EffectSettings {
+            enabled: true,
+            wet: 0.5,
+            params: EffectParams::Echo {
+                beats: 0.75,
+                feedback: 0.3,
+            },
+        };
+
+        engine
+            .set_effect(EffectTarget::Deck(Deck::A), settings)
+            .unwrap();
+        engine
+            .set_deck_bpm(Deck::A, Bpm::from_f32(126.0).unwrap())
+            .unwrap();
+
+        settings.wet = 2.0;
+        assert!(matches!(
+            engine.set_effect(EffectTarget::Master, settings),
+            Err(PlaybackError::InvalidEffect(_))
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+// Mixer format
+const SAMPLE_RATE: f32 = 48000.0;
+const CHANNELS: usize = 2;
+
+/// Frames the limiter looks ahead, about 1.3 ms
+pub(crate) const LOOKAHEAD: usize = 64;
+
+/// Highest sample the limiter lets through, -0.3 dBFS
+pub const CEILING: f32 = 0.966;
+
+// Time for the gain to come most of the way back after a peak
+const RELEASE_SECONDS: f32 = 0.1;
+
+/// Lookahead brickwall limiter for the master bus
+///
+/// The audio is delayed by the lookahead so the gain can come down ahead
+/// of a peak instead of clipping it. The gain needed for each frame is
+/// held as a minimum over the lookahead window and then averaged over the
+/// same window, which ramps into every peak and never undershoots it.
+pub struct Limiter {
+    // Delayed audio, one frame per lookahead slot
+    delay: Vec<[f32; CHANNELS]>,
+    // Gain each frame in the window needs to stay under the ceiling
+    required: Vec<f32>,
+    // Window minimums being averaged, and their sum
+    held: Vec<f32>,
+    held_sum: f32,
+    position: usize,
+    gain: f32,
+    release: f32,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self {
+            delay: vec![[0.0; CHANNELS]; LOOKAHEAD],
+            required: vec![1.0; LOOKAHEAD],
+            held: vec![1.0; LOOKAHEAD],
+            held_sum: LOOKAHEAD as f32,
+            position: 0,
+            gain: 1.0,
+            release: 1.0 - (-1.0 / (RELEASE_SECONDS * SAMPLE_RATE)).exp(),
+        }
+    }
+
+    /// Limit interleaved stereo in place, delayed by the lookahead
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for frame in buffer.chunks_exact_mut(CHANNELS) {
+            let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            self.required[self.position] = if peak > CEILING { CEILING / peak } else { 1.0 };
+
+            let hold = self.required.iter().copied().fold(1.0, f32::min);
+            self.held_sum += hold - self.held[self.position];
+            self.held[self.position] = hold;
+            let target = self.held_sum / LOOKAHEAD as f32;
+
+            // Attack follows the ramp, release eases back up
+            self.gain = if target < self.gain {
+                target
+            } else {
+                self.gain + (target - self.gain) * self.release
+            };
+
+            // The oldest frame leaves the window as this one enters it
+            let oldest = (self.position + 1) % LOOKAHEAD;
+            let delayed = self.delay[oldest];
+            self.delay[self.position].copy_from_slice(frame);
+            for (sample, delayed) in frame.iter_mut().zip(delayed) {
+                // The clamp only catches rounding in the running sum
+                *sample = (delayed * self.gain).clamp(-CEILING, CEILING);
+            }
+
+            self.position = oldest;
+        }
+    }
+
+    /// How much the limiter is pulling down right now, in dB
+    pub fn reduction_db(&self) -> f32 {
+        -20.0 * self.gain.log10()
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|frame| {
+                let phase = 2.0 * std::f32::consts::PI * 440.0 * frame as f32 / SAMPLE_RATE;
+                [amplitude * phase.sin(); CHANNELS]
+            })
+            .collect()
+    }
+
+    fn peak(buffer: &[f32]) -> f32 {
+        buffer.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn quiet_audio_passes_delayed() {
+        let input = sine(0.5, 1000);
+        let mut output = input.clone();
+        Limiter::new().process(&mut output);
+
+        let delay = (LOOKAHEAD - 1) * CHANNELS;
+        assert_eq!(output[..delay], vec![0.0; delay][..]);
+        assert_eq!(output[delay..], input[..input.len() - delay]);
+    }
+
+    #[test]
+    fn two_decks_at_unity_stay_under_the_ceiling() {
+        let mut limiter = Limiter::new();
+        let mut output = sine(2.0, 48000);
+        for block in output.chunks_mut(3840) {
+            limiter.process(block);
+        }
+
+        assert!(peak(&output) <= CEILING);
+        // Brought down to the ceiling, not far below it
+        assert!(peak(&output[48000..]) > CEILING * 0.95);
+        assert!(limiter.reduction_db() > 5.0);
+    }
+
+    #[test]
+    fn gain_ramps_down_before_a_spike() {
+        let mut buffer = vec![0.1; 400 * CHANNELS];
+        buffer[200 * CHANNELS] = 4.0;
+        Limiter::new().process(&mut buffer);
+
+        // The spike comes out LOOKAHEAD - 1 frames late, at the ceiling
+        let spike = (200 + LOOKAHEAD - 1) * CHANNELS;
+        assert!((buffer[spike] - CEILING).abs() < 0.001);
+        // The frames leading up to it fade rather than jump
+        let before: Vec<f32> = (1..8).map(|i| buffer[spike - i * CHANNELS]).collect();
+        assert!(before.windows(2).all(|pair| pair[0] <= pair[1] + 1e-6));
+        assert!(before[0] < 0.1);
+    }
+
+    #[test]
+    fn releases_after_the_peak() {
+        let mut limiter = Limiter::new();
+        let mut loud = sine(2.0, 4800);
+        limiter.process(&mut loud);
+        let mut quiet = sine(0.5, 48000);
+        limiter.process(&mut quiet);
+
+        assert!(limiter.reduction_db() < 0.1);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::state::{Level, Levels};
+use parking_lot::Mutex;
+use playback_primitives::Deck;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Interleaved samples a second in the mixer format
+const SAMPLES_PER_SECOND: f32 = 48000.0 * 2.0;
+
+// Peak hold falls back at this rate, like a PPM
+const PEAK_DECAY_DB_PER_SECOND: f32 = 20.0;
+// Averaging time of the RMS reading
+const RMS_SECONDS: f32 = 0.3;
+
+/// Peak and power of one mixer block
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockStats {
+    peak: f32,
+    sum_squares: f32,
+    samples: usize,
+}
+
+impl BlockStats {
+    pub fn add(&mut self, sample: f32) {
+        self.peak = self.peak.max(sample.abs());
+        self.sum_squares += sample * sample;
+        self.samples += 1;
+    }
+
+    pub fn of(buffer: &[f32]) -> Self {
+        let mut stats = Self::default();
+        buffer.iter().for_each(|sample| stats.add(*sample));
+        stats
+    }
+
+    /// Take in the stats of more of the same block
+    pub fn merge(&mut self, other: BlockStats) {
+        self.peak = self.peak.max(other.peak);
+        self.sum_squares += other.sum_squares;
+        self.samples += other.samples;
+    }
+
+    /// A block of `samples` silent samples
+    pub fn silence(samples: usize) -> Self {
+        Self {
+            samples,
+            ..Self::default()
+        }
+    }
+}
+
+/// Meter ballistics, updated a block at a time
+#[derive(Debug, Clone, Copy, Default)]
+struct Ballistics {
+    peak: f32,
+    mean_square: f32,
+}
+
+impl Ballistics {
+    fn update(&mut self, block: BlockStats) {
+        if block.samples == 0 {
+            return;
+        }
+        let seconds = block.samples as f32 / SAMPLES_PER_SECOND;
+
+        let decay = 10.0f32.powf(-PEAK_DECAY_DB_PER_SECOND * seconds / 20.0);
+        self.peak = block.peak.max(self.peak * decay);
+
+        let block_mean_square = block.sum_squares / block.samples as f32;
+        let weight = 1.0 - (-seconds / RMS_SECONDS).exp();
+        self.mean_square += (block_mean_square - self.mean_square) * weight;
+    }
+
+    fn level(&self) -> Level {
+        Level {
+            peak_db: to_db(self.peak),
+            rms_db: to_db(self.mean_square.sqrt()),
+        }
+    }
+}
+
+fn to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.log10()).max(Level::SILENT.peak_db)
+}
+
+#[derive(Default)]
+struct MeterState {
+    decks: HashMap<Deck, Ballistics>,
+    master: Ballistics,
+    clips: u64,
+    reduction_db: f32,
+}
+
+/// Levels written by the mix thread and read by anyone holding a clone
+#[derive(Clone, Default)]
+pub struct Meters(Arc<Mutex<MeterState>>);
+
+impl Meters {
+    /// Fold in one mixer block: every deck's output, the master, its overs
+    /// and how hard the limiter was working at the end of it
+    pub(crate) fn update(
+        &self,
+        decks: &[(Deck, BlockStats)],
+        master: BlockStats,
+        clips: u64,
+        reduction_db: f32,
+    ) {
+        let mut state = self.0.lock();
+        for (deck, block) in decks {
+            state.decks.entry(*deck).or_default().update(*block);
+        }
+        state.master.update(master);
+        state.clips += clips;
+        state.reduction_db = reduction_db;
+    }
+
+    pub(crate) fn remove(&self, deck: Deck) {
+        self.0.lock().decks.remove(&deck);
+    }
+
+    /// Level of one deck, silent if it has never had a track
+    pub fn deck(&self, deck: Deck) -> Level {
+        self.0
+            .lock()
+            .decks
+            .get(&deck)
+            .map_or(Level::SILENT, Ballistics::level)
+    }
+
+    pub fn levels(&self) -> Levels {
+        let state = self.0.lock();
+        Levels {
+            decks: state
+                .decks
+                .iter()
+                .map(|(deck, ballistics)| (*deck, ballistics.level()))
+                .collect(),
+            master: state.master.level(),
+            clips: state.clips,
+            reduction_db: state.reduction_db,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 40ms blocks, as the mixer produces them
+    const BLOCK: usize = 3840;
+
+    fn block(value: f32) -> BlockStats {
+        BlockStats::of(&[value; BLOCK])
+    }
+
+    #[test]
+    fn full_scale_reads_zero_db() {
+        let meters = Meters::default();
+        for _ in 0..100 {
+            meters.update(&[(Deck::A, block(1.0))], block(0.5), 0, 0.0);
+        }
+
+        let levels = meters.levels();
+        let deck = levels.decks[&Deck::A];
+        assert!(deck.peak_db.abs() < 0.01);
+        assert!(deck.rms_db.abs() < 0.01);
+        assert!((levels.master.peak_db + 6.02).abs() < 0.01);
+    }
+
+    #[test]
+    fn peaks_hold_and_fall_back() {
+        let meters = Meters::default();
+        meters.update(&[(Deck::B, block(1.0))], block(0.0), 0, 0.0);
+
+        // One second of silence later
+        for _ in 0..25 {
+            meters.update(&[(Deck::B, BlockStats::silence(BLOCK))], block(0.0), 0, 0.0);
+        }
+
+        let deck = meters.levels().decks[&Deck::B];
+        assert!((deck.peak_db + PEAK_DECAY_DB_PER_SECOND).abs() < 0.5);
+        assert!(deck.rms_db < deck.peak_db);
+    }
+
+    #[test]
+    fn silence_reads_the_floor_and_clips_add_up() {
+        let meters = Meters::default();
+        meters.update(&[], BlockStats::silence(BLOCK), 3, 0.0);
+        meters.update(&[], BlockStats::silence(BLOCK), 2, 0.0);
+
+        let levels = meters.levels();
+        assert_eq!(levels.master, Level::SILENT);
+        assert_eq!(levels.clips, 5);
+        assert!(levels.decks.is_empty());
+    }
+}
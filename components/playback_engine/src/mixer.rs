@@ -1,18 +1,120 @@
 // in mixer.rs
+use crate::cue::{Cue, CueId, Cues, MixPosition};
+use crate::effects::EffectChain;
 use crate::error::PlaybackError;
-use playback_primitives::Deck;
+use crate::health::DeckHealth;
+use crate::limiter::Limiter;
+use crate::meter::{BlockStats, Meters};
+use crate::output_config::MIX_CHANNELS;
+use crate::recorder::Tap;
+use crate::sampler::Sampler;
+use crate::track::Playhead;
+use parking_lot::Mutex;
+use playback_primitives::{Deck, EffectSettings, EffectTarget};
 use ringbuf::{HeapConsumer, HeapProducer};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use time_primitives::Tempo;
+
+/// Below this an effect tail is inaudible, about -100 dB
+const TAIL_SILENCE: f32 = 1e-5;
+
+/// Echoes come back up to four seconds later, a tail is only over after that much silence
+const TAIL_HOLD: usize = 4 * 48000 * MIX_CHANNELS;
+
+/// Audio from one deck along with the track state the mixer needs
+pub struct MixerInput {
+    pub consumer: HeapConsumer<f32>,
+    // Stopped decks are left in their buffer, everything mixed moves it on
+    pub playhead: Arc<Playhead>,
+    // Samples taken from `consumer` so far
+    pub played: usize,
+    pub health: DeckHealth,
+}
+
+/// Where a deck's fader is, as the mixer last set it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Fader {
+    pub db: f32,
+    pub muted: bool,
+}
+
+impl Default for Fader {
+    fn default() -> Self {
+        Self {
+            db: 0.0,
+            muted: false,
+        }
+    }
+}
+
+/// Every deck's fader, shared so cues made on the mix thread show up in deck state
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Faders(Arc<Mutex<HashMap<Deck, Fader>>>);
+
+impl Faders {
+    pub fn get(&self, deck: Deck) -> Fader {
+        self.0.lock().get(&deck).copied().unwrap_or_default()
+    }
+
+    pub fn set_volume(&self, deck: Deck, db: f32) {
+        self.0.lock().entry(deck).or_default().db = db;
+    }
+
+    pub fn set_mute(&self, deck: Deck, muted: bool) {
+        self.0.lock().entry(deck).or_default().muted = muted;
+    }
+}
 
 pub struct Mixer {
     volumes: HashMap<Deck, f32>,
+    muted: HashSet<Deck>,
+    faders: Faders,
+    sampler: Sampler,
+    deck_effects: HashMap<Deck, EffectChain>,
+    // Decks whose effects may still be sounding after their audio, with
+    // how many silent samples the tail has given since
+    ringing: HashMap<Deck, usize>,
+    master_effects: EffectChain,
+    // One deck's audio while its effects run
+    deck_buffer: Vec<f32>,
+    limiter: Limiter,
+    meters: Meters,
+    // Each deck's output this block, handed to the meters in one go
+    deck_stats: Vec<(Deck, BlockStats)>,
+    // Set while the master is being recorded
+    tap: Option<Tap>,
+    cues: Cues,
+    // Frames mixed so far, shared with whoever places cues
+    frames: u64,
+    position: MixPosition,
     output_producer: HeapProducer<f32>, // Mixer output
 }
 
 impl Mixer {
-    pub fn new(output_producer: HeapProducer<f32>) -> Self {
+    pub(crate) fn new(
+        output_producer: HeapProducer<f32>,
+        meters: Meters,
+        position: MixPosition,
+        faders: Faders,
+    ) -> Self {
         Self {
             volumes: HashMap::new(),
+            muted: HashSet::new(),
+            faders,
+            sampler: Sampler::default(),
+            deck_effects: HashMap::new(),
+            ringing: HashMap::new(),
+            master_effects: EffectChain::default(),
+            deck_buffer: Vec::new(),
+            limiter: Limiter::new(),
+            meters,
+            deck_stats: Vec::new(),
+            tap: None,
+            cues: Cues::default(),
+            frames: position.frames(),
+            position,
             output_producer,
         }
     }
@@ -21,66 +123,442 @@ impl Mixer {
         &mut self,
         output: &mut [f32], // Temporary buffer for mixing
         samples_per_callback: usize,
-        consumers: &mut HashMap<Deck, HeapConsumer<f32>>,
+        inputs: &mut HashMap<Deck, MixerInput>,
     ) -> Result<(), PlaybackError> {
         // Clear output buffer
         output[..samples_per_callback].fill(0.0);
 
-        // Mix each active track
+        // The decks are mixed up to each cue due this block, then it is made
+        self.deck_stats.clear();
+        let end = self.frames + (samples_per_callback / MIX_CHANNELS) as u64;
+        let mut from = 0;
+        while let Some((frame, cue)) = self.cues.next_before(end) {
+            // One that came too late for its frame happens at the start of the block
+            let at = frame.saturating_sub(self.frames) as usize * MIX_CHANNELS;
+            self.mix_decks(&mut output[from..at.max(from)], inputs);
+            self.apply(cue, inputs);
+            from = at.max(from);
+        }
+        self.mix_decks(&mut output[from..samples_per_callback], inputs);
 
-        for (deck, consumer) in consumers.iter_mut() {
-            // Get volume
-            let volume = *self.volumes.get(deck).unwrap_or(&1.0);
+        // Pads go on top of the decks
+        self.sampler.mix_into(&mut output[..samples_per_callback]);
+        self.master_effects
+            .process(&mut output[..samples_per_callback]);
 
-            // Read from consumer and mix with volume
-            let available = consumer.len();
-            let to_mix = std::cmp::min(available, samples_per_callback);
+        // Count what would have clipped, then make sure nothing does
+        let clips = output[..samples_per_callback]
+            .iter()
+            .filter(|sample| sample.abs() > 1.0)
+            .count() as u64;
+        self.limiter.process(&mut output[..samples_per_callback]);
+        let master = BlockStats::of(&output[..samples_per_callback]);
+        self.meters
+            .update(&self.deck_stats, master, clips, self.limiter.reduction_db());
+        if let Some(tap) = &mut self.tap {
+            tap.capture(&output[..samples_per_callback]);
+        }
 
-            if to_mix > 0 {
-                // Mix samples
-                (0..to_mix).for_each(|i| {
-                    if let Some(sample) = consumer.pop() {
-                        output[i] += sample * volume;
-                    }
-                });
-            }
+        // Only called with room for a whole block, see `buffered`
+        let written = self
+            .output_producer
+            .push_slice(&output[..samples_per_callback]);
+        if written < samples_per_callback {
+            tracing::warn!(
+                "Output buffer full, {} samples dropped",
+                samples_per_callback - written
+            );
+        }
+        self.frames = end;
+        self.position.set(end);
+
+        Ok(())
+    }
+
+    // Mix each active track into part of a block
+    fn mix_decks(&mut self, output: &mut [f32], inputs: &mut HashMap<Deck, MixerInput>) {
+        if output.is_empty() {
+            return;
         }
+        let samples_per_callback = output.len();
+        for (deck, input) in inputs.iter_mut() {
+            // Skipped audio goes whether or not the deck is playing
+            let stale = input.playhead.stale(input.played);
+            if stale > 0 {
+                input.played += input.consumer.skip(stale);
+                input.playhead.advance(0, input.played);
+            }
+
+            let playing = input.playhead.playing.load(Ordering::Relaxed);
+            let ringing = self.ringing.contains_key(deck);
+            if !playing && !ringing {
+                let silence = BlockStats::silence(samples_per_callback);
+                add_stats(&mut self.deck_stats, *deck, silence);
+                continue;
+            }
+
+            // Get volume
+            let volume = match self.muted.contains(deck) {
+                true => 0.0,
+                false => *self.volumes.get(deck).unwrap_or(&1.0),
+            };
 
-        // Now write the mixed output to the output producer
-        let mut written = 0;
-        let to_write = samples_per_callback;
+            // Read from consumer, a stopped deck has nothing to give
+            let to_mix = match playing {
+                true => std::cmp::min(input.consumer.len(), samples_per_callback),
+                false => 0,
+            };
+            // Effect tails carry on over whatever the deck couldn't supply
+            let length = if ringing {
+                samples_per_callback
+            } else {
+                to_mix
+            };
+            // Whatever neither could supply is silence
+            let mut stats = BlockStats::silence(samples_per_callback - length);
 
-        let mut write_attempts = 0;
-        while written < to_write {
-            let remaining = to_write - written;
-            let pushed = self.output_producer.push_slice(&output[written..to_write]);
+            if length > 0 {
+                if self.deck_buffer.len() < length {
+                    self.deck_buffer.resize(length, 0.0);
+                }
+                let buffer = &mut self.deck_buffer[..length];
+                input.consumer.pop_slice(&mut buffer[..to_mix]);
+                buffer[to_mix..].fill(0.0);
+                let effects = self.deck_effects.get_mut(deck);
+                let active = effects.as_ref().is_some_and(|effects| effects.is_active());
+                if let Some(effects) = effects {
+                    effects.process(buffer);
+                }
 
-            written += pushed;
+                // Mix samples
+                for (out, sample) in output.iter_mut().zip(buffer.iter()) {
+                    *out += sample * volume;
+                    stats.add(sample * volume);
+                }
 
-            // If we couldn't write everything, yield and retry
-            if pushed < remaining {
-                write_attempts += 1;
-                if write_attempts % 1_000_000 == 0 {
-                    tracing::debug!(
-                        "Output buffer full attempt {}, wrote {}/{} samples, yielding and retrying",
-                        write_attempts,
-                        pushed,
-                        remaining
-                    );
+                // Rings until the tail alone has been silent for long enough
+                let silent = buffer.iter().all(|s| s.abs() < TAIL_SILENCE);
+                let quiet = match self.ringing.get(deck) {
+                    Some(quiet) if to_mix == 0 && silent => quiet + length,
+                    _ => 0,
+                };
+                if active && quiet < TAIL_HOLD {
+                    self.ringing.insert(*deck, quiet);
+                } else {
+                    self.ringing.remove(deck);
                 }
-                std::thread::yield_now(); // Standard library yield, not tokio
             }
+            input.played += to_mix;
+            input.playhead.advance(to_mix, input.played);
+            // Running dry at the end of the queue is no underrun
+            if playing
+                && to_mix < samples_per_callback
+                && !input.playhead.exhausted.load(Ordering::Relaxed)
+            {
+                input.health.buffer_underrun();
+            }
+            add_stats(&mut self.deck_stats, *deck, stats);
         }
-        if written < to_write {
-            tracing::debug!(
-                "Output buffer full, {} samples unwritten",
-                to_write - written
-            );
+    }
+
+    fn apply(&mut self, cue: Cue, inputs: &HashMap<Deck, MixerInput>) {
+        match cue {
+            Cue::SetVolume { deck, db } => self.set_volume(deck, db),
+            Cue::SetMute { deck, muted } => self.set_mute(deck, muted),
+            Cue::Play { deck } => {
+                if let Some(input) = inputs.get(&deck) {
+                    input.playhead.playing.store(true, Ordering::Relaxed);
+                }
+            }
         }
+    }
 
-        Ok(())
+    /// Mixed samples waiting for the output
+    pub(crate) fn buffered(&self) -> usize {
+        self.output_producer.len()
+    }
+
+    /// Start or stop sending the master to a recording
+    pub(crate) fn set_tap(&mut self, tap: Option<Tap>) {
+        self.tap = tap;
+    }
+
+    pub(crate) fn sampler(&mut self) -> &mut Sampler {
+        &mut self.sampler
+    }
+
+    pub(crate) fn set_effect(&mut self, target: EffectTarget, settings: EffectSettings) {
+        match target {
+            EffectTarget::Deck(deck) => self.deck_effects.entry(deck).or_default(),
+            EffectTarget::Master => &mut self.master_effects,
+        }
+        .apply(settings);
     }
+
+    /// Tempo for the beat timed effects on a deck, or on master
+    ///
+    /// A deck's tempo also quantises pad loops while sync is off.
+    pub(crate) fn set_bpm(&mut self, target: EffectTarget, bpm: f32) {
+        if let (EffectTarget::Deck(_), Ok(tempo)) = (target, Tempo::new(bpm as f64)) {
+            self.sampler.set_deck_tempo(tempo);
+        }
+        match target {
+            EffectTarget::Deck(deck) => self.deck_effects.entry(deck).or_default(),
+            EffectTarget::Master => &mut self.master_effects,
+        }
+        .set_bpm(bpm);
+    }
+
+    // Volumes arrive in dB, mixing needs a linear gain
     pub(crate) fn set_volume(&mut self, deck: Deck, db: f32) {
-        self.volumes.insert(deck, db);
+        self.volumes.insert(deck, 10.0f32.powf(db / 20.0));
+        self.faders.set_volume(deck, db);
+    }
+
+    /// Silence a deck without losing its volume
+    pub(crate) fn set_mute(&mut self, deck: Deck, muted: bool) {
+        match muted {
+            true => self.muted.insert(deck),
+            false => self.muted.remove(&deck),
+        };
+        self.faders.set_mute(deck, muted);
+    }
+
+    pub(crate) fn cue(&mut self, frame: u64, id: CueId, cue: Cue) {
+        self.cues.add(frame, id, cue);
+    }
+
+    pub(crate) fn cancel_cue(&mut self, id: CueId) {
+        self.cues.cancel(id);
+    }
+}
+
+// A deck mixed in parts has its stats for the block added up
+fn add_stats(stats: &mut Vec<(Deck, BlockStats)>, deck: Deck, more: BlockStats) {
+    match stats.iter_mut().find(|(known, _)| *known == deck) {
+        Some((_, block)) => block.merge(more),
+        None => stats.push((deck, more)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demand::Demand;
+    use crate::health::Health;
+    use crate::limiter::{CEILING, LOOKAHEAD};
+    use crate::track::Entry;
+    use playback_primitives::EffectParams;
+    use ringbuf::HeapRb;
+    use std::path::PathBuf;
+
+    const BLOCK: usize = 3840;
+
+    fn playing_input(value: f32) -> MixerInput {
+        let (mut producer, consumer) = HeapRb::<f32>::new(BLOCK * 4).split();
+        producer.push_slice(&[value; BLOCK * 4]);
+        let playhead = Playhead::new(entry("/music/loud.flac"));
+        playhead.playing.store(true, Ordering::Relaxed);
+        MixerInput {
+            consumer,
+            playhead: Arc::new(playhead),
+            played: 0,
+            health: DeckHealth::default(),
+        }
+    }
+
+    fn entry(path: &str) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            length: None,
+        }
+    }
+
+    #[test]
+    fn loud_decks_are_limited_and_metered() {
+        let (producer, mut mixed) = HeapRb::<f32>::new(BLOCK * 4).split();
+        let meters = Meters::default();
+        let mut mixer = Mixer::new(
+            producer,
+            meters.clone(),
+            MixPosition::default(),
+            Faders::default(),
+        );
+        let mut inputs =
+            HashMap::from([(Deck::A, playing_input(0.8)), (Deck::B, playing_input(0.8))]);
+        mixer.set_volume(Deck::B, -6.0);
+
+        let mut buffer = vec![0.0; BLOCK];
+        for _ in 0..4 {
+            mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        }
+
+        let mut output = vec![0.0; BLOCK * 4];
+        mixed.pop_slice(&mut output);
+        assert!(output.iter().all(|sample| sample.abs() <= CEILING));
+
+        let levels = meters.levels();
+        assert!((levels.decks[&Deck::A].peak_db + 1.94).abs() < 0.01);
+        assert!((levels.decks[&Deck::B].peak_db + 7.94).abs() < 0.01);
+        assert!(levels.master.peak_db <= 0.0);
+        assert_eq!(levels.clips, BLOCK as u64 * 4);
+        assert!(levels.reduction_db > 1.0);
+    }
+
+    #[test]
+    fn starved_decks_count_underruns_until_the_queue_runs_out() {
+        let (producer, _mixed) = HeapRb::<f32>::new(BLOCK * 8).split();
+        let mut mixer = Mixer::new(
+            producer,
+            Meters::default(),
+            MixPosition::default(),
+            Faders::default(),
+        );
+        let health = Health::new(Arc::new(Demand::default()));
+
+        // Three and a half blocks buffered, with more to come
+        let mut input = playing_input(0.5);
+        input.consumer.skip(BLOCK / 2);
+        input.health = health.deck(Deck::A);
+        let mut inputs = HashMap::from([(Deck::A, input)]);
+
+        // The fourth block comes up short and the fifth gets nothing
+        let mut buffer = vec![0.0; BLOCK];
+        for _ in 0..5 {
+            mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        }
+        assert_eq!(health.stats().decks[&Deck::A].buffer_underruns, 2);
+
+        // Once the decoder has nothing left, running dry is expected
+        let playhead = inputs[&Deck::A].playhead.clone();
+        playhead.exhausted.store(true, Ordering::Relaxed);
+        mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        assert_eq!(health.stats().totals.buffer_underruns, 2);
+    }
+
+    #[test]
+    fn effect_tails_ring_on_after_the_deck_stops() {
+        let (producer, mut mixed) = HeapRb::<f32>::new(BLOCK * 4).split();
+        let mut mixer = Mixer::new(
+            producer,
+            Meters::default(),
+            MixPosition::default(),
+            Faders::default(),
+        );
+        let mut inputs = HashMap::from([(Deck::A, playing_input(0.5))]);
+        let echo = EffectSettings {
+            enabled: true,
+            wet: 1.0,
+            params: EffectParams::Echo {
+                beats: 0.25,
+                feedback: 0.3,
+            },
+        };
+        mixer.set_effect(EffectTarget::Deck(Deck::A), echo);
+
+        let mut buffer = vec![0.0; BLOCK];
+        let mut out = vec![0.0; BLOCK];
+        mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        let playhead = inputs[&Deck::A].playhead.clone();
+        playhead.playing.store(false, Ordering::Relaxed);
+
+        // The first repeat comes an eighth of a second after the audio
+        let mut loudest = 0.0f32;
+        for _ in 0..4 {
+            mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+            mixed.pop_slice(&mut out);
+            loudest = out.iter().fold(loudest, |max, s| max.max(s.abs()));
+        }
+        assert!(loudest > 0.1, "tail peaked at {loudest}");
+        assert!(mixer.ringing.contains_key(&Deck::A));
+
+        // Stopped decks give nothing, the tail did not take the audio left
+        assert_eq!(playhead.position.load(Ordering::Relaxed), BLOCK);
+
+        // Once it has died away the deck is left alone again
+        for _ in 0..200 {
+            mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+            mixed.pop_slice(&mut out);
+        }
+        assert!(!mixer.ringing.contains_key(&Deck::A));
+        assert!(out.iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn position_restarts_where_the_next_entry_begins() {
+        let (producer, _mixed) = HeapRb::<f32>::new(BLOCK * 8).split();
+        let mut mixer = Mixer::new(
+            producer,
+            Meters::default(),
+            MixPosition::default(),
+            Faders::default(),
+        );
+        let mut inputs = HashMap::from([(Deck::A, playing_input(0.5))]);
+        let playhead = inputs[&Deck::A].playhead.clone();
+        // The next file starts a quarter of the way into the second block
+        playhead.announce(entry("/music/next.flac"), BLOCK + BLOCK / 4);
+
+        let mut buffer = vec![0.0; BLOCK];
+        mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        assert_eq!(playhead.position.load(Ordering::Relaxed), BLOCK);
+        assert_eq!(playhead.current().path, PathBuf::from("/music/loud.flac"));
+
+        mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        assert_eq!(playhead.position.load(Ordering::Relaxed), BLOCK * 3 / 4);
+        assert_eq!(playhead.current().path, PathBuf::from("/music/next.flac"));
+    }
+
+    #[test]
+    fn cues_land_on_their_frame() {
+        let (producer, mut mixed) = HeapRb::<f32>::new(BLOCK * 4).split();
+        let position = MixPosition::default();
+        let faders = Faders::default();
+        let mut mixer = Mixer::new(
+            producer,
+            Meters::default(),
+            position.clone(),
+            faders.clone(),
+        );
+        let mut inputs = HashMap::from([(Deck::A, playing_input(0.5))]);
+        let frames = (BLOCK / MIX_CHANNELS) as u64;
+
+        // Part way into the second block, and one taken back before it
+        let cut = frames + 300;
+        let quieten = Cue::SetVolume {
+            deck: Deck::A,
+            db: -6.0,
+        };
+        let mute = Cue::SetMute {
+            deck: Deck::A,
+            muted: true,
+        };
+        mixer.cue(cut, CueId(0), quieten);
+        mixer.cue(cut - 100, CueId(1), mute);
+        mixer.cancel_cue(CueId(1));
+
+        let mut buffer = vec![0.0; BLOCK];
+        mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        assert_eq!(position.frames(), frames * 2);
+
+        let mut out = vec![0.0; BLOCK * 2];
+        assert_eq!(mixed.pop_slice(&mut out), BLOCK * 2);
+        // Everything comes out the limiter's lookahead late
+        let heard = (cut as usize + LOOKAHEAD - 1) * MIX_CHANNELS;
+        assert!(out[BLOCK..heard].iter().all(|s| (s - 0.5).abs() < 1e-6));
+        let quieter = 0.5 * 10.0f32.powf(-6.0 / 20.0);
+        assert!(out[heard..].iter().all(|s| (s - quieter).abs() < 1e-6));
+
+        // Deck state sees what the cues did
+        assert_eq!(
+            faders.get(Deck::A),
+            Fader {
+                db: -6.0,
+                muted: false
+            }
+        );
+        mixer.cue(frames * 2, CueId(2), mute);
+        mixer.mix(&mut buffer, BLOCK, &mut inputs).unwrap();
+        assert!(faders.get(Deck::A).muted);
     }
 }
@@ -0,0 +1,66 @@
+use crate::demand::Demand;
+use ringbuf::HeapConsumer;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const RATE: usize = 48000;
+const CHANNELS: usize = 2;
+
+// How often the fake device asks for audio
+const PERIOD: Duration = Duration::from_millis(5);
+
+/// Output that discards the mix at the pace of a real 48 kHz stereo device
+///
+/// Used where there is no sound server, e.g. in tests and CI. Because it
+/// consumes in real time, track positions advance like they would on
+/// hardware.
+pub struct NullOutput {
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl NullOutput {
+    pub fn new(mut sample_consumer: HeapConsumer<f32>, demand: Arc<Demand>) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let keep_running = running.clone();
+
+        info!("create null output thread");
+        let thread = thread::spawn(move || {
+            let start = Instant::now();
+            let mut consumed = 0usize;
+            let mut scratch = vec![0.0f32; RATE * CHANNELS / 10];
+
+            while keep_running.load(Ordering::Relaxed) {
+                thread::sleep(PERIOD);
+
+                // Catch up with the wall clock, like a device pulling periods
+                let due = (start.elapsed().as_secs_f64() * (RATE * CHANNELS) as f64) as usize;
+                let wanted = (due - consumed).min(scratch.len());
+                let popped = sample_consumer.pop_slice(&mut scratch[..wanted]);
+                if popped < wanted {
+                    demand.underrun();
+                }
+                // Missing samples count as played silence, as on a device
+                consumed += wanted;
+                demand.wake();
+            }
+        });
+
+        Self {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Rate the mixer runs at, whatever the device is set to
+pub const MIX_RATE: u32 = 48000;
+/// The mix is always stereo, other layouts are mapped from it
+pub const MIX_CHANNELS: usize = 2;
+
+/// Most output channels we know how to position, up to 7.1
+pub const MAX_CHANNELS: u16 = 8;
+
+// Largest block the mixer works in, 40 ms
+const MAX_BLOCK_FRAMES: usize = 1920;
+
+// Smallest deck buffer, plenty for short latencies
+const MIN_DECK_BUFFER: usize = 16384;
+
+/// Sample encoding sent to the sound server, always little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    #[default]
+    S16,
+    /// Packed three byte samples
+    S24,
+    S32,
+    /// The mix as it is, no conversion at all
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bytes(&self) -> usize {
+        match self {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::S32 | SampleFormat::F32 => 4,
+        }
+    }
+
+    /// Write one sample into `out`, which is `bytes()` long
+    pub fn encode(&self, sample: f32, out: &mut [u8]) {
+        let sample = sample.clamp(-1.0, 1.0);
+        match self {
+            SampleFormat::S16 => out.copy_from_slice(&((sample * 32767.0) as i16).to_le_bytes()),
+            SampleFormat::S24 => {
+                let value = (sample * 8_388_607.0) as i32;
+                out.copy_from_slice(&value.to_le_bytes()[..3]);
+            }
+            SampleFormat::S32 => {
+                let value = (sample as f64 * 2_147_483_647.0) as i32;
+                out.copy_from_slice(&value.to_le_bytes());
+            }
+            SampleFormat::F32 => out.copy_from_slice(&sample.to_le_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SampleFormat::S16 => "s16",
+            SampleFormat::S24 => "s24",
+            SampleFormat::S32 => "s32",
+            SampleFormat::F32 => "f32",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for SampleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "s16" => Ok(SampleFormat::S16),
+            "s24" => Ok(SampleFormat::S24),
+            "s32" => Ok(SampleFormat::S32),
+            "f32" => Ok(SampleFormat::F32),
+            _ => Err(format!(
+                "Unknown sample format {}, use s16, s24, s32 or f32",
+                s
+            )),
+        }
+    }
+}
+
+/// How the engine talks to the audio device
+///
+/// The mix itself is always 48 kHz stereo. A different `rate` asks
+/// PipeWire to run the device at that rate and convert, and the stereo mix
+/// is spread over however many `channels` the output has.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Preferred encoding, 16 bit is used if the device refuses it
+    pub format: SampleFormat,
+    pub rate: u32,
+    pub channels: u16,
+    /// Node name or serial to play to, the default sink when `None`
+    pub target: Option<String>,
+    /// Frames per period, PipeWire picks when `None`
+    pub quantum: Option<u32>,
+    /// How far ahead of the device the mixer keeps, in milliseconds
+    pub latency_ms: u32,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            format: SampleFormat::default(),
+            rate: MIX_RATE,
+            channels: MIX_CHANNELS as u16,
+            target: None,
+            quantum: None,
+            latency_ms: 40,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(8000..=384_000).contains(&self.rate) {
+            return Err(format!("rate {} is not between 8000 and 384000", self.rate));
+        }
+        if !(1..=MAX_CHANNELS).contains(&self.channels) {
+            return Err(format!(
+                "{} channels, only 1 to {} are supported",
+                self.channels, MAX_CHANNELS
+            ));
+        }
+        if let Some(quantum) = self.quantum {
+            if !quantum.is_power_of_two() || !(32..=8192).contains(&quantum) {
+                return Err(format!(
+                    "quantum {} is not a power of two from 32 to 8192",
+                    quantum
+                ));
+            }
+        }
+        if self.target.as_deref() == Some("") {
+            return Err("target is empty".to_string());
+        }
+        if !(5..=250).contains(&self.latency_ms) {
+            return Err(format!(
+                "latency {} ms is not between 5 and 250",
+                self.latency_ms
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency_ms as u64)
+    }
+
+    /// Interleaved mix samples to keep queued for the output
+    pub fn latency_samples(&self) -> usize {
+        self.latency_ms as usize * MIX_RATE as usize / 1000 * MIX_CHANNELS
+    }
+
+    /// Interleaved samples the mixer renders at a time
+    ///
+    /// Half the latency, so a fresh block is ready well before the queue
+    /// runs out.
+    pub fn block_samples(&self) -> usize {
+        let frames = self.latency_samples() / MIX_CHANNELS / 2;
+        frames.min(MAX_BLOCK_FRAMES) * MIX_CHANNELS
+    }
+
+    /// Interleaved samples each deck decodes ahead into
+    ///
+    /// The mixer can take a whole latency's worth at once, a deck needs as
+    /// much again behind it to not run dry while it decodes more.
+    pub fn deck_buffer_samples(&self) -> usize {
+        (self.latency_samples() * 2).max(MIN_DECK_BUFFER)
+    }
+}
+
+/// Turns the stereo mix into frames for the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    pub format: SampleFormat,
+    pub channels: usize,
+}
+
+impl Encoding {
+    /// Bytes in one frame
+    pub fn stride(&self) -> usize {
+        self.format.bytes() * self.channels
+    }
+
+    /// Encode stereo frames from `mix` into `out`, a whole number of frames
+    ///
+    /// Mono gets the average of both sides. Past stereo the mix goes to
+    /// front left and right and the other channels stay silent.
+    pub fn write(&self, mix: &[f32], out: &mut [u8]) {
+        let size = self.format.bytes();
+        for (frame, stereo) in out
+            .chunks_exact_mut(self.stride())
+            .zip(mix.chunks_exact(MIX_CHANNELS))
+        {
+            for (channel, sample) in frame.chunks_exact_mut(size).enumerate() {
+                let value = match (self.channels, channel) {
+                    (1, _) => (stereo[0] + stereo[1]) * 0.5,
+                    (_, 0 | 1) => stereo[channel],
+                    _ => 0.0,
+                };
+                self.format.encode(value, sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_encode_full_scale() {
+        let encode = |format: SampleFormat, sample: f32| {
+            let mut out = vec![0; format.bytes()];
+            format.encode(sample, &mut out);
+            out
+        };
+
+        assert_eq!(encode(SampleFormat::S16, 1.0), 32767i16.to_le_bytes());
+        assert_eq!(encode(SampleFormat::S16, -2.0), (-32767i16).to_le_bytes());
+        assert_eq!(encode(SampleFormat::S24, 1.0), [0xff, 0xff, 0x7f]);
+        assert_eq!(encode(SampleFormat::S24, -1.0), [0x01, 0x00, 0x80]);
+        assert_eq!(encode(SampleFormat::S32, 1.0), i32::MAX.to_le_bytes());
+        assert_eq!(encode(SampleFormat::F32, 0.25), 0.25f32.to_le_bytes());
+    }
+
+    #[test]
+    fn f32_output_is_the_mix_untouched() {
+        let mix = [0.1234f32, -0.9876, 1e-7, -1e-7];
+        let encoding = Encoding {
+            format: SampleFormat::F32,
+            channels: 2,
+        };
+        let mut out = vec![0; encoding.stride() * 2];
+        encoding.write(&mix, &mut out);
+
+        let decoded: Vec<f32> = out
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(decoded, mix);
+    }
+
+    #[test]
+    fn stereo_is_mapped_onto_other_layouts() {
+        let mix = [0.5, -0.25, 1.0, 0.0];
+        let decode = |channels: usize| {
+            let encoding = Encoding {
+                format: SampleFormat::F32,
+                channels,
+            };
+            let mut out = vec![0; encoding.stride() * 2];
+            encoding.write(&mix, &mut out);
+            out.chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(decode(1), [0.125, 0.5]);
+        assert_eq!(decode(4), [0.5, -0.25, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn config_is_validated() {
+        assert!(OutputConfig::default().validate().is_ok());
+
+        let bad = [
+            OutputConfig {
+                rate: 1000,
+                ..OutputConfig::default()
+            },
+            OutputConfig {
+                channels: 0,
+                ..OutputConfig::default()
+            },
+            OutputConfig {
+                quantum: Some(100),
+                ..OutputConfig::default()
+            },
+            OutputConfig {
+                target: Some(String::new()),
+                ..OutputConfig::default()
+            },
+            OutputConfig {
+                latency_ms: 1000,
+                ..OutputConfig::default()
+            },
+        ];
+        for config in bad {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn blocks_follow_the_latency() {
+        let config = |latency_ms| OutputConfig {
+            latency_ms,
+            ..OutputConfig::default()
+        };
+
+        assert_eq!(config(40).latency_samples(), 3840);
+        assert_eq!(config(40).block_samples(), 1920);
+        assert_eq!(config(5).block_samples(), 240);
+        // Long latencies still mix in 40 ms blocks
+        assert_eq!(config(250).block_samples(), 3840);
+
+        assert_eq!(config(40).deck_buffer_samples(), MIN_DECK_BUFFER);
+        assert_eq!(config(250).deck_buffer_samples(), 48000);
+    }
+
+    #[test]
+    fn formats_parse_in_any_case() {
+        assert_eq!("F32".parse(), Ok(SampleFormat::F32));
+        assert_eq!("s24".parse(), Ok(SampleFormat::S24));
+        assert!("u8".parse::<SampleFormat>().is_err());
+    }
+}
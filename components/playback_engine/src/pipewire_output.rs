@@ -1,14 +1,71 @@
+use std::sync::Arc;
 use std::thread;
 
+use crate::demand::Demand;
+use crate::output_config::{Encoding, OutputConfig, SampleFormat, MIX_CHANNELS, MIX_RATE};
 use pipewire as pw;
 use pw::{properties::properties, spa};
 use ringbuf::HeapConsumer;
+use spa::param::audio::{AudioFormat, AudioInfoRaw};
+use spa::param::format::{MediaSubtype, MediaType};
+use spa::param::format_utils;
 use spa::pod::Pod;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+// Speaker positions in PipeWire's order, the first N are used for N channels
+const POSITIONS: [u32; 8] = [
+    spa_sys::SPA_AUDIO_CHANNEL_FL,
+    spa_sys::SPA_AUDIO_CHANNEL_FR,
+    spa_sys::SPA_AUDIO_CHANNEL_FC,
+    spa_sys::SPA_AUDIO_CHANNEL_LFE,
+    spa_sys::SPA_AUDIO_CHANNEL_RL,
+    spa_sys::SPA_AUDIO_CHANNEL_RR,
+    spa_sys::SPA_AUDIO_CHANNEL_SL,
+    spa_sys::SPA_AUDIO_CHANNEL_SR,
+];
+
+const FORMATS: [SampleFormat; 4] = [
+    SampleFormat::S16,
+    SampleFormat::S24,
+    SampleFormat::S32,
+    SampleFormat::F32,
+];
+
+fn spa_format(format: SampleFormat) -> AudioFormat {
+    match format {
+        SampleFormat::S16 => AudioFormat::S16LE,
+        SampleFormat::S24 => AudioFormat::S24LE,
+        SampleFormat::S32 => AudioFormat::S32LE,
+        SampleFormat::F32 => AudioFormat::F32LE,
+    }
+}
 
-pub const DEFAULT_RATE: u32 = 48000;
-pub const DEFAULT_CHANNELS: u32 = 2;
-pub const CHAN_SIZE: usize = std::mem::size_of::<i16>();
+// EnumFormat param offering `format` at the mix rate
+fn format_param(format: SampleFormat, channels: u16) -> Vec<u8> {
+    let mut audio_info = AudioInfoRaw::new();
+    audio_info.set_format(spa_format(format));
+    audio_info.set_rate(MIX_RATE);
+    audio_info.set_channels(channels as u32);
+    let mut position = [0; spa::param::audio::MAX_CHANNELS];
+    if channels == 1 {
+        position[0] = spa_sys::SPA_AUDIO_CHANNEL_MONO;
+    } else {
+        position[..channels as usize].copy_from_slice(&POSITIONS[..channels as usize]);
+    }
+    audio_info.set_position(position);
+
+    pw::spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(pw::spa::pod::Object {
+            type_: spa_sys::SPA_TYPE_OBJECT_Format,
+            id: spa_sys::SPA_PARAM_EnumFormat,
+            properties: audio_info.into(),
+        }),
+    )
+    .unwrap()
+    .0
+    .into_inner()
+}
 
 pub struct PipewireOutput {
     // Thread handle to keep the PipeWire thread alive
@@ -16,9 +73,12 @@ pub struct PipewireOutput {
 }
 
 impl PipewireOutput {
-    pub fn new(sample_consumer: HeapConsumer<f32>) -> Result<Self, pw::Error> {
-        // Create a ring buffer for audio samples
-        info!("create pipe wire thread");
+    pub fn new(
+        sample_consumer: HeapConsumer<f32>,
+        config: OutputConfig,
+        demand: Arc<Demand>,
+    ) -> Result<Self, pw::Error> {
+        info!("create pipe wire thread for {:?}", config);
         // Spawn PipeWire thread
         let pw_thread = thread::spawn(move || {
             pw::init();
@@ -29,32 +89,94 @@ impl PipewireOutput {
             // Create user data struct to hold consumer
             struct UserData {
                 consumer: HeapConsumer<f32>,
+                // Woken after every period, so the mixer refills what we took
+                demand: Arc<Demand>,
+                // Replaced once PipeWire says what it settled on
+                encoding: Encoding,
+                // Mix read for one period
+                mix: Vec<f32>,
                 frame_count: usize, // For debugging
             }
 
             let user_data = UserData {
                 consumer: sample_consumer,
+                demand,
+                encoding: Encoding {
+                    format: config.format,
+                    channels: config.channels as usize,
+                },
+                mix: Vec::new(),
                 frame_count: 0,
             };
 
-            let stream = pw::stream::Stream::new(
-                &core,
-                "mdma-audio-output",
-                properties! {
-                    *pw::keys::MEDIA_TYPE => "Audio",
-                    *pw::keys::MEDIA_ROLE => "Music",
-                    *pw::keys::MEDIA_CATEGORY => "Playback",
-                    *pw::keys::AUDIO_CHANNELS => "2",
-                },
-            )?;
+            let mut props = properties! {
+                *pw::keys::MEDIA_TYPE => "Audio",
+                *pw::keys::MEDIA_ROLE => "Music",
+                *pw::keys::MEDIA_CATEGORY => "Playback",
+                *pw::keys::AUDIO_CHANNELS => config.channels.to_string(),
+            };
+            if config.rate != MIX_RATE {
+                // Run the graph at this rate, PipeWire converts the mix to it
+                props.insert(*pw::keys::NODE_RATE, format!("1/{}", config.rate));
+            }
+            if let Some(quantum) = config.quantum {
+                props.insert(
+                    *pw::keys::NODE_LATENCY,
+                    format!("{}/{}", quantum, config.rate),
+                );
+            }
+            if let Some(target) = &config.target {
+                props.insert(*pw::keys::TARGET_OBJECT, target.as_str());
+            }
+
+            let stream = pw::stream::Stream::new(&core, "mdma-audio-output", props)?;
 
             let _listener = stream
                 .add_local_listener_with_user_data(user_data)
+                .param_changed(|_, user_data, id, param| {
+                    let Some(param) = param else {
+                        return;
+                    };
+                    if id != spa::param::ParamType::Format.as_raw() {
+                        return;
+                    }
+                    match format_utils::parse_format(param) {
+                        Ok((MediaType::Audio, MediaSubtype::Raw)) => {}
+                        _ => return,
+                    }
+
+                    let mut audio_info = AudioInfoRaw::new();
+                    if let Err(e) = audio_info.parse(param) {
+                        warn!("Could not read the negotiated format: {}", e);
+                        return;
+                    }
+                    let format = FORMATS
+                        .into_iter()
+                        .find(|format| spa_format(*format) == audio_info.format());
+                    match format {
+                        Some(format) => {
+                            user_data.encoding = Encoding {
+                                format,
+                                channels: audio_info.channels() as usize,
+                            };
+                            info!(
+                                "PipeWire output is {} with {} channels",
+                                format,
+                                audio_info.channels()
+                            );
+                        }
+                        None => warn!(
+                            "PipeWire picked {:?}, keeping our format",
+                            audio_info.format()
+                        ),
+                    }
+                })
                 .process(|stream, user_data| match stream.dequeue_buffer() {
                     None => println!("No buffer received"),
                     Some(mut buffer) => {
                         let datas = buffer.datas_mut();
-                        let stride = CHAN_SIZE * DEFAULT_CHANNELS as usize;
+                        let encoding = user_data.encoding;
+                        let stride = encoding.stride();
                         let data = &mut datas[0];
 
                         let n_frames = if let Some(slice) = data.data() {
@@ -70,41 +192,21 @@ impl PipewireOutput {
                                 );
                             }
 
-                            // Temporary buffer to read from consumer
-                            let mut f32_buffer = vec![0.0f32; n_frames * DEFAULT_CHANNELS as usize];
-
-                            // Read from consumer
-                            let samples_read = user_data.consumer.pop_slice(&mut f32_buffer);
+                            // One stereo frame of mix for every output frame
+                            user_data.mix.resize(n_frames * MIX_CHANNELS, 0.0);
+                            let samples_read = user_data.consumer.pop_slice(&mut user_data.mix);
+                            // Whatever the mixer hasn't got ready plays as silence
+                            if samples_read < user_data.mix.len() {
+                                user_data.demand.underrun();
+                                user_data.mix[samples_read..].fill(0.0);
+                            }
+                            user_data.demand.wake();
 
                             if user_data.frame_count % 100 == 0 && samples_read > 0 {
                                 debug!("Read {} samples from consumer", samples_read);
                             }
 
-                            // Convert f32 samples to i16 and copy to output buffer
-                            for i in 0..n_frames {
-                                for c in 0..DEFAULT_CHANNELS {
-                                    // Calculate index in f32 buffer
-                                    let f32_idx = i * DEFAULT_CHANNELS as usize + c as usize;
-
-                                    // Get sample (or 0 if we've read all samples)
-                                    let f32_sample = if f32_idx < samples_read {
-                                        f32_buffer[f32_idx]
-                                    } else {
-                                        0.0
-                                    };
-
-                                    // Convert to i16 (-1.0..1.0 -> -32767..32767)
-                                    let val =
-                                        (f32_sample * 32767.0).clamp(-32767.0, 32767.0) as i16;
-
-                                    // Copy to output buffer
-                                    let start = i * stride + (c as usize * CHAN_SIZE);
-                                    let end = start + CHAN_SIZE;
-                                    let chan = &mut slice[start..end];
-                                    chan.copy_from_slice(&i16::to_le_bytes(val));
-                                }
-                            }
-
+                            encoding.write(&user_data.mix, &mut slice[..n_frames * stride]);
                             n_frames
                         } else {
                             0
@@ -118,28 +220,15 @@ impl PipewireOutput {
                 })
                 .register()?;
 
-            let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-            audio_info.set_format(spa::param::audio::AudioFormat::S16LE);
-            audio_info.set_rate(DEFAULT_RATE);
-            audio_info.set_channels(DEFAULT_CHANNELS);
-            let mut position = [0; spa::param::audio::MAX_CHANNELS];
-            position[0] = spa_sys::SPA_AUDIO_CHANNEL_FL;
-            position[1] = spa_sys::SPA_AUDIO_CHANNEL_FR;
-            audio_info.set_position(position);
-
-            let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
-                std::io::Cursor::new(Vec::new()),
-                &pw::spa::pod::Value::Object(pw::spa::pod::Object {
-                    type_: spa_sys::SPA_TYPE_OBJECT_Format,
-                    id: spa_sys::SPA_PARAM_EnumFormat,
-                    properties: audio_info.into(),
-                }),
-            )
-            .unwrap()
-            .0
-            .into_inner();
-
-            let mut params = [Pod::from_bytes(&values).unwrap()];
+            // The configured format first, 16 bit for devices that won't take it
+            let mut offers = vec![format_param(config.format, config.channels)];
+            if config.format != SampleFormat::S16 {
+                offers.push(format_param(SampleFormat::S16, config.channels));
+            }
+            let mut params: Vec<&Pod> = offers
+                .iter()
+                .map(|values| Pod::from_bytes(values).unwrap())
+                .collect();
 
             stream.connect(
                 spa::utils::Direction::Output,
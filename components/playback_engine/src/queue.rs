@@ -0,0 +1,206 @@
+use crate::error::PlaybackError;
+use crate::health::DeckHealth;
+use crate::source::{decode_samples, FlacSource, Source};
+use crate::state::QueueState;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Longest crossfade between queue entries
+pub const MAX_CROSSFADE_SECONDS: f32 = 30.0;
+
+/// Opens a queued file when its turn comes
+pub(crate) type OpenSource =
+    Arc<dyn Fn(&Path) -> Result<Box<dyn Source>, PlaybackError> + Send + Sync>;
+
+pub(crate) fn open_flac() -> OpenSource {
+    Arc::new(|path| Ok(Box::new(FlacSource::new(path)?)))
+}
+
+#[derive(Default)]
+struct Entries {
+    paths: VecDeque<PathBuf>,
+    crossfade_seconds: f32,
+}
+
+/// Files a deck plays after the current one, shared with its decoder
+#[derive(Clone)]
+pub(crate) struct Queue {
+    entries: Arc<Mutex<Entries>>,
+    open: OpenSource,
+}
+
+impl Queue {
+    pub fn new(open: OpenSource) -> Self {
+        Self {
+            entries: Arc::default(),
+            open,
+        }
+    }
+
+    /// Open a file the way the decoder will, to find out now if it can't be
+    pub fn open(&self, path: &Path) -> Result<Box<dyn Source>, PlaybackError> {
+        (self.open)(path)
+    }
+
+    pub fn push(&self, path: PathBuf) {
+        self.entries.lock().paths.push_back(path);
+    }
+
+    /// Put back an entry that was taken but never heard
+    pub fn push_front(&self, path: PathBuf) {
+        self.entries.lock().paths.push_front(path);
+    }
+
+    /// Take the first entry if it is still `path`
+    pub fn take_front_if(&self, path: &Path) -> bool {
+        let mut entries = self.entries.lock();
+        let first = entries.paths.front().is_some_and(|front| front == path);
+        if first {
+            entries.paths.pop_front();
+        }
+        first
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().paths.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().paths.is_empty()
+    }
+
+    pub fn crossfade_seconds(&self) -> f32 {
+        self.entries.lock().crossfade_seconds
+    }
+
+    pub fn set_crossfade_seconds(&self, seconds: f32) {
+        self.entries.lock().crossfade_seconds = seconds;
+    }
+
+    /// Take entries off the front until one opens
+    ///
+    /// Files that fail to open count as decode errors and are dropped.
+    pub fn open_next(&self, health: &DeckHealth) -> Option<(PathBuf, Box<dyn Source>)> {
+        loop {
+            let path = self.entries.lock().paths.pop_front()?;
+            match self.open(&path) {
+                Ok(source) => return Some((path, source)),
+                Err(e) => {
+                    tracing::warn!("Skipping queued {}: {}", path.display(), e);
+                    health.decode_error();
+                }
+            }
+        }
+    }
+
+    pub fn state(&self) -> QueueState {
+        let entries = self.entries.lock();
+        QueueState {
+            entries: entries.paths.iter().cloned().collect(),
+            crossfade_seconds: entries.crossfade_seconds,
+        }
+    }
+}
+
+/// The next entry fading in over the end of the current one
+pub(crate) struct Crossfade {
+    pub incoming: Box<dyn Source>,
+    /// Where `incoming` came from, to requeue it if the fade is called off
+    pub path: PathBuf,
+    // Decoded from `incoming` but not mixed yet
+    buffered: VecDeque<f32>,
+    done: usize,
+    length: usize,
+}
+
+impl Crossfade {
+    /// Fade over the `length` samples the current entry has left
+    pub fn new(path: PathBuf, incoming: Box<dyn Source>, length: usize) -> Self {
+        Self {
+            incoming,
+            path,
+            buffered: VecDeque::new(),
+            done: 0,
+            length: length.max(1),
+        }
+    }
+
+    /// Mix the incoming entry into samples of the outgoing one, equal power
+    pub fn mix(&mut self, outgoing: &mut [f32], health: &DeckHealth) {
+        while self.buffered.len() < outgoing.len() {
+            match decode_samples(&*self.incoming) {
+                Ok(samples) if samples.is_empty() => break,
+                Ok(samples) => self.buffered.extend(samples),
+                Err(e) => {
+                    tracing::error!("failed to decode incoming entry: {e}");
+                    health.decode_error();
+                    break;
+                }
+            }
+        }
+
+        for sample in outgoing.iter_mut() {
+            let t = (self.done as f32 / self.length as f32).min(1.0) * FRAC_PI_2;
+            let incoming = self.buffered.pop_front().unwrap_or(0.0);
+            *sample = *sample * t.cos() + incoming * t.sin();
+            self.done += 1;
+        }
+    }
+
+    /// The incoming entry, once the outgoing one has run out
+    pub fn finish(self) -> (Box<dyn Source>, Vec<f32>) {
+        (self.incoming, self.buffered.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::TestSource;
+
+    fn test_queue() -> Queue {
+        Queue::new(Arc::new(|path| {
+            if path.starts_with("/missing") {
+                Err(PlaybackError::TrackNotFound(path.to_path_buf()))
+            } else {
+                Ok(Box::new(TestSource::new_with_pattern("silence", 0.1)))
+            }
+        }))
+    }
+
+    #[test]
+    fn entries_that_fail_to_open_are_skipped() {
+        let queue = test_queue();
+        queue.push(PathBuf::from("/missing/one.flac"));
+        queue.push(PathBuf::from("/music/two.flac"));
+        let health = DeckHealth::default();
+
+        let (path, _) = queue.open_next(&health).unwrap();
+        assert_eq!(path, PathBuf::from("/music/two.flac"));
+        assert!(queue.open_next(&health).is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn crossfade_hands_over_at_equal_power() {
+        let incoming = TestSource::new_from_samples(vec![1.0; 4096]);
+        let mut fade = Crossfade::new(PathBuf::from("/music/next.flac"), Box::new(incoming), 2048);
+        let health = DeckHealth::default();
+
+        let mut outgoing = vec![1.0; 2048];
+        fade.mix(&mut outgoing, &health);
+
+        assert_eq!(outgoing[0], 1.0);
+        // Halfway each side is at -3 dB, together a little louder than either
+        assert!((outgoing[1024] - std::f32::consts::SQRT_2).abs() < 0.01);
+        assert!((outgoing[2047] - 1.0).abs() < 0.01);
+
+        // What was decoded past the fade plays straight after it
+        let (_, rest) = fade.finish();
+        assert_eq!(rest.len(), 2048);
+        assert!(rest.iter().all(|sample| *sample == 1.0));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::PlaybackError;
+use crate::flac::{FlacWriter, BLOCK_FRAMES};
+use crate::output_config::{MIX_CHANNELS, MIX_RATE};
+use crate::state::RecordingState;
+use chrono::{DateTime, SecondsFormat, Utc};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Mix held for the writer, enough to ride out a slow disk
+const TAP_SECONDS: usize = 4;
+// Headroom over 16 bits, so quiet passages keep their detail
+const BITS: u32 = 24;
+// How long the writer naps once it has caught up
+const WRITER_NAP: Duration = Duration::from_millis(20);
+
+#[derive(Default)]
+struct Progress {
+    captured: AtomicU64,
+    dropped: AtomicU64,
+    // The tap is gone, whatever is buffered is all there will be
+    closed: AtomicBool,
+}
+
+/// The mixer's end of a recording, fed the master after the limiter
+///
+/// Capturing never waits on the writer. If it falls so far behind that
+/// the buffer fills, the mix goes on and the samples are counted as lost.
+pub(crate) struct Tap {
+    producer: HeapProducer<f32>,
+    progress: Arc<Progress>,
+}
+
+impl Tap {
+    pub fn capture(&mut self, block: &[f32]) {
+        let pushed = self.producer.push_slice(block);
+        self.progress
+            .captured
+            .fetch_add(pushed as u64, Ordering::Relaxed);
+        if pushed < block.len() {
+            self.progress
+                .dropped
+                .fetch_add((block.len() - pushed) as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        self.progress.closed.store(true, Ordering::Release);
+    }
+}
+
+/// A recording of the master output, encoded to FLAC on its own thread
+pub struct Recording {
+    path: PathBuf,
+    started: DateTime<Utc>,
+    progress: Arc<Progress>,
+    // Only taken by `finish`
+    writer: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Recording {
+    /// Create the file and its writer, the tap goes to the mixer
+    ///
+    /// An existing file is never overwritten, nor are missing directories
+    /// created for it.
+    pub(crate) fn start(path: &Path) -> Result<(Self, Tap), PlaybackError> {
+        let started = Utc::now();
+        let file = BufWriter::new(File::create_new(path)?);
+        let comments = [
+            ("DATE", started.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            ("ENCODER", "mdma playback-engine".to_string()),
+        ];
+        let flac = FlacWriter::new(file, MIX_RATE, MIX_CHANNELS, BITS, &comments)?;
+
+        let (producer, consumer) =
+            HeapRb::new(TAP_SECONDS * MIX_RATE as usize * MIX_CHANNELS).split();
+        let progress = Arc::new(Progress::default());
+        let writer_progress = progress.clone();
+        let writer = thread::Builder::new()
+            .name("recorder".into())
+            .spawn(move || write_out(consumer, flac, &writer_progress))?;
+
+        let tap = Tap {
+            producer,
+            progress: progress.clone(),
+        };
+        let recording = Self {
+            path: path.to_path_buf(),
+            started,
+            progress,
+            writer: Some(writer),
+        };
+        Ok((recording, tap))
+    }
+
+    pub fn state(&self) -> RecordingState {
+        let samples_per_second = (MIX_RATE as usize * MIX_CHANNELS) as f64;
+        RecordingState {
+            path: self.path.clone(),
+            started: self.started,
+            seconds: self.progress.captured.load(Ordering::Relaxed) as f64 / samples_per_second,
+            dropped_samples: self.progress.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Wait for the writer to empty the buffer and close the file
+    ///
+    /// Only returns once the tap has been dropped by the mixer.
+    pub fn finish(mut self) -> Result<RecordingState, PlaybackError> {
+        if let Some(writer) = self.writer.take() {
+            writer.join().map_err(|_| PlaybackError::TaskCancelled)??;
+        }
+        let state = self.state();
+        tracing::info!(
+            "Recorded {:.1}s to {}, {} samples lost",
+            state.seconds,
+            state.path.display(),
+            state.dropped_samples
+        );
+        Ok(state)
+    }
+}
+
+fn write_out(
+    mut consumer: HeapConsumer<f32>,
+    mut flac: FlacWriter<BufWriter<File>>,
+    progress: &Progress,
+) -> io::Result<()> {
+    let mut buffer = vec![0.0; BLOCK_FRAMES * MIX_CHANNELS];
+    loop {
+        // Checked before taking, so nothing pushed before the tap closed is left
+        let closed = progress.closed.load(Ordering::Acquire);
+        let taken = consumer.pop_slice(&mut buffer);
+        flac.write(&buffer[..taken])?;
+        if taken == 0 {
+            if closed {
+                break;
+            }
+            thread::sleep(WRITER_NAP);
+        }
+    }
+    flac.finish()?.get_ref().sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{FlacSource, Source};
+
+    #[test]
+    fn recordings_play_back_what_was_captured() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("first.flac");
+        let (recording, mut tap) = Recording::start(&path).unwrap();
+
+        let block: Vec<f32> = (0..960).map(|i| (i as f32 / 960.0) - 0.5).collect();
+        for _ in 0..100 {
+            tap.capture(&block);
+        }
+        assert!((recording.state().seconds - 1.0).abs() < 1e-9);
+        assert!(Recording::start(&path).is_err(), "overwrote a recording");
+
+        drop(tap);
+        let state = recording.finish().unwrap();
+        assert_eq!(state.dropped_samples, 0);
+
+        let source = FlacSource::new(&path).unwrap();
+        assert_eq!(source.length(), Some(96000));
+        let samples = crate::source::decode_samples(&source).unwrap();
+        assert!((samples[480] - block[480]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn a_full_buffer_loses_samples_rather_than_waiting() {
+        let (producer, _consumer) = HeapRb::new(1000).split();
+        let mut tap = Tap {
+            producer,
+            progress: Arc::default(),
+        };
+
+        tap.capture(&[0.1; 960]);
+        tap.capture(&[0.1; 960]);
+
+        assert_eq!(tap.progress.captured.load(Ordering::Relaxed), 1000);
+        assert_eq!(tap.progress.dropped.load(Ordering::Relaxed), 920);
+    }
+}
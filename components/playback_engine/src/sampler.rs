@@ -0,0 +1,328 @@
+use crate::error::PlaybackError;
+use crate::source::Source;
+use playback_primitives::{Pad, PadMode};
+use std::collections::HashMap;
+use std::sync::Arc;
+use time_primitives::Tempo;
+
+// Mixer format, clips are played as decoded
+const SAMPLE_RATE: usize = 48000;
+const CHANNELS: usize = 2;
+
+/// Longest clip a pad will hold, pads are for shots and short loops
+pub const MAX_CLIP_SECONDS: usize = 30;
+
+/// A pad's clip, decoded up front so triggering never waits on the disk
+pub type Clip = Arc<[f32]>;
+
+/// Decode a whole source into memory, it has to be in the mixer's format
+pub fn load_clip<S: Source>(source: &S) -> Result<Clip, PlaybackError> {
+    let (sample_rate, channels) = (source.sample_rate(), source.audio_channels());
+    if sample_rate as usize != SAMPLE_RATE || channels as usize != CHANNELS {
+        return Err(PlaybackError::ClipFormat(sample_rate, channels));
+    }
+    let limit = MAX_CLIP_SECONDS * SAMPLE_RATE * CHANNELS;
+    let mut samples = Vec::new();
+
+    loop {
+        let segments = source.decode_next_frame()?;
+        if segments.is_empty() {
+            break;
+        }
+        for segment in segments {
+            samples.extend_from_slice(&segment.segment.samples);
+        }
+        if samples.len() > limit {
+            return Err(PlaybackError::ClipTooLong(MAX_CLIP_SECONDS));
+        }
+    }
+
+    // The last segment is padded with silence
+    if let Some(length) = source.length() {
+        samples.truncate(length);
+    }
+    Ok(samples.into())
+}
+
+/// A decoded clip and how its pad plays it
+pub struct PadClip {
+    pub samples: Clip,
+    pub mode: PadMode,
+    pub gain_db: f32,
+}
+
+struct Voice {
+    clip: Clip,
+    mode: PadMode,
+    gain: f32,
+    // Where a loop wraps, the clip length unless quantised
+    loop_length: usize,
+    position: usize,
+    playing: bool,
+}
+
+impl Voice {
+    fn next_sample(&mut self) -> Option<f32> {
+        let end = match self.mode {
+            PadMode::Loop => self.loop_length,
+            PadMode::OneShot | PadMode::Gated => self.clip.len(),
+        };
+        if self.mode == PadMode::Loop && self.position >= end {
+            self.position = 0;
+        }
+        if self.position >= end {
+            return None;
+        }
+
+        // Loops quantised longer than the clip fill up with silence
+        let sample = self.clip.get(self.position).copied().unwrap_or(0.0);
+        self.position += 1;
+        Some(sample * self.gain)
+    }
+}
+
+/// The pad bank, mixed on top of the decks
+///
+/// Loops are quantised to the sync tempo, or with sync off to the tempo
+/// of the deck last given one.
+#[derive(Default)]
+pub struct Sampler {
+    voices: HashMap<Pad, Voice>,
+    sync_tempo: Option<Tempo>,
+    deck_tempo: Option<Tempo>,
+}
+
+impl Sampler {
+    pub fn load(&mut self, pad: Pad, clip: PadClip) {
+        let voice = Voice {
+            loop_length: self.loop_length(&clip.samples),
+            clip: clip.samples,
+            mode: clip.mode,
+            gain: 10.0f32.powf(clip.gain_db / 20.0),
+            position: 0,
+            playing: false,
+        };
+        self.voices.insert(pad, voice);
+    }
+
+    pub fn unload(&mut self, pad: Pad) {
+        self.voices.remove(&pad);
+    }
+
+    /// Start a pad from the top, also when it is already playing
+    pub fn trigger(&mut self, pad: Pad) {
+        if let Some(voice) = self.voices.get_mut(&pad) {
+            voice.position = 0;
+            voice.playing = true;
+        }
+    }
+
+    /// Let go of a pad, which only stops gated pads
+    pub fn release(&mut self, pad: Pad) {
+        if let Some(voice) = self.voices.get_mut(&pad) {
+            if voice.mode == PadMode::Gated {
+                voice.playing = false;
+            }
+        }
+    }
+
+    pub fn stop(&mut self, pad: Pad) {
+        if let Some(voice) = self.voices.get_mut(&pad) {
+            voice.playing = false;
+        }
+    }
+
+    /// Quantise loops to whole beats at `tempo`, or to the deck tempo when `None`
+    pub fn set_sync_tempo(&mut self, tempo: Option<Tempo>) {
+        self.sync_tempo = tempo;
+        self.requantise();
+    }
+
+    /// Tempo of a deck's track, loops follow it while sync is off
+    pub fn set_deck_tempo(&mut self, tempo: Tempo) {
+        self.deck_tempo = Some(tempo);
+        self.requantise();
+    }
+
+    fn requantise(&mut self) {
+        let lengths: Vec<(Pad, usize)> = self
+            .voices
+            .iter()
+            .map(|(pad, voice)| (*pad, self.loop_length(&voice.clip)))
+            .collect();
+        for (pad, length) in lengths {
+            if let Some(voice) = self.voices.get_mut(&pad) {
+                voice.loop_length = length;
+            }
+        }
+    }
+
+    // Clip length rounded to the nearest whole number of beats, at least one
+    fn loop_length(&self, clip: &Clip) -> usize {
+        let Some(tempo) = self.sync_tempo.or(self.deck_tempo) else {
+            return clip.len();
+        };
+
+        let frames_per_beat = SAMPLE_RATE as f64 * 60.0 / tempo.raw();
+        let beats = (clip.len() as f64 / CHANNELS as f64 / frames_per_beat)
+            .round()
+            .max(1.0);
+        (beats * frames_per_beat).round() as usize * CHANNELS
+    }
+
+    /// Add every playing pad to `output`
+    pub fn mix_into(&mut self, output: &mut [f32]) {
+        for voice in self.voices.values_mut().filter(|voice| voice.playing) {
+            for out in output.iter_mut() {
+                match voice.next_sample() {
+                    Some(sample) => *out += sample,
+                    None => {
+                        voice.playing = false;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::TestSource;
+
+    fn pad(index: u8) -> Pad {
+        Pad::new(index).unwrap()
+    }
+
+    fn clip(length: usize) -> Clip {
+        vec![1.0; length].into()
+    }
+
+    fn load(sampler: &mut Sampler, index: u8, samples: Clip, mode: PadMode, gain_db: f32) {
+        let clip = PadClip {
+            samples,
+            mode,
+            gain_db,
+        };
+        sampler.load(pad(index), clip);
+    }
+
+    fn mix(sampler: &mut Sampler, length: usize) -> Vec<f32> {
+        let mut output = vec![0.0; length];
+        sampler.mix_into(&mut output);
+        output
+    }
+
+    #[test]
+    fn pads_are_silent_until_triggered() {
+        let mut sampler = Sampler::default();
+        load(&mut sampler, 0, clip(8), PadMode::OneShot, 0.0);
+
+        assert_eq!(mix(&mut sampler, 4), [0.0; 4]);
+    }
+
+    #[test]
+    fn one_shot_plays_once_and_ignores_release() {
+        let mut sampler = Sampler::default();
+        load(&mut sampler, 0, clip(4), PadMode::OneShot, 0.0);
+
+        sampler.trigger(pad(0));
+        sampler.release(pad(0));
+
+        assert_eq!(mix(&mut sampler, 6), [1.0, 1.0, 1.0, 1.0, 0.0, 0.0]);
+        assert_eq!(mix(&mut sampler, 2), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn gated_pad_stops_on_release() {
+        let mut sampler = Sampler::default();
+        load(&mut sampler, 0, clip(8), PadMode::Gated, 0.0);
+
+        sampler.trigger(pad(0));
+        assert_eq!(mix(&mut sampler, 2), [1.0, 1.0]);
+        sampler.release(pad(0));
+
+        assert_eq!(mix(&mut sampler, 2), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn loop_repeats_until_stopped() {
+        let mut sampler = Sampler::default();
+        let ramp: Clip = vec![1.0, 2.0, 3.0, 4.0].into();
+        load(&mut sampler, 0, ramp, PadMode::Loop, 0.0);
+
+        sampler.trigger(pad(0));
+        assert_eq!(mix(&mut sampler, 6), [1.0, 2.0, 3.0, 4.0, 1.0, 2.0]);
+        sampler.stop(pad(0));
+
+        assert_eq!(mix(&mut sampler, 2), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn pads_mix_with_their_gain() {
+        let mut sampler = Sampler::default();
+        load(&mut sampler, 0, clip(2), PadMode::OneShot, 0.0);
+        load(&mut sampler, 1, clip(2), PadMode::OneShot, -6.0);
+
+        sampler.trigger(pad(0));
+        sampler.trigger(pad(1));
+
+        let output = mix(&mut sampler, 2);
+        assert!((output[0] - 1.501).abs() < 0.001, "got {}", output[0]);
+    }
+
+    #[test]
+    fn synced_loops_round_to_whole_beats() {
+        let mut sampler = Sampler::default();
+        // 120 BPM is 24000 frames a beat, this clip is a little over one
+        let frames = 25000;
+        load(&mut sampler, 0, clip(frames * CHANNELS), PadMode::Loop, 0.0);
+
+        sampler.set_sync_tempo(Some(Tempo::new(120.0).unwrap()));
+        assert_eq!(sampler.voices[&pad(0)].loop_length, 24000 * CHANNELS);
+
+        sampler.set_sync_tempo(Some(Tempo::new(60.0).unwrap()));
+        assert_eq!(sampler.voices[&pad(0)].loop_length, 48000 * CHANNELS);
+        sampler.trigger(pad(0));
+        let output = mix(&mut sampler, 48000 * CHANNELS + 2);
+        // Padded with silence to the beat, then around again
+        assert_eq!(output[frames * CHANNELS], 0.0);
+        assert_eq!(output[48000 * CHANNELS], 1.0);
+
+        sampler.set_sync_tempo(None);
+        assert_eq!(sampler.voices[&pad(0)].loop_length, frames * CHANNELS);
+    }
+
+    #[test]
+    fn loops_follow_the_deck_while_sync_is_off() {
+        let mut sampler = Sampler::default();
+        load(&mut sampler, 0, clip(25000 * CHANNELS), PadMode::Loop, 0.0);
+
+        sampler.set_deck_tempo(Tempo::new(120.0).unwrap());
+        assert_eq!(sampler.voices[&pad(0)].loop_length, 24000 * CHANNELS);
+
+        // Sync wins while it is on
+        sampler.set_sync_tempo(Some(Tempo::new(60.0).unwrap()));
+        assert_eq!(sampler.voices[&pad(0)].loop_length, 48000 * CHANNELS);
+        sampler.set_sync_tempo(None);
+        assert_eq!(sampler.voices[&pad(0)].loop_length, 24000 * CHANNELS);
+    }
+
+    #[test]
+    fn clips_in_another_format_are_refused() {
+        let source = TestSource::new_with_pattern("sine", 0.1);
+        assert!(load_clip(&source).is_ok());
+
+        let source = TestSource::new_with_pattern("sine", 0.1).with_format(44100, 2);
+        assert!(matches!(
+            load_clip(&source),
+            Err(PlaybackError::ClipFormat(44100, 2))
+        ));
+        let source = TestSource::new_with_pattern("sine", 0.1).with_format(48000, 1);
+        assert!(matches!(
+            load_clip(&source),
+            Err(PlaybackError::ClipFormat(48000, 1))
+        ));
+    }
+}
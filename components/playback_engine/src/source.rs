@@ -73,6 +73,104 @@ pub trait Source: Send + Sync {
     fn audio_channels(&self) -> u16;
     // New method to get current position
     fn current_position(&self) -> usize;
+    // Total number of samples, if the container tells us
+    fn length(&self) -> Option<usize>;
+}
+
+/// Decode one frame as plain samples, without the padding after the last one
+///
+/// Only the final segment of a frame is padded, and only when the source
+/// counts what it really decoded in `current_position`.
+pub(crate) fn decode_samples(source: &dyn Source) -> Result<Vec<f32>, PlaybackError> {
+    let before = source.current_position();
+    let segments = source.decode_next_frame()?;
+    let mut samples: Vec<f32> = segments
+        .iter()
+        .flat_map(|segment| segment.segment.samples)
+        .collect();
+    let decoded = source.current_position().saturating_sub(before);
+    if decoded > 0 {
+        samples.truncate(decoded);
+    }
+    Ok(samples)
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn decode_next_frame(&self) -> Result<Vec<DecodedSegment>, PlaybackError> {
+        (**self).decode_next_frame()
+    }
+
+    fn seek(&self, position: usize) -> Result<(), PlaybackError> {
+        (**self).seek(position)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        (**self).sample_rate()
+    }
+
+    fn audio_channels(&self) -> u16 {
+        (**self).audio_channels()
+    }
+
+    fn current_position(&self) -> usize {
+        (**self).current_position()
+    }
+
+    fn length(&self) -> Option<usize> {
+        (**self).length()
+    }
+}
+
+/// Endless 48 kHz stereo computed a frame at a time, for playing without files
+///
+/// `render` gives the value of both channels at a frame.
+pub struct FnSource<F> {
+    render: F,
+    position: AtomicUsize,
+}
+
+impl<F: Fn(usize) -> f32 + Send + Sync> FnSource<F> {
+    pub fn new(render: F) -> Self {
+        Self {
+            render,
+            position: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<F: Fn(usize) -> f32 + Send + Sync> Source for FnSource<F> {
+    fn decode_next_frame(&self) -> Result<Vec<DecodedSegment>, PlaybackError> {
+        let start = self.position.fetch_add(SEGMENT_SIZE, Ordering::Relaxed);
+        let mut samples = [0.0; SEGMENT_SIZE];
+        for (offset, pair) in samples.chunks_exact_mut(2).enumerate() {
+            pair.fill((self.render)(start / 2 + offset));
+        }
+        Ok(vec![DecodedSegment {
+            index: SegmentIndex::from_sample_position(start),
+            segment: AudioSegment { samples },
+        }])
+    }
+
+    fn seek(&self, position: usize) -> Result<(), PlaybackError> {
+        self.position.store(position, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        48000
+    }
+
+    fn audio_channels(&self) -> u16 {
+        2
+    }
+
+    fn current_position(&self) -> usize {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    fn length(&self) -> Option<usize> {
+        None
+    }
 }
 
 pub struct FlacSource {
@@ -85,6 +183,7 @@ pub struct FlacSource {
     // Basic metadata
     sample_rate: u32,
     audio_channels: u16,
+    length: Option<usize>,
 
     // End-of-file status
     is_eof: AtomicBool,
@@ -101,6 +200,7 @@ type DecoderResult = Result<
         Box<dyn symphonia::core::codecs::Decoder>,
         u32,
         u16,
+        Option<usize>,
     ),
     PlaybackError,
 >;
@@ -109,7 +209,7 @@ impl FlacSource {
     pub fn new(path: impl AsRef<Path>) -> Result<Self, PlaybackError> {
         tracing::debug!("Opening file: {:?}", path.as_ref());
         // Initialize the decoder and format reader
-        let (format_reader, decoder, sample_rate, audio_channels) =
+        let (format_reader, decoder, sample_rate, audio_channels, length) =
             Self::init_decoder(path.as_ref())?;
 
         // Create the decoder state
@@ -124,6 +224,7 @@ impl FlacSource {
             current_position: AtomicUsize::new(0),
             sample_rate,
             audio_channels,
+            length,
             is_eof: AtomicBool::new(false),
         };
 
@@ -155,13 +256,18 @@ impl FlacSource {
 
         let audio_channels = track.codec_params.channels.map(|c| c.count()).unwrap_or(2) as u16;
         let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
+        // Positions count interleaved samples, frames hold one per channel
+        let length = track
+            .codec_params
+            .n_frames
+            .map(|frames| frames as usize * audio_channels as usize);
 
         // Create decoder
         let decoder = symphonia::default::get_codecs()
             .make(&track.codec_params, &DecoderOptions::default())
             .map_err(|e| PlaybackError::Decoder(e.to_string()))?;
 
-        Ok((probed.format, decoder, sample_rate, audio_channels))
+        Ok((probed.format, decoder, sample_rate, audio_channels, length))
     }
 
     fn position_to_time(&self, position: usize) -> Time {
@@ -303,6 +409,10 @@ impl Source for FlacSource {
     fn current_position(&self) -> usize {
         self.current_position.load(Ordering::Relaxed)
     }
+
+    fn length(&self) -> Option<usize> {
+        self.length
+    }
 }
 
 impl Drop for FlacSource {
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+use playback_primitives::Deck;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// What a deck is doing at the moment it was asked
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeckState {
+    pub deck: Deck,
+    pub volume_db: f32,
+    /// Silenced by a cue, the volume is kept for when it lifts
+    pub muted: bool,
+    /// `None` when nothing is loaded
+    pub track: Option<TrackState>,
+    /// After effects and volume, before the master bus
+    pub level: Level,
+}
+
+/// The track loaded on a deck
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackState {
+    pub path: PathBuf,
+    pub playing: bool,
+    /// Interleaved samples played so far
+    pub position: usize,
+    /// Total interleaved samples, when the file says
+    pub length: Option<usize>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Times the deck has moved on to the next queue entry since the load
+    pub advances: u64,
+}
+
+/// Meter reading in dBFS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    /// Recent peak, falling back slowly
+    pub peak_db: f32,
+    /// Average power over the last few hundred milliseconds
+    pub rms_db: f32,
+}
+
+impl Level {
+    /// Lowest reading, anything quieter shows as this
+    pub const SILENT: Level = Level {
+        peak_db: -96.0,
+        rms_db: -96.0,
+    };
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Self::SILENT
+    }
+}
+
+/// Every meter the mixer keeps
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Levels {
+    /// Decks that have had a track loaded
+    pub decks: HashMap<Deck, Level>,
+    /// The output, after the limiter
+    pub master: Level,
+    /// Samples over full scale the limiter had to catch since startup
+    pub clips: u64,
+    /// Gain the limiter is taking off the master right now
+    pub reduction_db: f32,
+}
+
+/// Glitches one deck has had, or all of them together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeckStats {
+    /// Blocks the deck was playing but had too little decoded audio for
+    pub buffer_underruns: u64,
+    /// Frames the decoder failed on and skipped
+    pub decode_errors: u64,
+    /// Seeks the decoder was slow to carry out
+    pub late_seeks: u64,
+}
+
+/// Audio health since the engine started
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    /// Decks that have had a track loaded
+    pub decks: HashMap<Deck, DeckStats>,
+    /// Every deck added up
+    pub totals: DeckStats,
+    /// Times the device got silence because the mixer fell behind
+    pub output_underruns: u64,
+}
+
+/// What a deck plays once the current track ends
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct QueueState {
+    pub entries: Vec<PathBuf>,
+    /// Overlap between entries, 0 for a gapless join
+    pub crossfade_seconds: f32,
+}
+
+/// A recording of the master output, as it stands or as it ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingState {
+    pub path: PathBuf,
+    pub started: DateTime<Utc>,
+    /// Mix captured so far
+    pub seconds: f64,
+    /// Samples lost because the disk couldn't keep up
+    pub dropped_samples: u64,
+}
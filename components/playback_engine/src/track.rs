@@ -1,86 +1,381 @@
 use crate::error::PlaybackError;
+use crate::health::DeckHealth;
+use crate::queue::{open_flac, Crossfade, Queue};
+use crate::source::{decode_samples, Source};
 #[cfg(test)]
-use crate::source::{AudioSegment, SegmentIndex, SEGMENT_SIZE};
-use crate::source::{DecodedSegment, Source};
+use crate::source::{AudioSegment, DecodedSegment, SegmentIndex, SEGMENT_SIZE};
 
+use parking_lot::Mutex;
 use tokio::sync::mpsc;
 
-use std::collections::VecDeque;
-#[cfg(test)]
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use std::sync::{atomic::AtomicBool, Arc};
+use std::time::{Duration, Instant};
 
 use ringbuf::HeapProducer;
 #[cfg(test)]
 use ringbuf::HeapRb;
 
 pub struct Track {
-    playing: Arc<AtomicBool>,
+    playhead: Arc<Playhead>,
+    sample_rate: u32,
+    channels: u16,
     command_tx: mpsc::Sender<TrackCommand>,
     decoder_task: Option<tokio::task::JoinHandle<()>>,
 }
 
+// How long to leave a full buffer before topping it up, a few ms of the
+// deck's buffered audio
+const FULL_BUFFER_NAP: Duration = Duration::from_millis(5);
+
+// A seek that takes longer than this to reach the decoder is heard as a
+// stumble, the mixer plays on from the old position until then
+const LATE_SEEK: Duration = Duration::from_millis(50);
+
+// No entry waiting to take over
+const NO_NEXT: usize = usize::MAX;
+
 // Update TrackCommand to include potential new commands
 pub enum TrackCommand {
-    FillFrom(usize),
+    FillFrom { position: usize, requested: Instant },
+    Skip,
     Shutdown,
 }
 
-async fn decoder_task<S: Source + Send + Sync + 'static>(
-    source: S,
+/// A file played on a deck, the first one loaded or one from the queue
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub path: PathBuf,
+    pub length: Option<usize>,
+}
+
+struct Entries {
+    current: Entry,
+    next: Option<Entry>,
+    advances: u64,
+}
+
+/// Playback state a track shares with the mixer
+///
+/// The decoder runs ahead of what is heard, so when it starts on the next
+/// queue entry it only says where in the deck's buffer that entry begins.
+/// The mixer switches over when it gets there.
+pub(crate) struct Playhead {
+    pub playing: AtomicBool,
+    /// Samples of the current entry played, moved by seeks
+    pub position: AtomicUsize,
+    /// The decoder has nothing more to give, an empty buffer is expected
+    pub exhausted: AtomicBool,
+    // Samples through the buffer where the next entry begins
+    next_at: AtomicUsize,
+    // Drop the rest of the current entry from the buffer, it was skipped
+    flush: AtomicBool,
+    entries: Mutex<Entries>,
+}
+
+impl Playhead {
+    pub fn new(entry: Entry) -> Self {
+        Self {
+            playing: AtomicBool::new(false),
+            position: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+            next_at: AtomicUsize::new(NO_NEXT),
+            flush: AtomicBool::new(false),
+            entries: Mutex::new(Entries {
+                current: entry,
+                next: None,
+                advances: 0,
+            }),
+        }
+    }
+
+    /// Count `samples` played, `played` in all since the track was created
+    pub fn advance(&self, samples: usize, played: usize) {
+        let next_at = self.next_at.load(Ordering::Acquire);
+        if played >= next_at {
+            self.take_next();
+            self.position.store(played - next_at, Ordering::Relaxed);
+        } else {
+            self.position.fetch_add(samples, Ordering::Relaxed);
+        }
+    }
+
+    /// Buffered samples to throw away before the next entry, after a skip
+    pub fn stale(&self, played: usize) -> usize {
+        if !self.flush.swap(false, Ordering::Acquire) {
+            return 0;
+        }
+        let next_at = self.next_at.load(Ordering::Acquire);
+        if next_at == NO_NEXT {
+            0
+        } else {
+            next_at.saturating_sub(played)
+        }
+    }
+
+    /// The decoder started on `entry`, heard once `at` samples have played
+    pub fn announce(&self, entry: Entry, at: usize) {
+        self.entries.lock().next = Some(entry);
+        self.next_at.store(at, Ordering::Release);
+        self.exhausted.store(false, Ordering::Relaxed);
+    }
+
+    /// The next entry is already in the buffer, waiting to be heard
+    pub fn is_waiting(&self) -> bool {
+        self.next_at.load(Ordering::Acquire) != NO_NEXT
+    }
+
+    fn take_next(&self) {
+        self.next_at.store(NO_NEXT, Ordering::Release);
+        let mut entries = self.entries.lock();
+        if let Some(next) = entries.next.take() {
+            entries.current = next;
+            entries.advances += 1;
+        }
+    }
+
+    fn cancel_next(&self) -> Option<Entry> {
+        self.next_at.store(NO_NEXT, Ordering::Release);
+        self.entries.lock().next.take()
+    }
+
+    pub fn current(&self) -> Entry {
+        self.entries.lock().current.clone()
+    }
+}
+
+/// Decodes the current entry into the deck's buffer, then the queue
+struct Decoder {
+    source: Box<dyn Source>,
+    // The entry still being heard once `source` has moved on to the next
+    outgoing: Option<Box<dyn Source>>,
+    // The first queue entry, opened ahead of its turn and put back
+    prefetched: Option<(PathBuf, Box<dyn Source>)>,
+    fade: Option<Crossfade>,
+    queue: Queue,
+    playhead: Arc<Playhead>,
+    health: DeckHealth,
+    // Samples pushed into the buffer since the track was created
+    pushed: usize,
+}
+
+impl Decoder {
+    /// Samples to push next, `None` when there is nothing to decode for now
+    fn next_chunk(&mut self) -> Option<Vec<f32>> {
+        if !self.playhead.is_waiting() {
+            self.outgoing = None;
+        }
+        if self.fade.is_none() {
+            self.start_crossfade();
+        }
+
+        match decode_samples(&*self.source) {
+            Ok(mut samples) if !samples.is_empty() => {
+                if let Some(fade) = &mut self.fade {
+                    fade.mix(&mut samples, &self.health);
+                }
+                Some(samples)
+            }
+            Ok(_) => self.end_of_entry(),
+            Err(error) => {
+                tracing::error!("failed to decode segment: {error}");
+                self.health.decode_error();
+                None
+            }
+        }
+    }
+
+    fn end_of_entry(&mut self) -> Option<Vec<f32>> {
+        // The incoming entry carries on from where the fade got to
+        if let Some(fade) = self.fade.take() {
+            let (source, rest) = fade.finish();
+            self.outgoing = Some(std::mem::replace(&mut self.source, source));
+            return Some(rest);
+        }
+
+        // Past the end, nothing to do until a seek or an enqueue
+        if self.queue.is_empty() {
+            self.playhead.exhausted.store(true, Ordering::Relaxed);
+            return None;
+        }
+        // Another entry still has to be heard before this one can start
+        if self.playhead.is_waiting() {
+            return None;
+        }
+        match self.open_next() {
+            Some((path, source)) => {
+                tracing::info!("Next in the queue: {}", path.display());
+                self.start(path, source);
+                Some(Vec::new())
+            }
+            // Nothing in the queue would open
+            None => {
+                self.playhead.exhausted.store(true, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    // Start fading in the next entry once the current one is close enough
+    // to its end, which needs its length
+    fn start_crossfade(&mut self) {
+        let seconds = self.queue.crossfade_seconds();
+        if seconds <= 0.0 || self.playhead.is_waiting() || self.queue.is_empty() {
+            return;
+        }
+        let Some(length) = self.source.length() else {
+            return;
+        };
+        let remaining = length.saturating_sub(self.source.current_position());
+        let samples_per_second =
+            self.source.sample_rate() as f32 * self.source.audio_channels() as f32;
+        if remaining == 0 || remaining > (seconds * samples_per_second) as usize {
+            return;
+        }
+
+        if let Some((path, incoming)) = self.open_next() {
+            // Mixed sample for sample, anything else is cut to instead
+            let format = (incoming.sample_rate(), incoming.audio_channels());
+            if format != (self.source.sample_rate(), self.source.audio_channels()) {
+                tracing::debug!("Not fading into {}, its format differs", path.display());
+                self.put_back(path, incoming);
+                return;
+            }
+            tracing::info!("Fading into {} over {} samples", path.display(), remaining);
+            let entry = Entry {
+                path: path.clone(),
+                length: incoming.length(),
+            };
+            self.playhead.announce(entry, self.pushed);
+            self.fade = Some(Crossfade::new(path, incoming, remaining));
+        }
+    }
+
+    fn start(&mut self, path: PathBuf, source: Box<dyn Source>) {
+        let entry = Entry {
+            path,
+            length: source.length(),
+        };
+        self.playhead.announce(entry, self.pushed);
+        self.outgoing = Some(std::mem::replace(&mut self.source, source));
+    }
+
+    /// Next queue entry that opens, the prefetched one if it is still first
+    fn open_next(&mut self) -> Option<(PathBuf, Box<dyn Source>)> {
+        if let Some((path, source)) = self.prefetched.take() {
+            if self.queue.take_front_if(&path) {
+                return Some((path, source));
+            }
+        }
+        self.queue.open_next(&self.health)
+    }
+
+    /// Return an entry to the front of the queue, keeping it open for its turn
+    fn put_back(&mut self, path: PathBuf, source: Box<dyn Source>) {
+        self.queue.push_front(path.clone());
+        if source.seek(0).is_ok() {
+            self.prefetched = Some((path, source));
+        }
+    }
+
+    fn seek(&mut self, position: usize, requested: Instant) {
+        tracing::debug!("seek to {position}");
+        // Seeks are within what is heard, not what is fading in or waiting
+        if let Some(fade) = self.fade.take() {
+            self.playhead.cancel_next();
+            self.put_back(fade.path, fade.incoming);
+        } else if self.playhead.is_waiting() {
+            match self.outgoing.take() {
+                // Back to the one heard, unless the mixer just got to the next
+                Some(outgoing) => {
+                    if let Some(next) = self.playhead.cancel_next() {
+                        let incoming = std::mem::replace(&mut self.source, outgoing);
+                        self.put_back(next.path, incoming);
+                    }
+                }
+                // Skipped to, so the next entry is the one to seek in
+                None => self.playhead.take_next(),
+            }
+        }
+        self.playhead.exhausted.store(false, Ordering::Relaxed);
+
+        if let Err(res) = self.source.seek(position) {
+            tracing::error!("failed to seek {res}");
+            self.health.decode_error();
+        } else {
+            tracing::debug!("seeked to position {position}");
+        }
+        let took = requested.elapsed();
+        if took > LATE_SEEK {
+            tracing::warn!("seek to {position} took {took:?}");
+            self.health.late_seek();
+        }
+    }
+
+    fn skip(&mut self) {
+        if let Some(fade) = self.fade.take() {
+            // Already under way, let it have the deck to itself
+            self.source = fade.finish().0;
+        } else if !self.playhead.is_waiting() {
+            let Some((path, source)) = self.open_next() else {
+                return;
+            };
+            tracing::info!("Skipping to {}", path.display());
+            self.start(path, source);
+        }
+        // Don't make anyone sit through what is left in the buffer
+        self.playhead.flush.store(true, Ordering::Release);
+        self.outgoing = None;
+    }
+}
+
+async fn decoder_task(
+    mut decoder: Decoder,
     mut output: HeapProducer<f32>,
     mut command_rx: mpsc::Receiver<TrackCommand>,
 ) {
-    let mut decoded_segments = VecDeque::new();
-    let mut next_segment: Option<DecodedSegment> = None;
+    let mut chunk = Vec::new();
     let mut written: usize = 0;
 
     loop {
-        if decoded_segments.is_empty() {
-            tracing::debug!("no more segments, decode");
-            match source.decode_next_frame() {
-                Ok(next_frame) => {
-                    for segment in next_frame {
-                        decoded_segments.push_back(segment);
-                    }
-                }
-                Err(error) => {
-                    tracing::error!("failed to decode segment: {error}");
+        if written == chunk.len() {
+            match decoder.next_chunk() {
+                Some(samples) => {
+                    chunk = samples;
+                    written = 0;
                 }
+                None => tokio::time::sleep(FULL_BUFFER_NAP).await,
             }
         }
 
-        if let Some(ref segment) = next_segment {
-            let to_write = segment.segment.samples.len() - written;
-            let actually_written = output.push_slice(&segment.segment.samples[written..]);
+        if written < chunk.len() {
+            let actually_written = output.push_slice(&chunk[written..]);
             written += actually_written;
+            decoder.pushed += actually_written;
 
-            // Check if we've written the entire segment
-            if written == segment.segment.samples.len() {
-                next_segment = None;
-                written = 0;
-            }
-
-            // If we couldn't write everything, yield to let the mixer consume some data
-            if actually_written < to_write {
-                tokio::task::yield_now().await;
+            // If we couldn't write everything, let the mixer consume some data
+            if written < chunk.len() {
+                tokio::time::sleep(FULL_BUFFER_NAP).await;
             }
-        } else {
-            next_segment = decoded_segments.pop_front();
         }
 
         while let Ok(command) = command_rx.try_recv() {
             match command {
-                TrackCommand::FillFrom(position) => {
-                    tracing::debug!("seek to {position}");
-                    if let Err(res) = source.seek(position) {
-                        tracing::error!("failed to seek {res}");
-                    } else {
-                        //current_position = position;
-                        tracing::debug!("seeked to position {position}");
-                    }
+                TrackCommand::FillFrom {
+                    position,
+                    requested,
+                } => {
+                    // Whatever was decoded before the seek is stale
+                    chunk.clear();
+                    written = 0;
+                    decoder.seek(position, requested);
+                }
+                TrackCommand::Skip => {
+                    chunk.clear();
+                    written = 0;
+                    decoder.skip();
                 }
                 TrackCommand::Shutdown => {
                     tracing::info!("Decoder task received shutdown command");
@@ -95,18 +390,56 @@ impl Track {
         source: S,
         output_producer: HeapProducer<f32>,
     ) -> Result<Self, PlaybackError> {
-        let playing = Arc::new(AtomicBool::new(false));
+        let queue = Queue::new(open_flac());
+        Self::start(
+            PathBuf::new(),
+            Box::new(source),
+            output_producer,
+            DeckHealth::default(),
+            queue,
+        )
+        .await
+    }
+
+    /// Track for the file at `path`, going on to `queue` when it ends
+    ///
+    /// Decode errors and late seeks are counted in `health`.
+    pub(crate) async fn start(
+        path: PathBuf,
+        source: Box<dyn Source>,
+        output_producer: HeapProducer<f32>,
+        health: DeckHealth,
+        queue: Queue,
+    ) -> Result<Self, PlaybackError> {
+        let sample_rate = source.sample_rate();
+        let channels = source.audio_channels();
+        let playhead = Arc::new(Playhead::new(Entry {
+            path,
+            length: source.length(),
+        }));
 
         // Command channels
         let (command_tx, command_rx) = mpsc::channel(32);
 
         // Create decoder task
+        let decoder = Decoder {
+            source,
+            outgoing: None,
+            prefetched: None,
+            fade: None,
+            queue,
+            playhead: playhead.clone(),
+            health,
+            pushed: 0,
+        };
         let decoder_task = tokio::spawn(async move {
-            decoder_task(source, output_producer, command_rx).await;
+            decoder_task(decoder, output_producer, command_rx).await;
         });
 
         let track = Self {
-            playing,
+            playhead,
+            sample_rate,
+            channels,
             command_tx,
             decoder_task: Some(decoder_task),
         };
@@ -116,25 +449,71 @@ impl Track {
 
     // Update seek to use the tracker
     pub fn seek(&mut self, position: usize) -> Result<(), PlaybackError> {
+        self.playhead.position.store(position, Ordering::Relaxed);
+
         // Request buffer filling from new position (unchanged)
-        if let Err(e) = self.command_tx.try_send(TrackCommand::FillFrom(position)) {
+        let command = TrackCommand::FillFrom {
+            position,
+            requested: Instant::now(),
+        };
+        if let Err(e) = self.command_tx.try_send(command) {
             tracing::error!("Failed to send fill command after seek: {}", e);
         }
 
         Ok(())
     }
 
+    /// Go straight on to the next queue entry
+    pub(crate) fn skip(&self) -> Result<(), PlaybackError> {
+        self.command_tx
+            .try_send(TrackCommand::Skip)
+            .map_err(|_| PlaybackError::TaskCancelled)
+    }
+
     pub fn play(&mut self) {
-        self.playing.store(true, Ordering::Relaxed);
+        self.playhead.playing.store(true, Ordering::Relaxed);
         tracing::info!("Track set to playing state");
     }
 
     pub fn stop(&mut self) {
-        self.playing.store(false, Ordering::Relaxed);
+        self.playhead.playing.store(false, Ordering::Relaxed);
     }
 
     pub fn is_playing(&self) -> bool {
-        self.playing.load(Ordering::Relaxed)
+        self.playhead.playing.load(Ordering::Relaxed)
+    }
+
+    /// Samples of the current entry played so far, as counted by the mixer
+    pub fn position(&self) -> usize {
+        self.playhead.position.load(Ordering::Relaxed)
+    }
+
+    /// Length of the current entry
+    pub fn length(&self) -> Option<usize> {
+        self.playhead.current().length
+    }
+
+    /// File being heard, the loaded one until the queue moves on
+    pub(crate) fn path(&self) -> PathBuf {
+        self.playhead.current().path
+    }
+
+    /// Queue entries moved on to since the track was loaded
+    pub(crate) fn advances(&self) -> u64 {
+        self.playhead.entries.lock().advances
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    // Shared with the mixer so it can skip stopped tracks and count what it plays
+    pub(crate) fn playhead(&self) -> Arc<Playhead> {
+        self.playhead.clone()
     }
 }
 
@@ -167,6 +546,8 @@ pub struct TestSource {
     position: AtomicUsize, // Track which frame we're on
     samples: Vec<Vec<DecodedSegment>>,
     current_sample_position: AtomicUsize, // Track current sample position
+    sample_rate: u32,
+    channels: u16,
 }
 
 #[cfg(test)]
@@ -177,9 +558,18 @@ impl TestSource {
             position: AtomicUsize::new(0),
             samples: vec![segments], // Wrap in vector to simulate frames
             current_sample_position: AtomicUsize::new(0), // Initialize to 0
+            sample_rate: 48000,
+            channels: 2,
         }
     }
 
+    /// Claim another format, the samples stay as they are
+    pub fn with_format(mut self, sample_rate: u32, channels: u16) -> Self {
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+        self
+    }
+
     // Create decoded segments from a flat vector of samples
     fn create_segments_from_samples(samples: Vec<f32>) -> Vec<DecodedSegment> {
         let mut segments = Vec::new();
@@ -356,16 +746,26 @@ impl Source for TestSource {
     }
 
     fn sample_rate(&self) -> u32 {
-        48000
+        self.sample_rate
     }
 
     fn audio_channels(&self) -> u16 {
-        2
+        self.channels
     }
 
     fn current_position(&self) -> usize {
         self.current_sample_position.load(Ordering::Relaxed)
     }
+
+    fn length(&self) -> Option<usize> {
+        Some(
+            self.samples
+                .iter()
+                .map(|segments| segments.len())
+                .sum::<usize>()
+                * SEGMENT_SIZE,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -391,3 +791,184 @@ impl Track {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Steady samples, one segment at a time, with a real end like a file
+    struct Steady {
+        value: f32,
+        length: usize,
+        position: AtomicUsize,
+    }
+
+    impl Steady {
+        fn boxed(value: f32, length: usize) -> Box<dyn Source> {
+            Box::new(Self {
+                value,
+                length,
+                position: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    impl Source for Steady {
+        fn decode_next_frame(&self) -> Result<Vec<DecodedSegment>, PlaybackError> {
+            let start = self.position.load(Ordering::Relaxed);
+            let count = SEGMENT_SIZE.min(self.length - start);
+            if count == 0 {
+                return Ok(Vec::new());
+            }
+            let mut samples = [0.0; SEGMENT_SIZE];
+            samples[..count].fill(self.value);
+            self.position.store(start + count, Ordering::Relaxed);
+            Ok(vec![DecodedSegment {
+                index: SegmentIndex::from_sample_position(start),
+                segment: AudioSegment { samples },
+            }])
+        }
+
+        fn seek(&self, position: usize) -> Result<(), PlaybackError> {
+            self.position
+                .store(position.min(self.length), Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn sample_rate(&self) -> u32 {
+            48000
+        }
+
+        fn audio_channels(&self) -> u16 {
+            2
+        }
+
+        fn current_position(&self) -> usize {
+            self.position.load(Ordering::Relaxed)
+        }
+
+        fn length(&self) -> Option<usize> {
+            Some(self.length)
+        }
+    }
+
+    // Queue where every file is 4000 samples of 0.5
+    fn queue_of(paths: &[&str]) -> Queue {
+        let queue = Queue::new(Arc::new(|_| Ok(Steady::boxed(0.5, 4000))));
+        for path in paths {
+            queue.push(PathBuf::from(path));
+        }
+        queue
+    }
+
+    // Run a track on `first` until the decoder has run out, returning all it wrote
+    async fn decode_all(first: Box<dyn Source>, queue: Queue) -> (Track, Vec<f32>) {
+        let (producer, mut consumer) = HeapRb::<f32>::new(32768).split();
+        let track = Track::start(
+            PathBuf::from("/music/first.flac"),
+            first,
+            producer,
+            DeckHealth::default(),
+            queue,
+        )
+        .await
+        .unwrap();
+
+        let playhead = track.playhead();
+        while !playhead.exhausted.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let mut samples = vec![0.0; consumer.len()];
+        consumer.pop_slice(&mut samples);
+        (track, samples)
+    }
+
+    #[tokio::test]
+    async fn queued_entries_follow_without_a_gap() {
+        let queue = queue_of(&["/music/second.flac"]);
+        let (track, samples) = decode_all(Steady::boxed(0.25, 3000), queue).await;
+
+        // No padding between the two, even though 3000 isn't whole segments
+        assert_eq!(samples.len(), 7000);
+        assert!(samples[..3000].iter().all(|sample| *sample == 0.25));
+        assert!(samples[3000..].iter().all(|sample| *sample == 0.5));
+
+        let playhead = track.playhead();
+        playhead.advance(2999, 2999);
+        assert_eq!(track.path(), PathBuf::from("/music/first.flac"));
+        playhead.advance(4001, 7000);
+        assert_eq!(track.path(), PathBuf::from("/music/second.flac"));
+        assert_eq!(track.position(), 4000);
+        assert_eq!(track.length(), Some(4000));
+        assert_eq!(track.advances(), 1);
+    }
+
+    #[tokio::test]
+    async fn seeks_stay_in_the_entry_heard_while_the_next_waits() {
+        let queue = queue_of(&["/music/second.flac"]);
+        let (producer, mut consumer) = HeapRb::<f32>::new(32768).split();
+        let mut track = Track::start(
+            PathBuf::from("/music/first.flac"),
+            Steady::boxed(0.25, 3000),
+            producer,
+            DeckHealth::default(),
+            queue.clone(),
+        )
+        .await
+        .unwrap();
+        let playhead = track.playhead();
+        while !playhead.exhausted.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        consumer.skip(7000);
+        assert!(playhead.is_waiting());
+
+        // The second is back in the queue, and follows the rest of the first
+        track.seek(1000).unwrap();
+        while consumer.len() < 6000 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(queue.is_empty());
+        let mut samples = vec![0.0; consumer.len()];
+        consumer.pop_slice(&mut samples);
+        assert_eq!(samples.len(), 6000);
+        assert!(samples[..2000].iter().all(|sample| *sample == 0.25));
+        assert!(samples[2000..].iter().all(|sample| *sample == 0.5));
+
+        assert_eq!(track.path(), PathBuf::from("/music/first.flac"));
+        playhead.advance(2000, 9000);
+        assert_eq!(track.path(), PathBuf::from("/music/second.flac"));
+        assert_eq!(track.position(), 0);
+    }
+
+    #[tokio::test]
+    async fn entries_in_another_format_are_cut_to() {
+        let queue = Queue::new(Arc::new(|_| {
+            let source = TestSource::new_from_samples(vec![0.5; 4096]);
+            Ok(Box::new(source.with_format(44100, 2)))
+        }));
+        queue.push(PathBuf::from("/music/second.flac"));
+        queue.set_crossfade_seconds(0.01);
+        let (_, samples) = decode_all(Steady::boxed(0.5, 4000), queue).await;
+
+        assert_eq!(samples.len(), 4000 + 4096);
+        assert!(samples.iter().all(|sample| *sample == 0.5));
+    }
+
+    #[tokio::test]
+    async fn crossfades_overlap_the_end_of_each_entry() {
+        let queue = queue_of(&["/music/second.flac"]);
+        // 10 ms, 960 samples, so the fade starts with the last segment
+        queue.set_crossfade_seconds(0.01);
+        let (track, samples) = decode_all(Steady::boxed(0.5, 4000), queue).await;
+
+        // The last 928 samples of the first play over the start of the second
+        assert_eq!(samples.len(), 4000 + 4000 - 928);
+        assert!(samples[3072 + 464] > 0.6);
+        assert!(samples[4000..].iter().all(|sample| *sample == 0.5));
+
+        track.playhead().advance(3072, 3072);
+        assert_eq!(track.path(), PathBuf::from("/music/second.flac"));
+        assert_eq!(track.position(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::Deck;
+use serde::{Deserialize, Serialize};
+
+/// Where an effect sits in the signal path
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EffectTarget {
+    /// Before the deck's volume
+    Deck(Deck),
+    /// After every deck and pad is summed
+    Master,
+}
+
+/// The effects a chain can hold, each at most once
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EffectKind {
+    Filter,
+    Echo,
+    Reverb,
+}
+
+/// Parameters of one effect, which also say which effect it is
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "effect")]
+pub enum EffectParams {
+    /// Sweep filter, -1 closes the low pass, 1 closes the high pass, 0 is open
+    Filter { amount: f32 },
+    /// Delay of `beats` at the tempo, repeating with `feedback` (0 to 0.95)
+    Echo { beats: f32, feedback: f32 },
+    /// Room size and high frequency damping, both 0 to 1
+    Reverb { room_size: f32, damping: f32 },
+}
+
+impl EffectParams {
+    pub fn kind(&self) -> EffectKind {
+        match self {
+            Self::Filter { .. } => EffectKind::Filter,
+            Self::Echo { .. } => EffectKind::Echo,
+            Self::Reverb { .. } => EffectKind::Reverb,
+        }
+    }
+
+    /// Describe the first parameter that is out of range
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Self::Filter { amount } => check("amount", amount, -1.0, 1.0),
+            Self::Echo { beats, feedback } => {
+                check("beats", beats, 1.0 / 16.0, 4.0)?;
+                check("feedback", feedback, 0.0, 0.95)
+            }
+            Self::Reverb { room_size, damping } => {
+                check("room_size", room_size, 0.0, 1.0)?;
+                check("damping", damping, 0.0, 1.0)
+            }
+        }
+    }
+}
+
+/// An effect as a whole: on or off, how much of it is heard, and its parameters
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EffectSettings {
+    pub enabled: bool,
+    /// 0 is only the dry signal, 1 only the effect
+    pub wet: f32,
+    pub params: EffectParams,
+}
+
+impl EffectSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        check("wet", self.wet, 0.0, 1.0)?;
+        self.params.validate()
+    }
+}
+
+fn check(name: &str, value: f32, min: f32, max: f32) -> Result<(), String> {
+    if (min..=max).contains(&value) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} must be between {} and {}, got {}",
+            name, min, max, value
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialization() {
+        let settings = EffectSettings {
+            enabled: true,
+            wet: 0.5,
+            params: EffectParams::Echo {
+                beats: 0.75,
+                feedback: 0.4,
+            },
+        };
+        let json = serde_json::to_string(&settings).unwrap();
+        assert!(json.contains("\"effect\":\"echo\""), "{}", json);
+        let decoded: EffectSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, settings);
+
+        let target = serde_json::to_string(&EffectTarget::Deck(Deck::B)).unwrap();
+        assert_eq!(target, r#"{"deck":"B"}"#);
+        assert_eq!(
+            serde_json::to_string(&EffectTarget::Master).unwrap(),
+            r#""master""#
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_parameters() {
+        let mut settings = EffectSettings {
+            enabled: true,
+            wet: 1.5,
+            params: EffectParams::Filter { amount: 0.2 },
+        };
+        assert!(settings.validate().unwrap_err().contains("wet"));
+
+        settings.wet = 1.0;
+        assert!(settings.validate().is_ok());
+
+        settings.params = EffectParams::Echo {
+            beats: 0.5,
+            feedback: 1.0,
+        };
+        assert!(settings.validate().unwrap_err().contains("feedback"));
+    }
+}
@@ -1,5 +1,9 @@
+mod effect;
+
 use std::fmt::Display;
+use std::str::FromStr;
 
+pub use effect::{EffectKind, EffectParams, EffectSettings, EffectTarget};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -7,6 +11,8 @@ use thiserror::Error;
 pub enum PlaybackError {
     #[error("Invalid channel number")]
     InvalidChannel,
+    #[error("Invalid pad number")]
+    InvalidPad,
     #[error("Value out of range")]
     ValueOutOfRange,
 }
@@ -48,31 +54,129 @@ impl Db for Volume {
 }
 
 /// Identifies a playback channel (deck)
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
-pub enum Deck {
-    A,
-    B,
+///
+/// Decks are numbered from 0 and named by letter, A being the first. They
+/// serialize as their letter, so messages written for the two deck days
+/// still read the same.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Deck(u8);
+
+impl Deck {
+    /// How many decks a unit can have
+    pub const MAX: u8 = 4;
+
+    pub const A: Self = Self(0);
+    pub const B: Self = Self(1);
+    pub const C: Self = Self(2);
+    pub const D: Self = Self(3);
+
+    pub fn new(deck: u8) -> Result<Self, PlaybackError> {
+        if deck < Self::MAX {
+            Ok(Self(deck))
+        } else {
+            Err(PlaybackError::InvalidChannel)
+        }
+    }
+
+    /// Every deck, in order
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..Self::MAX).map(Self)
+    }
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
 }
 
 impl Display for Deck {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Deck::A => write!(f, "A"),
-            Deck::B => write!(f, "B"),
-        }
+        write!(f, "{}", (b'A' + self.0) as char)
     }
 }
 
-impl Deck {
-    pub fn new(deck: u8) -> Result<Self, PlaybackError> {
-        match deck {
-            0 => Ok(Self::A),
-            1 => Ok(Self::B),
+impl FromStr for Deck {
+    type Err = PlaybackError;
+
+    /// Parse a deck letter, in either case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [letter] if letter.is_ascii_alphabetic() => {
+                Self::new(letter.to_ascii_uppercase() - b'A')
+            }
             _ => Err(PlaybackError::InvalidChannel),
         }
     }
 }
 
+impl TryFrom<String> for Deck {
+    type Error = PlaybackError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Deck> for String {
+    fn from(deck: Deck) -> Self {
+        deck.to_string()
+    }
+}
+
+/// Identifies a sampler pad, counted from 0
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Pad(u8);
+
+impl Pad {
+    /// Size of the sampler bank
+    pub const MAX: u8 = 16;
+
+    pub fn new(pad: u8) -> Result<Self, PlaybackError> {
+        if pad < Self::MAX {
+            Ok(Self(pad))
+        } else {
+            Err(PlaybackError::InvalidPad)
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl Display for Pad {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl TryFrom<u8> for Pad {
+    type Error = PlaybackError;
+
+    fn try_from(pad: u8) -> Result<Self, Self::Error> {
+        Self::new(pad)
+    }
+}
+
+impl From<Pad> for u8 {
+    fn from(pad: Pad) -> Self {
+        pad.0
+    }
+}
+
+/// How a pad responds to being triggered and released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PadMode {
+    /// Plays the whole clip once, releasing does nothing
+    OneShot,
+    /// Plays while held, stops on release
+    Gated,
+    /// Repeats until stopped
+    Loop,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,9 +247,32 @@ mod tests {
             assert!(matches!(Deck::new(1), Ok(Deck::B)));
         }
 
+        #[test]
+        fn creates_channel_d() {
+            assert!(matches!(Deck::new(3), Ok(Deck::D)));
+        }
+
         #[test]
         fn rejects_invalid_channel() {
-            assert!(matches!(Deck::new(2), Err(PlaybackError::InvalidChannel)));
+            assert!(matches!(
+                Deck::new(Deck::MAX),
+                Err(PlaybackError::InvalidChannel)
+            ));
+        }
+
+        #[test]
+        fn parses_letters() {
+            assert_eq!("a".parse::<Deck>().unwrap(), Deck::A);
+            assert_eq!("C".parse::<Deck>().unwrap(), Deck::C);
+            assert!("E".parse::<Deck>().is_err());
+            assert!("AB".parse::<Deck>().is_err());
+            assert!("1".parse::<Deck>().is_err());
+        }
+
+        #[test]
+        fn lists_all_decks() {
+            let names: Vec<String> = Deck::all().map(|deck| deck.to_string()).collect();
+            assert_eq!(names, ["A", "B", "C", "D"]);
         }
 
         #[test]
@@ -155,5 +282,36 @@ mod tests {
             let decoded: Deck = serde_json::from_str(&json).unwrap();
             assert_eq!(channel, decoded);
         }
+
+        #[test]
+        fn serializes_as_letter() {
+            // Same as the old `enum Deck { A, B }`
+            assert_eq!(serde_json::to_string(&Deck::B).unwrap(), "\"B\"");
+            let decoded: Deck = serde_json::from_str("\"D\"").unwrap();
+            assert_eq!(decoded, Deck::D);
+            assert!(serde_json::from_str::<Deck>("\"Z\"").is_err());
+        }
+    }
+
+    mod pad_tests {
+        use super::*;
+
+        #[test]
+        fn rejects_pads_past_the_bank() {
+            assert!(Pad::new(Pad::MAX - 1).is_ok());
+            assert!(matches!(Pad::new(Pad::MAX), Err(PlaybackError::InvalidPad)));
+        }
+
+        #[test]
+        fn test_serialization() {
+            let pad = Pad::new(3).unwrap();
+            assert_eq!(serde_json::to_string(&pad).unwrap(), "3");
+            assert_eq!(serde_json::from_str::<Pad>("3").unwrap(), pad);
+            assert!(serde_json::from_str::<Pad>("16").is_err());
+            assert_eq!(
+                serde_json::to_string(&PadMode::OneShot).unwrap(),
+                "\"one_shot\""
+            );
+        }
     }
 }
@@ -0,0 +1,339 @@
+use crate::{Ppqn, Ticks, TimeError};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// Beats to the bar and the note that gets a beat, 4/4 unless told otherwise
+///
+/// Serializes as it is written, "6/8".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeSignature {
+    beats: u8,
+    unit: u8,
+}
+
+impl TimeSignature {
+    pub const COMMON: Self = Self { beats: 4, unit: 4 };
+
+    /// `beats` of 1/`unit` notes to the bar, the unit a power of two up to 32
+    pub fn new(beats: u8, unit: u8) -> Result<Self, TimeError> {
+        if beats == 0 || !unit.is_power_of_two() || unit > 32 {
+            return Err(TimeError::InvalidTimeSignature { beats, unit });
+        }
+        Ok(Self { beats, unit })
+    }
+
+    pub fn beats(&self) -> u8 {
+        self.beats
+    }
+
+    pub fn unit(&self) -> u8 {
+        self.unit
+    }
+
+    /// Ticks in one beat, a quarter note being `ppqn`
+    ///
+    /// The resolution has to split the beat evenly, a part of a tick left
+    /// over is dropped.
+    pub fn ticks_per_beat(&self, ppqn: Ppqn) -> Ticks {
+        Ticks::new(ppqn.raw() as u64 * 4 / self.unit as u64)
+    }
+
+    pub fn ticks_per_bar(&self, ppqn: Ppqn) -> Ticks {
+        Ticks::new(self.ticks_per_beat(ppqn).raw() * self.beats as u64)
+    }
+
+    /// Length of `bars` bars, for loops and quantising
+    pub fn bars(&self, bars: u32, ppqn: Ppqn) -> Ticks {
+        Ticks::new(self.ticks_per_bar(ppqn).raw() * bars as u64)
+    }
+
+    /// First boundary of every `bars` bars from tick 0, at or after `ticks`
+    pub fn next_boundary(&self, ticks: Ticks, bars: u32, ppqn: Ppqn) -> Ticks {
+        let every = self.bars(bars.max(1), ppqn).raw().max(1);
+        Ticks::new(ticks.raw().div_ceil(every) * every)
+    }
+
+    /// Where the next phrase starts, `ticks` itself when a phrase starts there
+    pub fn next_phrase(&self, ticks: Ticks, phrase: Phrase, ppqn: Ppqn) -> Ticks {
+        self.next_boundary(ticks, phrase.bars(), ppqn)
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self::COMMON
+    }
+}
+
+impl Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.beats, self.unit)
+    }
+}
+
+impl FromStr for TimeSignature {
+    type Err = TimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TimeError::UnreadableTimeSignature(s.to_string());
+        let (beats, unit) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let beats = beats.parse().map_err(|_| invalid())?;
+        let unit = unit.parse().map_err(|_| invalid())?;
+        Self::new(beats, unit)
+    }
+}
+
+impl TryFrom<String> for TimeSignature {
+    type Error = TimeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeSignature> for String {
+    fn from(signature: TimeSignature) -> Self {
+        signature.to_string()
+    }
+}
+
+/// Run of bars a DJ mixes in, tracks are built out of these
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Phrase {
+    Eight,
+    Sixteen,
+    ThirtyTwo,
+}
+
+impl Phrase {
+    pub fn bars(&self) -> u32 {
+        match self {
+            Phrase::Eight => 8,
+            Phrase::Sixteen => 16,
+            Phrase::ThirtyTwo => 32,
+        }
+    }
+}
+
+/// Position as bar, beat and tick into the beat, written "17.3.480"
+///
+/// Bars and beats count from 1 the way they are read off a display, ticks
+/// from 0. Serializes as it is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BarBeatTick {
+    bar: u32,
+    beat: u32,
+    tick: u32,
+}
+
+impl BarBeatTick {
+    pub const START: Self = Self {
+        bar: 1,
+        beat: 1,
+        tick: 0,
+    };
+
+    pub fn new(bar: u32, beat: u32, tick: u32) -> Result<Self, TimeError> {
+        if bar == 0 || beat == 0 {
+            return Err(TimeError::InvalidPosition(format!("{bar}.{beat}.{tick}")));
+        }
+        Ok(Self { bar, beat, tick })
+    }
+
+    pub fn bar(&self) -> u32 {
+        self.bar
+    }
+
+    pub fn beat(&self) -> u32 {
+        self.beat
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    /// Where `ticks` falls in bars of `signature`
+    pub fn from_ticks(ticks: Ticks, signature: TimeSignature, ppqn: Ppqn) -> Self {
+        let per_beat = signature.ticks_per_beat(ppqn).raw().max(1);
+        let beats = ticks.raw() / per_beat;
+        Self {
+            bar: (beats / signature.beats as u64) as u32 + 1,
+            beat: (beats % signature.beats as u64) as u32 + 1,
+            tick: (ticks.raw() % per_beat) as u32,
+        }
+    }
+
+    /// Ticks from the start, beats and ticks past the end of their bar or beat carry over
+    pub fn to_ticks(&self, signature: TimeSignature, ppqn: Ppqn) -> Ticks {
+        let bars = signature.bars(self.bar - 1, ppqn).raw();
+        let beats = signature.ticks_per_beat(ppqn).raw() * (self.beat - 1) as u64;
+        Ticks::new(bars + beats + self.tick as u64)
+    }
+}
+
+impl Display for BarBeatTick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.bar, self.beat, self.tick)
+    }
+}
+
+impl FromStr for BarBeatTick {
+    type Err = TimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TimeError::InvalidPosition(s.to_string());
+        let mut parts = s.trim().split('.').map(|part| part.parse::<u32>());
+        let (Some(Ok(bar)), Some(Ok(beat)), Some(Ok(tick)), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        Self::new(bar, beat, tick)
+    }
+}
+
+impl TryFrom<String> for BarBeatTick {
+    type Error = TimeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BarBeatTick> for String {
+    fn from(position: BarBeatTick) -> Self {
+        position.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn signature(beats: u8, unit: u8) -> TimeSignature {
+        TimeSignature::new(beats, unit).unwrap()
+    }
+
+    #[test]
+    fn signatures_need_beats_and_a_note_value() {
+        assert!(TimeSignature::new(0, 4).is_err());
+        assert!(TimeSignature::new(4, 3).is_err());
+        assert!(TimeSignature::new(4, 64).is_err());
+        assert_eq!("6/8".parse::<TimeSignature>().unwrap(), signature(6, 8));
+        assert!("6-8".parse::<TimeSignature>().is_err());
+        assert_eq!(TimeSignature::default().to_string(), "4/4");
+    }
+
+    #[test]
+    fn beats_follow_the_note_value() {
+        assert_eq!(
+            TimeSignature::COMMON.ticks_per_beat(Ppqn::DEFAULT),
+            Ticks::new(960)
+        );
+        assert_eq!(
+            TimeSignature::COMMON.ticks_per_bar(Ppqn::DEFAULT),
+            Ticks::new(3840)
+        );
+        assert_eq!(
+            signature(6, 8).ticks_per_beat(Ppqn::DEFAULT),
+            Ticks::new(480)
+        );
+        assert_eq!(
+            signature(6, 8).ticks_per_bar(Ppqn::DEFAULT),
+            Ticks::new(2880)
+        );
+        assert_eq!(
+            signature(3, 4).bars(8, Ppqn::DEFAULT),
+            Ticks::new(8 * 3 * 960)
+        );
+    }
+
+    #[test]
+    fn positions_count_bars_and_beats_from_one() {
+        let bar = 3840;
+        let position = BarBeatTick::from_ticks(
+            Ticks::new(16 * bar + 2 * 960 + 480),
+            TimeSignature::COMMON,
+            Ppqn::DEFAULT,
+        );
+        assert_eq!(position, BarBeatTick::new(17, 3, 480).unwrap());
+        assert_eq!(position.to_string(), "17.3.480");
+        assert_eq!(
+            BarBeatTick::from_ticks(Ticks::ZERO, TimeSignature::COMMON, Ppqn::DEFAULT),
+            BarBeatTick::START
+        );
+
+        // Beat 5 of a bar of four is the next bar
+        let past = BarBeatTick::new(1, 5, 0).unwrap();
+        assert_eq!(
+            past.to_ticks(TimeSignature::COMMON, Ppqn::DEFAULT),
+            Ticks::new(bar)
+        );
+    }
+
+    #[test]
+    fn positions_parse_as_they_are_shown() {
+        let position: BarBeatTick = "17.3.480".parse().unwrap();
+        assert_eq!(
+            (position.bar(), position.beat(), position.tick()),
+            (17, 3, 480)
+        );
+        for bad in ["17.3", "17.3.480.1", "0.1.0", "1.0.0", "a.b.c", ""] {
+            assert!(bad.parse::<BarBeatTick>().is_err(), "{bad} parsed");
+        }
+    }
+
+    #[test]
+    fn phrases_start_on_their_boundary() {
+        let bar = TimeSignature::COMMON.ticks_per_bar(Ppqn::DEFAULT).raw();
+        let common = TimeSignature::COMMON;
+        let next = |ticks, phrase| common.next_phrase(Ticks::new(ticks), phrase, Ppqn::DEFAULT);
+
+        assert_eq!(next(0, Phrase::Eight), Ticks::ZERO);
+        assert_eq!(next(1, Phrase::Eight), Ticks::new(8 * bar));
+        assert_eq!(next(8 * bar, Phrase::Sixteen), Ticks::new(16 * bar));
+        assert_eq!(next(16 * bar, Phrase::Sixteen), Ticks::new(16 * bar));
+        assert_eq!(next(17 * bar, Phrase::ThirtyTwo), Ticks::new(32 * bar));
+    }
+
+    #[test]
+    fn positions_and_signatures_round_trip_through_json() {
+        let position = BarBeatTick::new(17, 3, 480).unwrap();
+        let json = serde_json::to_string(&position).unwrap();
+        assert_eq!(json, r#""17.3.480""#);
+        assert_eq!(
+            serde_json::from_str::<BarBeatTick>(&json).unwrap(),
+            position
+        );
+        assert!(serde_json::from_str::<BarBeatTick>(r#""0.0.0""#).is_err());
+
+        let json = serde_json::to_string(&signature(7, 8)).unwrap();
+        assert_eq!(json, r#""7/8""#);
+        assert_eq!(
+            serde_json::from_str::<TimeSignature>(&json).unwrap(),
+            signature(7, 8)
+        );
+    }
+
+    fn signatures() -> impl Strategy<Value = TimeSignature> {
+        (1u8..=16, 0u32..=5).prop_map(|(beats, unit)| signature(beats, 1 << unit))
+    }
+
+    proptest! {
+        #[test]
+        fn ticks_come_back_from_their_position(
+            signature in signatures(),
+            ticks in 0u64..100_000_000,
+        ) {
+            let ticks = Ticks::new(ticks);
+            let position = BarBeatTick::from_ticks(ticks, signature, Ppqn::DEFAULT);
+            prop_assert!(position.beat() <= signature.beats() as u32);
+            prop_assert_eq!(position.to_ticks(signature, Ppqn::DEFAULT), ticks);
+            prop_assert_eq!(position.to_string().parse::<BarBeatTick>().unwrap(), position);
+        }
+    }
+}
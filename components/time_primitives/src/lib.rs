@@ -1,13 +1,27 @@
 use std::ops::{Add, Sub};
+use std::time::Duration;
 use thiserror::Error;
 use serde::{Serialize, Deserialize};
 
+mod bars;
+mod tempo_map;
+pub use bars::{BarBeatTick, Phrase, TimeSignature};
+pub use tempo_map::{Curve, TempoMap, TempoPoint};
+
 #[derive(Debug, Error)]
 pub enum TimeError {
     #[error("PPQN cannot be zero")]
     ZeroPpqn,
     #[error("Tempo must be between {min} and {max} BPM")]
     TempoOutOfRange { min: f64, max: f64, value: f64 },
+    #[error("Tempo map must start at tick 0 with its points in order")]
+    UnorderedTempoMap,
+    #[error("{beats}/{unit} is not a time signature")]
+    InvalidTimeSignature { beats: u8, unit: u8 },
+    #[error("Can't read {0:?} as a time signature like 4/4")]
+    UnreadableTimeSignature(String),
+    #[error("Can't read {0:?} as bar.beat.tick, bars and beats count from 1")]
+    InvalidPosition(String),
 }
 
 /// Number of ticks in the musical timeline
@@ -24,6 +38,34 @@ impl Ticks {
     pub fn raw(&self) -> u64 {
         self.0
     }
+
+    /// Wall-clock time these ticks span at a constant tempo
+    pub fn to_duration(&self, tempo: Tempo, ppqn: Ppqn) -> Duration {
+        let beats = self.0 as f64 / ppqn.raw() as f64;
+        Duration::from_secs_f64(beats * 60.0 / tempo.raw())
+    }
+
+    /// Whole ticks in a number of beats
+    pub fn from_beats(beats: f64, ppqn: Ppqn) -> Self {
+        // Landing exactly on a tick reaches it, even when the float falls just short
+        Self((beats * ppqn.raw() as f64 + 1e-6) as u64)
+    }
+
+    /// Whole ticks that pass in `duration` at a constant tempo
+    pub fn from_duration(duration: Duration, tempo: Tempo, ppqn: Ppqn) -> Self {
+        Self::from_beats(duration.as_secs_f64() * tempo.raw() / 60.0, ppqn)
+    }
+
+    /// Sample frames these ticks span at `rate`, to the nearest frame
+    pub fn to_frames(&self, tempo: Tempo, ppqn: Ppqn, rate: u32) -> u64 {
+        let beats = self.0 as f64 / ppqn.raw() as f64;
+        (beats * 60.0 / tempo.raw() * rate as f64).round() as u64
+    }
+
+    /// Whole ticks that pass in `frames` at `rate`
+    pub fn from_frames(frames: u64, tempo: Tempo, ppqn: Ppqn, rate: u32) -> Self {
+        Self::from_beats(frames as f64 / rate as f64 * tempo.raw() / 60.0, ppqn)
+    }
 }
 
 impl Add for Ticks {
@@ -124,6 +166,57 @@ mod tests {
         assert_eq!(Tempo::DEFAULT.raw(), 120.0);
     }
 
+    #[test]
+    fn test_ticks_to_duration() {
+        let tempo = Tempo::new(120.0).unwrap();
+
+        // One beat at 120 BPM is half a second
+        assert_eq!(
+            Ticks::new(960).to_duration(tempo, Ppqn::DEFAULT),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            Ticks::new(4 * 24).to_duration(tempo, Ppqn::new(24).unwrap()),
+            Duration::from_secs(2)
+        );
+        assert_eq!(Ticks::ZERO.to_duration(tempo, Ppqn::DEFAULT), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_ticks_from_duration_and_frames() {
+        let tempo = Tempo::new(120.0).unwrap();
+
+        assert_eq!(
+            Ticks::from_duration(Duration::from_millis(500), tempo, Ppqn::DEFAULT),
+            Ticks::new(960)
+        );
+        // Part of a tick isn't one yet
+        assert_eq!(
+            Ticks::from_duration(Duration::from_micros(500), tempo, Ppqn::DEFAULT),
+            Ticks::ZERO
+        );
+
+        // A beat at 120 BPM is 24000 frames at 48 kHz
+        assert_eq!(
+            Ticks::new(960).to_frames(tempo, Ppqn::DEFAULT, 48000),
+            24000
+        );
+        assert_eq!(Ticks::new(1).to_frames(tempo, Ppqn::DEFAULT, 48000), 25);
+        assert_eq!(
+            Ticks::from_frames(24000, tempo, Ppqn::DEFAULT, 48000),
+            Ticks::new(960)
+        );
+        assert_eq!(
+            Ticks::from_frames(
+                44100,
+                Tempo::new(60.0).unwrap(),
+                Ppqn::new(24).unwrap(),
+                44100
+            ),
+            Ticks::new(24)
+        );
+    }
+
     #[test]
     fn test_serialization() {
         let ticks = Ticks::new(42);
@@ -0,0 +1,452 @@
+use crate::{Ppqn, Tempo, Ticks, TimeError};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How the tempo moves from a point until the next one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Curve {
+    /// Holds until the next point
+    Step,
+    /// Changes evenly with every beat, reaching this tempo at the next point
+    ///
+    /// With no point after it there is nowhere to ramp to, the tempo holds.
+    Ramp(Tempo),
+}
+
+/// Where the tempo changes, and how it goes on from there
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TempoPoint {
+    pub tick: Ticks,
+    pub tempo: Tempo,
+    pub curve: Curve,
+}
+
+impl TempoPoint {
+    pub fn step(tick: Ticks, tempo: Tempo) -> Self {
+        Self {
+            tick,
+            tempo,
+            curve: Curve::Step,
+        }
+    }
+
+    pub fn ramp(tick: Ticks, from: Tempo, to: Tempo) -> Self {
+        Self {
+            tick,
+            tempo: from,
+            curve: Curve::Ramp(to),
+        }
+    }
+}
+
+/// Tempo over the whole timeline, as steps and linear ramps
+///
+/// Ramps are linear in beats, so the time they take is worked out in
+/// closed form rather than summed tick by tick. Going from ticks to time
+/// and back gives the same ticks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Points")]
+pub struct TempoMap {
+    ppqn: Ppqn,
+    // Never empty, the first is at tick 0 and the rest follow in order
+    points: Vec<TempoPoint>,
+    // One for each point, worked out whenever the points change so reads
+    // only search them
+    #[serde(skip)]
+    segments: Vec<Segment>,
+}
+
+#[derive(Deserialize)]
+struct Points {
+    ppqn: Ppqn,
+    points: Vec<TempoPoint>,
+}
+
+impl TryFrom<Points> for TempoMap {
+    type Error = TimeError;
+
+    fn try_from(map: Points) -> Result<Self, TimeError> {
+        Self::from_points(map.ppqn, map.points)
+    }
+}
+
+// One stretch between points, in beats and seconds from the start
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    start_beat: f64,
+    start_seconds: f64,
+    // Infinite for the last one
+    beats: f64,
+    from: f64,
+    to: f64,
+}
+
+impl Segment {
+    // Change in tempo per beat, zero while it holds
+    fn slope(&self) -> f64 {
+        if self.beats.is_finite() {
+            (self.to - self.from) / self.beats
+        } else {
+            0.0
+        }
+    }
+
+    fn tempo_at(&self, beat: f64) -> f64 {
+        self.from + self.slope() * (beat - self.start_beat)
+    }
+
+    // Seconds from the start of the segment to `beat` in it
+    fn seconds_to(&self, beat: f64) -> f64 {
+        let beats = beat - self.start_beat;
+        let slope = self.slope();
+        if slope == 0.0 {
+            return beats * 60.0 / self.from;
+        }
+        // Integral of 60 / tempo over the beats, ln_1p keeps gentle ramps precise
+        60.0 / slope * (slope * beats / self.from).ln_1p()
+    }
+
+    // Beats from the start of the segment after `seconds` in it
+    fn beats_after(&self, seconds: f64) -> f64 {
+        let slope = self.slope();
+        if slope == 0.0 {
+            return seconds * self.from / 60.0;
+        }
+        self.from * (slope * seconds / 60.0).exp_m1() / slope
+    }
+
+    fn end_seconds(&self) -> f64 {
+        self.start_seconds + self.seconds_to(self.start_beat + self.beats)
+    }
+}
+
+impl TempoMap {
+    /// One tempo all the way through
+    pub fn new(ppqn: Ppqn, tempo: Tempo) -> Self {
+        Self::with_points(ppqn, vec![TempoPoint::step(Ticks::ZERO, tempo)])
+    }
+
+    /// Map from its points, which start at tick 0 and go forward
+    pub fn from_points(ppqn: Ppqn, points: Vec<TempoPoint>) -> Result<Self, TimeError> {
+        let starts_at_zero = points
+            .first()
+            .is_some_and(|first| first.tick == Ticks::ZERO);
+        let in_order = points.windows(2).all(|pair| pair[0].tick < pair[1].tick);
+        if !starts_at_zero || !in_order {
+            return Err(TimeError::UnorderedTempoMap);
+        }
+        Ok(Self::with_points(ppqn, points))
+    }
+
+    // Points already checked to be in order
+    fn with_points(ppqn: Ppqn, points: Vec<TempoPoint>) -> Self {
+        let mut map = Self {
+            ppqn,
+            points,
+            segments: Vec::new(),
+        };
+        map.update_segments();
+        map
+    }
+
+    pub fn ppqn(&self) -> Ppqn {
+        self.ppqn
+    }
+
+    pub fn points(&self) -> &[TempoPoint] {
+        &self.points
+    }
+
+    /// Add a change, replacing any already at the same tick
+    pub fn insert(&mut self, point: TempoPoint) {
+        match self.points.binary_search_by_key(&point.tick, |p| p.tick) {
+            Ok(index) => self.points[index] = point,
+            Err(index) => self.points.insert(index, point),
+        }
+        self.update_segments();
+    }
+
+    /// Drop every change after `ticks`, holding the tempo reached there
+    ///
+    /// A ramp running through `ticks` keeps its course up to it.
+    pub fn end_at(&mut self, ticks: Ticks) {
+        let tempo = self.tempo_at(ticks);
+        let index = self.points.partition_point(|point| point.tick <= ticks);
+        self.points.truncate(index);
+
+        let last = self.points.last_mut().expect("tempo map is never empty");
+        if let Curve::Ramp(to) = &mut last.curve {
+            *to = tempo;
+        }
+        self.insert(TempoPoint::step(ticks, tempo));
+    }
+
+    /// Forget the changes before `ticks`, as if the tempo there had held from tick 0
+    ///
+    /// Times before `ticks` are no longer what they were, from there on the
+    /// map runs its course as before. Keeps a map that only ever moves on
+    /// from growing with its past.
+    pub fn forget_before(&mut self, ticks: Ticks) {
+        if ticks == Ticks::ZERO {
+            return;
+        }
+        let index = self.points.partition_point(|point| point.tick <= ticks);
+        let in_force = self.points[index - 1];
+        let tempo = self.tempo_at(ticks);
+
+        let mut points = vec![TempoPoint::step(Ticks::ZERO, tempo)];
+        // The rest of a ramp still has the same beats to go
+        if let (Curve::Ramp(_), true) = (in_force.curve, index < self.points.len()) {
+            points.push(TempoPoint {
+                tick: ticks,
+                tempo,
+                curve: in_force.curve,
+            });
+        }
+        points.extend_from_slice(&self.points[index..]);
+        self.points = points;
+        self.update_segments();
+    }
+
+    /// Tempo right at `ticks`, part way along a ramp if it is in one
+    pub fn tempo_at(&self, ticks: Ticks) -> Tempo {
+        let beat = self.beats_of(ticks);
+        let segment = self.segment_at(|segment| segment.start_beat <= beat);
+        Tempo(segment.tempo_at(beat))
+    }
+
+    /// Time from tick 0 to `ticks`, rounded up to the nanosecond
+    ///
+    /// Rounding up keeps `ticks_at` of the result from falling a tick short.
+    pub fn time_of(&self, ticks: Ticks) -> Duration {
+        self.time_at_beats(self.beats_of(ticks))
+    }
+
+    /// Whole ticks reached `time` after tick 0
+    pub fn ticks_at(&self, time: Duration) -> Ticks {
+        Ticks::from_beats(self.beats_at(time), self.ppqn)
+    }
+
+    /// Beats from tick 0 to `time`, with how far into the current one
+    pub fn beats_at(&self, time: Duration) -> f64 {
+        let seconds = time.as_secs_f64();
+        let segment = self.segment_at(|segment| segment.start_seconds <= seconds);
+        segment.start_beat + segment.beats_after(seconds - segment.start_seconds)
+    }
+
+    /// Time from tick 0 until `beats` in, rounded up to the nanosecond
+    pub fn time_at_beats(&self, beats: f64) -> Duration {
+        let segment = self.segment_at(|segment| segment.start_beat <= beats);
+        let seconds = segment.start_seconds + segment.seconds_to(beats);
+        Duration::from_nanos((seconds.max(0.0) * 1e9).ceil() as u64)
+    }
+
+    fn beats_of(&self, ticks: Ticks) -> f64 {
+        ticks.raw() as f64 / self.ppqn.raw() as f64
+    }
+
+    // The last segment starting at or before what `started` looks for
+    fn segment_at(&self, started: impl Fn(&Segment) -> bool) -> &Segment {
+        let index = self.segments.partition_point(started);
+        &self.segments[index.saturating_sub(1)]
+    }
+
+    fn update_segments(&mut self) {
+        let mut start_seconds = 0.0;
+        let segments = self.points.iter().enumerate().map(|(index, point)| {
+            let start_beat = self.beats_of(point.tick);
+            let next = self.points.get(index + 1);
+            let beats = next.map_or(f64::INFINITY, |next| self.beats_of(next.tick) - start_beat);
+            let to = match (point.curve, next) {
+                (Curve::Ramp(to), Some(_)) => to.raw(),
+                _ => point.tempo.raw(),
+            };
+            let segment = Segment {
+                start_beat,
+                start_seconds,
+                beats,
+                from: point.tempo.raw(),
+                to,
+            };
+            start_seconds = segment.end_seconds();
+            segment
+        });
+        self.segments = segments.collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn bpm(bpm: f64) -> Tempo {
+        Tempo::new(bpm).unwrap()
+    }
+
+    // 120 for 8 beats, ramping to 128 over the next 8, then holding
+    fn drift() -> TempoMap {
+        let mut map = TempoMap::new(Ppqn::DEFAULT, bpm(120.0));
+        map.insert(TempoPoint::ramp(
+            Ticks::new(8 * 960),
+            bpm(120.0),
+            bpm(128.0),
+        ));
+        map.insert(TempoPoint::step(Ticks::new(16 * 960), bpm(128.0)));
+        map
+    }
+
+    #[test]
+    fn steps_hold_until_the_next_point() {
+        let mut map = TempoMap::new(Ppqn::DEFAULT, bpm(120.0));
+        map.insert(TempoPoint::step(Ticks::new(4 * 960), bpm(60.0)));
+
+        assert_eq!(map.time_of(Ticks::new(4 * 960)), Duration::from_secs(2));
+        assert_eq!(map.time_of(Ticks::new(5 * 960)), Duration::from_secs(3));
+        assert_eq!(
+            map.ticks_at(Duration::from_millis(2500)),
+            Ticks::new(4 * 960 + 480)
+        );
+        assert_eq!(map.tempo_at(Ticks::new(4 * 960 - 1)), bpm(120.0));
+        assert_eq!(map.tempo_at(Ticks::new(4 * 960)), bpm(60.0));
+    }
+
+    #[test]
+    fn ramps_change_tempo_evenly_with_the_beat() {
+        let map = drift();
+
+        assert_eq!(map.tempo_at(Ticks::new(12 * 960)), bpm(124.0));
+        assert_eq!(map.tempo_at(Ticks::new(40 * 960)), bpm(128.0));
+
+        // 8 beats from 120 to 128 take 60 / (1 BPM per beat) * ln(128 / 120) seconds
+        let ramp = 60.0 * (128.0f64 / 120.0).ln();
+        let expected = 4.0 + ramp;
+        let time = map.time_of(Ticks::new(16 * 960)).as_secs_f64();
+        assert!((time - expected).abs() < 1e-9, "{time} != {expected}");
+
+        // Quicker than holding 120, slower than jumping straight to 128
+        assert!(ramp < 4.0 && ramp > 8.0 * 60.0 / 128.0);
+    }
+
+    #[test]
+    fn a_ramp_with_nothing_after_it_holds() {
+        let mut map = TempoMap::new(Ppqn::DEFAULT, bpm(120.0));
+        map.insert(TempoPoint::ramp(Ticks::ZERO, bpm(100.0), bpm(140.0)));
+
+        assert_eq!(map.tempo_at(Ticks::new(100 * 960)), bpm(100.0));
+        assert_eq!(map.time_of(Ticks::new(100 * 960)), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn ending_a_ramp_keeps_it_on_course() {
+        let mut map = drift();
+        let before = map.time_of(Ticks::new(12 * 960));
+
+        map.end_at(Ticks::new(12 * 960));
+        assert_eq!(map.time_of(Ticks::new(12 * 960)), before);
+        assert_eq!(map.tempo_at(Ticks::new(12 * 960)), bpm(124.0));
+        assert_eq!(map.tempo_at(Ticks::new(20 * 960)), bpm(124.0));
+        assert_eq!(map.tempo_at(Ticks::new(10 * 960)), bpm(122.0));
+        assert_eq!(map.points().len(), 3);
+    }
+
+    #[test]
+    fn forgetting_the_past_keeps_the_course_ahead() {
+        let mut map = drift();
+        let gap = |map: &TempoMap, from: u64, to: u64| {
+            let to = map.time_of(Ticks::new(to * 960)).as_secs_f64();
+            to - map.time_of(Ticks::new(from * 960)).as_secs_f64()
+        };
+        let ahead = gap(&map, 12, 20);
+
+        map.forget_before(Ticks::new(12 * 960));
+        assert_eq!(map.points().len(), 3);
+        assert!((gap(&map, 12, 20) - ahead).abs() < 1e-8);
+        assert_eq!(map.tempo_at(Ticks::new(14 * 960)), bpm(126.0));
+        // Before it, the tempo reached there holds
+        assert_eq!(map.tempo_at(Ticks::new(960)), bpm(124.0));
+
+        // Past the last change, only the held tempo is left
+        map.forget_before(Ticks::new(40 * 960));
+        assert_eq!(map, TempoMap::new(Ppqn::DEFAULT, bpm(128.0)));
+    }
+
+    #[test]
+    fn maps_must_start_at_zero_and_go_forward() {
+        let late = vec![TempoPoint::step(Ticks::new(1), bpm(120.0))];
+        assert!(matches!(
+            TempoMap::from_points(Ppqn::DEFAULT, late),
+            Err(TimeError::UnorderedTempoMap)
+        ));
+        let backwards = vec![
+            TempoPoint::step(Ticks::ZERO, bpm(120.0)),
+            TempoPoint::step(Ticks::new(960), bpm(124.0)),
+            TempoPoint::step(Ticks::new(480), bpm(128.0)),
+        ];
+        assert!(TempoMap::from_points(Ppqn::DEFAULT, backwards).is_err());
+        assert!(TempoMap::from_points(Ppqn::DEFAULT, vec![]).is_err());
+    }
+
+    #[test]
+    fn maps_round_trip_through_json() {
+        let map = drift();
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(serde_json::from_str::<TempoMap>(&json).unwrap(), map);
+
+        let unordered = r#"{"ppqn":960,"points":[{"tick":960,"tempo":120.0,"curve":"Step"}]}"#;
+        assert!(serde_json::from_str::<TempoMap>(unordered).is_err());
+    }
+
+    fn tempo_maps() -> impl Strategy<Value = TempoMap> {
+        let point = (
+            1u64..100_000,
+            20.0f64..=400.0,
+            20.0f64..=400.0,
+            any::<bool>(),
+        );
+        (
+            1u32..10_000,
+            20.0f64..=400.0,
+            prop::collection::vec(point, 0..8),
+        )
+            .prop_map(|(ppqn, first, points)| {
+                let mut map = TempoMap::new(Ppqn::new(ppqn).unwrap(), bpm(first));
+                let mut tick = 0;
+                for (gap, from, to, ramp) in points {
+                    tick += gap;
+                    let point = if ramp {
+                        TempoPoint::ramp(Ticks::new(tick), bpm(from), bpm(to))
+                    } else {
+                        TempoPoint::step(Ticks::new(tick), bpm(from))
+                    };
+                    map.insert(point);
+                }
+                map
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn ticks_come_back_from_their_time(map in tempo_maps(), ticks in 0u64..2_000_000) {
+            let ticks = Ticks::new(ticks);
+            prop_assert_eq!(map.ticks_at(map.time_of(ticks)), ticks);
+        }
+
+        #[test]
+        fn time_lands_within_a_tick(map in tempo_maps(), nanos in 0u64..600_000_000_000) {
+            let time = Duration::from_nanos(nanos);
+            let ticks = map.ticks_at(time);
+            let tick_start = map.time_of(ticks);
+            let tick_end = map.time_of(ticks + Ticks::new(1));
+            // Times a hair before a tick count as reaching it
+            prop_assert!(tick_start <= time + Duration::from_micros(10));
+            prop_assert!(time < tick_end);
+        }
+
+        #[test]
+        fn later_ticks_come_later(map in tempo_maps(), ticks in 0u64..2_000_000) {
+            let ticks = Ticks::new(ticks);
+            prop_assert!(map.time_of(ticks) < map.time_of(ticks + Ticks::new(1)));
+        }
+    }
+}
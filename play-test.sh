@@ -21,15 +21,17 @@ cleanup
 target/release/playback-server &
 SERVER_PID=$!
 
+# Index the library so the track can be found by query
+target/release/library-crawler ~/music --write-facts /tmp/mdma-facts.jsonl
+
 # Wait a moment for the server to start
 sleep 2
 
 # Play the downloaded song
 target/release/media-ctl load \
-    --library ~/music \
-    --artist "Rick Astley" \
-    --song "Rick Astley - Never Gonna Give You Up (Official Music Video)" \
-    --channel A
+    --facts /tmp/mdma-facts.jsonl \
+    --channel A \
+    rick astley never gonna give you up
 
 target/release/media-ctl play --channel A
 